@@ -0,0 +1,58 @@
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use mqtt_broker::{
+    core::enums::ProtocalVersion,
+    packets::{enums::QosLevel, Packet, PublishProperties},
+};
+
+fn publish(protocol: ProtocalVersion) -> Bytes {
+    Packet::make_publish(
+        false,
+        QosLevel::AtLeast,
+        false,
+        "sensors/building-1/floor-2/temperature".into(),
+        Some(1),
+        Bytes::from(vec![0u8; 1024]),
+        PublishProperties::default(),
+        Vec::new(),
+        protocol,
+    )
+}
+
+fn bench_unpack(c: &mut Criterion) {
+    for (name, protocol) in [
+        ("unpack publish v4", ProtocalVersion::Four),
+        ("unpack publish v5", ProtocalVersion::Five),
+    ] {
+        let bytes = publish(protocol);
+        c.bench_function(name, |b| {
+            b.iter_batched(
+                || bytes.clone(),
+                |mut bytes| Packet::unpack(&mut bytes, protocol).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+fn bench_pack(c: &mut Criterion) {
+    let payload = Bytes::from(vec![0u8; 1024]);
+    c.bench_function("pack publish", |b| {
+        b.iter(|| {
+            Packet::make_publish(
+                false,
+                QosLevel::AtLeast,
+                false,
+                "sensors/building-1/floor-2/temperature".into(),
+                Some(1),
+                payload.clone(),
+                PublishProperties::default(),
+                Vec::new(),
+                ProtocalVersion::Four,
+            )
+        })
+    });
+}
+
+criterion_group!(benches, bench_unpack, bench_pack);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use mqtt_broker::{
+    core::enums::{ClientEvent, ProtocalVersion},
+    packets::enums::QosLevel,
+    topic_heir::{SubscriptionLeaf, SubscriptionTree},
+};
+
+/// 10,000 clients each subscribed to their own topic, a wildcard filter and a shared wildcard filter
+fn tree() -> SubscriptionTree {
+    let (tx, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+    let mut tree = SubscriptionTree::new();
+
+    for id in 0..10_000u128 {
+        let filters = [
+            format!("sensors/{}/temperature", id),
+            format!("sensors/{}/+", id % 100),
+            "sensors/#".to_string(),
+        ];
+        for filter in filters {
+            let leaf = SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                id,
+                tx.clone(),
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            );
+            tree.insert(&filter, leaf).unwrap();
+        }
+    }
+
+    tree
+}
+
+fn bench_get(c: &mut Criterion) {
+    let tree = tree();
+
+    c.bench_function("subscription tree get", |b| {
+        b.iter(|| tree.get("sensors/42/temperature").unwrap())
+    });
+}
+
+criterion_group!(benches, bench_get);
+criterion_main!(benches);
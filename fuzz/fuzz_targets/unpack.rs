@@ -0,0 +1,17 @@
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use mqtt_broker::{core::enums::ProtocalVersion, packets::Packet};
+
+// Malformed packets must be rejected with an error, never a panic
+fuzz_target!(|data: &[u8]| {
+    for protocol in [ProtocalVersion::Four, ProtocalVersion::Five] {
+        let mut bytes = Bytes::copy_from_slice(data);
+        while !bytes.is_empty() {
+            if Packet::unpack(&mut bytes, protocol).is_err() {
+                break;
+            }
+        }
+    }
+});
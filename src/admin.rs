@@ -0,0 +1,639 @@
+//! ### Admin API
+//! A small HTTP API for inspecting a running broker, enabled with the `admin_listener` option.
+//!
+//! | Method | Path | |
+//! | - | - | - |
+//! | GET | `/clients` | List client sessions |
+//! | GET | `/clients/{client_id}` | Connection details of a client |
+//! | GET | `/clients/{client_id}/subscriptions` | Topic filters of a client |
+//! | DELETE | `/clients/{client_id}` | Disconnect a client and remove its session, an optional body `{"reason": 152}` sets the reason code sent to MQTT 5 clients |
+//! | DELETE | `/clients/{client_id}/subscriptions` | Remove a subscription of a client, body is `{"topic": "..."}` |
+//! | GET | `/bans` | Addresses banned for failing authentication or flooding CONNECT packets |
+//! | DELETE | `/bans/{address}` | Lift the ban on an address |
+//! | GET | `/trace` | Client ids and topic filters being traced |
+//! | POST | `/trace` | Log the packets of a client or topic, body is `{"client_id": "..."}` or `{"topic": "..."}` |
+//! | DELETE | `/trace` | Stop tracing, with the same body as `POST /trace` |
+//! | GET | `/retained` | Number and size of the retained messages, and the messages evicted or rejected by the retained limits |
+//! | GET | `/retained/messages?filter={filter}` | Topics with a retained message matching the topic filter, `#` if not set |
+//! | GET | `/retained/messages/{topic}` | Payload of the retained message of a topic, with its Content Type |
+//! | DELETE | `/retained/messages/{topic}` | Remove the retained message of a topic |
+//! | DELETE | `/retained/messages?filter={filter}` | Remove the retained messages matching the topic filter, responds with how many were removed |
+//! | GET | `/subscriptions` | Size of the subscription tree and subscriptions by the first level of their topic filter |
+//! | GET | `/latency` | Histogram of the time from a PUBLISH being received to it being queued for each subscriber |
+//! | POST | `/publish` | Publish a message, body is `{"topic": "...", "payload": "...", "qos": 0}`, `qos` is optional |
+//! | GET | `/log/level` | Maximum log level, like `{"level": "info"}` |
+//! | PUT | `/log/level` | Change the log level without restarting, body is `{"level": "debug"}` |
+//! | GET | `/healthz` | 200 while the command loop responds, 503 if it is stuck |
+//! | GET | `/readyz` | 200 while the broker is responsive and accepting connections, 503 otherwise |
+//!
+//! The `health_listener` option serves only `/healthz` and `/readyz`,
+//! so Kubernetes can probe the broker without exposing the rest of the API.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::UNIX_EPOCH,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, level_filters::LevelFilter};
+
+use crate::{
+    core::{broker_info, ClientInfo},
+    error::MqttError,
+    packets::{
+        enums::{DisconnectReasonCode, QosLevel},
+        topic,
+    },
+    Ban, BrokerHandle, Health, RetainedMessage, TraceFilter,
+};
+
+#[derive(Debug, Serialize)]
+struct ClientResponse {
+    client_id: String,
+    protocol_version: u8,
+    subscriptions: usize,
+    /// `null` for in process clients and bridges
+    address: Option<String>,
+    keepalive: Option<u16>,
+    /// Unix timestamp in seconds
+    connected_at: u64,
+    connected: bool,
+    /// Unix timestamp in seconds of the last packet from the client, `null` for in process clients and bridges
+    last_seen: Option<u64>,
+    inflight_outgoing: usize,
+    inflight_pending: usize,
+    inflight_incoming: usize,
+}
+
+impl From<ClientInfo> for ClientResponse {
+    fn from(client: ClientInfo) -> Self {
+        Self {
+            client_id: client.client_id,
+            protocol_version: client.protocol.into(),
+            subscriptions: client.subscriptions,
+            address: client.addr.map(|addr| addr.to_string()),
+            keepalive: client.keepalive,
+            connected_at: client
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            connected: client.connected,
+            last_seen: client.last_seen.map(|last_seen| {
+                last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            inflight_outgoing: client.inflight_outgoing,
+            inflight_pending: client.inflight_pending,
+            inflight_incoming: client.inflight_incoming,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SubscriptionResponse {
+    topic: String,
+    qos: u8,
+}
+
+/// Reason code sent to MQTT 5 clients, Administrative Action (0x98) if not given
+#[derive(Debug, Deserialize)]
+struct DisconnectRequest {
+    reason: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnsubscribeRequest {
+    topic: String,
+}
+
+#[derive(Debug, Serialize)]
+struct BanResponse {
+    address: String,
+    reason: &'static str,
+    /// Seconds until the ban is lifted
+    remaining: u64,
+    /// Times the address has been banned
+    count: u32,
+}
+
+impl From<Ban> for BanResponse {
+    fn from(ban: Ban) -> Self {
+        Self {
+            address: ban.addr.to_string(),
+            reason: ban.reason.as_str(),
+            remaining: ban.remaining.as_secs(),
+            count: ban.count,
+        }
+    }
+}
+
+/// Either `client_id` or `topic` is set
+#[derive(Debug, Serialize, Deserialize)]
+struct TraceRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+}
+
+impl From<TraceFilter> for TraceRequest {
+    fn from(filter: TraceFilter) -> Self {
+        match filter {
+            TraceFilter::ClientId(client_id) => Self {
+                client_id: Some(client_id),
+                topic: None,
+            },
+            TraceFilter::Topic(topic) => Self {
+                client_id: None,
+                topic: Some(topic),
+            },
+        }
+    }
+}
+
+impl TryFrom<TraceRequest> for TraceFilter {
+    type Error = StatusCode;
+
+    fn try_from(request: TraceRequest) -> Result<Self, Self::Error> {
+        match (request.client_id, request.topic) {
+            (Some(client_id), None) => Ok(Self::ClientId(client_id)),
+            (None, Some(topic)) => Ok(Self::Topic(topic)),
+            _ => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RetainedResponse {
+    count: usize,
+    bytes: usize,
+    evicted: usize,
+    rejected: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct RetainedQuery {
+    filter: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RetainedMessageResponse {
+    topic: String,
+    qos: u8,
+    /// Size of the payload
+    bytes: usize,
+    content_type: Option<String>,
+}
+
+impl From<(String, RetainedMessage)> for RetainedMessageResponse {
+    fn from((topic, message): (String, RetainedMessage)) -> Self {
+        Self {
+            topic,
+            qos: message.qos as u8,
+            bytes: message.payload.len(),
+            content_type: message.properties.content_type,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RemovedResponse {
+    removed: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct SubscriptionStatsResponse {
+    /// Levels of topic filters in the subscription tree
+    nodes: usize,
+    subscriptions: usize,
+    first_levels: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct LatencyBucket {
+    /// Upper bound in microseconds, `null` for the last bucket
+    le: Option<u64>,
+    count: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct LatencyResponse {
+    count: usize,
+    /// Sum of all latencies in microseconds
+    sum: u64,
+    buckets: Vec<LatencyBucket>,
+}
+
+/// One of `off`, `error`, `warn`, `info`, `debug` or `trace`
+#[derive(Debug, Serialize, Deserialize)]
+struct LogLevel {
+    level: String,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    /// `ok` or `unavailable`
+    status: &'static str,
+    responsive: bool,
+    listeners: usize,
+    shutting_down: bool,
+}
+
+impl HealthResponse {
+    fn new(health: Health, ok: bool) -> (StatusCode, Json<Self>) {
+        let (code, status) = if ok {
+            (StatusCode::OK, "ok")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+        };
+
+        (
+            code,
+            Json(Self {
+                status,
+                responsive: health.responsive,
+                listeners: health.listeners,
+                shutting_down: health.shutting_down,
+            }),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishRequest {
+    topic: String,
+    payload: String,
+    #[serde(default)]
+    qos: u8,
+}
+
+/// Serve the admin API until the cancellation token is triggered
+pub async fn run_admin(
+    addr: SocketAddr,
+    handle: BrokerHandle,
+    cancellation: CancellationToken,
+) -> Result<(), MqttError> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Starting admin API at: {}", addr);
+
+    axum::serve(listener, router(handle))
+        .with_graceful_shutdown(async move { cancellation.cancelled().await })
+        .await?;
+
+    Ok(())
+}
+
+/// Serve only the health probes until the cancellation token is triggered
+pub async fn run_health(
+    addr: SocketAddr,
+    handle: BrokerHandle,
+    cancellation: CancellationToken,
+) -> Result<(), MqttError> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Starting health probes at: {}", addr);
+
+    axum::serve(listener, health_router().with_state(handle))
+        .with_graceful_shutdown(async move { cancellation.cancelled().await })
+        .await?;
+
+    Ok(())
+}
+
+fn health_router() -> Router<BrokerHandle> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+fn router(handle: BrokerHandle) -> Router {
+    Router::new()
+        .route("/clients", get(list_clients))
+        .route(
+            "/clients/:client_id",
+            get(client_info).delete(disconnect_client),
+        )
+        .route(
+            "/clients/:client_id/subscriptions",
+            get(client_subscriptions).delete(unsubscribe_client),
+        )
+        .route("/bans", get(list_bans))
+        .route("/bans/:address", delete(unban))
+        .route(
+            "/trace",
+            get(list_traces).post(start_trace).delete(stop_trace),
+        )
+        .route("/retained", get(retained))
+        .route(
+            "/retained/messages",
+            get(list_retained).delete(remove_retained_matching),
+        )
+        .route(
+            "/retained/messages/*topic",
+            get(retained_message).delete(remove_retained),
+        )
+        .route("/subscriptions", get(subscription_stats))
+        .route("/latency", get(latency))
+        .route("/publish", post(publish))
+        .route("/log/level", get(log_level).put(set_log_level))
+        .merge(health_router())
+        .with_state(handle)
+}
+
+/// Invalid topics and QoS levels are reported as a 400 response, other errors as a 500 response
+struct ApiError(MqttError);
+
+impl From<MqttError> for ApiError {
+    fn from(value: MqttError) -> Self {
+        Self(value)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self.0 {
+            MqttError::TopicNameInvalid
+            | MqttError::TopicFilterInvalid
+            | MqttError::Convertion(..) => (StatusCode::BAD_REQUEST, self.0.to_string()),
+            err => {
+                error!("{}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+            }
+        }
+        .into_response()
+    }
+}
+
+async fn list_clients(
+    State(handle): State<BrokerHandle>,
+) -> Result<Json<Vec<ClientResponse>>, ApiError> {
+    let clients = handle
+        .clients()
+        .await?
+        .into_iter()
+        .map(ClientResponse::from)
+        .collect();
+
+    Ok(Json(clients))
+}
+
+async fn client_info(
+    State(handle): State<BrokerHandle>,
+    Path(client_id): Path<String>,
+) -> Result<Response, ApiError> {
+    match handle.client_info(client_id).await? {
+        Some(client) => Ok(Json(ClientResponse::from(client)).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+async fn client_subscriptions(
+    State(handle): State<BrokerHandle>,
+    Path(client_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let Some(subscriptions) = handle.client_subscriptions(client_id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let subscriptions = subscriptions
+        .into_iter()
+        .map(|(topic, qos)| SubscriptionResponse {
+            topic,
+            qos: qos as u8,
+        })
+        .collect::<Vec<SubscriptionResponse>>();
+
+    Ok(Json(subscriptions).into_response())
+}
+
+async fn disconnect_client(
+    State(handle): State<BrokerHandle>,
+    Path(client_id): Path<String>,
+    request: Option<Json<DisconnectRequest>>,
+) -> Result<StatusCode, ApiError> {
+    let reason = match request {
+        Some(Json(request)) => match DisconnectReasonCode::try_from(request.reason) {
+            Ok(reason) => reason,
+            Err(_) => return Ok(StatusCode::BAD_REQUEST),
+        },
+        None => DisconnectReasonCode::AdministrativeAction,
+    };
+
+    if handle
+        .disconnect_client_with_reason(client_id, reason)
+        .await?
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn unsubscribe_client(
+    State(handle): State<BrokerHandle>,
+    Path(client_id): Path<String>,
+    Json(request): Json<UnsubscribeRequest>,
+) -> Result<StatusCode, ApiError> {
+    if handle.unsubscribe_client(client_id, request.topic).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn list_bans(State(handle): State<BrokerHandle>) -> Json<Vec<BanResponse>> {
+    Json(handle.bans().into_iter().map(BanResponse::from).collect())
+}
+
+async fn unban(State(handle): State<BrokerHandle>, Path(address): Path<IpAddr>) -> StatusCode {
+    if handle.unban(address) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn list_traces(State(handle): State<BrokerHandle>) -> Json<Vec<TraceRequest>> {
+    Json(
+        handle
+            .traces()
+            .into_iter()
+            .map(TraceRequest::from)
+            .collect(),
+    )
+}
+
+async fn start_trace(
+    State(handle): State<BrokerHandle>,
+    Json(request): Json<TraceRequest>,
+) -> Result<StatusCode, ApiError> {
+    let Ok(filter) = TraceFilter::try_from(request) else {
+        return Ok(StatusCode::BAD_REQUEST);
+    };
+
+    if handle.trace(filter)? {
+        Ok(StatusCode::CREATED)
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+async fn stop_trace(
+    State(handle): State<BrokerHandle>,
+    Json(request): Json<TraceRequest>,
+) -> StatusCode {
+    match TraceFilter::try_from(request) {
+        Ok(filter) if handle.untrace(&filter) => StatusCode::NO_CONTENT,
+        Ok(_) => StatusCode::NOT_FOUND,
+        Err(code) => code,
+    }
+}
+
+async fn retained(State(handle): State<BrokerHandle>) -> Result<Json<RetainedResponse>, ApiError> {
+    let stats = handle.retained_stats().await?;
+    Ok(Json(RetainedResponse {
+        count: stats.messages,
+        bytes: stats.bytes,
+        evicted: stats.evicted,
+        rejected: stats.rejected,
+    }))
+}
+
+async fn list_retained(
+    State(handle): State<BrokerHandle>,
+    Query(query): Query<RetainedQuery>,
+) -> Result<Json<Vec<RetainedMessageResponse>>, ApiError> {
+    let filter = query.filter.unwrap_or_else(|| "#".into());
+    let messages = handle
+        .retained_messages(filter)
+        .await?
+        .into_iter()
+        .map(RetainedMessageResponse::from)
+        .collect();
+
+    Ok(Json(messages))
+}
+
+/// The payload is returned as it was published, with the Content Type of the message if it had a valid one
+async fn retained_message(
+    State(handle): State<BrokerHandle>,
+    Path(topic): Path<String>,
+) -> Result<Response, ApiError> {
+    let Some(message) = handle.retained_message(topic).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let content_type = message
+        .properties
+        .content_type
+        .and_then(|content_type| HeaderValue::from_str(&content_type).ok())
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    Ok(([(header::CONTENT_TYPE, content_type)], message.payload).into_response())
+}
+
+async fn remove_retained(
+    State(handle): State<BrokerHandle>,
+    Path(topic): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    // Only the exact topic, not every topic a wildcard in it would match
+    topic::validate_topic_name(&topic)?;
+    if handle.remove_retained(topic).await? > 0 {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+/// The filter is required so all retained messages aren't removed by mistake
+async fn remove_retained_matching(
+    State(handle): State<BrokerHandle>,
+    Query(query): Query<RetainedQuery>,
+) -> Result<Response, ApiError> {
+    let Some(filter) = query.filter else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let removed = handle.remove_retained(filter).await?;
+    Ok(Json(RemovedResponse { removed }).into_response())
+}
+
+async fn subscription_stats(
+    State(handle): State<BrokerHandle>,
+) -> Result<Json<SubscriptionStatsResponse>, ApiError> {
+    let stats = handle.subscription_stats().await?;
+    Ok(Json(SubscriptionStatsResponse {
+        nodes: stats.nodes,
+        subscriptions: stats.subscriptions,
+        first_levels: stats.first_levels,
+    }))
+}
+
+async fn latency() -> Json<LatencyResponse> {
+    let histogram = broker_info::delivery_latency();
+    Json(LatencyResponse {
+        count: histogram.count,
+        sum: histogram.sum,
+        buckets: histogram
+            .buckets
+            .into_iter()
+            .map(|(le, count)| LatencyBucket { le, count })
+            .collect(),
+    })
+}
+
+async fn publish(
+    State(handle): State<BrokerHandle>,
+    Json(request): Json<PublishRequest>,
+) -> Result<StatusCode, ApiError> {
+    let qos = QosLevel::try_from(request.qos)?;
+    handle
+        .publish(request.topic, Bytes::from(request.payload), qos)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// 404 if the program installed its own log subscriber
+async fn log_level(State(handle): State<BrokerHandle>) -> Result<Json<LogLevel>, StatusCode> {
+    let level = handle.log_level().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(LogLevel {
+        level: level.to_string().to_lowercase(),
+    }))
+}
+
+async fn set_log_level(
+    State(handle): State<BrokerHandle>,
+    Json(request): Json<LogLevel>,
+) -> StatusCode {
+    let Ok(level) = request.level.parse::<LevelFilter>() else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    if handle.set_log_level(level) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn healthz(State(handle): State<BrokerHandle>) -> (StatusCode, Json<HealthResponse>) {
+    let health = handle.health().await;
+    HealthResponse::new(health, health.is_live())
+}
+
+async fn readyz(State(handle): State<BrokerHandle>) -> (StatusCode, Json<HealthResponse>) {
+    let health = handle.health().await;
+    HealthResponse::new(health, health.is_ready())
+}
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tracing::warn;
+
+use super::{Authenticator, Credentials};
+use crate::{error::MqttError, hooks::HookFuture};
+
+/// Options of the [`HttpAuthenticator`], set with `auth_http_url` and `auth_http_timeout`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpAuthConfig {
+    /// Endpoint the credentials are posted to
+    pub url: String,
+    /// Longest to wait for the endpoint before the client is refused
+    pub timeout: Duration,
+}
+
+/// Body of the request to the auth endpoint
+#[derive(Debug, Serialize)]
+struct AuthRequest<'a> {
+    clientid: &'a str,
+    username: Option<&'a str>,
+    password: Option<&'a str>,
+}
+
+/// ### HTTP Authenticator
+/// Checks the credentials of a client with an external HTTP service.
+///
+/// The client id, username and password are posted as JSON, like
+/// `{"clientid": "sensor-1", "username": "sensor", "password": "secret"}`,
+/// and the client is accepted when the endpoint answers with a 2xx status.
+/// Any other status, an unreachable endpoint or a timeout refuses the client.
+/// Clients without a username or password are accepted with `allow_anonymous`, without asking the endpoint.
+#[derive(Debug, Clone)]
+pub struct HttpAuthenticator {
+    client: reqwest::Client,
+    url: String,
+    allow_anonymous: bool,
+}
+
+impl HttpAuthenticator {
+    pub fn new(config: &HttpAuthConfig, allow_anonymous: bool) -> Result<Self, MqttError> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .map_err(|err| MqttError::InvalidConfig(format!("auth_http_url: {}", err)))?;
+
+        Ok(Self {
+            client,
+            url: config.url.clone(),
+            allow_anonymous,
+        })
+    }
+
+    async fn check(&self, credentials: Credentials<'_>) -> bool {
+        if credentials.username.is_none() && credentials.password.is_none() {
+            return self.allow_anonymous;
+        }
+
+        let request = AuthRequest {
+            clientid: credentials.client_id,
+            username: credentials.username,
+            password: credentials.password,
+        };
+        match self.client.post(&self.url).json(&request).send().await {
+            Ok(response) => response.status().is_success(),
+            Err(err) => {
+                warn!("Auth endpoint {} failed: {}", self.url, err);
+                false
+            }
+        }
+    }
+}
+
+impl Authenticator for HttpAuthenticator {
+    fn authenticate<'a>(&'a self, credentials: Credentials<'a>) -> HookFuture<'a, bool> {
+        Box::pin(self.check(credentials))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, routing::post, Json, Router};
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Body {
+        username: Option<String>,
+        password: Option<String>,
+    }
+
+    async fn auth(Json(body): Json<Body>) -> StatusCode {
+        match (body.username.as_deref(), body.password.as_deref()) {
+            (Some("device-1"), Some("secret")) => StatusCode::OK,
+            _ => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn credentials<'a>(username: Option<&'a str>, password: Option<&'a str>) -> Credentials<'a> {
+        Credentials {
+            client_id: "client",
+            username,
+            password,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_authenticator() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, Router::new().route("/auth", post(auth)))
+                .await
+                .unwrap()
+        });
+
+        let config = HttpAuthConfig {
+            url: format!("http://{}/auth", addr),
+            timeout: Duration::from_secs(5),
+        };
+        let auth = HttpAuthenticator::new(&config, false).unwrap();
+        assert!(
+            auth.authenticate(credentials(Some("device-1"), Some("secret")))
+                .await
+        );
+        assert!(
+            !auth
+                .authenticate(credentials(Some("device-1"), Some("wrong")))
+                .await
+        );
+        assert!(!auth.authenticate(credentials(None, None)).await);
+
+        // Nothing listening
+        let config = HttpAuthConfig {
+            url: "http://127.0.0.1:1/auth".into(),
+            timeout: Duration::from_secs(1),
+        };
+        let auth = HttpAuthenticator::new(&config, true).unwrap();
+        assert!(
+            !auth
+                .authenticate(credentials(Some("device-1"), Some("secret")))
+                .await
+        );
+        assert!(auth.authenticate(credentials(None, None)).await);
+    }
+}
@@ -0,0 +1,243 @@
+use std::path::PathBuf;
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tracing::debug;
+
+use super::{Authenticator, Credentials};
+use crate::{error::MqttError, hooks::HookFuture};
+
+/// Options of the [`JwtAuthenticator`], set with the `auth_jwt_*` options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JwtAuthConfig {
+    /// PEM files with the public keys tokens can be signed with
+    pub key_files: Vec<PathBuf>,
+    /// Shared secret for tokens signed with HMAC
+    pub secret: Option<String>,
+    /// Signing algorithm, RS256 for keys and HS256 for a secret when not set
+    pub algorithm: Option<Algorithm>,
+    /// Required `iss` claim
+    pub issuer: Option<String>,
+    /// Required `aud` claim
+    pub audience: Option<String>,
+}
+
+/// Claims of the token that are checked besides `exp`, `iss` and `aud`
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: Option<String>,
+}
+
+/// ### JWT Authenticator
+/// Accepts clients that send a JSON Web Token as their password, like the tokens of an identity provider.
+///
+/// The token must be signed by one of the configured keys, must not have expired,
+/// and must have the configured issuer and audience. When the token has a `sub` claim,
+/// a client that sends a username must use the subject as its username.
+/// Clients without a username or password are accepted with `allow_anonymous`,
+/// a username without a token is refused so a client can't claim the username of another.
+///
+/// [RFC 7519](https://datatracker.ietf.org/doc/html/rfc7519)
+pub struct JwtAuthenticator {
+    keys: Vec<DecodingKey>,
+    validation: Validation,
+    allow_anonymous: bool,
+}
+
+impl std::fmt::Debug for JwtAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuthenticator")
+            .field("keys", &self.keys.len())
+            .field("algorithms", &self.validation.algorithms)
+            .finish()
+    }
+}
+
+impl JwtAuthenticator {
+    /// Load the keys of the config
+    pub fn new(config: &JwtAuthConfig, allow_anonymous: bool) -> Result<Self, MqttError> {
+        let algorithm = config.algorithm.unwrap_or(if config.secret.is_some() {
+            Algorithm::HS256
+        } else {
+            Algorithm::RS256
+        });
+
+        let mut keys = Vec::new();
+        if let Some(secret) = &config.secret {
+            if !matches!(
+                algorithm,
+                Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+            ) {
+                return Err(MqttError::InvalidConfig(format!(
+                    "auth_jwt_secret can't be used with {:?}",
+                    algorithm
+                )));
+            }
+            keys.push(DecodingKey::from_secret(secret.as_bytes()));
+        }
+        for path in &config.key_files {
+            let pem = std::fs::read(path)?;
+            let key = match algorithm {
+                Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512 => DecodingKey::from_rsa_pem(&pem),
+                Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                    return Err(MqttError::InvalidConfig(format!(
+                        "auth_jwt_key can't be used with {:?}, use auth_jwt_secret",
+                        algorithm
+                    )))
+                }
+            }
+            .map_err(|err| {
+                MqttError::InvalidConfig(format!(
+                    "Invalid auth_jwt_key '{}': {}",
+                    path.display(),
+                    err
+                ))
+            })?;
+            keys.push(key);
+        }
+
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            // Tokens for other audiences are only refused when an audience is set
+            None => validation.validate_aud = false,
+        }
+
+        Ok(Self {
+            keys,
+            validation,
+            allow_anonymous,
+        })
+    }
+
+    fn check(&self, credentials: Credentials) -> bool {
+        let Some(token) = credentials.password else {
+            return credentials.username.is_none() && self.allow_anonymous;
+        };
+
+        let Some(claims) = self.keys.iter().find_map(|key| {
+            jsonwebtoken::decode::<Claims>(token, key, &self.validation)
+                .map_err(|err| debug!("Token of '{}' refused: {}", credentials.client_id, err))
+                .ok()
+        }) else {
+            return false;
+        };
+
+        match (claims.claims.sub, credentials.username) {
+            (Some(subject), Some(username)) => subject == username,
+            _ => true,
+        }
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate<'a>(&'a self, credentials: Credentials<'a>) -> HookFuture<'a, bool> {
+        let accepted = self.check(credentials);
+        Box::pin(async move { accepted })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        iss: &'a str,
+        aud: &'a str,
+        exp: u64,
+    }
+
+    fn token(sub: &str, iss: &str, exp_offset: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = TestClaims {
+            sub,
+            iss,
+            aud: "mqtt",
+            exp: now.saturating_add_signed(exp_offset),
+        };
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    }
+
+    fn credentials<'a>(username: Option<&'a str>, password: Option<&'a str>) -> Credentials<'a> {
+        Credentials {
+            client_id: "client",
+            username,
+            password,
+        }
+    }
+
+    #[test]
+    fn test_jwt_authenticator() {
+        let config = JwtAuthConfig {
+            key_files: Vec::new(),
+            secret: Some("secret".into()),
+            algorithm: None,
+            issuer: Some("https://id.example.com".into()),
+            audience: Some("mqtt".into()),
+        };
+        let auth = JwtAuthenticator::new(&config, false).unwrap();
+
+        let valid = token("device-1", "https://id.example.com", 600);
+        assert!(auth.check(credentials(Some("device-1"), Some(&valid))));
+        assert!(auth.check(credentials(None, Some(&valid))));
+        // The username has to be the subject of the token
+        assert!(!auth.check(credentials(Some("device-2"), Some(&valid))));
+
+        let expired = token("device-1", "https://id.example.com", -600);
+        assert!(!auth.check(credentials(Some("device-1"), Some(&expired))));
+        let other_issuer = token("device-1", "https://other.example.com", 600);
+        assert!(!auth.check(credentials(Some("device-1"), Some(&other_issuer))));
+        assert!(!auth.check(credentials(Some("device-1"), Some("not a token"))));
+        assert!(!auth.check(credentials(None, None)));
+
+        // A secret can only sign HMAC tokens
+        let config = JwtAuthConfig {
+            algorithm: Some(Algorithm::RS256),
+            ..config
+        };
+        assert!(JwtAuthenticator::new(&config, false).is_err());
+    }
+
+    #[test]
+    fn test_jwt_authenticator_anonymous() {
+        let config = JwtAuthConfig {
+            key_files: Vec::new(),
+            secret: Some("secret".into()),
+            algorithm: None,
+            issuer: None,
+            audience: None,
+        };
+        let auth = JwtAuthenticator::new(&config, true).unwrap();
+
+        assert!(auth.check(credentials(None, None)));
+        // A username is only taken with a token for it
+        assert!(!auth.check(credentials(Some("device-1"), None)));
+        let valid = token("device-1", "https://id.example.com", 600);
+        assert!(auth.check(credentials(Some("device-1"), Some(&valid))));
+    }
+}
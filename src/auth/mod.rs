@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use crate::{config::Config, error::MqttError, hooks::HookFuture};
+
+mod http;
+mod jwt;
+
+pub use self::{
+    http::{HttpAuthConfig, HttpAuthenticator},
+    jwt::{JwtAuthConfig, JwtAuthenticator},
+};
+
+/// Credentials sent by a client in the CONNECT packet
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials<'a> {
+    pub client_id: &'a str,
+    /// User Name from the CONNECT packet, or the identity of the client certificate
+    /// when `use_identity_as_username` is set.
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+}
+
+/// ### Authenticator
+/// Decides if a client may connect, run by the broker for every CONNECT packet.
+///
+/// The default [`ConfigAuthenticator`] checks the `allow_anonymous` option,
+/// [`HttpAuthenticator`] and [`JwtAuthenticator`] check the credentials with an identity provider,
+/// and a custom authenticator can be set with [`crate::BrokerBuilder::authenticator`].
+///
+/// Clients are registered once their authenticator is done, so a slow authenticator,
+/// like a request to another service, only holds up the client that is connecting.
+///
+/// ```
+/// use mqtt_broker::{auth::{Authenticator, Credentials}, hooks::HookFuture};
+///
+/// struct DevicesOnly;
+///
+/// impl Authenticator for DevicesOnly {
+///     fn authenticate<'a>(&'a self, credentials: Credentials<'a>) -> HookFuture<'a, bool> {
+///         Box::pin(async move {
+///             credentials.username.is_some_and(|name| name.starts_with("device-"))
+///         })
+///     }
+/// }
+/// ```
+pub trait Authenticator: Send + Sync {
+    /// Return `true` to accept the client
+    fn authenticate<'a>(&'a self, credentials: Credentials<'a>) -> HookFuture<'a, bool>;
+}
+
+/// External service checking the credentials of clients, set with the `auth_http_*` or `auth_jwt_*` options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternalAuth {
+    Http(HttpAuthConfig),
+    Jwt(JwtAuthConfig),
+}
+
+/// The authenticator for the options of the config,
+/// the external service when one is set and the [`ConfigAuthenticator`] otherwise.
+pub fn from_config(config: &Config) -> Result<Arc<dyn Authenticator>, MqttError> {
+    Ok(match &config.external_auth {
+        Some(ExternalAuth::Http(http)) => {
+            Arc::new(HttpAuthenticator::new(http, config.allow_anonymous)?)
+        }
+        Some(ExternalAuth::Jwt(jwt)) => {
+            Arc::new(JwtAuthenticator::new(jwt, config.allow_anonymous)?)
+        }
+        None => Arc::new(ConfigAuthenticator::from(config)),
+    })
+}
+
+/// Accepts clients based on the broker [`Config`].
+/// Clients without a username are only accepted with `allow_anonymous`.
+#[derive(Debug, Clone)]
+pub struct ConfigAuthenticator {
+    allow_anonymous: bool,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// Accepts every client
+impl Default for ConfigAuthenticator {
+    fn default() -> Self {
+        Self {
+            allow_anonymous: true,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+impl From<&Config> for ConfigAuthenticator {
+    fn from(config: &Config) -> Self {
+        Self {
+            allow_anonymous: config.allow_anonymous,
+            username: config.user.clone(),
+            password: config.pass.clone(),
+        }
+    }
+}
+
+impl ConfigAuthenticator {
+    fn check(&self, credentials: Credentials) -> bool {
+        let Some(username) = credentials.username else {
+            return self.allow_anonymous;
+        };
+
+        match (&self.username, &self.password) {
+            (None, _) => true,
+            (Some(user), None) => user == username,
+            (Some(user), Some(pass)) => {
+                user == username && credentials.password == Some(pass.as_str())
+            }
+        }
+    }
+}
+
+impl Authenticator for ConfigAuthenticator {
+    fn authenticate<'a>(&'a self, credentials: Credentials<'a>) -> HookFuture<'a, bool> {
+        let accepted = self.check(credentials);
+        Box::pin(async move { accepted })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials<'a>(username: Option<&'a str>, password: Option<&'a str>) -> Credentials<'a> {
+        Credentials {
+            client_id: "client",
+            username,
+            password,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config_authenticator() {
+        let auth = ConfigAuthenticator {
+            allow_anonymous: false,
+            username: None,
+            password: None,
+        };
+        assert!(!auth.authenticate(credentials(None, None)).await);
+        assert!(auth.authenticate(credentials(Some("device-1"), None)).await);
+
+        let auth = ConfigAuthenticator {
+            allow_anonymous: true,
+            username: Some("admin".into()),
+            password: Some("secret".into()),
+        };
+        assert!(auth.authenticate(credentials(None, None)).await);
+        assert!(
+            auth.authenticate(credentials(Some("admin"), Some("secret")))
+                .await
+        );
+        assert!(
+            !auth
+                .authenticate(credentials(Some("admin"), Some("wrong")))
+                .await
+        );
+        assert!(!auth.authenticate(credentials(Some("other"), None)).await);
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, select, sync::mpsc::channel};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+};
+use tracing::{debug, error, info, instrument, warn};
+
+use self::topic::BridgeTopic;
+use crate::{
+    core::enums::{ClientEvent, ConnectionId, ProtocalVersion},
+    error::MqttError,
+    local_handle::LocalHandle,
+    packet_id::PacketIdAllocator,
+    packets::{
+        codec::MqttCodec,
+        enums::{ConnectReturnCode, DisconnectReasonCode, QosLevel, SubackReturnCode},
+        Packet, PublishProperties, SubscriptionOptions, VariableHeader,
+    },
+};
+
+pub mod topic;
+
+/// ### Bridge
+/// Connects to a remote broker as a client and mirrors the configured topics between the two brokers.
+///
+/// A message is never mirrored back the way it came. Locally the bridge subscribes with No Local,
+/// and with `try_private` it connects with the top bit of the protocol level set like mosquitto,
+/// which tells a broker that supports it to do the same. Brokers that refuse the CONNECT are retried without it.
+/// Three or more brokers bridged in a ring can still loop a message, so a topic should only be mirrored one way around a ring.
+///
+/// See [Mosquitto](https://mosquitto.org/man/mosquitto-conf-5.html) `Configuring Bridges`
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// Unique name of this bridge, set by the `connection` option.
+    pub name: String,
+    /// `host:port` of the remote broker
+    pub address: String,
+    /// Client id used when connecting to the remote broker
+    pub remote_client_id: String,
+    pub remote_username: Option<String>,
+    pub remote_password: Option<String>,
+    /// Client id used when registering with the local broker
+    pub local_client_id: String,
+    pub keepalive: u16,
+    pub clean_session: bool,
+    /// Seconds to wait before reconnecting after the bridge connection is lost.
+    pub restart_timeout: u64,
+    /// Tell the remote broker this client is a bridge, so it does not send the bridge's messages back
+    pub try_private: bool,
+    pub topics: Vec<BridgeTopic>,
+}
+
+impl BridgeConfig {
+    pub fn new(name: String) -> Self {
+        Self {
+            remote_client_id: format!("bridge.{}", name),
+            local_client_id: format!("local.bridge.{}", name),
+            name,
+            address: String::default(),
+            remote_username: None,
+            remote_password: None,
+            keepalive: 60,
+            clean_session: false,
+            restart_timeout: 30,
+            try_private: true,
+            topics: Vec::new(),
+        }
+    }
+}
+
+/// A message sent to the remote broker that has not been acknowledged yet
+#[derive(Debug)]
+enum Outgoing {
+    /// Waiting for the PUBACK or PUBREC
+    Publish {
+        topic: String,
+        qos: QosLevel,
+        retain: bool,
+        payload: Bytes,
+    },
+    /// Waiting for the PUBCOMP of a QoS 2 message
+    Released,
+}
+
+/// ### Remote Session
+/// The bridge's side of its session with the remote broker, kept across reconnects.
+///
+/// When the remote broker resumes the session the unacknowledged messages are sent again, with DUP set,
+/// and QoS 2 messages it sends again before their PUBREL are not published locally twice.
+/// A remote broker without the session has dropped its side of it, so the state is dropped too.
+///
+/// [(MQTT 3.1.1) 4.4 Message delivery retry](https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718103)
+#[derive(Debug, Default)]
+struct RemoteSession {
+    packet_ids: PacketIdAllocator,
+    /// QoS 1 and QoS 2 messages sent to the remote broker by packet identifier
+    outgoing: BTreeMap<u16, Outgoing>,
+    /// QoS 2 messages from the remote broker waiting for their PUBREL, they have been published locally
+    awaiting_pubrel: HashSet<u16>,
+}
+
+impl RemoteSession {
+    /// Pick up the session once connected, returns the packets to send again
+    fn resume(&mut self, session_present: bool) -> Vec<Bytes> {
+        if !session_present {
+            *self = Self::default();
+            return Vec::new();
+        }
+
+        // a SUBSCRIBE that was never acknowledged is sent again as a new one
+        let outgoing = &self.outgoing;
+        self.packet_ids.retain(|id| outgoing.contains_key(id));
+        self.outgoing
+            .iter()
+            .map(|(&id, message)| match message {
+                Outgoing::Publish {
+                    topic,
+                    qos,
+                    retain,
+                    payload,
+                } => Packet::make_publish(
+                    true,
+                    *qos,
+                    *retain,
+                    topic.clone(),
+                    Some(id),
+                    payload.clone(),
+                    PublishProperties::default(),
+                    Vec::new(),
+                    ProtocalVersion::Four,
+                ),
+                Outgoing::Released => Packet::make_pubrel(id),
+            })
+            .collect()
+    }
+
+    /// Free the packet identifier of an acknowledged message
+    fn acknowledged(&mut self, packet_id: u16) {
+        self.packet_ids.release(packet_id);
+        self.outgoing.remove(&packet_id);
+    }
+}
+
+/// Run a bridge until the cancellation token is triggered, reconnecting when the connection is lost.
+#[instrument(name = "bridge", skip_all, fields(name = %config.name))]
+pub async fn run_bridge(config: BridgeConfig, local: LocalHandle, cancellation: CancellationToken) {
+    let mut try_private = config.try_private;
+    let mut session = RemoteSession::default();
+
+    loop {
+        info!("Connecting to {}", config.address);
+        let connection_id = ConnectionId::next();
+        match bridge_handler(
+            &config,
+            try_private,
+            connection_id,
+            &local,
+            &cancellation,
+            &mut session,
+        )
+        .await
+        {
+            Ok(()) => break,
+            Err(MqttError::ConnectionRefused(ConnectReturnCode::V4UnacceptableProtocal))
+                if try_private =>
+            {
+                info!("Remote broker does not support bridges, connecting as a normal client");
+                try_private = false;
+                continue;
+            }
+            Err(err) => error!("{}", err),
+        }
+
+        if local
+            .disconnect(&config.local_client_id, connection_id)
+            .await
+            .is_err()
+        {
+            error!("Receiver dropped. Closing");
+            break;
+        }
+
+        select! {
+            () = cancellation.cancelled() => break,
+            () = tokio::time::sleep(Duration::from_secs(config.restart_timeout)) => {}
+        }
+    }
+
+    debug!("Exited");
+}
+
+async fn bridge_handler(
+    config: &BridgeConfig,
+    try_private: bool,
+    connection_id: ConnectionId,
+    local: &LocalHandle,
+    cancellation: &CancellationToken,
+    session: &mut RemoteSession,
+) -> Result<(), MqttError> {
+    // Nothing from the remote broker for one and a half times the Keep Alive means the connection is gone, like a broker allows its clients
+    let inactivity = Duration::from_millis(u64::from(config.keepalive.max(1)) * 1500);
+
+    let stream = TcpStream::connect(&config.address).await?;
+    let (read_stream, write_stream) = tokio::io::split(stream);
+    let mut reader = FramedRead::new(read_stream, MqttCodec::new(ProtocalVersion::Four));
+    let mut writer = FramedWrite::new(write_stream, MqttCodec::new(ProtocalVersion::Four));
+
+    writer
+        .send(Packet::make_connect(
+            config.remote_client_id.clone(),
+            config.keepalive,
+            config.clean_session,
+            config.remote_username.clone(),
+            config.remote_password.clone(),
+            try_private,
+        ))
+        .await?;
+
+    let packet = tokio::time::timeout(inactivity, reader.next())
+        .await
+        .map_err(|_| MqttError::RemoteTimeout("the CONNACK"))?
+        .ok_or(MqttError::MissingFixedHeader)??;
+
+    let session_present = match packet.variable {
+        VariableHeader::ConnAck {
+            return_code,
+            acknowledge_flags,
+            ..
+        } => {
+            if !matches!(return_code, ConnectReturnCode::Accepted) {
+                return Err(MqttError::ConnectionRefused(return_code));
+            }
+            acknowledge_flags.session_present()
+        }
+        _ => return Err(MqttError::ProtocolViolation),
+    };
+
+    info!("Connected");
+
+    let resend = session.resume(session_present);
+    if !resend.is_empty() {
+        debug!("Sending {} unacknowledged packets again", resend.len());
+        for packet in resend {
+            writer.feed(packet).await?;
+        }
+        writer.flush().await?;
+    }
+
+    let (tx, mut rx) = channel::<ClientEvent>(100);
+    let disconnect = cancellation.child_token();
+
+    let outgoing = config
+        .topics
+        .iter()
+        .filter(|t| t.is_outgoing())
+        // keep the RETAIN flag so retained messages stay retained on the remote broker,
+        // and skip the messages the bridge published itself so they don't go back to the remote broker
+        .map(|t| {
+            (
+                t.local_filter(),
+                SubscriptionOptions::new(t.qos, true, true),
+            )
+        })
+        .collect::<Vec<(String, SubscriptionOptions)>>();
+
+    local
+        .subscribe(
+            &config.local_client_id,
+            outgoing,
+            tx,
+            disconnect.clone(),
+            connection_id,
+        )
+        .await?;
+
+    let incoming = config
+        .topics
+        .iter()
+        .filter(|t| t.is_incoming())
+        .map(|t| (t.remote_filter(), t.qos))
+        .collect::<Vec<(String, QosLevel)>>();
+
+    if !incoming.is_empty() {
+        let packet_id = session.packet_ids.allocate().ok_or(MqttError::Unknown)?;
+        writer
+            .send(Packet::make_subscribe(packet_id, incoming))
+            .await?;
+    }
+
+    let mut keepalive = tokio::time::interval(Duration::from_secs(config.keepalive.max(1) as u64));
+    keepalive.tick().await;
+    // Reset by every packet from the remote broker, a half-open connection is dropped when it runs out
+    let remote_timeout = tokio::time::sleep(inactivity);
+    tokio::pin!(remote_timeout);
+
+    loop {
+        select! {
+            () = disconnect.cancelled() => {
+                if cancellation.is_cancelled() {
+                    break;
+                }
+                // dropped by the broker for not keeping up with the local messages, like a client sent Quota Exceeded
+                return Err(MqttError::BridgeDisconnected(DisconnectReasonCode::QuotaExceeded));
+            }
+            _ = keepalive.tick() => {
+                writer.send(Packet::make_ping_req()).await?;
+            }
+            () = &mut remote_timeout => {
+                return Err(MqttError::RemoteTimeout("a packet"));
+            }
+            frame = reader.next() => {
+                let packet = frame.ok_or_else(|| MqttError::Io(std::io::ErrorKind::ConnectionAborted.into()))??;
+                remote_timeout.as_mut().reset(tokio::time::Instant::now() + inactivity);
+
+                match packet.variable {
+                    VariableHeader::Publish { topic, packet_id, payload, .. } => {
+                        let qos = packet.fixed.get_qos()?;
+                        // Sent again before the PUBREL, the message has already been published locally
+                        let duplicate = qos == QosLevel::Exactly
+                            && !session.awaiting_pubrel.insert(packet_id.ok_or(MqttError::ProtocolViolation)?);
+                        let local_topic = config
+                            .topics
+                            .iter()
+                            .filter(|t| t.is_incoming())
+                            .find_map(|t| t.to_local(&topic));
+
+                        match local_topic {
+                            Some(_) if duplicate => debug!("Remote broker sent message {:?} again before the PUBREL", packet_id),
+                            Some(topic) => {
+                                local
+                                    .publish_as(&config.local_client_id, topic, payload, qos, packet.fixed.get_retain())
+                                    .await?;
+                            }
+                            None => {}
+                        }
+
+                        let resp = match qos {
+                            QosLevel::AtMost => None,
+                            QosLevel::AtLeast => Some(Packet::make_puback(packet_id.ok_or(MqttError::ProtocolViolation)?)),
+                            QosLevel::Exactly => Some(Packet::make_pubrec(packet_id.ok_or(MqttError::ProtocolViolation)?)),
+                        };
+
+                        if let Some(resp) = resp {
+                            writer.send(resp).await?;
+                        }
+                    }
+                    VariableHeader::PubRec { packet_id, reason_code, .. } if reason_code.is_failure() => {
+                        warn!("Remote broker refused message {} with {:?}", packet_id, reason_code);
+                        session.acknowledged(packet_id);
+                    }
+                    VariableHeader::PubRec { packet_id, .. } => {
+                        session.outgoing.insert(packet_id, Outgoing::Released);
+                        writer.send(Packet::make_pubrel(packet_id)).await?;
+                    }
+                    VariableHeader::PubAck { packet_id, .. } | VariableHeader::PubComp { packet_id, .. } => {
+                        session.acknowledged(packet_id);
+                    }
+                    VariableHeader::PubRel { packet_id, .. } => {
+                        session.awaiting_pubrel.remove(&packet_id);
+                        writer.send(Packet::make_pubcomp(packet_id)).await?;
+                    }
+                    VariableHeader::SubAck { packet_id, return_codes, .. } => {
+                        session.packet_ids.release(packet_id);
+                        if return_codes.iter().any(SubackReturnCode::is_failure) {
+                            error!("Remote broker rejected a subscription");
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            event = rx.recv() => {
+                match event {
+                    Some(ClientEvent::Message(mut msg)) => {
+                        let (packet, _) = Packet::unpack(&mut msg, ProtocalVersion::Four)?;
+                        if let VariableHeader::Publish { topic, payload, .. } = packet.variable {
+                            let mapping = config
+                                .topics
+                                .iter()
+                                .filter(|t| t.is_outgoing())
+                                .find_map(|t| t.to_remote(&topic).map(|remote| (remote, t.qos)));
+
+                            if let Some((remote, qos)) = mapping {
+                                let qos = qos.min(packet.fixed.get_qos()?);
+                                let retain = packet.fixed.get_retain();
+                                let id = if qos > QosLevel::AtMost {
+                                    let Some(id) = session.packet_ids.allocate() else {
+                                        error!("No free packet identifier, dropping message for '{}'", remote);
+                                        continue;
+                                    };
+                                    // kept until acknowledged, so it is sent again if the connection is lost first
+                                    session.outgoing.insert(id, Outgoing::Publish { topic: remote.clone(), qos, retain, payload: payload.clone() });
+                                    Some(id)
+                                } else {
+                                    None
+                                };
+                                writer
+                                    .send(Packet::make_publish(false, qos, retain, remote, id, payload, PublishProperties::default(), Vec::new(), ProtocalVersion::Four))
+                                    .await?;
+                            }
+                        }
+                    }
+                    Some(ClientEvent::Disconnect(reason)) => {
+                        return Err(MqttError::BridgeDisconnected(reason));
+                    }
+                    // the local broker has stopped
+                    None => {
+                        return Err(MqttError::BridgeDisconnected(DisconnectReasonCode::ServerShuttingDown));
+                    }
+                }
+            }
+        }
+    }
+
+    local
+        .disconnect(&config.local_client_id, connection_id)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_session_resume() {
+        let mut session = RemoteSession::default();
+        // the SUBSCRIBE
+        assert_eq!(session.packet_ids.allocate(), Some(1));
+        for qos in [QosLevel::AtLeast, QosLevel::Exactly, QosLevel::Exactly] {
+            let id = session.packet_ids.allocate().unwrap();
+            session.outgoing.insert(
+                id,
+                Outgoing::Publish {
+                    topic: "a".into(),
+                    qos,
+                    retain: false,
+                    payload: Bytes::from_static(b"x"),
+                },
+            );
+        }
+        // the first QoS 2 message has its PUBREC, the QoS 1 message is acknowledged
+        session.outgoing.insert(3, Outgoing::Released);
+        session.acknowledged(2);
+        session.awaiting_pubrel.insert(7);
+
+        let resend = session.resume(true);
+        assert_eq!(resend.len(), 2);
+        assert_eq!(resend[0], Packet::make_pubrel(3));
+        let (packet, _) = Packet::unpack(&mut resend[1].clone(), ProtocalVersion::Four).unwrap();
+        assert!(packet.fixed.get_dup());
+        assert!(matches!(
+            packet.variable,
+            VariableHeader::Publish {
+                packet_id: Some(4),
+                ..
+            }
+        ));
+        assert!(session.awaiting_pubrel.contains(&7));
+        // the unacknowledged SUBSCRIBE doesn't hold on to its packet identifier
+        assert_eq!(session.packet_ids.len(), 2);
+
+        // a remote broker without the session has dropped the messages
+        assert!(session.resume(false).is_empty());
+        assert!(session.outgoing.is_empty());
+        assert!(session.awaiting_pubrel.is_empty());
+        assert_eq!(session.packet_ids.len(), 0);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{error::MqttError, packets::enums::QosLevel, utils::topic_matches};
+
+/// Which way messages flow over a bridged topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeDirection {
+    /// Remote messages are published on the local broker.
+    In,
+    /// Local messages are published on the remote broker.
+    Out,
+    /// Messages flow in both directions.
+    Both,
+}
+
+impl TryFrom<&str> for BridgeDirection {
+    type Error = MqttError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "in" => Ok(Self::In),
+            "out" => Ok(Self::Out),
+            "both" => Ok(Self::Both),
+            _ => Err(MqttError::Convertion(
+                value.to_string(),
+                "BridgeDirection".into(),
+            )),
+        }
+    }
+}
+
+/// ### Bridge Topic
+/// A topic mapping between the local and remote broker.
+///
+/// Follows the mosquitto `topic` syntax:
+/// `topic pattern [[[ out | in | both ] qos-level] local-prefix remote-prefix]`
+///
+/// A prefix of `""` is treated as empty.
+/// See [Mosquitto](https://mosquitto.org/man/mosquitto-conf-5.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BridgeTopic {
+    pub pattern: String,
+    pub direction: BridgeDirection,
+    pub qos: QosLevel,
+    pub local_prefix: String,
+    pub remote_prefix: String,
+}
+
+impl BridgeTopic {
+    /// Parse the arguments of a `topic` config line.
+    pub fn parse(value: &str) -> Result<Self, MqttError> {
+        let args = value.split_whitespace().collect::<Vec<&str>>();
+
+        let pattern = args
+            .first()
+            .map(|x| unquote(x))
+            .ok_or_else(|| MqttError::InvalidConfig("Bridge topic is missing a pattern".into()))?;
+
+        let direction = match args.get(1) {
+            Some(dir) => BridgeDirection::try_from(*dir)?,
+            None => BridgeDirection::Out,
+        };
+
+        let qos = match args.get(2) {
+            Some(qos) => QosLevel::try_from(
+                qos.parse::<u8>()
+                    .map_err(|_| MqttError::Convertion(qos.to_string(), "QosLevel".into()))?,
+            )?,
+            None => QosLevel::AtMost,
+        };
+
+        let (local_prefix, remote_prefix) = match (args.get(3), args.get(4)) {
+            (Some(local), Some(remote)) => (unquote(local), unquote(remote)),
+            (None, None) => (String::default(), String::default()),
+            _ => {
+                return Err(MqttError::InvalidConfig(
+                    "Bridge topic requires both a local and remote prefix".into(),
+                ))
+            }
+        };
+
+        if pattern.is_empty() && local_prefix.is_empty() && remote_prefix.is_empty() {
+            return Err(MqttError::InvalidConfig(
+                "Bridge topic pattern can only be empty when a prefix is set".into(),
+            ));
+        }
+
+        Ok(Self {
+            pattern,
+            direction,
+            qos,
+            local_prefix,
+            remote_prefix,
+        })
+    }
+
+    /// Messages on the remote broker are forwarded to the local broker
+    pub fn is_incoming(&self) -> bool {
+        matches!(self.direction, BridgeDirection::In | BridgeDirection::Both)
+    }
+
+    /// Messages on the local broker are forwarded to the remote broker
+    pub fn is_outgoing(&self) -> bool {
+        matches!(self.direction, BridgeDirection::Out | BridgeDirection::Both)
+    }
+
+    /// The filter to subscribe to on the local broker
+    pub fn local_filter(&self) -> String {
+        format!("{}{}", self.local_prefix, self.pattern)
+    }
+
+    /// The filter to subscribe to on the remote broker
+    pub fn remote_filter(&self) -> String {
+        format!("{}{}", self.remote_prefix, self.pattern)
+    }
+
+    /// Map a topic published on the local broker to its remote topic.
+    /// Returns `None` if the topic is not covered by this mapping.
+    pub fn to_remote(&self, topic: &str) -> Option<String> {
+        if !topic_matches(&self.local_filter(), topic) {
+            return None;
+        }
+        let stripped = topic.strip_prefix(self.local_prefix.as_str())?;
+        Some(format!("{}{}", self.remote_prefix, stripped))
+    }
+
+    /// Map a topic published on the remote broker to its local topic.
+    /// Returns `None` if the topic is not covered by this mapping.
+    pub fn to_local(&self, topic: &str) -> Option<String> {
+        if !topic_matches(&self.remote_filter(), topic) {
+            return None;
+        }
+        let stripped = topic.strip_prefix(self.remote_prefix.as_str())?;
+        Some(format!("{}{}", self.local_prefix, stripped))
+    }
+}
+
+fn unquote(value: &str) -> String {
+    if value == "\"\"" {
+        String::default()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pattern_only() {
+        let topic = BridgeTopic::parse("sensors/#").expect("Failed to parse");
+
+        assert_eq!(topic.pattern, "sensors/#");
+        assert_eq!(topic.direction, BridgeDirection::Out);
+        assert_eq!(topic.qos, QosLevel::AtMost);
+        assert!(topic.local_prefix.is_empty());
+        assert!(topic.remote_prefix.is_empty());
+    }
+
+    #[test]
+    fn test_parse_full() {
+        let topic = BridgeTopic::parse("# both 1 local/ remote/").expect("Failed to parse");
+
+        assert_eq!(topic.direction, BridgeDirection::Both);
+        assert_eq!(topic.qos, QosLevel::AtLeast);
+        assert_eq!(topic.local_filter(), "local/#");
+        assert_eq!(topic.remote_filter(), "remote/#");
+    }
+
+    #[test]
+    fn test_parse_empty_prefix() {
+        let topic = BridgeTopic::parse("sensors/# in 2 \"\" cloud/").expect("Failed to parse");
+
+        assert!(topic.local_prefix.is_empty());
+        assert_eq!(topic.remote_prefix, "cloud/");
+    }
+
+    #[test]
+    fn test_parse_missing_remote_prefix() {
+        BridgeTopic::parse("sensors/# in 2 local/").expect_err("Expected missing prefix error");
+    }
+
+    #[test]
+    fn test_remap_topics() {
+        let topic = BridgeTopic::parse("sensors/# both 0 home/ cloud/").expect("Failed to parse");
+
+        assert_eq!(
+            topic.to_remote("home/sensors/temp"),
+            Some("cloud/sensors/temp".to_string())
+        );
+        assert_eq!(
+            topic.to_local("cloud/sensors/temp"),
+            Some("home/sensors/temp".to_string())
+        );
+        assert_eq!(topic.to_remote("other/sensors/temp"), None);
+    }
+}
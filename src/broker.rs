@@ -0,0 +1,1780 @@
+use std::{
+    any::Any,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::{
+    net::TcpListener,
+    select,
+    sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, WeakSender},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, info, level_filters::LevelFilter, warn};
+
+use crate::{
+    admin,
+    auth::{self, Authenticator},
+    bridge, cluster,
+    config::{Config, ConfigBuilder},
+    core::{
+        broker_info::{self, LatencyHistogram, Stats},
+        enums::{ClientEvent, Command, ConnectionId, ProtocalVersion},
+        App, ClientInfo,
+    },
+    error::MqttError,
+    handler::{client_handler, ClientSettings, Peer},
+    hooks::{BrokerHook, Interceptors, MessageInterceptor},
+    local_handle::LocalHandle,
+    logging,
+    packet_trace::{self, TraceFilter},
+    packets::{
+        enums::{DisconnectReasonCode, QosLevel},
+        topic, Packet, SubscriptionOptions, VariableHeader,
+    },
+    privileges, proxy_protocol,
+    quota::{Quotas, RetainedLimits},
+    rate_limit::{Ban, RateLimiter},
+    retained::{RetainedMessage, RetainedStats},
+    shard::{self, Dispatch},
+    socket::SocketOptions,
+    stamp::Stamper,
+    sys, systemd,
+    tls::{self, SharedAcceptor},
+    topic_heir::SubscriptionStats,
+    topic_policy::TopicPolicy,
+};
+
+/// How long a load balancer has to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait after the listener first fails to accept connections, eg. when the broker is out of file descriptors.
+/// Doubled for every failure in a row up to [`ACCEPT_BACKOFF_MAX`].
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// How long the command loop has to answer [`BrokerHandle::health`]
+pub const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Builder for a [`Broker`]
+///
+/// ```no_run
+/// # async fn run() -> Result<(), mqtt_broker::error::MqttError> {
+/// mqtt_broker::Broker::builder()
+///     .bind("127.0.0.1:1883".parse().unwrap())
+///     .run()
+///     .await
+/// # }
+/// ```
+pub struct BrokerBuilder {
+    config: ConfigBuilder,
+    hooks: Vec<Arc<dyn BrokerHook>>,
+    interceptors: Interceptors,
+    authenticator: Option<Arc<dyn Authenticator>>,
+}
+
+impl BrokerBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: ConfigBuilder::new().set_port(1883).set_sys_interval(0),
+            hooks: Vec::new(),
+            interceptors: Interceptors::default(),
+            authenticator: None,
+        }
+    }
+
+    /// Register a hook to be run on broker events
+    pub fn hook<H: BrokerHook + 'static>(mut self, hook: H) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Register an interceptor for the messages published to topics matching `filter`.
+    ///
+    /// Interceptors with a lower `order` are run first, those with the same order in the order they were registered.
+    pub fn interceptor<I: MessageInterceptor + 'static>(
+        mut self,
+        filter: &str,
+        order: i32,
+        interceptor: I,
+    ) -> Self {
+        self.interceptors
+            .add(filter.to_string(), order, Arc::new(interceptor));
+        self
+    }
+
+    /// Decide which clients can connect, replacing the `allow_anonymous` check
+    pub fn authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Use an existing config builder
+    pub fn config(mut self, config: ConfigBuilder) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Load options from a mosquitto style config file
+    pub fn config_file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, MqttError> {
+        self.config = self.config.load_file(path)?;
+        Ok(self)
+    }
+
+    /// Address for the tcp listener to bind to, replacing any added before
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.config = self.config.set_address(addr.to_string());
+        self
+    }
+
+    /// Bind another tcp listener, like `[::]:1883` to also accept IPv6 clients
+    pub fn add_listener(mut self, addr: SocketAddr) -> Self {
+        self.config = self.config.add_address(addr.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<Broker, MqttError> {
+        let mut broker = Broker::new(self.config.build()?);
+        broker.hooks = self.hooks;
+        broker.interceptors = self.interceptors;
+        broker.authenticator = self.authenticator;
+        Ok(broker)
+    }
+
+    /// Build and run the broker until it is shutdown
+    pub async fn run(self) -> Result<(), MqttError> {
+        self.build()?.run().await
+    }
+}
+
+impl Default for BrokerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ### Broker
+/// An MQTT broker that can be embedded into another program.
+///
+/// Use [`Broker::handle`] to publish and subscribe from within the same process.
+pub struct Broker {
+    config: Config,
+    hooks: Vec<Arc<dyn BrokerHook>>,
+    interceptors: Interceptors,
+    cancellation: CancellationToken,
+    message_bridge: Sender<Command>,
+    commands: Receiver<Command>,
+    limiter: Arc<RateLimiter>,
+    settings: Arc<RwLock<ClientSettings>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    listening: Arc<AtomicUsize>,
+    acceptor: SharedAcceptor,
+    started: Instant,
+}
+
+impl Broker {
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder::new()
+    }
+
+    pub fn new(config: Config) -> Self {
+        let (message_bridge, commands) = channel::<Command>(100);
+        Self {
+            limiter: Arc::new(RateLimiter::from_config(&config)),
+            settings: Arc::new(RwLock::new(ClientSettings::from(&config))),
+            config,
+            hooks: Vec::new(),
+            interceptors: Interceptors::default(),
+            cancellation: CancellationToken::new(),
+            message_bridge,
+            commands,
+            authenticator: None,
+            listening: Arc::new(AtomicUsize::new(0)),
+            acceptor: SharedAcceptor::default(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Config the broker was built with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Get a handle for interacting with the broker in process.
+    pub fn handle(&self) -> BrokerHandle {
+        BrokerHandle {
+            message_bridge: self.message_bridge.clone(),
+            cancellation: self.cancellation.clone(),
+            limiter: self.limiter.clone(),
+            settings: self.settings.clone(),
+            listening: self.listening.clone(),
+            acceptor: self.acceptor.clone(),
+            started: self.started,
+        }
+    }
+
+    /// Run the broker until [`BrokerHandle::shutdown`] is called.
+    pub async fn run(self) -> Result<(), MqttError> {
+        let handle = self.handle();
+        let Self {
+            config,
+            hooks,
+            interceptors,
+            cancellation,
+            message_bridge,
+            commands,
+            limiter,
+            settings,
+            authenticator,
+            listening,
+            acceptor,
+            started: _,
+        } = self;
+
+        if let Some(level) = config.log_level {
+            logging::set_level(level);
+        }
+
+        if let Some(tls) = &config.tls {
+            acceptor.load(tls)?;
+        }
+
+        let socket_options = SocketOptions::from(&config);
+        let mut listeners = if config.systemd {
+            systemd::listen_fds().map_err(MqttError::Io)?
+        } else {
+            Vec::new()
+        };
+        if listeners.is_empty() {
+            listeners = socket_options
+                .bind_all(&config.socket_addrs)
+                .map_err(MqttError::Io)?;
+        } else {
+            info!("Using {} listeners from socket activation", listeners.len());
+        }
+        for addr in listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+        {
+            match config.tls {
+                Some(_) => info!("Starting MQTT Broker with TLS at: {}", addr),
+                None => info!("Starting MQTT Broker at: {}", addr),
+            }
+        }
+
+        // The listeners stay open after root is given up, so they can use privileged ports
+        if let Some(user) = &config.run_as_user {
+            privileges::drop_privileges(user, config.run_as_group.as_deref())
+                .map_err(MqttError::Io)?;
+        }
+
+        let tracker = TaskTracker::new();
+        let clients = TaskTracker::new();
+
+        let authenticator = match authenticator {
+            Some(authenticator) => authenticator,
+            None => auth::from_config(&config)?,
+        };
+        let stamper = config.stamp_messages.then(Stamper::default);
+        let new_app = || {
+            App::with_hooks(hooks.clone())
+                .with_interceptors(interceptors.clone())
+                .with_authenticator(authenticator.clone())
+                .with_slow_client_policy(config.slow_client_policy, config.slow_client_timeout)
+                .with_offline_queue(config.max_queued_messages, config.queue_qos0_messages)
+                .with_topic_policy(TopicPolicy::from(&config))
+                .with_quotas(Quotas::from(&config))
+                .with_retained_limits(RetainedLimits::from(&config))
+                .with_events(config.event_topic_prefix.clone())
+                .with_stamper(stamper.clone())
+        };
+        if config.command_shards > 1 {
+            info!("Running {} command shards", config.command_shards);
+            let apps = (0..config.command_shards).map(|_| new_app()).collect();
+            let mut shards = Vec::with_capacity(config.command_shards);
+            for (app, dispatched) in shard::link(apps) {
+                let (shard_tx, shard_rx) = channel::<Command>(100);
+                tracker.spawn(command_loop(
+                    shard_rx,
+                    message_bridge.downgrade(),
+                    dispatched,
+                    app,
+                ));
+                shards.push(shard_tx);
+            }
+            tracker.spawn(shard::route_loop(commands, shards));
+        } else {
+            // Nothing is dispatched from other shards
+            let (_, dispatched) = unbounded_channel();
+            tracker.spawn(command_loop(
+                commands,
+                message_bridge.downgrade(),
+                dispatched,
+                new_app(),
+            ));
+        }
+
+        if let Some(interval) = config.tls.as_ref().and_then(|tls| tls.reload_interval) {
+            tracker.spawn(acceptor.clone().watch(interval, cancellation.clone()));
+        }
+
+        if config.sys_interval > 0 {
+            tracker.spawn(sys::run_sys(
+                Duration::from_secs(config.sys_interval),
+                config.sys_client_metrics,
+                LocalHandle::new(message_bridge.clone()),
+                cancellation.clone(),
+            ));
+        }
+
+        if let Some(addr) = config.health_addr {
+            let handle = handle.clone();
+            let cancellation = cancellation.clone();
+            tracker.spawn(async move {
+                if let Err(err) = admin::run_health(addr, handle, cancellation).await {
+                    error!("Health probes: {}", err);
+                }
+            });
+        }
+
+        if let Some(addr) = config.admin_addr {
+            let cancellation = cancellation.clone();
+            tracker.spawn(async move {
+                if let Err(err) = admin::run_admin(addr, handle, cancellation).await {
+                    error!("Admin API: {}", err);
+                }
+            });
+        }
+
+        for bridge_config in config.bridges {
+            tracker.spawn(bridge::run_bridge(
+                bridge_config,
+                LocalHandle::new(message_bridge.clone()),
+                cancellation.clone(),
+            ));
+        }
+
+        if let Some(cluster_config) = config.cluster {
+            info!("Starting cluster node '{}'", cluster_config.node_id);
+            for peer in cluster_config.peers.clone() {
+                tracker.spawn(cluster::run_link(
+                    cluster_config.clone(),
+                    peer,
+                    LocalHandle::new(message_bridge.clone()),
+                    cancellation.clone(),
+                ));
+            }
+        }
+
+        // Each listener has its own accept loop that hands connections to the same handler
+        let accepting = TaskTracker::new();
+        let listener_context = ListenerContext {
+            socket_options,
+            acceptor,
+            proxy_protocol: config.proxy_protocol,
+            message_bridge: message_bridge.clone(),
+            limiter,
+            settings,
+            clients: clients.clone(),
+            cancellation: cancellation.clone(),
+            listening,
+        };
+        for listener in listeners {
+            accepting.spawn(accept_loop(listener, listener_context.clone()));
+        }
+        drop(listener_context);
+
+        if config.systemd {
+            systemd::notify("READY=1");
+            if let Some(interval) = systemd::watchdog_interval() {
+                tracker.spawn(systemd::run_watchdog(
+                    interval,
+                    message_bridge.clone(),
+                    cancellation.clone(),
+                ));
+            }
+        }
+
+        // The accept loops drop their listeners on shutdown so no new connections
+        // are accepted while the clients are sent their remaining messages
+        accepting.close();
+        accepting.wait().await;
+        if config.systemd {
+            systemd::notify("STOPPING=1");
+        }
+        info!("Shutting down, disconnecting {} clients", clients.len());
+        clients.close();
+        clients.wait().await;
+
+        info!("Exiting");
+        if message_bridge.send(Command::Exit).await.is_err() {
+            error!("Failed to exit message loop");
+        }
+        tracker.close();
+
+        tracker.wait().await;
+
+        Ok(())
+    }
+}
+
+/// Everything an accept loop needs to hand its connections to [`client_handler`]
+#[derive(Clone)]
+struct ListenerContext {
+    socket_options: SocketOptions,
+    acceptor: SharedAcceptor,
+    proxy_protocol: bool,
+    message_bridge: Sender<Command>,
+    limiter: Arc<RateLimiter>,
+    settings: Arc<RwLock<ClientSettings>>,
+    clients: TaskTracker,
+    cancellation: CancellationToken,
+    /// Number of accept loops running
+    listening: Arc<AtomicUsize>,
+}
+
+/// Accept connections on a listener until the cancellation token is triggered
+async fn accept_loop(listener: TcpListener, context: ListenerContext) {
+    context.listening.fetch_add(1, Ordering::Relaxed);
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let accepted = select! {
+            () = context.cancellation.cancelled() => break,
+            res = listener.accept() => res,
+        };
+        let (mut stream, addr) = match accepted {
+            Ok(conn) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                conn
+            }
+            // Only the connection being accepted failed, the listener can go on straight away
+            Err(err) if is_connection_error(&err) => {
+                broker_info::accept_failed();
+                debug!("Failed to accept a connection: {}", err);
+                continue;
+            }
+            // Out of file descriptors or memory, accepting again right away would spin until some are freed
+            Err(err) => {
+                broker_info::accept_failed();
+                warn!(
+                    "Failed to accept a connection, retrying in {:?}: {}",
+                    backoff, err
+                );
+                select! {
+                    () = context.cancellation.cancelled() => break,
+                    () = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+        };
+
+        debug!("Connection Start: {:?}", addr);
+        if let Err(err) = context.socket_options.apply(&stream) {
+            debug!("Failed to set socket options for {}: {}", addr, err);
+        }
+        let cancellation = context.cancellation.clone();
+        let message_brige = context.message_bridge.clone();
+        let limiter = context.limiter.clone();
+        let settings = context
+            .settings
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+        let acceptor = context.acceptor.get();
+        let proxy_protocol = context.proxy_protocol;
+        // Run on its own task so a panic only ends this connection, and is counted and logged
+        let connection = tokio::spawn(async move {
+            // The PROXY header comes before the TLS handshake
+            let addr = if proxy_protocol {
+                match tokio::time::timeout(
+                    PROXY_HEADER_TIMEOUT,
+                    proxy_protocol::read_header(&mut stream),
+                )
+                .await
+                {
+                    Ok(Ok(client_addr)) => client_addr.unwrap_or(addr),
+                    Ok(Err(err)) => {
+                        debug!("Invalid PROXY header from {}: {}", addr, err);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("No PROXY header from {}", addr);
+                        return;
+                    }
+                }
+            } else {
+                addr
+            };
+
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let certificate_identity = stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .and_then(tls::peer_identity);
+                        let peer = Peer {
+                            addr,
+                            certificate_identity,
+                        };
+                        let (reader, writer) = tokio::io::split(stream);
+                        client_handler(
+                            reader,
+                            writer,
+                            peer,
+                            message_brige,
+                            limiter,
+                            settings,
+                            cancellation,
+                        )
+                        .await
+                    }
+                    Err(err) => {
+                        debug!("TLS handshake with {} failed: {}", addr, err);
+                        return;
+                    }
+                },
+                None => {
+                    let (reader, writer) = tokio::io::split(stream);
+                    client_handler(
+                        reader,
+                        writer,
+                        Peer::from(addr),
+                        message_brige,
+                        limiter,
+                        settings,
+                        cancellation,
+                    )
+                    .await
+                }
+            };
+            if let Err(err) = result {
+                error!("{}", err);
+            }
+            debug!("Exited TCP handler");
+        });
+        context.clients.spawn(async move {
+            if let Err(err) = connection.await {
+                if err.is_panic() {
+                    broker_info::client_panicked();
+                    error!(
+                        "Connection handler for {} panicked: {}",
+                        addr,
+                        panic_message(err.into_panic().as_ref())
+                    );
+                }
+            }
+        });
+    }
+    context.listening.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Errors of `accept` that only affect the connection being accepted, not the listener
+fn is_connection_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Message of a panic, from `panic!` with a string literal or a format string
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Run the commands on the app, and deliver the messages published on the other shards.
+///
+/// `tx` is the sender of the commands, a client is registered through it again once authenticated.
+async fn command_loop(
+    mut rx: Receiver<Command>,
+    tx: WeakSender<Command>,
+    mut dispatched: UnboundedReceiver<Dispatch>,
+    mut context: App,
+) {
+    loop {
+        let command = select! {
+            Some(message) = dispatched.recv() => {
+                context.dispatch(message).await;
+                continue;
+            }
+            command = rx.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+        };
+        match command {
+            Command::RegisterClient {
+                id,
+                message_channel,
+                disconnect,
+                protocol,
+                clean_session,
+                login,
+                connection,
+                connection_id,
+                callback,
+            } => {
+                // Authenticate outside of the loop, and register the client once it is accepted
+                if let Some(login) = login {
+                    let authenticate = context.authenticate(id.clone(), login);
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        if !authenticate.await {
+                            if callback.send(Err(MqttError::NotAuthorized)).is_err() {
+                                error!("Client does not exist");
+                            }
+                            return;
+                        }
+                        let Some(tx) = tx.upgrade() else {
+                            return;
+                        };
+                        let _ = tx
+                            .send(Command::RegisterClient {
+                                id,
+                                message_channel,
+                                disconnect,
+                                protocol,
+                                clean_session,
+                                login: None,
+                                connection,
+                                connection_id,
+                                callback,
+                            })
+                            .await;
+                    });
+                    continue;
+                }
+
+                context
+                    .connect(
+                        id,
+                        message_channel,
+                        disconnect,
+                        protocol,
+                        clean_session,
+                        connection,
+                        connection_id,
+                        callback,
+                    )
+                    .await
+            }
+            Command::Subscribe {
+                client,
+                topics,
+                subscription_identifier,
+                callback,
+            } => {
+                context
+                    .subscribe(client, topics, subscription_identifier, callback)
+                    .await
+            }
+
+            Command::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+                client,
+                properties,
+                received,
+            } => {
+                context
+                    .publish(topic, payload, qos, retain, client, properties, received)
+                    .await
+            }
+            Command::DirectRoute {
+                client,
+                topic,
+                callback,
+            } => {
+                if callback
+                    .send(context.direct_route(&client, &topic))
+                    .is_err()
+                {
+                    error!("receiver dropped");
+                }
+            }
+            Command::Unsubscribe {
+                topics,
+                cid,
+                callback,
+            } => context.unsubscribe(cid, topics, callback).await,
+            Command::DisconnectClient { id, connection_id } => {
+                context.disconnect(id, connection_id).await
+            }
+            Command::ConnectionClosed {
+                id,
+                connection_id,
+                reason,
+            } => context.connection_closed(id, connection_id, reason).await,
+            Command::Reload(config) => context.reload(&config),
+            Command::ListClients { callback } => {
+                if callback.send(context.clients()).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::GetClientInfo { id, callback } => {
+                if callback.send(context.client_info(&id)).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::ClientSubscriptions { client, callback } => {
+                if callback
+                    .send(context.client_subscriptions(&client))
+                    .is_err()
+                {
+                    error!("receiver dropped");
+                }
+            }
+            Command::ClusterFilters(callback) => {
+                if callback.send(context.cluster_filters()).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::RetainedCount(callback) => {
+                if callback.send(context.retained_count()).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::RetainedStats(callback) => {
+                if callback.send(context.retained_stats()).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::ListRetained { filter, callback } => {
+                if callback.send(context.retained_messages(&filter)).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::RemoveRetained { filter, callback } => {
+                if callback.send(context.remove_retained(&filter)).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::SubscriptionStats(callback) => {
+                if callback.send(context.subscription_stats()).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::AdminDisconnect {
+                client_id,
+                reason,
+                callback,
+            } => {
+                let found = context.kick(&client_id, reason).await;
+                if callback.send(found).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::AdminUnsubscribe {
+                client_id,
+                filter,
+                callback,
+            } => {
+                let found = context.revoke_subscription(&client_id, &filter);
+                if callback.send(found).is_err() {
+                    error!("receiver dropped");
+                }
+            }
+            Command::Exit => {
+                context.shutdown().await;
+                break;
+            }
+        }
+    }
+
+    debug!("Exiting Command loop");
+}
+
+/// ### Broker Handle
+/// Publish and subscribe to a running [`Broker`] without a network connection.
+#[derive(Debug, Clone)]
+pub struct BrokerHandle {
+    message_bridge: Sender<Command>,
+    cancellation: CancellationToken,
+    limiter: Arc<RateLimiter>,
+    settings: Arc<RwLock<ClientSettings>>,
+    listening: Arc<AtomicUsize>,
+    acceptor: SharedAcceptor,
+    /// When the broker was created
+    started: Instant,
+}
+
+impl BrokerHandle {
+    /// Apply a new config without dropping connected clients.
+    ///
+    /// Limits, the slow client policy, `$` namespaces, the log level and the TLS certificates are reloaded.
+    /// The listener address, bridges and turning TLS on or off only change on restart.
+    /// Connections made before the reload keep their queue size, Receive Maximum and TLS session.
+    pub async fn reload(&self, config: Config) -> Result<(), MqttError> {
+        // Nothing is applied if the new certificates can't be loaded
+        match &config.tls {
+            Some(tls) if self.acceptor.is_loaded() => self.acceptor.load(tls)?,
+            None if !self.acceptor.is_loaded() => {}
+            _ => warn!("TLS can only be turned on or off with a restart"),
+        }
+
+        if let Some(level) = config.log_level {
+            logging::set_level(level);
+        }
+
+        self.limiter.reload(&config);
+        *self.settings.write().unwrap_or_else(|err| err.into_inner()) =
+            ClientSettings::from(&config);
+
+        self.message_bridge
+            .send(Command::Reload(Arc::new(config)))
+            .await?;
+
+        info!("Config reloaded");
+        Ok(())
+    }
+
+    /// Load a mosquitto style config file and apply it with [`BrokerHandle::reload`].
+    pub async fn reload_file<P: AsRef<Path>>(&self, path: P) -> Result<(), MqttError> {
+        let config = ConfigBuilder::new().load_file(path)?.build()?;
+        self.reload(config).await
+    }
+
+    fn local(&self) -> LocalHandle {
+        LocalHandle::new(self.message_bridge.clone())
+    }
+
+    /// Publish a message to all matching subscribers,
+    /// each subscriber receives it at the lower of `qos` and the QoS of its subscription.
+    pub async fn publish<T: Into<String>>(
+        &self,
+        topic: T,
+        payload: Bytes,
+        qos: QosLevel,
+    ) -> Result<(), MqttError> {
+        let topic = topic.into();
+        topic::validate_topic_name(&topic)?;
+
+        self.local().publish(topic, payload, qos, false).await
+    }
+
+    /// Register an in process client and subscribe it to the given topic filters.
+    pub async fn subscribe<T: Into<String>>(
+        &self,
+        client_id: T,
+        topics: Vec<(String, QosLevel)>,
+    ) -> Result<Subscription, MqttError> {
+        let client_id = client_id.into();
+        let (tx, rx) = channel::<ClientEvent>(100);
+        let disconnect = self.cancellation.child_token();
+        let connection_id = ConnectionId::next();
+
+        self.local()
+            .subscribe(
+                &client_id,
+                topics
+                    .into_iter()
+                    .map(|(topic, qos)| (topic, SubscriptionOptions::from(qos)))
+                    .collect(),
+                tx,
+                disconnect.clone(),
+                connection_id,
+            )
+            .await?;
+
+        Ok(Subscription {
+            client_id,
+            events: rx,
+            disconnect,
+            connection_id,
+            message_bridge: self.message_bridge.clone(),
+        })
+    }
+
+    /// Sessions known to the broker
+    pub async fn clients(&self) -> Result<Vec<ClientInfo>, MqttError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge
+            .send(Command::ListClients { callback: tx })
+            .await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Connection details of a client, `None` if the client does not exist
+    pub async fn client_info<T: Into<String>>(
+        &self,
+        client_id: T,
+    ) -> Result<Option<ClientInfo>, MqttError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge
+            .send(Command::GetClientInfo {
+                id: client_id.into(),
+                callback: tx,
+            })
+            .await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Topic filters a client is subscribed to, `None` if the client does not exist
+    pub async fn client_subscriptions<T: Into<String>>(
+        &self,
+        client_id: T,
+    ) -> Result<Option<Vec<(String, QosLevel)>>, MqttError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge
+            .send(Command::ClientSubscriptions {
+                client: client_id.into(),
+                callback: tx,
+            })
+            .await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Number of topics with a retained message
+    pub async fn retained_count(&self) -> Result<usize, MqttError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge.send(Command::RetainedCount(tx)).await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Size of the retained message store and the messages refused or evicted by the retained limits
+    pub async fn retained_stats(&self) -> Result<RetainedStats, MqttError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge.send(Command::RetainedStats(tx)).await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Retained messages with a topic matching the topic filter, sorted by topic
+    pub async fn retained_messages(
+        &self,
+        filter: impl Into<String>,
+    ) -> Result<Vec<(String, RetainedMessage)>, MqttError> {
+        let filter = filter.into();
+        topic::validate_topic_filter(&filter)?;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge
+            .send(Command::ListRetained {
+                filter,
+                callback: tx,
+            })
+            .await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Retained message of a topic, `None` if the topic has none
+    pub async fn retained_message(
+        &self,
+        topic: impl Into<String>,
+    ) -> Result<Option<RetainedMessage>, MqttError> {
+        let topic = topic.into();
+        topic::validate_topic_name(&topic)?;
+
+        // A topic name is a filter that only matches itself
+        Ok(self
+            .retained_messages(topic)
+            .await?
+            .into_iter()
+            .next()
+            .map(|(_, message)| message))
+    }
+
+    /// Remove the retained messages with a topic matching the topic filter, returns how many were removed.
+    /// The subscribers of the topics are not sent anything, unlike when an empty retained message is published.
+    pub async fn remove_retained(&self, filter: impl Into<String>) -> Result<usize, MqttError> {
+        let filter = filter.into();
+        topic::validate_topic_filter(&filter)?;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge
+            .send(Command::RemoveRetained {
+                filter,
+                callback: tx,
+            })
+            .await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Number of nodes and subscriptions in the subscription tree, and subscriptions by the first level of their filter
+    pub async fn subscription_stats(&self) -> Result<SubscriptionStats, MqttError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge
+            .send(Command::SubscriptionStats(tx))
+            .await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Snapshot of the clients, subscriptions, retained messages and counters of the broker,
+    /// the values of the `$SYS` topics without subscribing to them.
+    ///
+    /// Take two snapshots and use [`BrokerStats::rates`] for the message and byte rates between them.
+    pub async fn stats(&self) -> Result<BrokerStats, MqttError> {
+        let sessions = self.clients().await?.len();
+        let subscriptions = self.subscription_stats().await?.subscriptions;
+        let retained = self.retained_stats().await?;
+        let counters = broker_info::get_stats();
+
+        Ok(BrokerStats {
+            taken_at: Instant::now(),
+            uptime: self.started.elapsed(),
+            clients_connected: counters.clients_connected,
+            sessions,
+            subscriptions,
+            retained,
+            counters,
+            delivery_latency: broker_info::delivery_latency(),
+        })
+    }
+
+    /// Disconnect a client with Administrative Action and remove its session, returns false if the client does not exist
+    pub async fn disconnect_client<T: Into<String>>(
+        &self,
+        client_id: T,
+    ) -> Result<bool, MqttError> {
+        self.disconnect_client_with_reason(client_id, DisconnectReasonCode::AdministrativeAction)
+            .await
+    }
+
+    /// Disconnect a client and remove its session, MQTT 5 clients are sent `reason`.
+    /// Returns false if the client does not exist.
+    pub async fn disconnect_client_with_reason<T: Into<String>>(
+        &self,
+        client_id: T,
+        reason: DisconnectReasonCode,
+    ) -> Result<bool, MqttError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge
+            .send(Command::AdminDisconnect {
+                client_id: client_id.into(),
+                reason,
+                callback: tx,
+            })
+            .await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Remove a subscription of a client, returns false if the client is not subscribed to the filter.
+    ///
+    /// The client is not told and keeps its session.
+    pub async fn unsubscribe_client<C: Into<String>, F: Into<String>>(
+        &self,
+        client_id: C,
+        filter: F,
+    ) -> Result<bool, MqttError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.message_bridge
+            .send(Command::AdminUnsubscribe {
+                client_id: client_id.into(),
+                filter: filter.into(),
+                callback: tx,
+            })
+            .await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+
+    /// Addresses banned for failing authentication or flooding CONNECT packets
+    pub fn bans(&self) -> Vec<Ban> {
+        self.limiter.bans()
+    }
+
+    /// Lift the ban on an address, returns false if it was not banned
+    pub fn unban(&self, addr: IpAddr) -> bool {
+        self.limiter.unban(addr)
+    }
+
+    /// Log the packets of a client or the PUBLISH packets of a topic filter,
+    /// returns false if they were already being traced
+    pub fn trace(&self, filter: TraceFilter) -> Result<bool, MqttError> {
+        packet_trace::enable(filter)
+    }
+
+    /// Stop tracing, returns false if the filter was not being traced
+    pub fn untrace(&self, filter: &TraceFilter) -> bool {
+        packet_trace::disable(filter)
+    }
+
+    /// Filters being traced
+    pub fn traces(&self) -> Vec<TraceFilter> {
+        packet_trace::filters()
+    }
+
+    /// Stop the broker and disconnect all clients.
+    ///
+    /// New connections are refused, v5 clients are sent a DISCONNECT with the Server Shutting Down reason code
+    /// once their queued messages are sent and acknowledged or the `shutdown_timeout` has passed.
+    /// [`BrokerHook::on_shutdown`] is run after every client has disconnected.
+    pub fn shutdown(&self) {
+        self.cancellation.cancel();
+    }
+
+    /// Change the maximum log level without restarting.
+    ///
+    /// Returns false if the program installed its own log subscriber instead of calling [`logging::init`].
+    pub fn set_log_level(&self, level: LevelFilter) -> bool {
+        let changed = logging::set_level(level);
+        if changed {
+            info!("Log level set to {}", level);
+        }
+        changed
+    }
+
+    /// Maximum log level, `None` if the program installed its own log subscriber.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        logging::level()
+    }
+
+    /// Check the broker for liveness and readiness probes.
+    ///
+    /// The command loop has to answer within [`HEALTH_TIMEOUT`] for the broker to be responsive.
+    pub async fn health(&self) -> Health {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let responsive = tokio::time::timeout(HEALTH_TIMEOUT, async {
+            self.message_bridge
+                .send(Command::RetainedCount(tx))
+                .await
+                .is_ok()
+                && rx.await.is_ok()
+        })
+        .await
+        .unwrap_or(false);
+
+        Health {
+            responsive,
+            listeners: self.listening.load(Ordering::Relaxed),
+            shutting_down: self.cancellation.is_cancelled(),
+        }
+    }
+}
+
+/// State of a running broker returned by [`BrokerHandle::health`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    /// The command loop answered in time
+    pub responsive: bool,
+    /// Listeners accepting connections
+    pub listeners: usize,
+    /// [`BrokerHandle::shutdown`] has been called
+    pub shutting_down: bool,
+}
+
+impl Health {
+    /// The broker is running and not stuck
+    pub fn is_live(&self) -> bool {
+        self.responsive
+    }
+
+    /// The broker is accepting and serving clients
+    pub fn is_ready(&self) -> bool {
+        self.responsive && self.listeners > 0 && !self.shutting_down
+    }
+}
+
+/// Snapshot of a broker returned by [`BrokerHandle::stats`]
+#[derive(Debug, Clone)]
+pub struct BrokerStats {
+    /// When the snapshot was taken
+    pub taken_at: Instant,
+    /// Time since the broker was created
+    pub uptime: Duration,
+    /// Clients with an open network connection
+    pub clients_connected: usize,
+    /// Sessions of connected clients, in process clients and disconnected clients with a persistent session
+    pub sessions: usize,
+    /// Topic filters subscribed to by every client
+    pub subscriptions: usize,
+    pub retained: RetainedStats,
+    /// Bytes, packets and messages counted since the process started, shared by the brokers of the process
+    pub counters: Stats,
+    pub delivery_latency: LatencyHistogram,
+}
+
+impl BrokerStats {
+    /// Per second rates of the counters between an earlier snapshot and this one
+    pub fn rates(&self, earlier: &BrokerStats) -> Rates {
+        let elapsed = self
+            .taken_at
+            .saturating_duration_since(earlier.taken_at)
+            .as_secs_f64();
+        let rate = |before: usize, after: usize| {
+            if elapsed > 0.0 {
+                after.saturating_sub(before) as f64 / elapsed
+            } else {
+                0.0
+            }
+        };
+        let (before, after) = (&earlier.counters, &self.counters);
+
+        Rates {
+            messages_received: rate(before.messages_received, after.messages_received),
+            messages_sent: rate(before.messages_sent, after.messages_sent),
+            publish_received: rate(before.publish_received, after.publish_received),
+            publish_sent: rate(before.publish_sent, after.publish_sent),
+            publish_dropped: rate(before.publish_dropped, after.publish_dropped),
+            bytes_received: rate(before.bytes_received, after.bytes_received),
+            bytes_sent: rate(before.bytes_sent, after.bytes_sent),
+        }
+    }
+}
+
+/// Per second rates between two [`BrokerStats`]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Rates {
+    pub messages_received: f64,
+    pub messages_sent: f64,
+    pub publish_received: f64,
+    pub publish_sent: f64,
+    pub publish_dropped: f64,
+    pub bytes_received: f64,
+    pub bytes_sent: f64,
+}
+
+/// A message delivered to an in process [`Subscription`]
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QosLevel,
+    pub retain: bool,
+}
+
+/// An in process client created by [`BrokerHandle::subscribe`].
+/// The client is disconnected from the broker when dropped.
+#[derive(Debug)]
+pub struct Subscription {
+    client_id: String,
+    events: Receiver<ClientEvent>,
+    disconnect: CancellationToken,
+    connection_id: ConnectionId,
+    message_bridge: Sender<Command>,
+}
+
+impl Subscription {
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Wait for the next message.
+    /// Returns `None` if the broker has shutdown or the client was disconnected.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            let event = select! {
+                event = self.events.recv() => event?,
+                () = self.disconnect.cancelled() => return None,
+            };
+
+            match event {
+                ClientEvent::Message(mut bytes) => {
+                    let (packet, _) = match Packet::unpack(&mut bytes, ProtocalVersion::Four) {
+                        Ok(packet) => packet,
+                        Err(err) => {
+                            error!("{}", err);
+                            continue;
+                        }
+                    };
+
+                    if let VariableHeader::Publish { topic, payload, .. } = packet.variable {
+                        return Some(Message {
+                            topic,
+                            payload,
+                            qos: packet.fixed.get_qos().ok()?,
+                            retain: packet.fixed.get_retain(),
+                        });
+                    }
+                }
+                ClientEvent::Disconnect(_) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if self
+            .message_bridge
+            .try_send(Command::DisconnectClient {
+                id: self.client_id.clone(),
+                connection_id: self.connection_id,
+            })
+            .is_err()
+        {
+            debug!("Failed to disconnect in process client");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use crate::hooks::{HookFuture, InterceptedMessage};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct TestHook {
+        connected: Arc<AtomicUsize>,
+        delivered: Arc<AtomicUsize>,
+    }
+
+    impl BrokerHook for TestHook {
+        fn on_connect<'a>(&'a self, _client_id: &'a str) -> HookFuture<'a, ()> {
+            Box::pin(async move {
+                self.connected.fetch_add(1, Ordering::Relaxed);
+            })
+        }
+
+        fn on_publish<'a>(&'a self, topic: &'a str, _payload: &'a Bytes) -> HookFuture<'a, bool> {
+            Box::pin(async move { !topic.starts_with("blocked/") })
+        }
+
+        fn on_message_delivered<'a>(
+            &'a self,
+            _client_id: &'a str,
+            _topic: &'a str,
+            _payload: &'a Bytes,
+        ) -> HookFuture<'a, ()> {
+            Box::pin(async move {
+                self.delivered.fetch_add(1, Ordering::Relaxed);
+            })
+        }
+    }
+
+    #[test]
+    fn test_accept_errors() {
+        use std::io::{Error, ErrorKind};
+
+        assert!(is_connection_error(&Error::from(
+            ErrorKind::ConnectionAborted
+        )));
+        // EMFILE, the listener has to back off until file descriptors are freed
+        assert!(!is_connection_error(&Error::from_raw_os_error(24)));
+    }
+
+    #[tokio::test]
+    async fn test_panic_message() {
+        let err = tokio::spawn(async { panic!("client {} broke", 1) })
+            .await
+            .unwrap_err();
+        assert_eq!(panic_message(err.into_panic().as_ref()), "client 1 broke");
+
+        let err = tokio::spawn(async { std::panic::panic_any(1) })
+            .await
+            .unwrap_err();
+        assert_eq!(panic_message(err.into_panic().as_ref()), "unknown panic");
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let hook = TestHook::default();
+        let connected = hook.connected.clone();
+        let delivered = hook.delivered.clone();
+
+        let broker = Broker::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .hook(hook)
+            .build()
+            .expect("Failed to build broker");
+        let handle = broker.handle();
+        let task = tokio::spawn(broker.run());
+
+        let mut sub = handle
+            .subscribe("test", vec![("#".into(), QosLevel::AtMost)])
+            .await
+            .expect("Failed to subscribe");
+
+        handle
+            .publish(
+                "blocked/topic",
+                Bytes::from_static(b"dropped"),
+                QosLevel::AtMost,
+            )
+            .await
+            .expect("Failed to publish");
+        handle
+            .publish(
+                "allowed/topic",
+                Bytes::from_static(b"delivered"),
+                QosLevel::AtMost,
+            )
+            .await
+            .expect("Failed to publish");
+
+        let msg = sub.recv().await.expect("Failed to get message");
+        assert_eq!(msg.topic, "allowed/topic");
+
+        // wait for the command loop to finish running the publish hooks
+        let _sync = handle
+            .subscribe("sync", Vec::new())
+            .await
+            .expect("Failed to subscribe");
+
+        assert_eq!(connected.load(Ordering::Relaxed), 2);
+        assert_eq!(delivered.load(Ordering::Relaxed), 1);
+
+        handle.shutdown();
+        task.await
+            .expect("Failed to join broker")
+            .expect("Broker failed");
+    }
+
+    /// Drops messages that are not JSON objects and redacts the password of the rest
+    struct RedactPassword;
+
+    impl MessageInterceptor for RedactPassword {
+        fn intercept<'a>(&'a self, message: &'a mut InterceptedMessage) -> HookFuture<'a, bool> {
+            Box::pin(async move {
+                let Ok(serde_json::Value::Object(mut json)) =
+                    serde_json::from_slice::<serde_json::Value>(&message.payload)
+                else {
+                    return false;
+                };
+                if json.contains_key("password") {
+                    json.insert("password".into(), "***".into());
+                }
+                message.payload = Bytes::from(serde_json::Value::Object(json).to_string());
+                true
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptors() {
+        let broker = Broker::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .interceptor("users/#", 0, RedactPassword)
+            .build()
+            .expect("Failed to build broker");
+        let handle = broker.handle();
+        let task = tokio::spawn(broker.run());
+
+        let mut sub = handle
+            .subscribe("test", vec![("#".into(), QosLevel::AtMost)])
+            .await
+            .expect("Failed to subscribe");
+
+        for (topic, payload) in [
+            ("users/1", &b"not json"[..]),
+            ("users/1", br#"{"name":"a","password":"hunter2"}"#),
+            ("other", b"not json"),
+        ] {
+            handle
+                .publish(topic, Bytes::from_static(payload), QosLevel::AtMost)
+                .await
+                .expect("Failed to publish");
+        }
+
+        let msg = sub.recv().await.expect("Failed to get message");
+        assert_eq!(msg.topic, "users/1");
+        assert_eq!(
+            msg.payload,
+            Bytes::from_static(br#"{"name":"a","password":"***"}"#)
+        );
+        // only messages to the filter of the interceptor are checked
+        let msg = sub.recv().await.expect("Failed to get message");
+        assert_eq!(msg.topic, "other");
+        assert_eq!(msg.payload, Bytes::from_static(b"not json"));
+
+        handle.shutdown();
+        task.await
+            .expect("Failed to join broker")
+            .expect("Broker failed");
+    }
+
+    #[tokio::test]
+    async fn test_in_process_publish() {
+        let broker = Broker::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .build()
+            .expect("Failed to build broker");
+        let handle = broker.handle();
+        let task = tokio::spawn(broker.run());
+
+        let mut sub = handle
+            .subscribe("test", vec![("sensors/+".into(), QosLevel::AtMost)])
+            .await
+            .expect("Failed to subscribe");
+
+        handle
+            .publish("sensors/temp", Bytes::from_static(b"21"), QosLevel::AtMost)
+            .await
+            .expect("Failed to publish");
+
+        let msg = sub.recv().await.expect("Failed to get message");
+        assert_eq!(msg.topic, "sensors/temp");
+        assert_eq!(msg.payload, Bytes::from_static(b"21"));
+
+        handle.shutdown();
+        task.await
+            .expect("Failed to join broker")
+            .expect("Broker failed");
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        // the listener is dropped so the broker can bind the port it was given
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Failed to find a free port");
+        let broker = Broker::builder()
+            .bind(addr)
+            .build()
+            .expect("Failed to build broker");
+        let handle = broker.handle();
+        let task = tokio::spawn(broker.run());
+
+        let mut subs = [
+            handle
+                .subscribe("a", vec![("sensors/#".into(), QosLevel::AtMost)])
+                .await
+                .expect("Failed to subscribe"),
+            handle
+                .subscribe(
+                    "b",
+                    vec![
+                        ("sensors/+".into(), QosLevel::AtMost),
+                        ("lights/#".into(), QosLevel::AtMost),
+                    ],
+                )
+                .await
+                .expect("Failed to subscribe"),
+        ];
+        let before = handle.stats().await.expect("Failed to get stats");
+
+        handle
+            .local()
+            .publish(
+                "sensors/temp".into(),
+                Bytes::from_static(b"21"),
+                QosLevel::AtMost,
+                true,
+            )
+            .await
+            .expect("Failed to publish");
+        subs[0].recv().await.expect("Expected the retained message");
+
+        // a client publishing over the network, counted by the codec
+        let mut client = loop {
+            match TcpStream::connect(addr).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        client
+            .write_all(&[
+                0x10, 0x0D, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3C, 0x00, 0x01,
+                b'c',
+            ])
+            .await
+            .unwrap();
+        let mut connack = [0; 4];
+        client.read_exact(&mut connack).await.unwrap();
+        assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+        for _ in 0..5 {
+            let mut publish = vec![0x30, 0x10, 0x00, 0x0C];
+            publish.extend_from_slice(b"sensors/temp21");
+            client.write_all(&publish).await.unwrap();
+        }
+        for _ in 0..5 {
+            subs[0]
+                .recv()
+                .await
+                .expect("Expected the published message");
+        }
+        let after = handle.stats().await.expect("Failed to get stats");
+
+        assert_eq!(after.sessions, 3);
+        assert_eq!(after.subscriptions, 3);
+        assert_eq!(after.retained.messages, 1);
+        assert!(after.uptime >= before.uptime);
+        assert!(after.taken_at >= before.taken_at);
+        // the counters are shared with the other tests of the process, so they went up by at least as much
+        assert!(after.counters.publish_received >= before.counters.publish_received + 5);
+        let rates = after.rates(&before);
+        assert!(rates.publish_received > 0.0);
+        assert_eq!(before.rates(&after), Rates::default());
+
+        handle.shutdown();
+        task.await
+            .expect("Failed to join broker")
+            .expect("Broker failed");
+    }
+
+    #[tokio::test]
+    async fn test_command_shards() {
+        let config = ConfigBuilder::new()
+            .parse("command_shards 4\nsys_interval 0\n")
+            .expect("Failed to parse config");
+        let broker = Broker::builder()
+            .config(config)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .build()
+            .expect("Failed to build broker");
+        let handle = broker.handle();
+        let task = tokio::spawn(broker.run());
+
+        // the clients are spread over the shards
+        let mut subs = Vec::new();
+        for cid in ["a", "b", "c", "d", "e", "f"] {
+            let sub = handle
+                .subscribe(cid, vec![("sensors/#".into(), QosLevel::AtMost)])
+                .await
+                .expect("Failed to subscribe");
+            subs.push(sub);
+        }
+        assert_eq!(handle.clients().await.unwrap().len(), 6);
+
+        handle
+            .local()
+            .publish(
+                "sensors/temp".into(),
+                Bytes::from_static(b"21"),
+                QosLevel::AtMost,
+                true,
+            )
+            .await
+            .expect("Failed to publish");
+        for sub in &mut subs {
+            let msg = sub.recv().await.expect("Failed to get message");
+            assert_eq!(msg.topic, "sensors/temp");
+        }
+
+        // the retained store is shared by the shards
+        let mut late = handle
+            .subscribe("late", vec![("sensors/#".into(), QosLevel::AtMost)])
+            .await
+            .expect("Failed to subscribe");
+        let msg = late.recv().await.expect("Failed to get message");
+        assert_eq!(msg.payload, Bytes::from_static(b"21"));
+        assert_eq!(handle.retained_count().await.unwrap(), 1);
+        assert!(handle.health().await.responsive);
+
+        handle.shutdown();
+        task.await
+            .expect("Failed to join broker")
+            .expect("Broker failed");
+    }
+
+    #[tokio::test]
+    async fn test_command_shards_shared_subscription() {
+        let config = ConfigBuilder::new()
+            .parse("command_shards 4\nsys_interval 0\n")
+            .expect("Failed to parse config");
+        let broker = Broker::builder()
+            .config(config)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .build()
+            .expect("Failed to build broker");
+        let handle = broker.handle();
+        let task = tokio::spawn(broker.run());
+
+        // the members of the group are spread over the shards
+        let mut members = Vec::new();
+        for cid in ["a", "b", "c", "d", "e", "f"] {
+            let sub = handle
+                .subscribe(
+                    cid,
+                    vec![("$share/workers/jobs/#".into(), QosLevel::AtMost)],
+                )
+                .await
+                .expect("Failed to subscribe");
+            members.push(sub);
+        }
+        let mut watcher = handle
+            .subscribe("watcher", vec![("jobs/#".into(), QosLevel::AtMost)])
+            .await
+            .expect("Failed to subscribe");
+
+        for i in 0..12 {
+            handle
+                .local()
+                .publish(
+                    format!("jobs/{}", i),
+                    Bytes::from_static(b"job"),
+                    QosLevel::AtMost,
+                    false,
+                )
+                .await
+                .expect("Failed to publish");
+        }
+        for _ in 0..12 {
+            watcher.recv().await.expect("Failed to get message");
+        }
+
+        // each message went to exactly one member, the members took turns
+        let mut received = Vec::new();
+        for member in &mut members {
+            let mut count = 0;
+            while let Ok(Some(_)) =
+                tokio::time::timeout(Duration::from_millis(100), member.recv()).await
+            {
+                count += 1;
+            }
+            received.push(count);
+        }
+        assert_eq!(received.iter().sum::<usize>(), 12);
+        assert_eq!(received, vec![2; 6]);
+
+        handle.shutdown();
+        task.await
+            .expect("Failed to join broker")
+            .expect("Broker failed");
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let broker = Broker::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .build()
+            .expect("Failed to build broker");
+        let handle = broker.handle();
+        let task = tokio::spawn(broker.run());
+
+        let mut before = handle
+            .subscribe("before", vec![("$internal/#".into(), QosLevel::AtMost)])
+            .await
+            .expect("Failed to subscribe");
+
+        let config = ConfigBuilder::new()
+            .parse("allow_dollar_namespace $internal\n")
+            .and_then(ConfigBuilder::build)
+            .expect("Failed to build config");
+        handle.reload(config).await.expect("Failed to reload");
+
+        let mut after = handle
+            .subscribe("after", vec![("$internal/#".into(), QosLevel::AtMost)])
+            .await
+            .expect("Failed to subscribe");
+
+        handle
+            .publish("$internal/a", Bytes::from_static(b"data"), QosLevel::AtMost)
+            .await
+            .expect("Failed to publish");
+
+        let msg = after.recv().await.expect("Failed to get message");
+        assert_eq!(msg.topic, "$internal/a");
+
+        handle.shutdown();
+        // the subscription made before the reload was rejected
+        assert!(before.recv().await.is_none());
+        task.await
+            .expect("Failed to join broker")
+            .expect("Broker failed");
+    }
+}
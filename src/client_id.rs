@@ -0,0 +1,160 @@
+use crate::{
+    config::{ClientIdCharset, Config},
+    core::enums::ProtocalVersion,
+    error::MqttError,
+    packets::enums::ConnectReturnCode,
+};
+
+/// Prefix of client ids assigned by the server
+const AUTO_ID_PREFIX: &str = "auto-";
+
+/// ### Client Identifier
+/// Which client ids the server accepts.
+///
+/// A client connecting with an empty client id is assigned one by the server if allowed.
+/// A v3.1.1 client must also set Clean Session, a v5 client is told the assigned id
+/// with the Assigned Client Identifier property of the CONNACK.
+///
+/// [(MQTT 5) 3.1.3.1 Client Identifier](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901059)
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIdPolicy {
+    /// Accept empty client ids
+    pub allow_empty: bool,
+    /// Maximum length in bytes
+    pub max_length: usize,
+    pub charset: ClientIdCharset,
+}
+
+impl Default for ClientIdPolicy {
+    fn default() -> Self {
+        Self {
+            allow_empty: true,
+            max_length: u16::MAX as usize,
+            charset: ClientIdCharset::Any,
+        }
+    }
+}
+
+impl From<&Config> for ClientIdPolicy {
+    fn from(config: &Config) -> Self {
+        Self {
+            allow_empty: config.allow_zero_length_clientid,
+            max_length: config.max_clientid_length,
+            charset: config.clientid_charset,
+        }
+    }
+}
+
+/// A client id accepted by the [`ClientIdPolicy`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientId {
+    /// The id sent by the client
+    Client(String),
+    /// An id generated by the server for a client that sent an empty id
+    Assigned(String),
+}
+
+impl ClientId {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Client(id) | Self::Assigned(id) => id,
+        }
+    }
+}
+
+impl ClientIdPolicy {
+    /// Check the client id of a CONNECT packet, assigning one if it is empty
+    pub fn resolve(
+        &self,
+        client_id: String,
+        clean_session: bool,
+        protocol: ProtocalVersion,
+    ) -> Result<ClientId, MqttError> {
+        if client_id.is_empty() {
+            // A v3.1.1 client with an empty id can't resume a session
+            if !self.allow_empty || (protocol != ProtocalVersion::Five && !clean_session) {
+                return Err(MqttError::ClientIdentifierRejected);
+            }
+
+            let id = uuid::Uuid::new_v4().simple().to_string();
+            return Ok(ClientId::Assigned(format!("{}{}", AUTO_ID_PREFIX, id)));
+        }
+
+        if client_id.len() > self.max_length || !self.charset.allows(&client_id) {
+            return Err(MqttError::ClientIdentifierRejected);
+        }
+
+        Ok(ClientId::Client(client_id))
+    }
+}
+
+/// The CONNACK return code for a rejected client id
+pub fn rejected_code(protocol: ProtocalVersion) -> ConnectReturnCode {
+    match protocol {
+        ProtocalVersion::Five => ConnectReturnCode::ClientIdentifierNotValid,
+        _ => ConnectReturnCode::V4IdentifierRejected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assigned_client_id() {
+        let policy = ClientIdPolicy::default();
+
+        let id = policy
+            .resolve(String::new(), false, ProtocalVersion::Five)
+            .unwrap();
+        assert!(matches!(id, ClientId::Assigned(ref id) if id.starts_with(AUTO_ID_PREFIX)));
+
+        assert!(policy
+            .resolve(String::new(), true, ProtocalVersion::Four)
+            .is_ok());
+        assert!(policy
+            .resolve(String::new(), false, ProtocalVersion::Four)
+            .is_err());
+
+        let policy = ClientIdPolicy {
+            allow_empty: false,
+            ..ClientIdPolicy::default()
+        };
+        assert!(policy
+            .resolve(String::new(), true, ProtocalVersion::Five)
+            .is_err());
+    }
+
+    #[test]
+    fn test_client_id_policy() {
+        let policy = ClientIdPolicy {
+            allow_empty: true,
+            max_length: 23,
+            charset: ClientIdCharset::Alphanumeric,
+        };
+
+        assert_eq!(
+            policy
+                .resolve("sensor01".into(), true, ProtocalVersion::Four)
+                .unwrap(),
+            ClientId::Client("sensor01".into())
+        );
+        assert!(policy
+            .resolve("sensor-01".into(), true, ProtocalVersion::Four)
+            .is_err());
+        assert!(policy
+            .resolve("a".repeat(24), true, ProtocalVersion::Four)
+            .is_err());
+
+        let policy = ClientIdPolicy {
+            charset: ClientIdCharset::Printable,
+            ..policy
+        };
+        assert!(policy
+            .resolve("sensor-01".into(), true, ProtocalVersion::Four)
+            .is_ok());
+        assert!(policy
+            .resolve("sensor 01".into(), true, ProtocalVersion::Four)
+            .is_err());
+    }
+}
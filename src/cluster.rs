@@ -0,0 +1,225 @@
+use std::{collections::HashSet, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, select};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+};
+use tracing::{debug, error, info, instrument};
+
+use crate::{
+    core::enums::{Command, ProtocalVersion},
+    error::MqttError,
+    local_handle::LocalHandle,
+    packet_id::PacketIdAllocator,
+    packets::{
+        codec::MqttCodec,
+        enums::{ConnectReturnCode, QosLevel, SubackReturnCode},
+        Packet, VariableHeader,
+    },
+};
+
+/// Client ids of the connections between cluster nodes start with this prefix
+pub const CLIENT_ID_PREFIX: &str = "$cluster.";
+
+const KEEPALIVE: u16 = 60;
+
+/// Seconds to wait before reconnecting to a peer
+const RESTART_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Is the client a link from another cluster node
+pub fn is_peer(client_id: &str) -> bool {
+    client_id.starts_with(CLIENT_ID_PREFIX)
+}
+
+/// ### Cluster
+/// Experimental clustering of broker nodes in a full mesh.
+///
+/// Every node connects to each of its peers as an MQTT client with the client id `$cluster.{node_id}`,
+/// and keeps that link subscribed to the topic filters of its own clients.
+/// The filters are synced every `sync_interval` so a new subscription can take that long to reach the other nodes.
+///
+/// A message received from a peer is only delivered to the node's own clients, never to another peer,
+/// so a publish crosses at most one link and every node must list every other node as a peer.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Unique name of this node
+    pub node_id: String,
+    /// `host:port` of the MQTT listeners of the other nodes
+    pub peers: Vec<String>,
+    /// How often the subscriptions of local clients are sent to the peers
+    pub sync_interval: Duration,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl ClusterConfig {
+    pub fn new(node_id: String) -> Self {
+        Self {
+            node_id,
+            peers: Vec::new(),
+            sync_interval: Duration::from_secs(1),
+            username: None,
+            password: None,
+        }
+    }
+
+    /// Client id this node uses on its peers
+    pub fn client_id(&self) -> String {
+        format!("{}{}", CLIENT_ID_PREFIX, self.node_id)
+    }
+}
+
+/// Keep a link to a peer until the cancellation token is triggered, reconnecting when the connection is lost.
+#[instrument(name = "cluster", skip_all, fields(peer = %peer))]
+pub async fn run_link(
+    config: ClusterConfig,
+    peer: String,
+    local: LocalHandle,
+    cancellation: CancellationToken,
+) {
+    loop {
+        info!("Connecting to {}", peer);
+        match link_handler(&config, &peer, &local, &cancellation).await {
+            Ok(()) => break,
+            Err(err) => error!("{}", err),
+        }
+
+        select! {
+            () = cancellation.cancelled() => break,
+            () = tokio::time::sleep(RESTART_TIMEOUT) => {}
+        }
+    }
+
+    debug!("Exited");
+}
+
+async fn link_handler(
+    config: &ClusterConfig,
+    peer: &str,
+    local: &LocalHandle,
+    cancellation: &CancellationToken,
+) -> Result<(), MqttError> {
+    let stream = TcpStream::connect(peer).await?;
+    let (read_stream, write_stream) = tokio::io::split(stream);
+    let mut reader = FramedRead::new(read_stream, MqttCodec::new(ProtocalVersion::Four));
+    let mut writer = FramedWrite::new(write_stream, MqttCodec::new(ProtocalVersion::Four));
+
+    writer
+        .send(Packet::make_connect(
+            config.client_id(),
+            KEEPALIVE,
+            true,
+            config.username.clone(),
+            config.password.clone(),
+            false,
+        ))
+        .await?;
+
+    let packet = reader.next().await.ok_or(MqttError::MissingFixedHeader)??;
+
+    match packet.variable {
+        VariableHeader::ConnAck { return_code, .. } => {
+            if !matches!(return_code, ConnectReturnCode::Accepted) {
+                return Err(MqttError::ConnectionRefused(return_code));
+            }
+        }
+        _ => return Err(MqttError::ProtocolViolation),
+    }
+
+    info!("Connected");
+
+    // Messages from a peer are published locally with the peer as the publisher
+    let publisher = format!("{}{}", CLIENT_ID_PREFIX, peer);
+    let mut packet_ids = PacketIdAllocator::new();
+    // Filters the peer is forwarding to this node
+    let mut subscribed = HashSet::new();
+
+    let mut sync = tokio::time::interval(config.sync_interval);
+    let mut keepalive = tokio::time::interval(Duration::from_secs(KEEPALIVE as u64 / 2));
+    keepalive.tick().await;
+
+    loop {
+        select! {
+            () = cancellation.cancelled() => break,
+            _ = keepalive.tick() => {
+                writer.send(Packet::make_ping_req()).await?;
+            }
+            _ = sync.tick() => {
+                let filters = local.request(Command::ClusterFilters).await?;
+
+                // Subscribe at QoS 2 so messages keep the QoS they were published with
+                let added = filters
+                    .difference(&subscribed)
+                    .map(|filter| (filter.clone(), QosLevel::Exactly))
+                    .collect::<Vec<(String, QosLevel)>>();
+                let removed = subscribed
+                    .difference(&filters)
+                    .cloned()
+                    .collect::<Vec<String>>();
+
+                if !added.is_empty() {
+                    let packet_id = packet_ids.allocate().ok_or(MqttError::Unknown)?;
+                    writer.send(Packet::make_subscribe(packet_id, added)).await?;
+                }
+                if !removed.is_empty() {
+                    let packet_id = packet_ids.allocate().ok_or(MqttError::Unknown)?;
+                    writer.send(Packet::make_unsubscribe(packet_id, removed)).await?;
+                }
+
+                subscribed = filters;
+            }
+            frame = reader.next() => {
+                let packet = frame.ok_or_else(|| MqttError::Io(std::io::ErrorKind::ConnectionAborted.into()))??;
+
+                match packet.variable {
+                    VariableHeader::Publish { topic, packet_id, payload, .. } => {
+                        let qos = packet.fixed.get_qos()?;
+                        local
+                            .publish_as(&publisher, topic, payload, qos, packet.fixed.get_retain())
+                            .await?;
+
+                        let resp = match qos {
+                            QosLevel::AtMost => None,
+                            QosLevel::AtLeast => Some(Packet::make_puback(packet_id.ok_or(MqttError::ProtocolViolation)?)),
+                            QosLevel::Exactly => Some(Packet::make_pubrec(packet_id.ok_or(MqttError::ProtocolViolation)?)),
+                        };
+
+                        if let Some(resp) = resp {
+                            writer.send(resp).await?;
+                        }
+                    }
+                    VariableHeader::PubRel { packet_id, .. } => {
+                        writer.send(Packet::make_pubcomp(packet_id)).await?;
+                    }
+                    VariableHeader::SubAck { packet_id, return_codes, .. } => {
+                        packet_ids.release(packet_id);
+                        if return_codes.iter().any(SubackReturnCode::is_failure) {
+                            error!("Peer rejected a subscription");
+                        }
+                    }
+                    VariableHeader::UnsubAck { packet_id, .. } => {
+                        packet_ids.release(packet_id);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_peer() {
+        let config = ClusterConfig::new("node-1".into());
+        assert_eq!(config.client_id(), "$cluster.node-1");
+        assert!(is_peer(&config.client_id()));
+        assert!(!is_peer("sensor-1"));
+    }
+}
@@ -1,15 +1,114 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
+use tracing::level_filters::LevelFilter;
+
+use crate::{
+    auth::{ExternalAuth, HttpAuthConfig, JwtAuthConfig},
+    bridge::{topic::BridgeTopic, BridgeConfig},
+    cluster::ClusterConfig,
+    error::MqttError,
+    logging::LogFormat,
+    mount_point,
+    packets::{enums::QosLevel, topic},
+    quota::{PayloadLimit, PayloadLimitReason, RetainedFilterLimit, RetainedPolicy},
+    tls::TlsConfig,
+    topic_policy::FilterNormalization,
+};
+
+#[derive(Debug)]
 pub struct ConfigBuilder {
     username: Option<String>,
     password: Option<String>,
     allow_anonymous: bool,
-    address: String,
+    anonymous_topics: Vec<String>,
+    mount_point: Option<String>,
+    user_mount_points: HashMap<String, String>,
+    auth_http_url: Option<String>,
+    auth_http_timeout: u64,
+    auth_jwt_keys: Vec<PathBuf>,
+    auth_jwt_secret: Option<String>,
+    auth_jwt_algorithm: Option<jsonwebtoken::Algorithm>,
+    auth_jwt_issuer: Option<String>,
+    auth_jwt_audience: Option<String>,
+    addresses: Vec<String>,
     port: u16,
     sys_interval: u64,
+    sys_client_metrics: bool,
+    event_topic_prefix: Option<String>,
+    max_connections: Option<usize>,
+    max_connection_rate: Option<u32>,
+    max_publish_rate: Option<u32>,
+    user_publish_rates: HashMap<String, Option<u32>>,
+    max_publish_burst: Option<u32>,
+    publish_flood_timeout: u64,
+    ban_auth_failures: Option<u32>,
+    ban_connection_floods: Option<u32>,
+    ban_time: u64,
+    ban_time_max: u64,
+    max_inflight_messages: u16,
+    max_qos: QosLevel,
+    retain_available: bool,
+    wildcard_subscription_available: bool,
+    shared_subscription_available: bool,
+    max_queued_messages: usize,
+    queue_qos0_messages: bool,
+    max_subscriptions_per_client: Option<usize>,
+    max_topic_levels: Option<usize>,
+    max_topic_length: Option<usize>,
+    payload_limits: Vec<PayloadLimit>,
+    payload_limit_reason: PayloadLimitReason,
+    max_retained_messages: Option<usize>,
+    max_retained_bytes: Option<usize>,
+    retained_filter_limits: Vec<RetainedFilterLimit>,
+    retained_policy: RetainedPolicy,
+    strict_payload_format: bool,
+    stamp_messages: bool,
+    slow_client_policy: SlowClientPolicy,
+    slow_client_timeout: u64,
+    command_shards: usize,
+    direct_delivery: bool,
+    dollar_namespaces: Vec<String>,
+    topic_filter_normalization: FilterNormalization,
+    allow_zero_length_clientid: bool,
+    max_clientid_length: usize,
+    clientid_charset: ClientIdCharset,
+    log_level: Option<LevelFilter>,
+    log_format: LogFormat,
+    admin_listener: Option<String>,
+    health_listener: Option<String>,
+    certfile: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+    cafile: Option<PathBuf>,
+    crlfile: Option<PathBuf>,
+    require_certificate: bool,
+    tls_reload_interval: u64,
+    use_identity_as_username: bool,
+    proxy_protocol: bool,
+    systemd: bool,
+    run_as_user: Option<String>,
+    run_as_group: Option<String>,
+    allow_mqtt31: bool,
+    tcp_nodelay: bool,
+    reuse_address: bool,
+    tcp_keepalive: Option<u64>,
+    tcp_keepalive_interval: Option<u64>,
+    tcp_keepalive_retries: Option<u32>,
+    socket_send_buffer: Option<u32>,
+    socket_recv_buffer: Option<u32>,
+    shutdown_timeout: u64,
+    write_timeout: u64,
+    packet_timeout: u64,
+    max_packet_size: Option<usize>,
+    server_reference: Option<String>,
+    bridges: Vec<BridgeConfig>,
+    cluster: Option<ClusterConfig>,
 }
 
 impl ConfigBuilder {
@@ -18,9 +117,88 @@ impl ConfigBuilder {
             username: None,
             password: None,
             allow_anonymous: true,
-            address: "0.0.0.0".into(),
+            anonymous_topics: Vec::new(),
+            mount_point: None,
+            user_mount_points: HashMap::new(),
+            auth_http_url: None,
+            auth_http_timeout: 5,
+            auth_jwt_keys: Vec::new(),
+            auth_jwt_secret: None,
+            auth_jwt_algorithm: None,
+            auth_jwt_issuer: None,
+            auth_jwt_audience: None,
+            addresses: Vec::new(),
             port: 1833,
             sys_interval: 10,
+            sys_client_metrics: false,
+            event_topic_prefix: None,
+            max_connections: None,
+            max_connection_rate: None,
+            max_publish_rate: None,
+            user_publish_rates: HashMap::new(),
+            max_publish_burst: None,
+            publish_flood_timeout: 10,
+            ban_auth_failures: None,
+            ban_connection_floods: None,
+            ban_time: 60,
+            ban_time_max: 3600,
+            max_inflight_messages: 20,
+            max_qos: QosLevel::Exactly,
+            retain_available: true,
+            wildcard_subscription_available: true,
+            shared_subscription_available: true,
+            max_queued_messages: 100,
+            queue_qos0_messages: false,
+            max_subscriptions_per_client: None,
+            max_topic_levels: Some(200),
+            max_topic_length: None,
+            strict_payload_format: false,
+            stamp_messages: false,
+            payload_limits: Vec::new(),
+            payload_limit_reason: PayloadLimitReason::QuotaExceeded,
+            max_retained_messages: None,
+            max_retained_bytes: None,
+            retained_filter_limits: Vec::new(),
+            retained_policy: RetainedPolicy::Reject,
+            slow_client_policy: SlowClientPolicy::DropQosZero,
+            slow_client_timeout: 5,
+            command_shards: 1,
+            direct_delivery: false,
+            dollar_namespaces: Vec::new(),
+            topic_filter_normalization: FilterNormalization::Keep,
+            allow_zero_length_clientid: true,
+            max_clientid_length: u16::MAX as usize,
+            clientid_charset: ClientIdCharset::Any,
+            log_level: None,
+            log_format: LogFormat::Text,
+            admin_listener: None,
+            health_listener: None,
+            certfile: None,
+            keyfile: None,
+            cafile: None,
+            crlfile: None,
+            require_certificate: false,
+            tls_reload_interval: 0,
+            use_identity_as_username: false,
+            proxy_protocol: false,
+            systemd: false,
+            run_as_user: None,
+            run_as_group: None,
+            allow_mqtt31: true,
+            tcp_nodelay: true,
+            reuse_address: true,
+            tcp_keepalive: None,
+            tcp_keepalive_interval: None,
+            tcp_keepalive_retries: None,
+            socket_send_buffer: None,
+            socket_recv_buffer: None,
+            shutdown_timeout: 5,
+            write_timeout: 30,
+            packet_timeout: 30,
+            max_packet_size: None,
+            server_reference: None,
+            bridges: Vec::new(),
+            cluster: None,
         }
     }
 
@@ -37,12 +215,20 @@ impl ConfigBuilder {
     pub fn set_allow_anonymous(mut self, anonymous: bool) -> Self {
         self.allow_anonymous = anonymous;
         self
-    }
+    }*/
 
+    /// Bind the listener to a single address, replacing any added before
     pub fn set_address(mut self, address: String) -> Self {
-        self.address = address;
+        self.addresses = vec![address];
         self
-    }*/
+    }
+
+    /// Bind another listener, the address is an IP like `10.0.0.1` or `[::]`
+    /// that listens on `port`, or a socket address like `[::1]:1884` with its own port.
+    pub fn add_address(mut self, address: String) -> Self {
+        self.addresses.push(address);
+        self
+    }
 
     pub fn set_port(mut self, port: u16) -> Self {
         self.port = port;
@@ -54,29 +240,1192 @@ impl ConfigBuilder {
         self
     }
 
-    pub fn build(self) -> Result<Config, ()> {
-        let host = IpAddr::from_str(&self.address).map_err(|_| ())?;
+    /// Load options from a mosquitto style config file.
+    /// Options in the file override any options already set on the builder.
+    pub fn load_file<P: AsRef<Path>>(self, path: P) -> Result<Self, MqttError> {
+        let content = std::fs::read_to_string(path)?;
+        self.parse(&content)
+    }
+
+    /// Parse a mosquitto style config.
+    ///
+    /// Each line is an option name followed by its value. Lines starting with `#` are comments.
+    /// Bridge options apply to the bridge started by the most recent `connection` line.
+    pub fn parse(mut self, content: &str) -> Result<Self, MqttError> {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value.trim()),
+                None => (line, ""),
+            };
+
+            match key {
+                "port" => self.port = parse_value(key, value)?,
+                "bind_address" => self
+                    .addresses
+                    .extend(value.split_whitespace().map(str::to_string)),
+                "allow_anonymous" => self.allow_anonymous = parse_value(key, value)?,
+                "anonymous_topics" => {
+                    topic::validate_topic_filter(value).map_err(|_| {
+                        MqttError::InvalidConfig(format!(
+                            "Invalid topic filter '{}' for '{}'",
+                            value, key
+                        ))
+                    })?;
+                    self.anonymous_topics.push(value.to_string());
+                }
+                "mount_point" => self.mount_point = Some(parse_mount_point(key, value)?),
+                "user_mount_point" => {
+                    // user_mount_point <username> <mount point>
+                    let Some((user, mount_point)) = value.split_once(char::is_whitespace) else {
+                        return Err(MqttError::InvalidConfig(format!(
+                            "Missing mount point for '{}'",
+                            key
+                        )));
+                    };
+                    self.user_mount_points.insert(
+                        user.to_string(),
+                        parse_mount_point(key, mount_point.trim())?,
+                    );
+                }
+                "auth_http_url" => self.auth_http_url = Some(value.to_string()),
+                "auth_http_timeout" => self.auth_http_timeout = parse_value(key, value)?,
+                "auth_jwt_key" => self.auth_jwt_keys.push(PathBuf::from(value)),
+                "auth_jwt_secret" => self.auth_jwt_secret = Some(value.to_string()),
+                "auth_jwt_algorithm" => self.auth_jwt_algorithm = Some(parse_value(key, value)?),
+                "auth_jwt_issuer" => self.auth_jwt_issuer = Some(value.to_string()),
+                "auth_jwt_audience" => self.auth_jwt_audience = Some(value.to_string()),
+                "sys_interval" => self.sys_interval = parse_value(key, value)?,
+                "sys_client_metrics" => self.sys_client_metrics = parse_value(key, value)?,
+                "event_topic_prefix" => {
+                    // The events are published under the prefix, so a trailing `/` is dropped
+                    let prefix = value.trim_end_matches('/');
+                    if topic::validate_topic_name(prefix).is_err() {
+                        return Err(MqttError::InvalidConfig(format!(
+                            "Invalid topic '{}' for '{}'",
+                            value, key
+                        )));
+                    }
+                    self.event_topic_prefix = Some(prefix.to_string());
+                }
+                "max_connections" => self.max_connections = parse_limit(key, value)?,
+                "max_connection_rate" => self.max_connection_rate = parse_limit(key, value)?,
+                "max_publish_rate" => self.max_publish_rate = parse_limit(key, value)?,
+                "user_publish_rate" => {
+                    // user_publish_rate <username> <rate>
+                    let Some((user, rate)) = value.split_once(char::is_whitespace) else {
+                        return Err(MqttError::InvalidConfig(format!(
+                            "Missing rate for '{}'",
+                            key
+                        )));
+                    };
+                    self.user_publish_rates
+                        .insert(user.to_string(), parse_limit(key, rate.trim())?);
+                }
+                "max_publish_burst" => self.max_publish_burst = parse_limit(key, value)?,
+                "publish_flood_timeout" => self.publish_flood_timeout = parse_value(key, value)?,
+                "ban_auth_failures" => self.ban_auth_failures = parse_limit(key, value)?,
+                "ban_connection_floods" => self.ban_connection_floods = parse_limit(key, value)?,
+                "ban_time" => self.ban_time = parse_value(key, value)?,
+                "ban_time_max" => self.ban_time_max = parse_value(key, value)?,
+                "max_inflight_messages" => {
+                    // 0 means no limit, which is the largest Receive Maximum a v5 client can be sent
+                    self.max_inflight_messages = match parse_value(key, value)? {
+                        0 => u16::MAX,
+                        value => value,
+                    }
+                }
+                "max_qos" => {
+                    self.max_qos =
+                        QosLevel::try_from(parse_value::<u8>(key, value)?).map_err(|_| {
+                            MqttError::InvalidConfig(format!(
+                                "Invalid value '{}' for '{}'",
+                                value, key
+                            ))
+                        })?
+                }
+                "retain_available" => self.retain_available = parse_value(key, value)?,
+                "wildcard_subscription_available" => {
+                    self.wildcard_subscription_available = parse_value(key, value)?
+                }
+                "shared_subscription_available" => {
+                    self.shared_subscription_available = parse_value(key, value)?
+                }
+                "allow_zero_length_clientid" => {
+                    self.allow_zero_length_clientid = parse_value(key, value)?
+                }
+                "max_clientid_length" => self.max_clientid_length = parse_value(key, value)?,
+                "clientid_charset" => self.clientid_charset = value.parse()?,
+                "log_level" => self.log_level = Some(parse_value(key, value)?),
+                "log_format" => self.log_format = value.parse()?,
+                "admin_listener" => self.admin_listener = Some(value.to_string()),
+                "health_listener" => self.health_listener = Some(value.to_string()),
+                "certfile" => self.certfile = Some(PathBuf::from(value)),
+                "keyfile" => self.keyfile = Some(PathBuf::from(value)),
+                "cafile" => self.cafile = Some(PathBuf::from(value)),
+                "crlfile" => self.crlfile = Some(PathBuf::from(value)),
+                "tls_reload_interval" => self.tls_reload_interval = parse_value(key, value)?,
+                "require_certificate" => self.require_certificate = parse_value(key, value)?,
+                "proxy_protocol" => self.proxy_protocol = parse_value(key, value)?,
+                "systemd" => self.systemd = parse_value(key, value)?,
+                "user" => self.run_as_user = Some(value.to_string()),
+                "group" => self.run_as_group = Some(value.to_string()),
+                "allow_mqtt31" => self.allow_mqtt31 = parse_value(key, value)?,
+                "tcp_nodelay" => self.tcp_nodelay = parse_value(key, value)?,
+                "reuse_address" => self.reuse_address = parse_value(key, value)?,
+                "tcp_keepalive" => self.tcp_keepalive = parse_limit(key, value)?,
+                "tcp_keepalive_interval" => {
+                    self.tcp_keepalive_interval = Some(parse_value(key, value)?)
+                }
+                "tcp_keepalive_retries" => {
+                    self.tcp_keepalive_retries = Some(parse_value(key, value)?)
+                }
+                "socket_send_buffer" => self.socket_send_buffer = Some(parse_value(key, value)?),
+                "socket_recv_buffer" => self.socket_recv_buffer = Some(parse_value(key, value)?),
+                "shutdown_timeout" => self.shutdown_timeout = parse_value(key, value)?,
+                "write_timeout" => self.write_timeout = parse_value(key, value)?,
+                "packet_timeout" => self.packet_timeout = parse_value(key, value)?,
+                "max_packet_size" => {
+                    self.max_packet_size = match value {
+                        "-1" => None,
+                        size => Some(parse_size(key, size)?),
+                    }
+                }
+                "server_reference" => self.server_reference = Some(value.to_string()),
+                "use_identity_as_username" => {
+                    self.use_identity_as_username = parse_value(key, value)?
+                }
+                "max_queued_messages" => self.max_queued_messages = parse_value(key, value)?,
+                "queue_qos0_messages" => self.queue_qos0_messages = parse_value(key, value)?,
+                "max_subscriptions_per_client" => {
+                    self.max_subscriptions_per_client = parse_limit(key, value)?
+                }
+                "max_topic_levels" => self.max_topic_levels = parse_limit(key, value)?,
+                "max_topic_length" => self.max_topic_length = parse_limit(key, value)?,
+                "strict_payload_format" => self.strict_payload_format = parse_value(key, value)?,
+                "stamp_messages" => self.stamp_messages = parse_value(key, value)?,
+                "max_payload_size" => {
+                    // max_payload_size <size> [topic filter]
+                    let (size, filter) = match value.split_once(char::is_whitespace) {
+                        Some((size, filter)) => (size, Some(filter.trim())),
+                        None => (value, None),
+                    };
+                    if let Some(filter) = filter {
+                        topic::validate_topic_filter(filter).map_err(|_| {
+                            MqttError::InvalidConfig(format!(
+                                "Invalid topic filter '{}' for '{}'",
+                                filter, key
+                            ))
+                        })?;
+                    }
+                    self.payload_limits.push(PayloadLimit {
+                        filter: filter.map(str::to_string),
+                        max_size: parse_size(key, size)?,
+                    });
+                }
+                "payload_limit_reason" => self.payload_limit_reason = value.parse()?,
+                "max_retained_messages" => self.max_retained_messages = parse_limit(key, value)?,
+                "max_retained_bytes" => {
+                    self.max_retained_bytes = match value {
+                        "-1" => None,
+                        size => Some(parse_size(key, size)?),
+                    }
+                }
+                "max_retained_per_filter" => {
+                    // max_retained_per_filter <count> <topic filter>
+                    let Some((count, filter)) = value.split_once(char::is_whitespace) else {
+                        return Err(MqttError::InvalidConfig(format!(
+                            "Missing topic filter for '{}'",
+                            key
+                        )));
+                    };
+                    let filter = filter.trim();
+                    topic::validate_topic_filter(filter).map_err(|_| {
+                        MqttError::InvalidConfig(format!(
+                            "Invalid topic filter '{}' for '{}'",
+                            filter, key
+                        ))
+                    })?;
+                    self.retained_filter_limits.push(RetainedFilterLimit {
+                        filter: filter.to_string(),
+                        max_messages: parse_value(key, count)?,
+                    });
+                }
+                "retained_policy" => self.retained_policy = value.parse()?,
+                "slow_client_policy" => self.slow_client_policy = value.parse()?,
+                "slow_client_timeout" => self.slow_client_timeout = parse_value(key, value)?,
+                "command_shards" => self.command_shards = parse_value(key, value)?,
+                "direct_delivery" => self.direct_delivery = parse_value(key, value)?,
+                "allow_dollar_namespace" => {
+                    if !value.starts_with('$')
+                        || value.contains('/')
+                        || value == "$SYS"
+                        || value == "$share"
+                    {
+                        return Err(MqttError::InvalidConfig(format!(
+                            "Invalid value '{}' for '{}'",
+                            value, key
+                        )));
+                    }
+                    self.dollar_namespaces.push(value.to_string());
+                }
+                "topic_filter_normalization" => self.topic_filter_normalization = value.parse()?,
+                "cluster_node_id" => {
+                    if value.is_empty() {
+                        return Err(MqttError::InvalidConfig(
+                            "cluster_node_id requires a name".into(),
+                        ));
+                    }
+                    self.cluster
+                        .get_or_insert_with(|| ClusterConfig::new(String::new()))
+                        .node_id = value.to_string();
+                }
+                "cluster_peer"
+                | "cluster_sync_interval"
+                | "cluster_username"
+                | "cluster_password" => {
+                    let cluster = self
+                        .cluster
+                        .get_or_insert_with(|| ClusterConfig::new(String::new()));
+
+                    match key {
+                        "cluster_peer" => cluster.peers.push(if value.contains(':') {
+                            value.to_string()
+                        } else {
+                            format!("{}:1883", value)
+                        }),
+                        "cluster_sync_interval" => {
+                            cluster.sync_interval = Duration::from_secs(parse_value(key, value)?)
+                        }
+                        "cluster_username" => cluster.username = Some(value.to_string()),
+                        "cluster_password" => cluster.password = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+                "connection" => {
+                    if value.is_empty() {
+                        return Err(MqttError::InvalidConfig(
+                            "connection requires a name".into(),
+                        ));
+                    }
+                    self.bridges.push(BridgeConfig::new(value.to_string()))
+                }
+                "address" | "addresses" | "topic" | "remote_clientid" | "remote_username"
+                | "remote_password" | "local_clientid" | "keepalive_interval" | "cleansession"
+                | "restart_timeout" | "try_private" => {
+                    let bridge = self.bridges.last_mut().ok_or_else(|| {
+                        MqttError::InvalidConfig(format!("'{}' must follow a connection", key))
+                    })?;
+
+                    match key {
+                        "address" | "addresses" => {
+                            let address = value.split_whitespace().next().unwrap_or_default();
+                            bridge.address = if address.contains(':') {
+                                address.to_string()
+                            } else {
+                                format!("{}:1883", address)
+                            };
+                        }
+                        "topic" => bridge.topics.push(BridgeTopic::parse(value)?),
+                        "remote_clientid" => bridge.remote_client_id = value.to_string(),
+                        "remote_username" => bridge.remote_username = Some(value.to_string()),
+                        "remote_password" => bridge.remote_password = Some(value.to_string()),
+                        "local_clientid" => bridge.local_client_id = value.to_string(),
+                        "keepalive_interval" => bridge.keepalive = parse_value(key, value)?,
+                        "cleansession" => bridge.clean_session = parse_value(key, value)?,
+                        "restart_timeout" => bridge.restart_timeout = parse_value(key, value)?,
+                        "try_private" => bridge.try_private = parse_value(key, value)?,
+                        _ => {}
+                    }
+                }
+                _ => tracing::warn!("Unknown config option '{}'", key),
+            }
+        }
+
+        if let Some(bridge) = self.bridges.iter().find(|b| b.address.is_empty()) {
+            return Err(MqttError::InvalidConfig(format!(
+                "Bridge '{}' is missing an address",
+                bridge.name
+            )));
+        }
+
+        if self
+            .cluster
+            .as_ref()
+            .is_some_and(|cluster| cluster.node_id.is_empty())
+        {
+            return Err(MqttError::InvalidConfig(
+                "Cluster options need a cluster_node_id".into(),
+            ));
+        }
+
+        Ok(self)
+    }
+
+    pub fn build(self) -> Result<Config, MqttError> {
+        let mut socket_addrs = Vec::new();
+        if self.addresses.is_empty() {
+            socket_addrs.push(SocketAddr::new(IpAddr::from([0, 0, 0, 0]), self.port));
+        }
+        for address in &self.addresses {
+            let addr = parse_bind_address(address, self.port).ok_or_else(|| {
+                MqttError::InvalidConfig(format!("Invalid bind address '{}'", address))
+            })?;
+            if !socket_addrs.contains(&addr) {
+                socket_addrs.push(addr);
+            }
+        }
+
+        let admin_addr = self
+            .admin_listener
+            .map(|addr| {
+                SocketAddr::from_str(&addr).map_err(|_| {
+                    MqttError::InvalidConfig(format!("Invalid admin listener '{}'", addr))
+                })
+            })
+            .transpose()?;
+
+        let health_addr = self
+            .health_listener
+            .map(|addr| {
+                SocketAddr::from_str(&addr).map_err(|_| {
+                    MqttError::InvalidConfig(format!("Invalid health listener '{}'", addr))
+                })
+            })
+            .transpose()?;
+
+        let tls = match (self.certfile, self.keyfile) {
+            (Some(cert_file), Some(key_file)) => Some(TlsConfig {
+                cert_file,
+                key_file,
+                ca_file: self.cafile,
+                crl_file: self.crlfile,
+                require_certificate: self.require_certificate,
+                use_identity_as_username: self.use_identity_as_username,
+                reload_interval: (self.tls_reload_interval > 0)
+                    .then_some(Duration::from_secs(self.tls_reload_interval)),
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(MqttError::InvalidConfig(
+                    "certfile and keyfile must be set together".into(),
+                ))
+            }
+        };
+
+        if let Some(tls) = &tls {
+            if tls.require_certificate && tls.ca_file.is_none() {
+                return Err(MqttError::InvalidConfig(
+                    "require_certificate needs a cafile".into(),
+                ));
+            }
+            if tls.crl_file.is_some() && tls.ca_file.is_none() {
+                return Err(MqttError::InvalidConfig("crlfile needs a cafile".into()));
+            }
+        }
+
+        if self.use_identity_as_username && !tls.as_ref().is_some_and(|tls| tls.require_certificate)
+        {
+            return Err(MqttError::InvalidConfig(
+                "use_identity_as_username needs require_certificate".into(),
+            ));
+        }
+
+        let has_jwt_key = !self.auth_jwt_keys.is_empty() || self.auth_jwt_secret.is_some();
+        let external_auth = match self.auth_http_url {
+            Some(_) if has_jwt_key => {
+                return Err(MqttError::InvalidConfig(
+                    "auth_http_url and auth_jwt options can't be used together".into(),
+                ))
+            }
+            Some(url) => Some(ExternalAuth::Http(HttpAuthConfig {
+                url,
+                timeout: Duration::from_secs(self.auth_http_timeout),
+            })),
+            None if has_jwt_key => Some(ExternalAuth::Jwt(JwtAuthConfig {
+                key_files: self.auth_jwt_keys,
+                secret: self.auth_jwt_secret,
+                algorithm: self.auth_jwt_algorithm,
+                issuer: self.auth_jwt_issuer,
+                audience: self.auth_jwt_audience,
+            })),
+            None if self.auth_jwt_algorithm.is_some()
+                || self.auth_jwt_issuer.is_some()
+                || self.auth_jwt_audience.is_some() =>
+            {
+                return Err(MqttError::InvalidConfig(
+                    "auth_jwt options need an auth_jwt_key or auth_jwt_secret".into(),
+                ))
+            }
+            None => None,
+        };
+
+        // Clients must always be allowed ids of 1 to 23 characters
+        if self.max_clientid_length < 23 {
+            return Err(MqttError::InvalidConfig(
+                "max_clientid_length must be at least 23".into(),
+            ));
+        }
 
-        let address = SocketAddr::new(host, self.port);
+        if self.max_topic_levels == Some(0) || self.max_topic_length == Some(0) {
+            return Err(MqttError::InvalidConfig(
+                "max_topic_levels and max_topic_length must be at least 1".into(),
+            ));
+        }
+
+        if self.max_queued_messages == 0 {
+            return Err(MqttError::InvalidConfig(
+                "max_queued_messages must be greater than 0".into(),
+            ));
+        }
 
         Ok(Config {
             user: self.username,
             pass: self.password,
             allow_anonymous: self.allow_anonymous,
-            socket_addr: address,
+            anonymous_topics: self.anonymous_topics,
+            mount_point: self.mount_point,
+            user_mount_points: self.user_mount_points,
+            external_auth,
+            socket_addrs,
             sys_interval: self.sys_interval,
+            sys_client_metrics: self.sys_client_metrics,
+            event_topic_prefix: self.event_topic_prefix,
+            max_connections: self.max_connections,
+            max_connection_rate: self.max_connection_rate,
+            max_publish_rate: self.max_publish_rate,
+            user_publish_rates: self.user_publish_rates,
+            max_publish_burst: self.max_publish_burst,
+            publish_flood_timeout: Duration::from_secs(self.publish_flood_timeout),
+            ban_auth_failures: self.ban_auth_failures,
+            ban_connection_floods: self.ban_connection_floods,
+            ban_time: Duration::from_secs(self.ban_time),
+            ban_time_max: Duration::from_secs(self.ban_time_max.max(self.ban_time)),
+            max_inflight_messages: self.max_inflight_messages,
+            max_qos: self.max_qos,
+            retain_available: self.retain_available,
+            wildcard_subscription_available: self.wildcard_subscription_available,
+            shared_subscription_available: self.shared_subscription_available,
+            max_queued_messages: self.max_queued_messages,
+            queue_qos0_messages: self.queue_qos0_messages,
+            max_subscriptions_per_client: self.max_subscriptions_per_client,
+            max_topic_levels: self.max_topic_levels,
+            max_topic_length: self.max_topic_length,
+            strict_payload_format: self.strict_payload_format,
+            stamp_messages: self.stamp_messages,
+            payload_limits: self.payload_limits,
+            payload_limit_reason: self.payload_limit_reason,
+            max_retained_messages: self.max_retained_messages,
+            max_retained_bytes: self.max_retained_bytes,
+            retained_filter_limits: self.retained_filter_limits,
+            retained_policy: self.retained_policy,
+            slow_client_policy: self.slow_client_policy,
+            slow_client_timeout: Duration::from_secs(self.slow_client_timeout),
+            command_shards: match self.command_shards {
+                0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+                shards => shards,
+            },
+            direct_delivery: self.direct_delivery,
+            dollar_namespaces: self.dollar_namespaces,
+            topic_filter_normalization: self.topic_filter_normalization,
+            allow_zero_length_clientid: self.allow_zero_length_clientid,
+            max_clientid_length: self.max_clientid_length,
+            clientid_charset: self.clientid_charset,
+            log_level: self.log_level,
+            log_format: self.log_format,
+            admin_addr,
+            health_addr,
+            tls,
+            proxy_protocol: self.proxy_protocol,
+            systemd: self.systemd,
+            run_as_user: self.run_as_user,
+            run_as_group: self.run_as_group,
+            allow_mqtt31: self.allow_mqtt31,
+            tcp_nodelay: self.tcp_nodelay,
+            reuse_address: self.reuse_address,
+            tcp_keepalive: self.tcp_keepalive.map(Duration::from_secs),
+            tcp_keepalive_interval: self.tcp_keepalive_interval.map(Duration::from_secs),
+            tcp_keepalive_retries: self.tcp_keepalive_retries,
+            socket_send_buffer: self.socket_send_buffer,
+            socket_recv_buffer: self.socket_recv_buffer,
+            shutdown_timeout: Duration::from_secs(self.shutdown_timeout),
+            write_timeout: (self.write_timeout > 0)
+                .then_some(Duration::from_secs(self.write_timeout)),
+            packet_timeout: (self.packet_timeout > 0)
+                .then_some(Duration::from_secs(self.packet_timeout)),
+            max_packet_size: self.max_packet_size,
+            server_reference: self.server_reference,
+            bridges: self.bridges,
+            cluster: self.cluster,
         })
     }
 }
 
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, MqttError> {
+    value
+        .parse::<T>()
+        .map_err(|_| MqttError::InvalidConfig(format!("Invalid value '{}' for '{}'", value, key)))
+}
+
+/// Parse a bind address, either a socket address or an IP that listens on `port`.
+/// IPv6 addresses can be wrapped in brackets like `[::]`.
+fn parse_bind_address(address: &str, port: u16) -> Option<SocketAddr> {
+    if let Ok(addr) = SocketAddr::from_str(address) {
+        return Some(addr);
+    }
+
+    let host = address
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(address);
+    IpAddr::from_str(host)
+        .ok()
+        .map(|host| SocketAddr::new(host, port))
+}
+
+/// Parse a size in bytes with an optional `KB`, `MB` or `GB` suffix, eg. `4KB` is 4096 bytes
+fn parse_size(key: &str, value: &str) -> Result<usize, MqttError> {
+    let upper = value.to_ascii_uppercase();
+    let (number, unit) = [("KB", 1 << 10), ("MB", 1 << 20), ("GB", 1 << 30), ("B", 1)]
+        .into_iter()
+        .find_map(|(suffix, unit)| upper.strip_suffix(suffix).map(|number| (number, unit)))
+        .unwrap_or((&upper, 1));
+
+    parse_value::<usize>(key, number.trim())?
+        .checked_mul(unit)
+        .ok_or_else(|| MqttError::InvalidConfig(format!("Invalid value '{}' for '{}'", value, key)))
+}
+
+fn parse_mount_point(key: &str, value: &str) -> Result<String, MqttError> {
+    if !mount_point::is_valid(value) {
+        return Err(MqttError::InvalidConfig(format!(
+            "Invalid mount point '{}' for '{}'",
+            value, key
+        )));
+    }
+    Ok(value.to_string())
+}
+
+/// Parse an optional limit where `-1` means unlimited
+fn parse_limit<T: FromStr>(key: &str, value: &str) -> Result<Option<T>, MqttError> {
+    if value == "-1" {
+        return Ok(None);
+    }
+    parse_value(key, value).map(Some)
+}
+
+/// What to do with a message for a client whose queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Disconnect the client straight away
+    Disconnect,
+    /// Drop QoS 0 messages, hold QoS 1 and 2 messages in the client's overflow until there is room, up to the timeout
+    DropQosZero,
+    /// Hold every message in the client's overflow until there is room, up to the timeout
+    Block,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = MqttError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "disconnect" => Ok(Self::Disconnect),
+            "drop_qos0" => Ok(Self::DropQosZero),
+            "block" => Ok(Self::Block),
+            _ => Err(MqttError::InvalidConfig(format!(
+                "Invalid value '{}' for 'slow_client_policy'",
+                value
+            ))),
+        }
+    }
+}
+
+/// Characters allowed in a client id
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClientIdCharset {
+    /// Any UTF-8 characters
+    #[default]
+    Any,
+    /// Only `0-9`, `a-z` and `A-Z`, the characters every server must accept
+    Alphanumeric,
+    /// Printable ASCII characters without spaces
+    Printable,
+}
+
+impl ClientIdCharset {
+    /// Check if every character of the client id is in the set
+    pub fn allows(&self, client_id: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Alphanumeric => client_id.chars().all(|c| c.is_ascii_alphanumeric()),
+            Self::Printable => client_id.chars().all(|c| c.is_ascii_graphic()),
+        }
+    }
+}
+
+impl FromStr for ClientIdCharset {
+    type Err = MqttError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "any" => Ok(Self::Any),
+            "alphanumeric" => Ok(Self::Alphanumeric),
+            "printable" => Ok(Self::Printable),
+            _ => Err(MqttError::InvalidConfig(format!(
+                "Invalid value '{}' for 'clientid_charset'",
+                value
+            ))),
+        }
+    }
+}
+
 /// Config
 /// See [Mosquitto](https://mosquitto.org/man/mosquitto-conf-5.html)
+#[derive(Debug)]
 pub struct Config {
     pub user: Option<String>,
     pub pass: Option<String>,
     pub allow_anonymous: bool,
+    /// Topic filters clients without a username are confined to, no restriction if empty.
+    pub anonymous_topics: Vec<String>,
+    /// Topic prefix of every client without a `user_mount_points` entry, like the `mount_point` of mosquitto.
+    pub mount_point: Option<String>,
+    /// Topic prefix of the clients with each username.
+    pub user_mount_points: HashMap<String, String>,
+    /// Service checking the credentials of clients instead of `user` and `pass`.
+    pub external_auth: Option<ExternalAuth>,
 
-    pub socket_addr: SocketAddr,
+    /// Addresses of the client listeners, each has its own accept loop.
+    pub socket_addrs: Vec<SocketAddr>,
 
     pub sys_interval: u64,
+    /// Publish the keep alive status of every client under `$SYS/broker/clients/{client_id}/`
+    pub sys_client_metrics: bool,
+    /// Publish client connect, disconnect, subscribe and unsubscribe events under this topic, see [`crate::events`]
+    pub event_topic_prefix: Option<String>,
+
+    /// Maximum number of client connections, `None` is unlimited.
+    pub max_connections: Option<usize>,
+    /// Maximum CONNECT packets per second from a single address.
+    pub max_connection_rate: Option<u32>,
+    /// Maximum PUBLISH packets per second from a single client.
+    pub max_publish_rate: Option<u32>,
+    /// `max_publish_rate` of the clients with a username, `None` is unlimited.
+    pub user_publish_rates: HashMap<String, Option<u32>>,
+    /// PUBLISH packets a client can send at once above its rate, `None` is the same as the rate.
+    pub max_publish_burst: Option<u32>,
+    /// How long a client can keep publishing QoS 1 and 2 messages ahead of its tokens until it is disconnected.
+    pub publish_flood_timeout: Duration,
+    /// Failed authentications in a row before an address is banned, `None` never bans.
+    pub ban_auth_failures: Option<u32>,
+    /// Times an address can exceed `max_connection_rate` before it is banned, `None` never bans.
+    pub ban_connection_floods: Option<u32>,
+    /// How long the first ban of an address lasts, each further ban lasts twice as long.
+    pub ban_time: Duration,
+    /// Longest an address can be banned for.
+    pub ban_time_max: Duration,
+    /// Maximum QoS 1 and QoS 2 messages a client may have in flight, sent as the Receive Maximum.
+    pub max_inflight_messages: u16,
+    /// Highest QoS clients can publish and subscribe with, sent to v5 clients as the Maximum QoS.
+    pub max_qos: QosLevel,
+    /// Clients can publish retained messages and have retained wills.
+    pub retain_available: bool,
+    /// Clients can subscribe to topic filters with `+` and `#`.
+    pub wildcard_subscription_available: bool,
+    /// Clients can make `$share/{ShareName}/{filter}` subscriptions.
+    pub shared_subscription_available: bool,
+    /// Size of the queue of messages waiting to be sent to each client.
+    pub max_queued_messages: usize,
+    /// QoS 0 messages are queued for offline clients with a persistent session, like QoS 1 and 2 messages.
+    pub queue_qos0_messages: bool,
+    /// Maximum topic filters a client can be subscribed to, `None` is unlimited.
+    pub max_subscriptions_per_client: Option<usize>,
+    /// Maximum levels of a topic name or filter, `None` is unlimited
+    pub max_topic_levels: Option<usize>,
+    /// Maximum length of a topic name or filter in bytes, `None` is unlimited
+    pub max_topic_length: Option<usize>,
+    /// Maximum payload sizes of published messages, see [`crate::quota::Quotas`].
+    pub payload_limits: Vec<PayloadLimit>,
+    /// Reason code clients publishing a payload over the limit are disconnected with.
+    pub payload_limit_reason: PayloadLimitReason,
+    /// Maximum number of retained messages, `None` is unlimited. See [`crate::quota::RetainedLimits`].
+    pub max_retained_messages: Option<usize>,
+    /// Maximum size of all retained messages in bytes, `None` is unlimited.
+    pub max_retained_bytes: Option<usize>,
+    /// Maximum number of retained messages for the topics matching a filter.
+    pub retained_filter_limits: Vec<RetainedFilterLimit>,
+    /// Whether a retained message over the limits is refused or older messages are evicted.
+    pub retained_policy: RetainedPolicy,
+    /// Disconnect clients that publish a payload that is not valid UTF-8 with the Payload Format Indicator set to UTF-8.
+    pub strict_payload_format: bool,
+    /// Add the time a message was received and its sequence number on the topic as User Properties.
+    pub stamp_messages: bool,
+    /// What to do when a client's queue is full.
+    pub slow_client_policy: SlowClientPolicy,
+    /// How long to wait for room in a full queue before disconnecting the client.
+    pub slow_client_timeout: Duration,
+    /// Command loops the sessions and subscriptions are split over, one per core with `0` in the config.
+    pub command_shards: usize,
+    /// Send QoS 0 messages to a topic with a single subscriber without the command loop.
+    pub direct_delivery: bool,
+
+    /// `$` namespaces clients can publish and subscribe to, `$SYS` can always be subscribed to.
+    pub dollar_namespaces: Vec<String>,
+    /// How topic filters that only differ by empty levels are treated, only read on start.
+    pub topic_filter_normalization: FilterNormalization,
+
+    /// Accept clients connecting with an empty client id and assign them one.
+    pub allow_zero_length_clientid: bool,
+    /// Maximum length of a client id in bytes.
+    pub max_clientid_length: usize,
+    /// Characters allowed in a client id.
+    pub clientid_charset: ClientIdCharset,
+
+    /// Maximum level of log messages, `None` keeps the level set by the program.
+    pub log_level: Option<LevelFilter>,
+    /// Format of the log output, only used when the program calls [`crate::logging::init`].
+    pub log_format: LogFormat,
+
+    /// Address of the admin HTTP API, disabled if `None`.
+    pub admin_addr: Option<SocketAddr>,
+    /// Address serving only the `/healthz` and `/readyz` probes of the admin API, disabled if `None`.
+    pub health_addr: Option<SocketAddr>,
+
+    /// TLS for the client listener, plain TCP if `None`.
+    pub tls: Option<TlsConfig>,
+    /// Expect a PROXY protocol v1 or v2 header at the start of every connection.
+    pub proxy_protocol: bool,
+    /// Use listeners from systemd socket activation and send service notifications on Linux.
+    pub systemd: bool,
+    /// User to switch to once the client listeners are bound when started as root, Unix only.
+    pub run_as_user: Option<String>,
+    /// Group to switch to with `run_as_user`, the primary group of the user if `None`.
+    pub run_as_group: Option<String>,
+    /// Accept MQTT 3.1 clients, which connect with the protocol name `MQIsdp`.
+    pub allow_mqtt31: bool,
+
+    /// Set TCP_NODELAY on accepted sockets.
+    pub tcp_nodelay: bool,
+    /// Set SO_REUSEADDR on the listener.
+    pub reuse_address: bool,
+    /// Idle time before TCP keepalive probes are sent, `None` leaves keepalive off.
+    pub tcp_keepalive: Option<Duration>,
+    /// Time between TCP keepalive probes.
+    pub tcp_keepalive_interval: Option<Duration>,
+    /// Unanswered TCP keepalive probes before the connection is dropped.
+    pub tcp_keepalive_retries: Option<u32>,
+    /// SO_SNDBUF of the listener and accepted sockets in bytes.
+    pub socket_send_buffer: Option<u32>,
+    /// SO_RCVBUF of the listener and accepted sockets in bytes.
+    pub socket_recv_buffer: Option<u32>,
+    /// How long a write to a client can wait before the client is disconnected, `None` waits forever.
+    pub write_timeout: Option<Duration>,
+    /// How long a client has to send the rest of a packet once it started sending it, `None` waits forever.
+    pub packet_timeout: Option<Duration>,
+    /// Largest packet accepted from a client, checked against the Remaining Length before the packet is read.
+    /// Sent to v5 clients as the Maximum Packet Size.
+    pub max_packet_size: Option<usize>,
+
+    /// How long clients have on shutdown to acknowledge the QoS 1 and 2 messages in flight.
+    pub shutdown_timeout: Duration,
+    /// Server Reference sent to v5 clients on shutdown, so they can move to another server.
+    pub server_reference: Option<String>,
+
+    pub bridges: Vec<BridgeConfig>,
+
+    /// Experimental cluster mode, disabled if `None`.
+    pub cluster: Option<ClusterConfig>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bridge::topic::BridgeDirection;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = ConfigBuilder::new()
+            .parse(
+                "# Broker\n\
+                 port 1884\n\
+                 health_listener 0.0.0.0:8081\n\
+                 user mosquitto\n\
+                 sys_client_metrics true\n\
+                 event_topic_prefix $SYS/broker/events/\n\
+                 \n\
+                 connection cloud\n\
+                 address 10.0.0.1\n\
+                 remote_clientid edge-01\n\
+                 topic sensors/# out 1 \"\" edge/\n\
+                 topic commands/# in 2\n",
+            )
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+
+        assert_eq!(config.socket_addrs, vec!["0.0.0.0:1884".parse().unwrap()]);
+        assert!(config.max_connections.is_none());
+        assert_eq!(config.health_addr, Some("0.0.0.0:8081".parse().unwrap()));
+        assert_eq!(config.run_as_user.as_deref(), Some("mosquitto"));
+        assert!(config.run_as_group.is_none());
+        assert!(config.sys_client_metrics);
+        assert_eq!(
+            config.event_topic_prefix.as_deref(),
+            Some("$SYS/broker/events")
+        );
+        assert_eq!(config.bridges.len(), 1);
+
+        let bridge = &config.bridges[0];
+        assert_eq!(bridge.name, "cloud");
+        assert_eq!(bridge.address, "10.0.0.1:1883");
+        assert_eq!(bridge.remote_client_id, "edge-01");
+        assert_eq!(bridge.topics.len(), 2);
+        assert_eq!(bridge.topics[0].remote_filter(), "edge/sensors/#");
+        assert_eq!(bridge.topics[1].direction, BridgeDirection::In);
+        assert!(bridge.try_private);
+    }
+
+    #[test]
+    fn test_parse_bind_address() {
+        let config = ConfigBuilder::new()
+            .parse(
+                "port 1884\n\
+                 bind_address 127.0.0.1 [::]\n\
+                 bind_address [::1]:1885 ::1\n\
+                 bind_address 127.0.0.1\n",
+            )
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+
+        assert_eq!(
+            config.socket_addrs,
+            vec![
+                "127.0.0.1:1884".parse().unwrap(),
+                "[::]:1884".parse().unwrap(),
+                "[::1]:1885".parse().unwrap(),
+                "[::1]:1884".parse().unwrap(),
+            ]
+        );
+
+        assert!(ConfigBuilder::new()
+            .parse("bind_address localhost\n")
+            .unwrap()
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let config = ConfigBuilder::new()
+            .parse(
+                "max_connections 100
+max_connection_rate -1
+max_publish_rate 10
+user_publish_rate sensor-1 100
+user_publish_rate admin -1
+max_publish_burst 20
+ban_auth_failures 5
+ban_time 30
+max_inflight_messages 0
+max_qos 1
+retain_available false
+wildcard_subscription_available false
+max_queued_messages 10
+queue_qos0_messages true
+max_subscriptions_per_client 5
+max_topic_levels 16
+max_topic_length -1
+strict_payload_format true
+stamp_messages true
+max_payload_size 1024
+max_payload_size 64KB firmware/#
+max_payload_size 4kb telemetry/#
+payload_limit_reason packet_too_large
+max_retained_messages 1000
+max_retained_bytes 1MB
+max_retained_per_filter 10 devices/+/status
+retained_policy evict_lru
+slow_client_policy disconnect
+command_shards 4
+direct_delivery true
+log_level info
+log_format json
+proxy_protocol true
+allow_mqtt31 false
+tcp_nodelay false
+tcp_keepalive 30
+socket_send_buffer 65536
+shutdown_timeout 10
+write_timeout 0
+packet_timeout 5
+max_packet_size 256KB
+server_reference backup:1883
+allow_zero_length_clientid false
+max_clientid_length 64
+clientid_charset alphanumeric
+",
+            )
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+
+        assert_eq!(config.max_connections, Some(100));
+        assert_eq!(config.max_connection_rate, None);
+        assert_eq!(config.max_publish_rate, Some(10));
+        assert_eq!(config.user_publish_rates.get("sensor-1"), Some(&Some(100)));
+        assert_eq!(config.user_publish_rates.get("admin"), Some(&None));
+        assert_eq!(config.max_publish_burst, Some(20));
+        assert_eq!(config.publish_flood_timeout, Duration::from_secs(10));
+        assert_eq!(config.ban_auth_failures, Some(5));
+        assert_eq!(config.ban_connection_floods, None);
+        assert_eq!(config.ban_time, Duration::from_secs(30));
+        assert_eq!(config.ban_time_max, Duration::from_secs(3600));
+        assert_eq!(config.max_inflight_messages, u16::MAX);
+        assert_eq!(config.max_qos, QosLevel::AtLeast);
+        assert!(!config.retain_available);
+        assert!(!config.wildcard_subscription_available);
+        assert!(config.shared_subscription_available);
+        assert_eq!(config.max_queued_messages, 10);
+        assert!(config.queue_qos0_messages);
+        assert_eq!(config.max_subscriptions_per_client, Some(5));
+        assert_eq!(config.max_topic_levels, Some(16));
+        assert_eq!(config.max_topic_length, None);
+        assert!(config.strict_payload_format);
+        assert!(config.stamp_messages);
+        assert_eq!(
+            config.payload_limits[1],
+            PayloadLimit {
+                filter: Some("firmware/#".into()),
+                max_size: 65536
+            }
+        );
+        assert_eq!(config.payload_limits[2].max_size, 4096);
+        assert_eq!(
+            config.payload_limit_reason,
+            PayloadLimitReason::PacketTooLarge
+        );
+        assert_eq!(config.max_retained_messages, Some(1000));
+        assert_eq!(config.max_retained_bytes, Some(1 << 20));
+        assert_eq!(
+            config.retained_filter_limits,
+            [RetainedFilterLimit {
+                filter: "devices/+/status".into(),
+                max_messages: 10
+            }]
+        );
+        assert_eq!(config.retained_policy, RetainedPolicy::EvictLru);
+        assert_eq!(config.slow_client_policy, SlowClientPolicy::Disconnect);
+        assert_eq!(config.command_shards, 4);
+        assert!(config.direct_delivery);
+        assert_eq!(config.log_level, Some(LevelFilter::INFO));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert!(config.proxy_protocol);
+        assert!(!config.allow_mqtt31);
+        assert!(!config.tcp_nodelay);
+        assert_eq!(config.tcp_keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(config.socket_send_buffer, Some(65536));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+        assert_eq!(config.write_timeout, None);
+        assert_eq!(config.packet_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.max_packet_size, Some(256 << 10));
+        assert_eq!(config.server_reference.as_deref(), Some("backup:1883"));
+        assert!(!config.allow_zero_length_clientid);
+        assert_eq!(config.max_clientid_length, 64);
+        assert_eq!(config.clientid_charset, ClientIdCharset::Alphanumeric);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("max_payload_size", "1024").unwrap(), 1024);
+        assert_eq!(parse_size("max_payload_size", "100B").unwrap(), 100);
+        assert_eq!(parse_size("max_payload_size", "4KB").unwrap(), 4096);
+        assert_eq!(parse_size("max_payload_size", "2mb").unwrap(), 2 << 20);
+        assert_eq!(parse_size("max_payload_size", "1GB").unwrap(), 1 << 30);
+        assert!(parse_size("max_payload_size", "KB").is_err());
+        assert!(parse_size("max_payload_size", "4TB").is_err());
+    }
+
+    #[test]
+    fn test_parse_tls() {
+        let config = ConfigBuilder::new()
+            .parse(
+                "certfile server.crt
+keyfile server.key
+cafile ca.crt
+crlfile ca.crl
+require_certificate true
+use_identity_as_username true
+tls_reload_interval 60
+",
+            )
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+
+        let tls = config.tls.expect("TLS config");
+        assert_eq!(tls.cert_file, PathBuf::from("server.crt"));
+        assert_eq!(tls.ca_file, Some(PathBuf::from("ca.crt")));
+        assert_eq!(tls.crl_file, Some(PathBuf::from("ca.crl")));
+        assert_eq!(tls.reload_interval, Some(Duration::from_secs(60)));
+        assert!(tls.use_identity_as_username);
+
+        for content in [
+            "certfile server.crt\n",
+            "certfile server.crt\nkeyfile server.key\nrequire_certificate true\n",
+            "use_identity_as_username true\n",
+            "certfile server.crt\nkeyfile server.key\ncrlfile ca.crl\n",
+        ] {
+            assert!(ConfigBuilder::new()
+                .parse(content)
+                .and_then(ConfigBuilder::build)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_dollar_namespace() {
+        let config = ConfigBuilder::new()
+            .parse("allow_dollar_namespace $internal\ntopic_filter_normalization collapse\n")
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+
+        assert_eq!(config.dollar_namespaces, vec!["$internal".to_string()]);
+        assert_eq!(
+            config.topic_filter_normalization,
+            FilterNormalization::Collapse
+        );
+
+        let config = ConfigBuilder::new()
+            .parse("anonymous_topics public/#\nanonymous_topics devices/+/status\n")
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+        assert_eq!(
+            config.anonymous_topics,
+            vec!["public/#", "devices/+/status"]
+        );
+
+        ConfigBuilder::new()
+            .parse("anonymous_topics public/#/a\n")
+            .expect_err("Expected an invalid filter to be rejected");
+
+        ConfigBuilder::new()
+            .parse("allow_dollar_namespace $SYS\n")
+            .expect_err("Expected $SYS to be rejected");
+    }
+
+    #[test]
+    fn test_parse_mount_point() {
+        let config = ConfigBuilder::new()
+            .parse("mount_point public/\nuser_mount_point acme tenants/acme/\n")
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+        assert_eq!(config.mount_point.as_deref(), Some("public/"));
+        assert_eq!(
+            config.user_mount_points.get("acme").map(String::as_str),
+            Some("tenants/acme/")
+        );
+
+        for content in [
+            "mount_point $SYS/\n",
+            "mount_point tenants/+/\n",
+            "user_mount_point acme\n",
+        ] {
+            ConfigBuilder::new()
+                .parse(content)
+                .expect_err("Expected an invalid mount point to be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_external_auth() {
+        let config = ConfigBuilder::new()
+            .parse("auth_http_url http://localhost:8080/auth\nauth_http_timeout 2\n")
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+        assert_eq!(
+            config.external_auth,
+            Some(ExternalAuth::Http(HttpAuthConfig {
+                url: "http://localhost:8080/auth".into(),
+                timeout: Duration::from_secs(2),
+            }))
+        );
+
+        let config = ConfigBuilder::new()
+            .parse("auth_jwt_key keys/a.pem\nauth_jwt_key keys/b.pem\nauth_jwt_algorithm ES256\nauth_jwt_audience mqtt\n")
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+        assert_eq!(
+            config.external_auth,
+            Some(ExternalAuth::Jwt(JwtAuthConfig {
+                key_files: vec![PathBuf::from("keys/a.pem"), PathBuf::from("keys/b.pem")],
+                secret: None,
+                algorithm: Some(jsonwebtoken::Algorithm::ES256),
+                issuer: None,
+                audience: Some("mqtt".into()),
+            }))
+        );
+
+        for content in [
+            "auth_http_url http://localhost/auth\nauth_jwt_secret secret\n",
+            "auth_jwt_issuer https://id.example.com\n",
+        ] {
+            ConfigBuilder::new()
+                .parse(content)
+                .expect("Failed to parse config")
+                .build()
+                .expect_err("Expected conflicting auth options to be rejected");
+        }
+        ConfigBuilder::new()
+            .parse("auth_jwt_algorithm XY256\n")
+            .expect_err("Expected an unknown algorithm to be rejected");
+    }
+
+    #[test]
+    fn test_parse_cluster() {
+        let config = ConfigBuilder::new()
+            .parse(
+                "cluster_node_id node-1
+cluster_peer 10.0.0.2
+cluster_peer 10.0.0.3:1884
+cluster_sync_interval 2
+",
+            )
+            .expect("Failed to parse config")
+            .build()
+            .expect("Failed to build config");
+
+        let cluster = config.cluster.expect("Cluster config");
+        assert_eq!(cluster.node_id, "node-1");
+        assert_eq!(cluster.peers, vec!["10.0.0.2:1883", "10.0.0.3:1884"]);
+        assert_eq!(cluster.sync_interval, Duration::from_secs(2));
+
+        ConfigBuilder::new()
+            .parse("cluster_peer 10.0.0.2\n")
+            .expect_err("Expected missing node id error");
+    }
+
+    #[test]
+    fn test_parse_bridge_option_without_connection() {
+        ConfigBuilder::new()
+            .parse("address 10.0.0.1:1883\n")
+            .expect_err("Expected bridge option error");
+    }
 }
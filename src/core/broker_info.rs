@@ -1,4 +1,7 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
 
 static TASKS_COUNT: AtomicUsize = AtomicUsize::new(0);
 /// The total number of bytes received since the broker started.
@@ -16,16 +19,166 @@ static MESSAGES_PUBLISH_RECEIVED: AtomicUsize = AtomicUsize::new(0);
 /// The total number of PUBLISH messages sent since the broker started.
 static MESSAGES_PUBLISH_SENT: AtomicUsize = AtomicUsize::new(0);
 
-pub fn get_stats() -> (usize, usize, usize, usize, usize, usize, usize) {
-    (
-        BYTES_RECEIVED.load(Ordering::Relaxed),
-        BYTES_SENT.load(Ordering::Relaxed),
-        CLIENTS_CONNECTED.load(Ordering::Relaxed),
-        MESSAGES_RECEIVED.load(Ordering::Relaxed),
-        MESSAGES_SENT.load(Ordering::Relaxed),
-        MESSAGES_PUBLISH_RECEIVED.load(Ordering::Relaxed),
-        MESSAGES_PUBLISH_SENT.load(Ordering::Relaxed),
-    )
+/// The total number of publish messages that have been dropped due to inflight/queuing limits.
+static MESSAGES_PUBLISH_DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// The total number of QoS 0 publish messages that have been dropped for being over the publish rate of their client.
+static MESSAGES_PUBLISH_THROTTLED_DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// The total number of QoS 1 and 2 publish messages that have been delayed for being over the publish rate of their client.
+static MESSAGES_PUBLISH_THROTTLED_DELAYED: AtomicUsize = AtomicUsize::new(0);
+
+/// The total number of times a listener failed to accept a connection since the broker started.
+static ACCEPT_ERRORS: AtomicUsize = AtomicUsize::new(0);
+/// The total number of connection handlers that panicked since the broker started.
+static CLIENTS_PANICKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Upper bounds in microseconds of the delivery latency buckets, the last bucket has no bound
+pub const LATENCY_BUCKETS: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// Messages delivered in each latency bucket, plus one for the messages slower than every bound
+static DELIVERY_LATENCY: [AtomicUsize; LATENCY_BUCKETS.len() + 1] =
+    [const { AtomicUsize::new(0) }; LATENCY_BUCKETS.len() + 1];
+/// Sum of every delivery latency in microseconds
+static DELIVERY_LATENCY_SUM: AtomicU64 = AtomicU64::new(0);
+
+/// ### Latency Histogram
+/// Time from a PUBLISH being received to the message being queued for a subscriber,
+/// counted once for every subscriber the message is delivered to.
+///
+/// The buckets are not cumulative, `buckets[i]` counts the latencies above the previous bound and at most `LATENCY_BUCKETS[i]`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// The bound of the bucket in microseconds, `None` for the last bucket, and the number of messages in it
+    pub buckets: Vec<(Option<u64>, usize)>,
+    /// Total number of deliveries
+    pub count: usize,
+    /// Sum of all latencies in microseconds
+    pub sum: u64,
+}
+
+impl LatencyHistogram {
+    /// Mean latency, zero if nothing has been delivered
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_micros(self.sum / count as u64),
+        }
+    }
+}
+
+/// Messages and bytes published to a single topic
+#[cfg(feature = "topic-stats")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TopicStats {
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+#[cfg(feature = "topic-stats")]
+static TOPICS: std::sync::LazyLock<dashmap::DashMap<String, TopicStats>> =
+    std::sync::LazyLock::new(dashmap::DashMap::new);
+
+/// Snapshot of the broker counters
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    pub bytes_received: usize,
+    pub bytes_sent: usize,
+    pub clients_connected: usize,
+    pub messages_received: usize,
+    pub messages_sent: usize,
+    pub publish_received: usize,
+    pub publish_sent: usize,
+    pub publish_dropped: usize,
+    pub publish_throttled_dropped: usize,
+    pub publish_throttled_delayed: usize,
+    pub accept_errors: usize,
+    pub clients_panicked: usize,
+}
+
+pub fn get_stats() -> Stats {
+    Stats {
+        bytes_received: BYTES_RECEIVED.load(Ordering::Relaxed),
+        bytes_sent: BYTES_SENT.load(Ordering::Relaxed),
+        clients_connected: CLIENTS_CONNECTED.load(Ordering::Relaxed),
+        messages_received: MESSAGES_RECEIVED.load(Ordering::Relaxed),
+        messages_sent: MESSAGES_SENT.load(Ordering::Relaxed),
+        publish_received: MESSAGES_PUBLISH_RECEIVED.load(Ordering::Relaxed),
+        publish_sent: MESSAGES_PUBLISH_SENT.load(Ordering::Relaxed),
+        publish_dropped: MESSAGES_PUBLISH_DROPPED.load(Ordering::Relaxed),
+        publish_throttled_dropped: MESSAGES_PUBLISH_THROTTLED_DROPPED.load(Ordering::Relaxed),
+        publish_throttled_delayed: MESSAGES_PUBLISH_THROTTLED_DELAYED.load(Ordering::Relaxed),
+        accept_errors: ACCEPT_ERRORS.load(Ordering::Relaxed),
+        clients_panicked: CLIENTS_PANICKED.load(Ordering::Relaxed),
+    }
+}
+
+pub fn dropped_published() {
+    MESSAGES_PUBLISH_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn publish_throttled_dropped() {
+    MESSAGES_PUBLISH_THROTTLED_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn publish_throttled_delayed() {
+    MESSAGES_PUBLISH_THROTTLED_DELAYED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn accept_failed() {
+    ACCEPT_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn client_panicked() {
+    CLIENTS_PANICKED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a message published to a topic
+#[cfg(feature = "topic-stats")]
+pub fn topic_published(topic: &str, bytes: usize) {
+    let mut stats = TOPICS.entry(topic.to_string()).or_default();
+    stats.messages += 1;
+    stats.bytes += bytes;
+}
+
+/// Counters of every topic that has been published to
+#[cfg(feature = "topic-stats")]
+pub fn topic_stats() -> Vec<(String, TopicStats)> {
+    TOPICS
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect()
+}
+
+/// Record the time it took to queue a message for a subscriber
+pub fn delivered(latency: Duration) {
+    let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+    let bucket = LATENCY_BUCKETS
+        .iter()
+        .position(|bound| micros <= *bound)
+        .unwrap_or(LATENCY_BUCKETS.len());
+
+    DELIVERY_LATENCY[bucket].fetch_add(1, Ordering::Relaxed);
+    DELIVERY_LATENCY_SUM.fetch_add(micros, Ordering::Relaxed);
+}
+
+pub fn delivery_latency() -> LatencyHistogram {
+    let buckets = DELIVERY_LATENCY
+        .iter()
+        .enumerate()
+        .map(|(i, count)| {
+            (
+                LATENCY_BUCKETS.get(i).copied(),
+                count.load(Ordering::Relaxed),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    LatencyHistogram {
+        count: buckets.iter().map(|(_, count)| count).sum(),
+        buckets,
+        sum: DELIVERY_LATENCY_SUM.load(Ordering::Relaxed),
+    }
 }
 
 pub fn received_published() {
@@ -46,6 +199,11 @@ pub fn sent_data(sent_bytes: usize) {
     MESSAGES_SENT.fetch_add(1, Ordering::Relaxed);
 }
 
+/// The number of currently connected clients
+pub fn clients_connected() -> usize {
+    CLIENTS_CONNECTED.load(Ordering::Relaxed)
+}
+
 /// incress connected clients count
 pub fn client_inc() {
     CLIENTS_CONNECTED.fetch_add(1, Ordering::Relaxed);
@@ -1,49 +1,232 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
 use bytes::Bytes;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::Config;
+use crate::direct::Route;
+use crate::events::DisconnectReason;
+use crate::flow_control::Inflight;
+use crate::packets::{
+    enums::{DisconnectReasonCode, QosLevel, UnsubackReasonCode},
+    PublishProperties, SubscriptionOptions,
+};
+use crate::retained::{RetainedMessage, RetainedStats};
+use crate::topic_heir::SubscriptionStats;
 
-use crate::packets::enums::QosLevel;
+use super::ClientInfo;
 use crate::{error::MqttError, packets::enums::SubackReturnCode};
 
 pub type Responder<T> = tokio::sync::oneshot::Sender<T>;
+
+/// User Name and Password sent by a network client in the CONNECT packet
+#[derive(Debug, Default, Clone)]
+pub struct Login {
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
 pub type Receiver<T> = tokio::sync::mpsc::Sender<T>;
 
+/// Details of a network connection reported in [`ClientInfo`]
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub addr: SocketAddr,
+    /// Keep Alive in seconds sent in the CONNECT packet
+    pub keepalive: u16,
+    /// Connected without a username, confined to the `anonymous_topics` filters
+    pub anonymous: bool,
+    /// Connected as a bridge, which is never sent its own messages back
+    pub bridge: bool,
+    /// Prefix of the topics of the client, added by the connection handler and removed from the messages it is sent
+    pub mount_point: Option<Arc<str>>,
+    /// Updated by the connection handler as messages are sent and acknowledged
+    pub inflight: Arc<Inflight>,
+    /// Updated by the connection handler for every packet it receives
+    pub last_seen: Arc<LastSeen>,
+}
+
+/// When a connection last received a packet, shared with the command loop for [`ClientInfo`]
+#[derive(Debug)]
+pub struct LastSeen(AtomicU64);
+
+impl Default for LastSeen {
+    fn default() -> Self {
+        let last_seen = Self(AtomicU64::new(0));
+        last_seen.touch();
+        last_seen
+    }
+}
+
+impl LastSeen {
+    /// Record a packet received now
+    pub fn touch(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.0.store(now, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// ### Connection Id
+/// Identifies one connection of a client.
+///
+/// The command loop handles commands one at a time in the order they were sent,
+/// so the CONNECT and DISCONNECT of every client id are already serialized.
+/// When a new connection takes over a session, the old connection can still send a
+/// [`Command::DisconnectClient`] that was queued behind the new [`Command::RegisterClient`],
+/// the id makes sure that only ends the session of the connection it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Unique id for a new connection
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 /// Enum for handling messages sent to the message listener
 #[derive(Debug)]
 pub enum Command {
     RegisterClient {
         id: String,
         message_channel: Receiver<ClientEvent>,
+        /// Cancelled by the broker to drop the client, eg. when it can't keep up with its messages
+        disconnect: CancellationToken,
         protocol: ProtocalVersion,
         clean_session: bool,
-        callback: Responder<Result<(), MqttError>>,
+        /// Checked by the [`crate::auth::Authenticator`], `None` for in process clients and bridges which are always accepted.
+        login: Option<Login>,
+        /// `None` for in process clients and bridges
+        connection: Option<Connection>,
+        connection_id: ConnectionId,
+        /// Responds with Session Present
+        callback: Responder<Result<bool, MqttError>>,
     },
 
     Subscribe {
         client: String,
-        topics: Vec<(String, QosLevel)>,
+        topics: Vec<(String, SubscriptionOptions)>,
+        /// v5 Subscription Identifier for all of the topics
+        subscription_identifier: Option<u32>,
         callback: Responder<Result<Vec<SubackReturnCode>, MqttError>>,
     },
 
     Publish {
         topic: String,
         payload: Bytes,
+        qos: QosLevel,
+        retain: bool,
+        /// Client id of the publisher, `None` for messages published in process.
+        client: Option<String>,
+        properties: PublishProperties,
+        /// When the PUBLISH was received, for the delivery latency histogram
+        received: Instant,
+    },
+    /// Where a client can send its QoS 0 messages to the topic without the command loop,
+    /// `None` if they have to go through it. Used with the `direct_delivery` option.
+    DirectRoute {
+        client: String,
+        topic: String,
+        callback: Responder<Option<Route>>,
     },
     Unsubscribe {
         topics: Vec<String>,
         cid: String,
-        callback: Responder<Result<(), MqttError>>,
+        /// Responds with a reason code for each of the topics
+        callback: Responder<Result<Vec<UnsubackReasonCode>, MqttError>>,
+    },
+    /// Remove the session of a client, ignored if the session has since been taken over by another connection
+    DisconnectClient {
+        id: String,
+        connection_id: ConnectionId,
+    },
+    /// The network connection of a client has closed, sent after any [`Command::DisconnectClient`]
+    ConnectionClosed {
+        id: String,
+        connection_id: ConnectionId,
+        reason: DisconnectReason,
+    },
+    /// Apply the options of a reloaded config, shared by the shards
+    Reload(Arc<Config>),
+    ListClients {
+        callback: Responder<Vec<ClientInfo>>,
+    },
+    /// Connection details of a single client, `None` if the client does not exist
+    GetClientInfo {
+        id: String,
+        callback: Responder<Option<ClientInfo>>,
+    },
+    ClientSubscriptions {
+        client: String,
+        callback: Responder<Option<Vec<(String, QosLevel)>>>,
+    },
+    RetainedCount(Responder<usize>),
+    /// Size of the retained message store and the messages dropped by its limits
+    RetainedStats(Responder<RetainedStats>),
+    /// Retained messages with a topic matching the topic filter
+    ListRetained {
+        filter: String,
+        callback: Responder<Vec<(String, RetainedMessage)>>,
+    },
+    /// Remove the retained messages matching the topic filter, responds with how many were removed
+    RemoveRetained {
+        filter: String,
+        callback: Responder<usize>,
+    },
+    /// Size of the subscription tree
+    SubscriptionStats(Responder<SubscriptionStats>),
+    /// Topic filters of the clients connected to this node, sent to the other cluster nodes
+    ClusterFilters(Responder<HashSet<String>>),
+    /// Disconnect a client and remove its session, responds with false if the client does not exist
+    AdminDisconnect {
+        client_id: String,
+        /// Sent to MQTT 5 clients in the DISCONNECT
+        reason: DisconnectReasonCode,
+        callback: Responder<bool>,
+    },
+    /// Remove a subscription of a client, responds with false if the client is not subscribed to the filter
+    AdminUnsubscribe {
+        client_id: String,
+        filter: String,
+        callback: Responder<bool>,
     },
-    DisconnectClient(String),
     Exit,
 }
 
+/// ### Client Event
+/// Events for a single connection.
+///
+/// Every event for a client goes through the one bounded channel given when the client connected,
+/// and its connection handler writes them to the socket in the order they were received.
+/// When a client's channel is full the command loop follows the configured [`SlowClientPolicy`](crate::config::SlowClientPolicy),
+/// messages are never reordered and one slow client can't hold up delivery to the others.
+///
+/// [(MQTT 5) 4.6 Message ordering](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901240)
 #[derive(Debug)]
 pub enum ClientEvent {
     Message(Bytes),
-    Disconnect,
+    Disconnect(DisconnectReasonCode),
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
 pub enum ProtocalVersion {
+    /// MQTT 3.1, protocol name `MQIsdp`. Handled as v3.1.1 once connected.
+    Three,
     Four,
     Five,
     Unknown,
@@ -54,6 +237,7 @@ impl From<ProtocalVersion> for u8 {
         match value {
             ProtocalVersion::Five => 5,
             ProtocalVersion::Four => 4,
+            ProtocalVersion::Three => 3,
             _ => 0,
         }
     }
@@ -62,6 +246,7 @@ impl From<ProtocalVersion> for u8 {
 impl From<u8> for ProtocalVersion {
     fn from(value: u8) -> Self {
         match value {
+            3 => Self::Three,
             4 => Self::Four,
             5 => Self::Five,
             _ => Self::Unknown,
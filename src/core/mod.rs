@@ -1,23 +1,88 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
 
 use bytes::Bytes;
-use log::{debug, error};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
 
 use crate::{
+    auth::{Authenticator, ConfigAuthenticator, Credentials},
+    cluster,
+    config::{Config, SlowClientPolicy},
+    direct::{self, Route},
     error::MqttError,
+    events::{DisconnectReason, Event},
+    hooks::{BrokerHook, InterceptedMessage, Interceptors},
+    mount_point,
     packets::{
-        enums::{QosLevel, SubackReturnCode},
-        Packet,
+        enums::{DisconnectReasonCode, QosLevel, SubackReturnCode, UnsubackReasonCode},
+        topic, Packet, PublishProperties, RetainHandling, SubscriptionOptions,
     },
-    topic_heir::{SubscriptionLeaf, SubscriptionTree},
+    quota::{Quotas, RetainedLimits},
+    retained::{RetainedMessage, RetainedMessages, RetainedStats},
+    shard::{self, Dispatch},
+    stamp::Stamper,
+    topic_heir::{SharePicks, Subscriber, SubscriptionLeaf, SubscriptionStats, SubscriptionTree},
+    topic_policy::TopicPolicy,
 };
 
 use self::{
-    enums::{ClientEvent, ProtocalVersion},
+    enums::{ClientEvent, Connection, ConnectionId, Login, ProtocalVersion},
     session::Session,
 };
 
+/// Summary of a client session
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub protocol: ProtocalVersion,
+    /// Number of topic filters the client is subscribed to
+    pub subscriptions: usize,
+    /// Remote address, `None` for in process clients and bridges
+    pub addr: Option<SocketAddr>,
+    /// Keep Alive in seconds, `None` for in process clients and bridges
+    pub keepalive: Option<u16>,
+    /// When the client last connected
+    pub connected_at: SystemTime,
+    /// `false` for a persistent session without a connection
+    pub connected: bool,
+    /// When the client last sent a packet, `None` for in process clients and bridges
+    pub last_seen: Option<SystemTime>,
+    /// Outgoing QoS 1 and QoS 2 messages waiting to be acknowledged
+    pub inflight_outgoing: usize,
+    /// Outgoing messages held back by the client's Receive Maximum
+    pub inflight_pending: usize,
+    /// Incoming QoS 2 messages waiting for a PUBREL
+    pub inflight_incoming: usize,
+}
+
+impl ClientInfo {
+    fn new(client_id: &str, session: &Session) -> Self {
+        let connection = session.connection.as_ref();
+        let inflight = connection.map(|connection| &connection.inflight);
+
+        Self {
+            client_id: client_id.to_string(),
+            protocol: session.protocol,
+            subscriptions: session.subscriptions.len(),
+            addr: connection.map(|connection| connection.addr),
+            keepalive: connection.map(|connection| connection.keepalive),
+            connected_at: session.connected_at,
+            connected: !session.bridge.is_closed(),
+            last_seen: connection.map(|connection| connection.last_seen.get()),
+            inflight_outgoing: inflight.map_or(0, |inflight| inflight.outgoing()),
+            inflight_pending: inflight.map_or(0, |inflight| inflight.pending()),
+            inflight_incoming: inflight.map_or(0, |inflight| inflight.incoming()),
+        }
+    }
+}
+
 pub mod broker_info;
 pub mod enums;
 mod session;
@@ -25,6 +90,28 @@ mod session;
 pub struct App {
     sessions: HashMap<String, Session>,
     subscriptions: SubscriptionTree,
+    /// Last retained message for each topic, shared by the shards
+    retained: Arc<Mutex<RetainedMessages>>,
+    hooks: Vec<Arc<dyn BrokerHook>>,
+    interceptors: Interceptors,
+    authenticator: Arc<dyn Authenticator>,
+    slow_client_policy: SlowClientPolicy,
+    slow_client_timeout: Duration,
+    /// Messages for the clients whose queue is full, by session id, moved into the queue as the client makes room
+    overflow: HashMap<u128, Sender<ClientEvent>>,
+    /// Size of the offline queue of a persistent session
+    max_queued_messages: usize,
+    queue_qos0_messages: bool,
+    topic_policy: TopicPolicy,
+    quotas: Quotas,
+    /// Topic prefix of the client events, `None` if they are not published
+    event_topic_prefix: Option<String>,
+    /// Adds the receive time and sequence number to messages, `None` unless `stamp_messages` is set
+    stamper: Option<Stamper>,
+    /// The other shards published messages are sent on to, empty unless `command_shards` is set
+    shards: Vec<UnboundedSender<Dispatch>>,
+    /// Runs the shutdown hooks, only the first shard does
+    primary: bool,
 }
 
 impl App {
@@ -32,32 +119,546 @@ impl App {
         Self {
             sessions: HashMap::new(),
             subscriptions: SubscriptionTree::new(),
+            retained: Arc::new(Mutex::new(RetainedMessages::new())),
+            hooks: Vec::new(),
+            interceptors: Interceptors::default(),
+            authenticator: Arc::new(ConfigAuthenticator::default()),
+            slow_client_policy: SlowClientPolicy::DropQosZero,
+            slow_client_timeout: Duration::from_secs(5),
+            overflow: HashMap::new(),
+            max_queued_messages: 100,
+            queue_qos0_messages: false,
+            topic_policy: TopicPolicy::default(),
+            quotas: Quotas::default(),
+            event_topic_prefix: None,
+            stamper: None,
+            shards: Vec::new(),
+            primary: true,
         }
     }
 
+    pub fn with_hooks(hooks: Vec<Arc<dyn BrokerHook>>) -> Self {
+        Self {
+            hooks,
+            ..Self::new()
+        }
+    }
+
+    /// Set how messages for clients with a full queue are handled
+    pub fn with_slow_client_policy(mut self, policy: SlowClientPolicy, timeout: Duration) -> Self {
+        self.slow_client_policy = policy;
+        self.slow_client_timeout = timeout;
+        self
+    }
+
+    /// Set how many messages are kept for offline clients with a persistent session,
+    /// and whether QoS 0 messages are kept as well as QoS 1 and 2
+    pub fn with_offline_queue(
+        mut self,
+        max_queued_messages: usize,
+        queue_qos0_messages: bool,
+    ) -> Self {
+        self.max_queued_messages = max_queued_messages;
+        self.queue_qos0_messages = queue_qos0_messages;
+        self
+    }
+
+    /// Set the authenticator that decides which clients can connect
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Check the login of a network client with the [`Authenticator`].
+    /// The check doesn't borrow the app, so it can run while other commands are handled.
+    pub fn authenticate(
+        &self,
+        client_id: String,
+        login: Login,
+    ) -> impl Future<Output = bool> + Send + 'static {
+        let authenticator = self.authenticator.clone();
+        async move {
+            authenticator
+                .authenticate(Credentials {
+                    client_id: &client_id,
+                    username: login.username.as_deref(),
+                    password: login.password.as_deref(),
+                })
+                .await
+        }
+    }
+
+    /// Set the rules for `$` topics
+    pub(crate) fn with_topic_policy(mut self, policy: TopicPolicy) -> Self {
+        self.topic_policy = policy;
+        self
+    }
+
+    /// Set the per client quotas
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Change or drop published messages before they are retained and delivered
+    pub fn with_interceptors(mut self, interceptors: Interceptors) -> Self {
+        self.interceptors = interceptors;
+        self
+    }
+
+    /// Set the limits on the retained message store
+    pub fn with_retained_limits(self, limits: RetainedLimits) -> Self {
+        self.retained().set_limits(limits);
+        self
+    }
+
+    /// Publish the client events under `prefix`
+    pub fn with_events(mut self, prefix: Option<String>) -> Self {
+        self.event_topic_prefix = prefix;
+        self
+    }
+
+    /// Stamp the published messages with the time they were received and their sequence number
+    pub(crate) fn with_stamper(mut self, stamper: Option<Stamper>) -> Self {
+        self.stamper = stamper;
+        self
+    }
+
+    /// Run as one of the shards, see [`shard::link`]
+    pub(crate) fn with_shard(
+        mut self,
+        primary: bool,
+        shards: Vec<UnboundedSender<Dispatch>>,
+        retained: Arc<Mutex<RetainedMessages>>,
+        shares: Arc<Mutex<SubscriptionTree>>,
+    ) -> Self {
+        self.primary = primary;
+        self.shards = shards;
+        self.retained = retained;
+        self.subscriptions = std::mem::take(&mut self.subscriptions).with_shares(shares);
+        self
+    }
+
+    pub(crate) fn retained_store(&self) -> Arc<Mutex<RetainedMessages>> {
+        self.retained.clone()
+    }
+
+    /// The lock is never held across an await, so it is only contended by shards using the store at the same moment
+    fn retained(&self) -> MutexGuard<'_, RetainedMessages> {
+        self.retained.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Sessions known to the broker
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.sessions
+            .iter()
+            .map(|(cid, session)| ClientInfo::new(cid, session))
+            .collect()
+    }
+
+    /// Connection details of a client, `None` if the client does not exist
+    pub fn client_info(&self, cid: &str) -> Option<ClientInfo> {
+        self.sessions
+            .get(cid)
+            .map(|session| ClientInfo::new(cid, session))
+    }
+
+    /// Topic filters a client is subscribed to, `None` if the client does not exist
+    pub fn client_subscriptions(&self, cid: &str) -> Option<Vec<(String, QosLevel)>> {
+        self.sessions
+            .get(cid)
+            .map(|session| session.subscriptions.clone())
+    }
+
+    /// Number of topics with a retained message
+    pub fn retained_count(&self) -> usize {
+        self.retained().len()
+    }
+
+    /// Size of the retained message store and the messages dropped by its limits
+    pub fn retained_stats(&self) -> RetainedStats {
+        self.retained().stats()
+    }
+
+    /// Retained messages with a topic matching the topic filter, sorted by topic
+    pub fn retained_messages(&self, filter: &str) -> Vec<(String, RetainedMessage)> {
+        let mut messages = self.retained().matches(filter);
+        messages.sort_by(|(a, _), (b, _)| a.cmp(b));
+        messages
+    }
+
+    /// Remove the retained messages with a topic matching the topic filter, returns how many were removed.
+    ///
+    /// Unlike a retained message with an empty payload, nothing is sent to the subscribers of the topics.
+    pub fn remove_retained(&mut self, filter: &str) -> usize {
+        let removed = self.retained().remove_matching(filter);
+        debug!(
+            "Removed {} retained messages matching '{}'",
+            removed, filter
+        );
+        removed
+    }
+
+    /// Size of the subscription tree
+    pub fn subscription_stats(&self) -> SubscriptionStats {
+        self.subscriptions.stats()
+    }
+
+    /// Topic filters of every client except the links from other cluster nodes
+    pub fn cluster_filters(&self) -> HashSet<String> {
+        self.sessions
+            .iter()
+            .filter(|(cid, _)| !cluster::is_peer(cid))
+            .flat_map(|(_, session)| session.subscriptions.iter().map(|(filter, _)| filter))
+            .cloned()
+            .collect()
+    }
+
+    /// Disconnect a client and remove its session, returns false if the client does not exist.
+    ///
+    /// MQTT 5 clients are sent a DISCONNECT with `reason`.
+    pub async fn kick(&mut self, cid: &str, reason: DisconnectReasonCode) -> bool {
+        let Some(session) = self.sessions.get(cid) else {
+            return false;
+        };
+
+        // The queue may be full, the handler can still be stopped without a reason code
+        if session
+            .bridge
+            .try_send(ClientEvent::Disconnect(reason))
+            .is_err()
+        {
+            session.disconnect.cancel();
+        }
+
+        self.remove_session(cid).await;
+        true
+    }
+
+    /// Remove a subscription of a client without it sending an UNSUBSCRIBE,
+    /// returns false if the client is not subscribed to the filter.
+    ///
+    /// The client is not told, MQTT has no way for the server to end a subscription.
+    pub fn revoke_subscription(&mut self, cid: &str, filter: &str) -> bool {
+        let Some(session) = self.sessions.get_mut(cid) else {
+            return false;
+        };
+        let Some(index) = session
+            .subscriptions
+            .iter()
+            .position(|(topic, _)| topic == filter)
+        else {
+            return false;
+        };
+
+        session.subscriptions.remove(index);
+        if self.subscriptions.delete(filter, session.id).is_err() {
+            error!("Failed to delete subscription from tree");
+        }
+        debug!("Revoked subscription '{}' of client '{}'", filter, cid);
+        true
+    }
+
+    /// Apply the options of a reloaded config, sessions and subscriptions are kept.
+    pub fn reload(&mut self, config: &Config) {
+        self.slow_client_policy = config.slow_client_policy;
+        self.slow_client_timeout = config.slow_client_timeout;
+        self.max_queued_messages = config.max_queued_messages;
+        self.queue_qos0_messages = config.queue_qos0_messages;
+        // Filters are only normalized the way the subscriptions were made with
+        self.topic_policy = TopicPolicy::from(config)
+            .with_filter_normalization(self.topic_policy.filter_normalization());
+        self.quotas = Quotas::from(config);
+        self.retained().set_limits(RetainedLimits::from(config));
+        self.event_topic_prefix = config.event_topic_prefix.clone();
+        direct::invalidate();
+    }
+
+    /// Publish a client event under the `event_topic_prefix`, see [`crate::events`]
+    async fn emit(&mut self, event: Event) {
+        let Some(prefix) = &self.event_topic_prefix else {
+            return;
+        };
+        let topic = event.topic(prefix);
+        let payload = event.payload(SystemTime::now());
+        self.publish(
+            topic,
+            payload,
+            QosLevel::AtLeast,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+    }
+
+    /// Run the shutdown hooks, called once every client has disconnected
+    pub async fn shutdown(&self) {
+        if !self.primary {
+            return;
+        }
+        for hook in &self.hooks {
+            hook.on_shutdown().await;
+        }
+    }
+
+    /// Queue a message for a client.
+    ///
+    /// A full queue is handled by the [`SlowClientPolicy`] so a slow client can't stall delivery to everyone else.
+    /// Messages that wait for room go into an overflow of the same size as the client's queue, which a task of
+    /// its own moves into the queue, so the command loop never waits on a client. Once a client has an overflow
+    /// its messages go through it to stay in order, clients that fill it or still have no room after
+    /// the timeout are disconnected.
+    /// Messages for an offline client are kept by [`App::queue_offline`].
+    /// Returns false if the message was not queued.
+    async fn deliver(
+        &mut self,
+        id: u128,
+        bridge: &Sender<ClientEvent>,
+        qos: QosLevel,
+        packet: Bytes,
+    ) -> bool {
+        let mut event = ClientEvent::Message(packet);
+        if let Some(overflow) = self.overflow.get(&id) {
+            if self.slow_client_policy == SlowClientPolicy::DropQosZero && qos == QosLevel::AtMost {
+                broker_info::dropped_published();
+                return false;
+            }
+            match overflow.try_send(event) {
+                Ok(()) => return true,
+                Err(TrySendError::Full(_)) => return self.disconnect_slow(id),
+                // the overflow ended with the connection
+                Err(TrySendError::Closed(returned)) => {
+                    self.overflow.remove(&id);
+                    event = returned;
+                }
+            }
+        }
+
+        let event = match bridge.try_send(event) {
+            Ok(()) => return true,
+            Err(TrySendError::Closed(ClientEvent::Message(packet))) => {
+                return self.queue_offline(id, qos, packet);
+            }
+            Err(TrySendError::Closed(_)) => {
+                error!("receiver dropped");
+                broker_info::dropped_published();
+                return false;
+            }
+            Err(TrySendError::Full(event)) => event,
+        };
+
+        let Some((cid, session)) = self.sessions.iter().find(|(_, session)| session.id == id)
+        else {
+            return false;
+        };
+
+        // already being disconnected
+        if session.disconnect.is_cancelled() {
+            return false;
+        }
+
+        match (self.slow_client_policy, qos) {
+            (SlowClientPolicy::Disconnect, _) => self.disconnect_slow(id),
+            (SlowClientPolicy::DropQosZero, QosLevel::AtMost) => {
+                debug!("Queue for '{}' is full, dropped message", cid);
+                broker_info::dropped_published();
+                false
+            }
+            _ => {
+                let (tx, rx) = channel(bridge.max_capacity());
+                if tx.try_send(event).is_err() {
+                    return false;
+                }
+                tokio::spawn(drain_overflow(
+                    rx,
+                    bridge.clone(),
+                    self.slow_client_timeout,
+                    session.disconnect.clone(),
+                ));
+                self.overflow.insert(id, tx);
+                true
+            }
+        }
+    }
+
+    /// Disconnect a client that can't keep up, the message is dropped
+    fn disconnect_slow(&mut self, id: u128) -> bool {
+        self.overflow.remove(&id);
+        if let Some((cid, session)) = self.sessions.iter().find(|(_, session)| session.id == id) {
+            debug!("Queue for '{}' is full, disconnecting", cid);
+            session.disconnect.cancel();
+        }
+        broker_info::dropped_published();
+        false
+    }
+
+    /// Keep a message for a client whose connection has closed until it resumes its session.
+    ///
+    /// Only persistent sessions have an offline queue, which holds up to `max_queued_messages` messages.
+    /// QoS 0 messages are dropped unless `queue_qos0_messages` is set, like mosquitto.
+    /// Returns false if the message was dropped.
+    fn queue_offline(&mut self, id: u128, qos: QosLevel, packet: Bytes) -> bool {
+        let Some((cid, session)) = self
+            .sessions
+            .iter_mut()
+            .find(|(_, session)| session.id == id)
+        else {
+            broker_info::dropped_published();
+            return false;
+        };
+
+        if session.clean_session || (matches!(qos, QosLevel::AtMost) && !self.queue_qos0_messages) {
+            debug!("Client '{}' is offline, dropped message", cid);
+            broker_info::dropped_published();
+            return false;
+        }
+        if session.offline.len() >= self.max_queued_messages {
+            debug!("Offline queue for '{}' is full, dropped message", cid);
+            broker_info::dropped_published();
+            return false;
+        }
+
+        session.offline.push_back(packet);
+        true
+    }
+
     /// Subscribe to the current topic at the given qos
-    pub fn subscribe(
+    ///
+    /// Responds with a return code for each topic filter, in the order of the filters.
+    /// The filters are handled one after the other as if each was sent in its own SUBSCRIBE,
+    /// so a filter that is refused doesn't affect the others.
+    ///
+    /// [(MQTT 5) 3.8.4 SUBSCRIBE Actions](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901177)
+    ///
+    /// Retained messages matching the new subscriptions are sent to the client with the RETAIN flag set,
+    /// unless the Retain Handling option of the subscription says otherwise.
+    ///
+    /// Subscriptions of a client that connected as a bridge always have No Local and Retain As Published set,
+    /// so a message mirrored by a bridge is not sent back the way it came. Shared subscriptions of a bridge don't get No Local.
+    ///
+    /// A SUBSCRIBE with No Local set on a shared subscription is a Protocol Error, and one with a QoS or Retain Handling of 3
+    /// is malformed. Either is refused as a whole and the client is disconnected.
+    ///
+    /// The filters of a client with a mount point already include it, the mount point is removed from
+    /// the topics of the messages the client is sent.
+    pub async fn subscribe(
         &mut self,
         cid: String,
-        topics: Vec<(String, QosLevel)>,
+        topics: Vec<(String, SubscriptionOptions)>,
+        subscription_identifier: Option<u32>,
         callback: tokio::sync::oneshot::Sender<Result<Vec<SubackReturnCode>, MqttError>>,
     ) {
-        let (id, bridge) = match self.sessions.get(&cid) {
-            Some(session) => (session.id, session.bridge.clone()),
-            None => {
-                if callback.send(Err(MqttError::Unknown)).is_err() {
-                    log::error!("Client does not exist");
+        let (id, bridge, protocol, mut subscription_count, anonymous, is_bridge, mount, network) =
+            match self.sessions.get(&cid) {
+                Some(session) => (
+                    session.id,
+                    session.bridge.clone(),
+                    session.protocol,
+                    session.subscriptions.len(),
+                    session.is_anonymous(),
+                    session.is_bridge(),
+                    session.mount_point(),
+                    session.connection.is_some(),
+                ),
+                None => {
+                    if callback.send(Err(MqttError::Unknown)).is_err() {
+                        tracing::error!("Client does not exist");
+                    }
+                    return;
+                }
+            };
+        // A QoS or Retain Handling of 3 is a malformed SUBSCRIBE, the codec refuses it from network clients
+        let topics = match topics
+            .into_iter()
+            .map(
+                |(topic, options)| match (options.qos(), options.retain_handling()) {
+                    (Ok(qos), Ok(retain_handling)) => Ok((topic, options, qos, retain_handling)),
+                    _ => Err(MqttError::MalformedPacket("Invalid Subscription Options")),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(topics) => topics,
+            Err(err) => {
+                debug!("Client '{}' sent invalid subscription options", cid);
+                if callback.send(Err(err)).is_err() {
+                    tracing::error!("Client does not exist");
                 }
                 return;
             }
         };
+        // [MQTT-3.8.3-4] No Local on a shared subscription is a Protocol Error
+        if topics
+            .iter()
+            .any(|(topic, options, ..)| topic.starts_with("$share/") && options.no_local())
+        {
+            debug!("Client '{}' set No Local on a shared subscription", cid);
+            if callback.send(Err(MqttError::ProtocolViolation)).is_err() {
+                tracing::error!("Client does not exist");
+            }
+            return;
+        }
+        let mut subscribed = Vec::new();
+        // Filters to send the retained messages of once the SUBACK has been sent
+        let mut send_retained = Vec::new();
         let codes = topics
             .into_iter()
-            .map(|(topic, qos)| {
-                let leaf = SubscriptionLeaf::new(qos, id, bridge.clone());
-                if self.subscriptions.insert(topic, leaf).is_err() {
-                    return SubackReturnCode::Failure;
+            .map(|(topic, options, qos, retain_handling)| {
+                let Some(topic) = self.topic_policy.normalize_filter(topic) else {
+                    debug!("Client '{}' sent a topic filter with empty levels", cid);
+                    return SubackReturnCode::TopicFilterInvalid;
+                };
+                if topic::validate_topic_filter(&topic).is_err() {
+                    debug!("Client '{}' sent invalid topic filter '{}'", cid, topic);
+                    return SubackReturnCode::TopicFilterInvalid;
+                }
+
+                if !self.topic_policy.can_subscribe(&topic, anonymous) {
+                    debug!("Client '{}' can not subscribe to '{}'", cid, topic);
+                    return SubackReturnCode::NotAuthorized;
+                }
+
+                // Replacing an existing subscription does not count towards the quota,
+                // including one made earlier in the same SUBSCRIBE
+                let is_new = !self.sessions.get(&cid).is_some_and(|session| {
+                    session
+                        .subscriptions
+                        .iter()
+                        .any(|(filter, _)| *filter == topic)
+                }) && !subscribed.iter().any(|(filter, _)| *filter == topic);
+                if is_new && !self.quotas.allows_subscription(subscription_count) {
+                    debug!("Client '{}' exceeded the subscription quota", cid);
+                    return SubackReturnCode::QuotaExceeded;
+                }
+
+                let leaf = SubscriptionLeaf::new(
+                    qos,
+                    id,
+                    bridge.clone(),
+                    protocol,
+                    // Bridges are not sent their own messages back, and keep the RETAIN flag
+                    // so retained messages stay retained on the other broker.
+                    // A share group has a single copy of each message so No Local is never set on it
+                    options.no_local() || (is_bridge && !topic.starts_with("$share/")),
+                    options.retain_as_published() || is_bridge,
+                    subscription_identifier,
+                )
+                .with_retain_handling(retain_handling)
+                .with_mount_point(mount.clone());
+                if self.subscriptions.insert(&topic, leaf).is_err() {
+                    return SubackReturnCode::ImplementationSpecificError;
+                }
+                if is_new {
+                    subscription_count += 1;
                 }
+                match retain_handling {
+                    RetainHandling::SendOnSubscribe => send_retained.push((topic.clone(), qos)),
+                    RetainHandling::SendIfNew if is_new => send_retained.push((topic.clone(), qos)),
+                    _ => {}
+                }
+                subscribed.push((topic, qos));
                 match qos {
                     QosLevel::AtMost => SubackReturnCode::SuccessQosZero,
                     QosLevel::AtLeast => SubackReturnCode::SuccessQosOne,
@@ -66,92 +667,2129 @@ impl App {
             })
             .collect::<Vec<SubackReturnCode>>();
 
+        for hook in &self.hooks {
+            for (topic, qos) in &subscribed {
+                hook.on_subscribe(&cid, topic, *qos).await;
+            }
+        }
+
+        if let Some(session) = self.sessions.get_mut(&cid) {
+            for (topic, qos) in &subscribed {
+                match session.subscriptions.iter_mut().find(|(t, _)| t == topic) {
+                    Some(existing) => existing.1 = *qos,
+                    None => session.subscriptions.push((topic.clone(), *qos)),
+                }
+            }
+        }
+
         if callback.send(Ok(codes)).is_err() {
-            log::error!("Client does not exist");
+            tracing::error!("Client does not exist");
+        }
+
+        if network {
+            for (filter, qos) in subscribed {
+                self.emit(Event::Subscribed {
+                    client_id: cid.clone(),
+                    filter,
+                    qos,
+                })
+                .await;
+            }
+        }
+
+        for (filter, qos) in send_retained {
+            let matches = self.retained().matches(&filter);
+            for (topic, message) in matches {
+                self.retained().touch(&topic);
+                let topic = match &mount {
+                    Some(mount) => match mount_point::unmount(mount, &topic) {
+                        Some(topic) => topic.to_string(),
+                        None => continue,
+                    },
+                    None => topic,
+                };
+                let qos = qos.min(message.qos);
+                let packet = Packet::make_publish(
+                    false,
+                    qos,
+                    true,
+                    topic,
+                    None,
+                    message.payload,
+                    message.properties,
+                    subscription_identifier.into_iter().collect(),
+                    protocol,
+                );
+                if !self.deliver(id, &bridge, qos, packet).await {
+                    return;
+                }
+            }
         }
     }
 
-    /// Unsubscribe to topic
-    pub fn unsubscribe(
+    /// Unsubscribe from the topic filters, responds with a reason code for each filter
+    pub async fn unsubscribe(
         &mut self,
         cid: String,
         topics: Vec<String>,
-        callback: tokio::sync::oneshot::Sender<Result<(), MqttError>>,
+        callback: tokio::sync::oneshot::Sender<Result<Vec<UnsubackReasonCode>, MqttError>>,
     ) {
-        let id = match self.sessions.get(&cid) {
-            Some(session) => session.id,
-            None => {
-                if callback.send(Err(MqttError::Unknown)).is_err() {
-                    log::error!("Client does not exist");
-                }
-                return;
+        let Some(session) = self.sessions.get_mut(&cid) else {
+            if callback.send(Err(MqttError::Unknown)).is_err() {
+                tracing::error!("Client does not exist");
             }
+            return;
         };
+        let id = session.id;
+        let anonymous = session.is_anonymous();
+        let network = session.connection.is_some();
+        let mut unsubscribed = Vec::new();
 
-        topics.into_iter().for_each(|topic| {
-            if self.subscriptions.delete(topic, id).is_err() {
-                error!("Failed to delete subscription from tree");
-            }
-        });
+        let codes = topics
+            .into_iter()
+            .map(|topic| {
+                let Some(topic) = self.topic_policy.normalize_filter(topic) else {
+                    return UnsubackReasonCode::TopicFilterInvalid;
+                };
+                if topic::validate_topic_filter(&topic).is_err() {
+                    return UnsubackReasonCode::TopicFilterInvalid;
+                }
+                if !self.topic_policy.can_subscribe(&topic, anonymous) {
+                    return UnsubackReasonCode::NotAuthorized;
+                }
+
+                let Some(index) = session
+                    .subscriptions
+                    .iter()
+                    .position(|(filter, _)| *filter == topic)
+                else {
+                    return UnsubackReasonCode::NoSubscriptionExisted;
+                };
+                session.subscriptions.remove(index);
+
+                if self.subscriptions.delete(&topic, id).is_err() {
+                    error!("Failed to delete subscription from tree");
+                    return UnsubackReasonCode::UnspecifiedError;
+                }
+                unsubscribed.push(topic);
+                UnsubackReasonCode::Success
+            })
+            .collect();
 
-        if callback.send(Ok(())).is_err() {
-            log::error!("receiver dropped");
+        if callback.send(Ok(codes)).is_err() {
+            tracing::error!("receiver dropped");
+        }
+
+        if network {
+            for filter in unsubscribed {
+                self.emit(Event::Unsubscribed {
+                    client_id: cid.clone(),
+                    filter,
+                })
+                .await;
+            }
         }
     }
 
+    /// Connect a client, taking over the session of an existing connection with the same client id.
+    ///
+    /// The existing connection is sent a DISCONNECT with Session Taken Over, or dropped straight away
+    /// if its queue is full so a stuck client can't hold up the command loop.
+    /// Commands are handled in the order they are received so the last CONNECT always wins,
+    /// and a later [`App::disconnect`] from the replaced connection leaves the new session alone.
+    ///
+    /// A client connecting with Clean Session unset resumes its persistent session,
+    /// the messages kept while it was offline are queued for the new connection.
+    /// Otherwise the subscriptions and messages of the existing session are discarded.
+    ///
+    /// Responds with Session Present, true if a persistent session was resumed.
+    ///
+    /// [(MQTT 5) 3.2.2.1.1 Session Present](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901078)
+    #[allow(clippy::too_many_arguments)]
     pub async fn connect(
         &mut self,
         client_id: String,
         message_channel: Sender<ClientEvent>,
-        _protocol: ProtocalVersion,
-        _clean_session: bool,
-        callback: tokio::sync::oneshot::Sender<Result<(), MqttError>>,
+        disconnect: CancellationToken,
+        protocol: ProtocalVersion,
+        clean_session: bool,
+        connection: Option<Connection>,
+        connection_id: ConnectionId,
+        callback: tokio::sync::oneshot::Sender<Result<bool, MqttError>>,
     ) {
         debug!("New client connecting with id of '{}'", client_id);
-        if self.sessions.contains_key(&client_id) {
-            if let Some(current_client) = self.sessions.get(&client_id) {
-                if let Err(err) = current_client.bridge.send(ClientEvent::Disconnect).await {
-                    error!("{}", err);
-                }
+        if let Some(current_client) = self.sessions.get(&client_id) {
+            if current_client
+                .bridge
+                .try_send(ClientEvent::Disconnect(
+                    DisconnectReasonCode::SessionTakenOver,
+                ))
+                .is_err()
+            {
+                current_client.disconnect.cancel();
             }
         }
 
-        if let Some(existing_client) = self.sessions.get_mut(&client_id) {
+        for hook in &self.hooks {
+            hook.on_connect(&client_id).await;
+        }
+
+        // The old connection closes after the new one is connected, so its disconnected event is published now
+        if self
+            .sessions
+            .get(&client_id)
+            .is_some_and(|session| session.online)
+        {
+            self.emit(Event::Disconnected {
+                client_id: client_id.clone(),
+                reason: DisconnectReason::Server(DisconnectReasonCode::SessionTakenOver),
+            })
+            .await;
+        }
+        let connected = connection.as_ref().map(|connection| Event::Connected {
+            client_id: client_id.clone(),
+            addr: connection.addr,
+            protocol,
+            clean_session,
+            keepalive: connection.keepalive,
+        });
+
+        let session_present = if let Some(existing_client) = self.sessions.get_mut(&client_id) {
+            // A clean session ends with its connection even if the client never sent a DISCONNECT
+            let resume = !clean_session && !existing_client.clean_session;
+
             existing_client.bridge = message_channel;
+            self.overflow.remove(&existing_client.id);
+            existing_client.protocol = protocol;
+            existing_client.disconnect = disconnect;
+            existing_client.connection = connection;
+            existing_client.connected_at = SystemTime::now();
+            existing_client.connection_id = connection_id;
+            existing_client.clean_session = clean_session;
+            existing_client.online = connected.is_some();
+
+            if !resume {
+                for (filter, _) in existing_client.subscriptions.drain(..) {
+                    if self
+                        .subscriptions
+                        .delete(&filter, existing_client.id)
+                        .is_err()
+                    {
+                        error!("Failed to delete subscription from tree");
+                    }
+                }
+                existing_client.offline.clear();
+            }
+
+            for (filter, _) in &existing_client.subscriptions {
+                if self
+                    .subscriptions
+                    .rebind(
+                        filter,
+                        existing_client.id,
+                        existing_client.bridge.clone(),
+                        protocol,
+                    )
+                    .is_err()
+                {
+                    error!("Failed to rebind subscription '{}'", filter);
+                }
+            }
+
+            while let Some(packet) = existing_client.offline.pop_front() {
+                if existing_client
+                    .bridge
+                    .try_send(ClientEvent::Message(packet))
+                    .is_err()
+                {
+                    broker_info::dropped_published();
+                }
+            }
+
+            resume
         } else {
-            self.sessions
-                .insert(client_id, Session::new(message_channel));
+            let mut session = Session::new(
+                message_channel,
+                protocol,
+                disconnect,
+                connection,
+                connection_id,
+                clean_session,
+            );
+            session.online = connected.is_some();
+            self.sessions.insert(client_id, session);
+            false
+        };
+
+        if callback.send(Ok(session_present)).is_err() {
+            tracing::error!("Client no longer exists");
         }
 
-        if callback.send(Ok(())).is_err() {
-            log::error!("Client no longer exists");
+        if let Some(event) = connected {
+            self.emit(event).await;
         }
     }
 
-    pub fn disconnect(&mut self, cid: String) {
-        /*if let Some(client) = self.sessions.get(&cid) {
+    /// Publish the disconnected event of a network connection that has closed.
+    ///
+    /// Nothing is published if another connection has since taken over the session,
+    /// [`App::connect`] already published the event for the old connection.
+    pub async fn connection_closed(
+        &mut self,
+        cid: String,
+        connection_id: ConnectionId,
+        reason: DisconnectReason,
+    ) {
+        match self.sessions.get_mut(&cid) {
+            Some(session) if session.connection_id != connection_id => return,
+            Some(session) => session.online = false,
+            // The session ended with the connection
+            None => {}
+        }
 
-        }*/
-        self.sessions.remove(&cid);
+        self.emit(Event::Disconnected {
+            client_id: cid,
+            reason,
+        })
+        .await;
     }
 
-    pub async fn publish(&self, topic: String, payload: Bytes) {
-        let subs = match self.subscriptions.get(topic.clone()) {
+    /// Remove the session of a client if it still belongs to the connection `connection_id`.
+    ///
+    /// A persistent session is kept so the client can resume it and get the messages it missed.
+    pub async fn disconnect(&mut self, cid: String, connection_id: ConnectionId) {
+        match self.sessions.get(&cid) {
+            Some(session) if session.connection_id != connection_id => {
+                debug!("Client '{}' was taken over, keeping its session", cid);
+            }
+            Some(session) if !session.clean_session => {
+                debug!(
+                    "Client '{}' disconnected, keeping its persistent session",
+                    cid
+                );
+                for hook in &self.hooks {
+                    hook.on_disconnect(&cid).await;
+                }
+            }
+            Some(_) => self.remove_session(&cid).await,
+            None => {}
+        }
+    }
+
+    /// Remove a session and its subscriptions
+    async fn remove_session(&mut self, cid: &str) {
+        let Some(session) = self.sessions.remove(cid) else {
+            return;
+        };
+        self.overflow.remove(&session.id);
+
+        for (filter, _) in &session.subscriptions {
+            if self.subscriptions.delete(filter, session.id).is_err() {
+                error!("Failed to delete subscription from tree");
+            }
+        }
+
+        for hook in &self.hooks {
+            hook.on_disconnect(cid).await;
+        }
+    }
+
+    /// Publish a message to all matching subscribers.
+    ///
+    /// If `retain` is set the message replaces the retained message for the topic,
+    /// a retained message with an empty payload removes it. A message over the [`RetainedLimits`]
+    /// may not be retained, it is still sent to the subscribers.
+    ///
+    /// Each subscriber receives the message at the lower of the publish QoS and the QoS of its subscription.
+    ///
+    /// `client` is the id of the publishing client, subscriptions of that client with No Local set are skipped.
+    /// Clients can only publish to `$` topics allowed by the [`TopicPolicy`], and anonymous clients only to their sandbox.
+    /// The message is then passed through the [`Interceptors`], which may change or drop it.
+    /// With `stamp_messages` the time it was received and its sequence number are added as User Properties.
+    ///
+    /// Messages from another cluster node are not sent on to the other nodes, see [`cluster::ClusterConfig`].
+    ///
+    /// When the broker is sharded the message is checked and retained here, and sent on to the other shards
+    /// for their subscribers.
+    ///
+    /// `received` is when the PUBLISH was received, the time until the message is queued for each subscriber
+    /// is recorded in the [`broker_info::delivery_latency`] histogram.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn publish(
+        &mut self,
+        topic: String,
+        payload: Bytes,
+        qos: QosLevel,
+        retain: bool,
+        client: Option<String>,
+        properties: PublishProperties,
+        received: Instant,
+    ) {
+        let anonymous = client
+            .as_ref()
+            .and_then(|cid| self.sessions.get(cid))
+            .is_some_and(Session::is_anonymous);
+        if client.is_some() && !self.topic_policy.can_publish(&topic, anonymous) {
+            debug!("Client {:?} can not publish to '{}'", client, topic);
+            return;
+        }
+
+        let mut message = InterceptedMessage::new(topic, payload, qos, retain, client, properties);
+        if !self.interceptors.run(&mut message).await {
+            debug!("Publish to '{}' dropped by interceptor", message.topic);
+            return;
+        }
+        let InterceptedMessage {
+            topic,
+            client,
+            payload,
+            qos,
+            retain,
+            mut properties,
+        } = message;
+
+        for hook in &self.hooks {
+            if !hook.on_publish(&topic, &payload).await {
+                debug!("Publish to '{}' dropped by hook", topic);
+                return;
+            }
+        }
+
+        if let Some(stamper) = &self.stamper {
+            stamper.stamp(&topic, &mut properties, received);
+        }
+
+        #[cfg(feature = "topic-stats")]
+        if !topic.starts_with('$') {
+            broker_info::topic_published(&topic, payload.len());
+        }
+
+        if retain {
+            let mut retained = self.retained();
+            if payload.is_empty() {
+                retained.remove(&topic);
+            } else if !retained.insert(&topic, qos, payload.clone(), properties.clone()) {
+                debug!(
+                    "Retained message for '{}' is over the retained limits",
+                    topic
+                );
+            }
+        }
+
+        let shared = self.subscriptions.shared_picks(&topic);
+        let message = Dispatch {
+            topic,
+            payload,
+            qos,
+            retain,
+            client,
+            properties,
+            received,
+            shared,
+        };
+        shard::forward(&self.shards, &message);
+        self.dispatch(message).await;
+    }
+
+    /// Where the QoS 0 messages of a client to a topic can be sent without the command loop.
+    ///
+    /// A route is only given when [`App::publish`] would send a QoS 0 message without the RETAIN flag
+    /// to a single subscriber without changing or recording it, `None` otherwise.
+    pub(crate) fn direct_route(&self, client: &str, topic: &str) -> Option<Route> {
+        if !self.shards.is_empty()
+            || !self.hooks.is_empty()
+            || !self.interceptors.is_empty()
+            || self.stamper.is_some()
+            || self.slow_client_policy != SlowClientPolicy::DropQosZero
+            || cluster::is_peer(client)
+        {
+            return None;
+        }
+
+        let session = self.sessions.get(client)?;
+        if !self.topic_policy.can_publish(topic, session.is_anonymous()) {
+            return None;
+        }
+
+        let generation = direct::generation();
+        let subs = self.subscriptions.get(topic).ok()?;
+        let [Subscriber {
+            leaf: sub,
+            subscription_identifiers,
+            shared,
+        }] = subs.as_slice()
+        else {
+            return None;
+        };
+        // the members of a share group take turns
+        if *shared
+            || !subscription_identifiers.is_empty()
+            || (sub.no_local && sub.identifier == session.id)
+        {
+            return None;
+        }
+
+        let sub_topic = match &sub.mount_point {
+            Some(mount) => mount_point::unmount(mount, topic)?,
+            None => topic,
+        };
+        Some(Route {
+            topic: sub_topic.to_string(),
+            protocol: sub.protocol,
+            bridge: sub.bridge.clone(),
+            generation,
+        })
+    }
+
+    /// Send a message to the matching subscribers of this shard.
+    ///
+    /// The message has already been checked by [`App::publish`], on this shard or the one it was published to.
+    pub(crate) async fn dispatch(&mut self, message: Dispatch) {
+        let Dispatch {
+            topic,
+            payload,
+            qos,
+            retain,
+            client,
+            properties,
+            received,
+            shared,
+        } = message;
+
+        let picks = match &shared {
+            Some(picks) => SharePicks::Given(picks),
+            None => SharePicks::RoundRobin,
+        };
+        let subs = match self.subscriptions.get_picked(&topic, picks) {
             Ok(subs) => subs,
             Err(_) => {
                 return;
             }
         };
 
-        for (_, bridge, qos) in subs {
-            let packet =
-                Packet::make_publish(false, qos, false, topic.clone(), None, payload.clone());
-            if let Err(e) = bridge.send(ClientEvent::Message(packet)).await {
-                log::error!("receiver dropped: {}", e);
+        let publisher = client
+            .as_ref()
+            .and_then(|cid| self.sessions.get(cid))
+            .map(|session| session.id);
+
+        let peers = if client.as_deref().is_some_and(cluster::is_peer) {
+            self.sessions
+                .iter()
+                .filter(|(cid, _)| cluster::is_peer(cid))
+                .map(|(_, session)| session.id)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let pack = |topic: &str, qos, retain, protocol, subscription_identifiers| {
+            Packet::make_publish(
+                false,
+                qos,
+                retain,
+                topic.to_string(),
+                None,
+                payload.clone(),
+                properties.clone(),
+                subscription_identifiers,
+                protocol,
+            )
+        };
+
+        // Subscribers without Subscription Identifiers share the packet packed for their QoS, RETAIN flag, protocol and mount point
+        type PacketKey = (QosLevel, bool, ProtocalVersion, Option<Arc<str>>);
+        let mut packed: Vec<(PacketKey, Bytes)> = Vec::new();
+
+        for Subscriber {
+            leaf: sub,
+            subscription_identifiers,
+            ..
+        } in subs
+        {
+            if sub.no_local && publisher == Some(sub.identifier) {
+                continue;
+            }
+            if peers.contains(&sub.identifier) {
+                continue;
+            }
+
+            // A client with a mount point is sent the topic without it
+            let sub_topic = match &sub.mount_point {
+                Some(mount) => match mount_point::unmount(mount, &topic) {
+                    Some(topic) => topic,
+                    None => continue,
+                },
+                None => &topic,
+            };
+
+            // Messages sent to established subscriptions only keep the RETAIN flag with Retain As Published
+            let key = (
+                sub.qos.min(qos),
+                retain && sub.retain_as_published,
+                sub.protocol,
+                sub.mount_point.clone(),
+            );
+
+            let packet = if subscription_identifiers.is_empty() {
+                match packed.iter().find(|(k, _)| *k == key) {
+                    Some((_, packet)) => packet.clone(),
+                    None => {
+                        let packet = pack(sub_topic, key.0, key.1, key.2, Vec::new());
+                        packed.push((key, packet.clone()));
+                        packet
+                    }
+                }
+            } else {
+                pack(sub_topic, key.0, key.1, key.2, subscription_identifiers)
+            };
+            if !self
+                .deliver(sub.identifier, &sub.bridge, key.0, packet)
+                .await
+            {
+                continue;
+            }
+            broker_info::delivered(received.elapsed());
+
+            if self.hooks.is_empty() {
+                continue;
+            }
+
+            if let Some((cid, _)) = self
+                .sessions
+                .iter()
+                .find(|(_, session)| session.id == sub.identifier)
+            {
+                for hook in &self.hooks {
+                    hook.on_message_delivered(cid, &topic, &payload).await;
+                }
+            }
+        }
+    }
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Move the messages that overflowed a client's queue into it as the client makes room.
+///
+/// Runs until the command loop drops the overflow or the connection closes,
+/// a client that has no room for a message within `timeout` is disconnected.
+async fn drain_overflow(
+    mut overflow: Receiver<ClientEvent>,
+    bridge: Sender<ClientEvent>,
+    timeout: Duration,
+    disconnect: CancellationToken,
+) {
+    while let Some(event) = overflow.recv().await {
+        match tokio::time::timeout(timeout, bridge.send(event)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => break,
+            Err(_) => {
+                debug!("Queue is still full, disconnecting slow client");
+                broker_info::dropped_published();
+                disconnect.cancel();
+                break;
             }
         }
     }
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use bytes::Bytes;
+    use tokio::sync::mpsc::{channel, Receiver};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        enums::{ClientEvent, Connection, ConnectionId, LastSeen, ProtocalVersion},
+        App,
+    };
+    use crate::{
+        config::SlowClientPolicy,
+        error::MqttError,
+        events::DisconnectReason,
+        flow_control::Inflight,
+        packets::{
+            enums::{DisconnectReasonCode, QosLevel, SubackReturnCode, UnsubackReasonCode},
+            Packet, PayloadFormat, PublishProperties, SubscriptionOptions, VariableHeader,
+        },
+        quota::Quotas,
+        topic_policy::{FilterNormalization, TopicPolicy},
+    };
+
+    async fn connect(app: &mut App, cid: &str, protocol: ProtocalVersion) -> Receiver<ClientEvent> {
+        let (tx, rx) = channel(10);
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.connect(
+            cid.into(),
+            tx,
+            CancellationToken::new(),
+            protocol,
+            true,
+            None,
+            ConnectionId::next(),
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap();
+        rx
+    }
+
+    async fn subscribe(app: &mut App, cid: &str, filter: &str, options: SubscriptionOptions) {
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.subscribe(cid.into(), vec![(filter.into(), options)], None, r_tx)
+            .await;
+        r_rx.await.unwrap().unwrap();
+    }
+
+    /// Returns the topic and RETAIN flag of the next message
+    fn next_message(rx: &mut Receiver<ClientEvent>, protocol: ProtocalVersion) -> (String, bool) {
+        match rx.try_recv().expect("Expected a message") {
+            ClientEvent::Message(mut bytes) => {
+                let (packet, _) = Packet::unpack(&mut bytes, protocol).unwrap();
+                match packet.variable {
+                    VariableHeader::Publish { topic, .. } => (topic, packet.fixed.get_retain()),
+                    _ => panic!("Expected a publish"),
+                }
+            }
+            ClientEvent::Disconnect(_) => panic!("Expected a message"),
+        }
+    }
+
+    /// Returns the Content Type of the next message
+    fn next_content_type(rx: &mut Receiver<ClientEvent>) -> Option<String> {
+        match rx.try_recv().expect("Expected a message") {
+            ClientEvent::Message(mut bytes) => {
+                let (packet, _) = Packet::unpack(&mut bytes, ProtocalVersion::Five).unwrap();
+                match packet.variable {
+                    VariableHeader::Publish { content_type, .. } => content_type,
+                    _ => panic!("Expected a publish"),
+                }
+            }
+            ClientEvent::Disconnect(_) => panic!("Expected a message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_properties() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+        subscribe(&mut app, "client", "a/#", QosLevel::AtMost.into()).await;
+
+        let properties = PublishProperties {
+            payload_format_indicator: Some(PayloadFormat::EncodedUTF8),
+            content_type: Some("application/json".into()),
+            ..Default::default()
+        };
+        app.publish(
+            "a/b".into(),
+            Bytes::from_static(b"{}"),
+            QosLevel::AtMost,
+            true,
+            None,
+            properties,
+            Instant::now(),
+        )
+        .await;
+        assert_eq!(
+            next_content_type(&mut rx).as_deref(),
+            Some("application/json")
+        );
+
+        // retained messages keep their properties
+        subscribe(&mut app, "client", "a/b", QosLevel::AtMost.into()).await;
+        assert_eq!(
+            next_content_type(&mut rx).as_deref(),
+            Some("application/json")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retained_message_on_subscribe() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Four).await;
+
+        app.publish(
+            "a/b".into(),
+            Bytes::from_static(b"retained"),
+            QosLevel::AtMost,
+            true,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        app.publish(
+            "a/c".into(),
+            Bytes::from_static(b"live"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+
+        subscribe(&mut app, "client", "a/+", QosLevel::AtMost.into()).await;
+
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Four),
+            ("a/b".into(), true)
+        );
+        assert!(rx.try_recv().is_err());
+
+        // Live messages to an established subscription are not retained
+        app.publish(
+            "a/b".into(),
+            Bytes::from_static(b"update"),
+            QosLevel::AtMost,
+            true,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Four),
+            ("a/b".into(), false)
+        );
+
+        // An empty retained message clears the topic
+        app.publish(
+            "a/b".into(),
+            Bytes::new(),
+            QosLevel::AtMost,
+            true,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        next_message(&mut rx, ProtocalVersion::Four);
+        subscribe(&mut app, "client", "a/#", QosLevel::AtMost.into()).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retain_handling() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+
+        app.publish(
+            "a/b".into(),
+            Bytes::from_static(b"retained"),
+            QosLevel::AtMost,
+            true,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+
+        // 2 - never sent
+        subscribe(&mut app, "client", "a/b", SubscriptionOptions::from(0x20)).await;
+        assert!(rx.try_recv().is_err());
+
+        // 1 - only sent for a new subscription
+        subscribe(&mut app, "client", "a/+", SubscriptionOptions::from(0x10)).await;
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Five),
+            ("a/b".into(), true)
+        );
+        subscribe(&mut app, "client", "a/+", SubscriptionOptions::from(0x10)).await;
+        assert!(rx.try_recv().is_err());
+
+        // 0 - sent on every subscribe
+        subscribe(&mut app, "client", "a/+", SubscriptionOptions::from(0x00)).await;
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Five),
+            ("a/b".into(), true)
+        );
+
+        // 3 - the whole SUBSCRIBE is malformed
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.subscribe(
+            "client".into(),
+            vec![
+                ("c".into(), SubscriptionOptions::from(0x00)),
+                ("d".into(), SubscriptionOptions::from(0x30)),
+            ],
+            None,
+            r_tx,
+        )
+        .await;
+        assert!(matches!(
+            r_rx.await.unwrap(),
+            Err(MqttError::MalformedPacket(_))
+        ));
+        assert_eq!(app.client_subscriptions("client").unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retained_wildcard_subscribe() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Four).await;
+
+        for topic in ["sensors/1/temp", "sensors/2/temp", "sensors/2/humidity"] {
+            app.publish(
+                topic.into(),
+                Bytes::from_static(b"1"),
+                QosLevel::AtMost,
+                true,
+                None,
+                PublishProperties::default(),
+                Instant::now(),
+            )
+            .await;
+        }
+
+        subscribe(
+            &mut app,
+            "client",
+            "sensors/+/temp",
+            QosLevel::AtMost.into(),
+        )
+        .await;
+        let mut topics = vec![
+            next_message(&mut rx, ProtocalVersion::Four).0,
+            next_message(&mut rx, ProtocalVersion::Four).0,
+        ];
+        topics.sort();
+        assert_eq!(topics, ["sensors/1/temp", "sensors/2/temp"]);
+        assert!(rx.try_recv().is_err());
+
+        subscribe(&mut app, "client", "sensors/#", QosLevel::AtMost.into()).await;
+        for _ in 0..3 {
+            assert!(next_message(&mut rx, ProtocalVersion::Four).1);
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// QoS of the next message
+    fn next_qos(rx: &mut Receiver<ClientEvent>) -> QosLevel {
+        match rx.try_recv().expect("Expected a message") {
+            ClientEvent::Message(bytes) => QosLevel::try_from((bytes[0] >> 1) & 0x03).unwrap(),
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_qos_downgrade() {
+        let mut app = App::new();
+        let mut zero_rx = connect(&mut app, "zero", ProtocalVersion::Four).await;
+        let mut two_rx = connect(&mut app, "two", ProtocalVersion::Four).await;
+        subscribe(&mut app, "zero", "a", QosLevel::AtMost.into()).await;
+        subscribe(&mut app, "two", "a", QosLevel::Exactly.into()).await;
+
+        app.publish(
+            "a".into(),
+            Bytes::from_static(b"data"),
+            QosLevel::AtLeast,
+            true,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert_eq!(next_qos(&mut zero_rx), QosLevel::AtMost);
+        assert_eq!(next_qos(&mut two_rx), QosLevel::AtLeast);
+
+        app.publish(
+            "a".into(),
+            Bytes::from_static(b"data"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert_eq!(next_qos(&mut zero_rx), QosLevel::AtMost);
+        assert_eq!(next_qos(&mut two_rx), QosLevel::AtMost);
+
+        // Retained messages keep the QoS they were published with
+        subscribe(&mut app, "two", "#", QosLevel::Exactly.into()).await;
+        assert_eq!(next_qos(&mut two_rx), QosLevel::AtLeast);
+    }
+
+    #[tokio::test]
+    async fn test_retain_as_published() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+
+        subscribe(
+            &mut app,
+            "client",
+            "a/b",
+            SubscriptionOptions::new(QosLevel::AtMost, false, true),
+        )
+        .await;
+
+        app.publish(
+            "a/b".into(),
+            Bytes::from_static(b"retained"),
+            QosLevel::AtMost,
+            true,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        app.publish(
+            "a/b".into(),
+            Bytes::from_static(b"live"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Five),
+            ("a/b".into(), true)
+        );
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Five),
+            ("a/b".into(), false)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_order() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Four).await;
+        subscribe(&mut app, "client", "a/+", QosLevel::AtLeast.into()).await;
+
+        let topics: Vec<String> = (0..5).map(|i| format!("a/{}", i)).collect();
+        for topic in &topics {
+            app.publish(
+                topic.clone(),
+                Bytes::from_static(b"data"),
+                QosLevel::AtMost,
+                false,
+                None,
+                PublishProperties::default(),
+                Instant::now(),
+            )
+            .await;
+        }
+
+        for topic in topics {
+            assert_eq!(next_message(&mut rx, ProtocalVersion::Four), (topic, false));
+        }
+    }
+
+    /// Connect a client with a queue of one message
+    async fn connect_slow(app: &mut App, cid: &str) -> (Receiver<ClientEvent>, CancellationToken) {
+        let (tx, rx) = channel(1);
+        let disconnect = CancellationToken::new();
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.connect(
+            cid.into(),
+            tx,
+            disconnect.clone(),
+            ProtocalVersion::Four,
+            true,
+            None,
+            ConnectionId::next(),
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap();
+        (rx, disconnect)
+    }
+
+    /// Connect a client as the connection `connection_id` with a queue of one message
+    async fn connect_as(
+        app: &mut App,
+        cid: &str,
+        connection_id: ConnectionId,
+    ) -> (Receiver<ClientEvent>, CancellationToken) {
+        let (tx, rx) = channel(1);
+        let disconnect = CancellationToken::new();
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.connect(
+            cid.into(),
+            tx,
+            disconnect.clone(),
+            ProtocalVersion::Four,
+            true,
+            None,
+            connection_id,
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap();
+        (rx, disconnect)
+    }
+
+    #[tokio::test]
+    async fn test_takeover() {
+        let mut app = App::new();
+        let first = ConnectionId::next();
+        let second = ConnectionId::next();
+
+        let (mut first_rx, first_disconnect) = connect_as(&mut app, "client", first).await;
+        let (mut second_rx, _) = connect_as(&mut app, "client", second).await;
+
+        assert!(matches!(
+            first_rx.try_recv(),
+            Ok(ClientEvent::Disconnect(
+                DisconnectReasonCode::SessionTakenOver
+            ))
+        ));
+        assert!(!first_disconnect.is_cancelled());
+
+        // the DISCONNECT of the old connection is handled after the new CONNECT and keeps the session
+        app.disconnect("client".into(), first).await;
+        assert!(app.client_info("client").is_some());
+
+        subscribe(
+            &mut app,
+            "client",
+            "a",
+            SubscriptionOptions::from(QosLevel::AtMost),
+        )
+        .await;
+        app.publish(
+            "a".into(),
+            Bytes::from_static(b"1"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert_eq!(next_message(&mut second_rx, ProtocalVersion::Four).0, "a");
+
+        app.disconnect("client".into(), second).await;
+        assert!(app.client_info("client").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_takeover_full_queue() {
+        let mut app = App::new();
+        let (_first_rx, first_disconnect) =
+            connect_as(&mut app, "client", ConnectionId::next()).await;
+        subscribe(
+            &mut app,
+            "client",
+            "a",
+            SubscriptionOptions::from(QosLevel::AtMost),
+        )
+        .await;
+        app.publish(
+            "a".into(),
+            Bytes::from_static(b"1"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+
+        // a client that isn't reading is dropped instead of blocking the command loop
+        let (_second_rx, second_disconnect) =
+            connect_as(&mut app, "client", ConnectionId::next()).await;
+        assert!(first_disconnect.is_cancelled());
+        assert!(!second_disconnect.is_cancelled());
+    }
+
+    /// Connect a client with Clean Session unset as the connection `connection_id`
+    async fn connect_persistent(
+        app: &mut App,
+        cid: &str,
+        connection_id: ConnectionId,
+    ) -> Receiver<ClientEvent> {
+        let (tx, rx) = channel(10);
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.connect(
+            cid.into(),
+            tx,
+            CancellationToken::new(),
+            ProtocalVersion::Four,
+            false,
+            None,
+            connection_id,
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap();
+        rx
+    }
+
+    async fn publish_qos(app: &mut App, topic: &str, qos: QosLevel) {
+        app.publish(
+            topic.into(),
+            Bytes::from_static(b"1"),
+            qos,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_offline_queue() {
+        let mut app = App::new().with_offline_queue(2, false);
+        let first = ConnectionId::next();
+
+        let rx = connect_persistent(&mut app, "client", first).await;
+        subscribe(
+            &mut app,
+            "client",
+            "a",
+            SubscriptionOptions::from(QosLevel::AtLeast),
+        )
+        .await;
+        drop(rx);
+        app.disconnect("client".into(), first).await;
+        assert!(app.client_info("client").is_some());
+
+        // QoS 0 messages are dropped and the queue holds two messages
+        publish_qos(&mut app, "a", QosLevel::AtMost).await;
+        for _ in 0..3 {
+            publish_qos(&mut app, "a", QosLevel::AtLeast).await;
+        }
+
+        let mut rx = connect_persistent(&mut app, "client", ConnectionId::next()).await;
+        assert_eq!(next_qos(&mut rx), QosLevel::AtLeast);
+        assert_eq!(next_qos(&mut rx), QosLevel::AtLeast);
+        assert!(rx.try_recv().is_err());
+
+        // the resumed session gets new messages on the new connection
+        publish_qos(&mut app, "a", QosLevel::AtMost).await;
+        assert_eq!(next_qos(&mut rx), QosLevel::AtMost);
+    }
+
+    #[tokio::test]
+    async fn test_queue_qos0_messages() {
+        let mut app = App::new().with_offline_queue(10, true);
+        let first = ConnectionId::next();
+
+        let rx = connect_persistent(&mut app, "client", first).await;
+        subscribe(
+            &mut app,
+            "client",
+            "a",
+            SubscriptionOptions::from(QosLevel::AtLeast),
+        )
+        .await;
+        drop(rx);
+
+        publish_qos(&mut app, "a", QosLevel::AtMost).await;
+
+        let mut rx = connect_persistent(&mut app, "client", ConnectionId::next()).await;
+        assert_eq!(next_qos(&mut rx), QosLevel::AtMost);
+    }
+
+    #[tokio::test]
+    async fn test_clean_session_not_queued() {
+        let mut app = App::new().with_offline_queue(10, true);
+
+        let rx = connect(&mut app, "client", ProtocalVersion::Four).await;
+        subscribe(
+            &mut app,
+            "client",
+            "a",
+            SubscriptionOptions::from(QosLevel::AtLeast),
+        )
+        .await;
+        drop(rx);
+
+        publish_qos(&mut app, "a", QosLevel::AtLeast).await;
+
+        let mut rx = connect_persistent(&mut app, "client", ConnectionId::next()).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// Connect a client and return Session Present
+    async fn connect_session(app: &mut App, cid: &str, clean_session: bool) -> bool {
+        let (tx, _rx) = channel(10);
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.connect(
+            cid.into(),
+            tx,
+            CancellationToken::new(),
+            ProtocalVersion::Four,
+            clean_session,
+            None,
+            ConnectionId::next(),
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_session_present() {
+        let mut app = App::new();
+
+        // no stored session
+        assert!(!connect_session(&mut app, "client", false).await);
+        assert!(!connect_session(&mut app, "other", true).await);
+
+        // a persistent session is resumed with its subscriptions
+        subscribe(
+            &mut app,
+            "client",
+            "a",
+            SubscriptionOptions::from(QosLevel::AtLeast),
+        )
+        .await;
+        assert!(connect_session(&mut app, "client", false).await);
+        assert_eq!(app.client_subscriptions("client").unwrap().len(), 1);
+
+        // Clean Session discards the stored session
+        assert!(!connect_session(&mut app, "client", true).await);
+        assert!(app.client_subscriptions("client").unwrap().is_empty());
+
+        // a clean session is never resumed
+        assert!(!connect_session(&mut app, "client", false).await);
+        assert!(connect_session(&mut app, "client", false).await);
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_drop_qos_zero() {
+        let mut app = App::new()
+            .with_slow_client_policy(SlowClientPolicy::DropQosZero, Duration::from_millis(10));
+        let (mut rx, disconnect) = connect_slow(&mut app, "slow").await;
+        let mut fast_rx = connect(&mut app, "fast", ProtocalVersion::Four).await;
+        subscribe(&mut app, "slow", "a/+", QosLevel::AtMost.into()).await;
+        subscribe(&mut app, "fast", "a/+", QosLevel::AtMost.into()).await;
+
+        app.publish(
+            "a/1".into(),
+            Bytes::from_static(b"data"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        app.publish(
+            "a/2".into(),
+            Bytes::from_static(b"data"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+
+        assert_eq!(next_message(&mut rx, ProtocalVersion::Four).0, "a/1");
+        assert!(rx.try_recv().is_err());
+        assert!(!disconnect.is_cancelled());
+
+        // Other clients still get every message
+        assert_eq!(next_message(&mut fast_rx, ProtocalVersion::Four).0, "a/1");
+        assert_eq!(next_message(&mut fast_rx, ProtocalVersion::Four).0, "a/2");
+
+        // QoS 1 messages wait for room then disconnect the client
+        subscribe(&mut app, "slow", "b", QosLevel::AtLeast.into()).await;
+        app.publish(
+            "a/3".into(),
+            Bytes::from_static(b"data"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        app.publish(
+            "b".into(),
+            Bytes::from_static(b"data"),
+            QosLevel::AtLeast,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert!(!disconnect.is_cancelled());
+        tokio::time::timeout(Duration::from_secs(1), disconnect.cancelled())
+            .await
+            .expect("Slow client was not disconnected");
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_does_not_block() {
+        let mut app = App::new()
+            .with_slow_client_policy(SlowClientPolicy::DropQosZero, Duration::from_secs(60));
+        let (mut rx, disconnect) = connect_slow(&mut app, "slow").await;
+        let mut fast_rx = connect(&mut app, "fast", ProtocalVersion::Four).await;
+        subscribe(&mut app, "slow", "a/+", QosLevel::AtLeast.into()).await;
+        subscribe(&mut app, "fast", "a/+", QosLevel::AtLeast.into()).await;
+
+        // the command loop doesn't wait for room in the queue of the slow client
+        tokio::time::timeout(Duration::from_secs(1), async {
+            for topic in ["a/1", "a/2", "a/3"] {
+                app.publish(
+                    topic.into(),
+                    Bytes::from_static(b"data"),
+                    QosLevel::AtLeast,
+                    false,
+                    None,
+                    PublishProperties::default(),
+                    Instant::now(),
+                )
+                .await;
+            }
+        })
+        .await
+        .expect("Publishing waited on the slow client");
+
+        for topic in ["a/1", "a/2", "a/3"] {
+            assert_eq!(next_message(&mut fast_rx, ProtocalVersion::Four).0, topic);
+        }
+
+        // the slow client gets the rest in order as it makes room
+        for topic in ["a/1", "a/2", "a/3"] {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(next_message(&mut rx, ProtocalVersion::Four).0, topic);
+        }
+        assert!(!disconnect.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_disconnect() {
+        let mut app =
+            App::new().with_slow_client_policy(SlowClientPolicy::Disconnect, Duration::ZERO);
+        let (_rx, disconnect) = connect_slow(&mut app, "slow").await;
+        subscribe(&mut app, "slow", "a", QosLevel::AtMost.into()).await;
+
+        app.publish(
+            "a".into(),
+            Bytes::from_static(b"data"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert!(!disconnect.is_cancelled());
+
+        app.publish(
+            "a".into(),
+            Bytes::from_static(b"data"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert!(disconnect.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_topic_filter() {
+        let mut app = App::new();
+        let _rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.subscribe(
+            "client".into(),
+            vec![
+                ("a/#/b".into(), QosLevel::AtMost.into()),
+                ("a/+".into(), QosLevel::AtMost.into()),
+            ],
+            None,
+            r_tx,
+        )
+        .await;
+        let codes = r_rx.await.unwrap().unwrap();
+        assert!(matches!(codes[0], SubackReturnCode::TopicFilterInvalid));
+        assert!(matches!(codes[1], SubackReturnCode::SuccessQosZero));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_quota() {
+        let mut app = App::new().with_quotas(Quotas {
+            max_subscriptions: Some(1),
+            ..Quotas::default()
+        });
+        let _rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+        subscribe(&mut app, "client", "a", QosLevel::AtMost.into()).await;
+
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.subscribe(
+            "client".into(),
+            vec![
+                ("a".into(), QosLevel::AtLeast.into()),
+                ("b".into(), QosLevel::AtMost.into()),
+            ],
+            None,
+            r_tx,
+        )
+        .await;
+        let codes = r_rx.await.unwrap().unwrap();
+        assert!(matches!(codes[0], SubackReturnCode::SuccessQosOne));
+        assert!(matches!(codes[1], SubackReturnCode::QuotaExceeded));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_return_codes() {
+        let mut app = App::new().with_quotas(Quotas {
+            max_subscriptions: Some(2),
+            ..Quotas::default()
+        });
+        let _rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.subscribe(
+            "client".into(),
+            vec![
+                ("a/+".into(), QosLevel::AtLeast.into()),
+                ("$internal/#".into(), QosLevel::AtMost.into()),
+                ("a/#/b".into(), QosLevel::AtMost.into()),
+                // replaces the first filter, so it doesn't count towards the quota
+                ("a/+".into(), QosLevel::AtMost.into()),
+                ("b".into(), QosLevel::AtMost.into()),
+                ("c".into(), QosLevel::AtMost.into()),
+            ],
+            None,
+            r_tx,
+        )
+        .await;
+        let codes = r_rx
+            .await
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(u8::from)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            [
+                SubackReturnCode::SuccessQosOne,
+                SubackReturnCode::NotAuthorized,
+                SubackReturnCode::TopicFilterInvalid,
+                SubackReturnCode::SuccessQosZero,
+                SubackReturnCode::SuccessQosZero,
+                SubackReturnCode::QuotaExceeded,
+            ]
+            .map(u8::from)
+        );
+        assert_eq!(
+            app.client_subscriptions("client"),
+            Some(vec![
+                ("a/+".to_string(), QosLevel::AtMost),
+                ("b".to_string(), QosLevel::AtMost)
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_info() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+        subscribe(&mut app, "client", "a/+", QosLevel::AtLeast.into()).await;
+        subscribe(&mut app, "client", "a/+", QosLevel::AtMost.into()).await;
+        subscribe(&mut app, "client", "b", QosLevel::AtMost.into()).await;
+
+        let clients = app.clients();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].subscriptions, 2);
+        assert_eq!(
+            app.client_subscriptions("client"),
+            Some(vec![
+                ("a/+".to_string(), QosLevel::AtMost),
+                ("b".to_string(), QosLevel::AtMost)
+            ])
+        );
+
+        assert!(
+            app.kick("client", DisconnectReasonCode::AdministrativeAction)
+                .await
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ClientEvent::Disconnect(
+                DisconnectReasonCode::AdministrativeAction
+            ))
+        ));
+        assert!(app.clients().is_empty());
+        assert!(
+            !app.kick("client", DisconnectReasonCode::AdministrativeAction)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoke_subscription() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Four).await;
+        subscribe(&mut app, "client", "a/+", QosLevel::AtMost.into()).await;
+        subscribe(&mut app, "client", "b", QosLevel::AtMost.into()).await;
+
+        assert!(app.revoke_subscription("client", "a/+"));
+        assert!(!app.revoke_subscription("client", "a/+"));
+        assert!(!app.revoke_subscription("other", "b"));
+        assert_eq!(
+            app.client_subscriptions("client"),
+            Some(vec![("b".to_string(), QosLevel::AtMost)])
+        );
+
+        for topic in ["a/1", "b"] {
+            app.publish(
+                topic.into(),
+                Bytes::from_static(b"1"),
+                QosLevel::AtMost,
+                false,
+                None,
+                PublishProperties::default(),
+                Instant::now(),
+            )
+            .await;
+        }
+        assert_eq!(next_message(&mut rx, ProtocalVersion::Four).0, "b");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection_info() {
+        let mut app = App::new();
+        let inflight = Arc::new(Inflight::default());
+        let (tx, _rx) = channel(10);
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.connect(
+            "client".into(),
+            tx,
+            CancellationToken::new(),
+            ProtocalVersion::Five,
+            true,
+            Some(Connection {
+                addr: "127.0.0.1:5000".parse().unwrap(),
+                keepalive: 30,
+                anonymous: false,
+                bridge: false,
+                mount_point: None,
+                inflight,
+                last_seen: Arc::new(LastSeen::default()),
+            }),
+            ConnectionId::next(),
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap();
+        let _in_process = connect(&mut app, "in-process", ProtocalVersion::Four).await;
+
+        let info = app.client_info("client").expect("Client info");
+        assert_eq!(info.protocol, ProtocalVersion::Five);
+        assert_eq!(info.addr, Some("127.0.0.1:5000".parse().unwrap()));
+        assert_eq!(info.keepalive, Some(30));
+        assert_eq!(info.inflight_outgoing, 0);
+        assert!(info.connected_at <= SystemTime::now());
+
+        let info = app.client_info("in-process").expect("Client info");
+        assert_eq!(info.addr, None);
+        assert_eq!(info.keepalive, None);
+        assert!(app.client_info("missing").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_reason_codes() {
+        let mut app = App::new();
+        let _rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+        subscribe(&mut app, "client", "a/+", QosLevel::AtMost.into()).await;
+
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.unsubscribe(
+            "client".into(),
+            vec![
+                "a/+".into(),
+                "b".into(),
+                "$internal/#".into(),
+                "a/#/b".into(),
+            ],
+            r_tx,
+        )
+        .await;
+        assert_eq!(
+            r_rx.await.unwrap().unwrap(),
+            vec![
+                UnsubackReasonCode::Success,
+                UnsubackReasonCode::NoSubscriptionExisted,
+                UnsubackReasonCode::NotAuthorized,
+                UnsubackReasonCode::TopicFilterInvalid,
+            ]
+        );
+        assert_eq!(app.client_subscriptions("client"), Some(Vec::new()));
+
+        // the subscription is gone, so unsubscribing again reports that it didn't exist
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.unsubscribe("client".into(), vec!["a/+".into()], r_tx)
+            .await;
+        assert_eq!(
+            r_rx.await.unwrap().unwrap(),
+            vec![UnsubackReasonCode::NoSubscriptionExisted]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_filter_normalization() {
+        let mut app = App::new().with_topic_policy(
+            TopicPolicy::default().with_filter_normalization(FilterNormalization::Collapse),
+        );
+        let _rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+        subscribe(&mut app, "client", "a//b/", QosLevel::AtMost.into()).await;
+        // the same filter spelled differently replaces the subscription
+        subscribe(&mut app, "client", "a/b", QosLevel::AtLeast.into()).await;
+        assert_eq!(
+            app.client_subscriptions("client"),
+            Some(vec![("a/b".into(), QosLevel::AtLeast)])
+        );
+
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.unsubscribe("client".into(), vec!["a/b//".into()], r_tx)
+            .await;
+        assert_eq!(
+            r_rx.await.unwrap().unwrap(),
+            vec![UnsubackReasonCode::Success]
+        );
+        assert_eq!(app.client_subscriptions("client"), Some(Vec::new()));
+
+        let mut app = App::new().with_topic_policy(
+            TopicPolicy::default().with_filter_normalization(FilterNormalization::Reject),
+        );
+        let _rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.subscribe(
+            "client".into(),
+            vec![
+                ("a/b/".into(), QosLevel::AtMost.into()),
+                ("a/b".into(), QosLevel::AtMost.into()),
+            ],
+            None,
+            r_tx,
+        )
+        .await;
+        let codes = r_rx.await.unwrap().unwrap();
+        assert!(matches!(codes[0], SubackReturnCode::TopicFilterInvalid));
+        assert!(matches!(codes[1], SubackReturnCode::SuccessQosZero));
+    }
+
+    #[tokio::test]
+    async fn test_dollar_topics() {
+        let mut app = App::new().with_topic_policy(TopicPolicy::new(vec!["$internal".into()]));
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Four).await;
+
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.subscribe(
+            "client".into(),
+            vec![
+                ("#".into(), QosLevel::AtMost.into()),
+                ("$SYS/#".into(), QosLevel::AtMost.into()),
+                ("$other/#".into(), QosLevel::AtMost.into()),
+            ],
+            None,
+            r_tx,
+        )
+        .await;
+        let codes = r_rx.await.unwrap().unwrap();
+        assert!(matches!(codes[2], SubackReturnCode::NotAuthorized));
+
+        // Clients can't publish to $SYS
+        app.publish(
+            "$SYS/broker/uptime".into(),
+            Bytes::from_static(b"1"),
+            QosLevel::AtMost,
+            false,
+            Some("client".into()),
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert!(rx.try_recv().is_err());
+
+        // The server can, and only the $SYS subscription matches
+        app.publish(
+            "$SYS/broker/uptime".into(),
+            Bytes::from_static(b"1"),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Four),
+            ("$SYS/broker/uptime".into(), false)
+        );
+        assert!(rx.try_recv().is_err());
+
+        app.publish(
+            "$internal/a".into(),
+            Bytes::from_static(b"1"),
+            QosLevel::AtMost,
+            false,
+            Some("client".into()),
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_local() {
+        let mut app = App::new();
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+        let mut other_rx = connect(&mut app, "other", ProtocalVersion::Five).await;
+
+        let options = SubscriptionOptions::new(QosLevel::AtMost, true, false);
+        subscribe(&mut app, "client", "a/b", options).await;
+        subscribe(&mut app, "other", "a/b", options).await;
+
+        app.publish(
+            "a/b".into(),
+            Bytes::from_static(b"own"),
+            QosLevel::AtMost,
+            false,
+            Some("client".into()),
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            next_message(&mut other_rx, ProtocalVersion::Five),
+            ("a/b".into(), false)
+        );
+
+        app.publish(
+            "a/b".into(),
+            Bytes::from_static(b"other"),
+            QosLevel::AtMost,
+            false,
+            Some("other".into()),
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Five),
+            ("a/b".into(), false)
+        );
+        assert!(other_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_local_shared_subscription() {
+        let mut app = App::new();
+        let _rx = connect(&mut app, "client", ProtocalVersion::Five).await;
+
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.subscribe(
+            "client".into(),
+            vec![
+                ("a".into(), QosLevel::AtMost.into()),
+                (
+                    "$share/g/a".into(),
+                    SubscriptionOptions::new(QosLevel::AtMost, true, false),
+                ),
+            ],
+            None,
+            r_tx,
+        )
+        .await;
+        assert!(matches!(
+            r_rx.await.unwrap(),
+            Err(MqttError::ProtocolViolation)
+        ));
+        // none of the filters are subscribed to
+        assert!(app.client_subscriptions("client").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bridge_not_sent_own_messages() {
+        let mut app = App::new();
+        let (tx, mut rx) = channel(10);
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.connect(
+            "bridge.edge".into(),
+            tx,
+            CancellationToken::new(),
+            ProtocalVersion::Four,
+            true,
+            Some(Connection {
+                addr: "127.0.0.1:5000".parse().unwrap(),
+                keepalive: 30,
+                anonymous: false,
+                bridge: true,
+                mount_point: None,
+                inflight: Arc::new(Inflight::default()),
+                last_seen: Arc::new(LastSeen::default()),
+            }),
+            ConnectionId::next(),
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap();
+
+        // v3.1.1 subscriptions have no options, a bridge gets No Local and Retain As Published anyway
+        subscribe(
+            &mut app,
+            "bridge.edge",
+            "sensors/#",
+            SubscriptionOptions::new(QosLevel::AtMost, false, false),
+        )
+        .await;
+
+        for client in ["bridge.edge", "device"] {
+            app.publish(
+                "sensors/temp".into(),
+                Bytes::from_static(b"21"),
+                QosLevel::AtMost,
+                true,
+                Some(client.into()),
+                PublishProperties::default(),
+                Instant::now(),
+            )
+            .await;
+        }
+
+        assert_eq!(
+            next_message(&mut rx, ProtocalVersion::Four),
+            ("sensors/temp".into(), true)
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mount_point() {
+        let mut app = App::new();
+        let mut receivers = Vec::new();
+        for (cid, mount_point) in [
+            ("tenant-a", Some("a/")),
+            ("tenant-b", Some("b/")),
+            ("admin", None),
+        ] {
+            let (tx, rx) = channel(10);
+            let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+            app.connect(
+                cid.into(),
+                tx,
+                CancellationToken::new(),
+                ProtocalVersion::Five,
+                true,
+                Some(Connection {
+                    addr: "127.0.0.1:5000".parse().unwrap(),
+                    keepalive: 30,
+                    anonymous: false,
+                    bridge: false,
+                    mount_point: mount_point.map(Arc::from),
+                    inflight: Arc::new(Inflight::default()),
+                    last_seen: Arc::new(LastSeen::default()),
+                }),
+                ConnectionId::next(),
+                r_tx,
+            )
+            .await;
+            r_rx.await.unwrap().unwrap();
+            receivers.push(rx);
+        }
+
+        // The connection handler has already added the mount points to the filters
+        for (cid, filter) in [
+            ("tenant-a", "a/sensors/#"),
+            ("tenant-b", "b/sensors/#"),
+            ("admin", "#"),
+        ] {
+            subscribe(
+                &mut app,
+                cid,
+                filter,
+                SubscriptionOptions::new(QosLevel::AtMost, false, false),
+            )
+            .await;
+        }
+
+        app.publish(
+            "a/sensors/temp".into(),
+            Bytes::from_static(b"21"),
+            QosLevel::AtMost,
+            false,
+            Some("tenant-a".into()),
+            PublishProperties::default(),
+            Instant::now(),
+        )
+        .await;
+
+        assert_eq!(
+            next_message(&mut receivers[0], ProtocalVersion::Five),
+            ("sensors/temp".into(), false)
+        );
+        assert!(receivers[1].try_recv().is_err());
+        assert_eq!(
+            next_message(&mut receivers[2], ProtocalVersion::Five),
+            ("a/sensors/temp".into(), false)
+        );
+    }
+
+    async fn connect_network(
+        app: &mut App,
+        cid: &str,
+        connection_id: ConnectionId,
+    ) -> Receiver<ClientEvent> {
+        let (tx, rx) = channel(10);
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.connect(
+            cid.into(),
+            tx,
+            CancellationToken::new(),
+            ProtocalVersion::Five,
+            true,
+            Some(Connection {
+                addr: "127.0.0.1:5000".parse().unwrap(),
+                keepalive: 30,
+                anonymous: false,
+                bridge: false,
+                mount_point: None,
+                inflight: Arc::new(Inflight::default()),
+                last_seen: Arc::new(LastSeen::default()),
+            }),
+            connection_id,
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap();
+        rx
+    }
+
+    /// Returns the topic and the JSON payload of the next event
+    fn next_event(rx: &mut Receiver<ClientEvent>) -> (String, serde_json::Value) {
+        match rx.try_recv().expect("Expected an event") {
+            ClientEvent::Message(mut bytes) => {
+                let (packet, _) = Packet::unpack(&mut bytes, ProtocalVersion::Four).unwrap();
+                match packet.variable {
+                    VariableHeader::Publish { topic, payload, .. } => {
+                        (topic, serde_json::from_slice(&payload).unwrap())
+                    }
+                    _ => panic!("Expected a publish"),
+                }
+            }
+            ClientEvent::Disconnect(_) => panic!("Expected an event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_events() {
+        let mut app = App::new().with_events(Some("$SYS/broker/events".into()));
+        let mut events = connect(&mut app, "watcher", ProtocalVersion::Four).await;
+        subscribe(
+            &mut app,
+            "watcher",
+            "$SYS/broker/events/#",
+            QosLevel::AtLeast.into(),
+        )
+        .await;
+        // In process clients have no events
+        assert!(events.try_recv().is_err());
+
+        let first = ConnectionId::next();
+        let _rx = connect_network(&mut app, "sensor", first).await;
+        let (topic, event) = next_event(&mut events);
+        assert_eq!(topic, "$SYS/broker/events/connected");
+        assert_eq!(event["client_id"], "sensor");
+        assert_eq!(event["addr"], "127.0.0.1:5000");
+        assert_eq!(event["protocol"], 5);
+
+        subscribe(&mut app, "sensor", "commands/#", QosLevel::AtLeast.into()).await;
+        let (topic, event) = next_event(&mut events);
+        assert_eq!(topic, "$SYS/broker/events/subscribed");
+        assert_eq!(event["filter"], "commands/#");
+        assert_eq!(event["qos"], 1);
+
+        let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+        app.unsubscribe(
+            "sensor".into(),
+            vec!["commands/#".into(), "other".into()],
+            r_tx,
+        )
+        .await;
+        r_rx.await.unwrap().unwrap();
+        let (topic, event) = next_event(&mut events);
+        assert_eq!(topic, "$SYS/broker/events/unsubscribed");
+        assert_eq!(event["filter"], "commands/#");
+        assert!(events.try_recv().is_err());
+
+        // The old connection is reported as taken over before the new one is connected
+        let second = ConnectionId::next();
+        let _rx = connect_network(&mut app, "sensor", second).await;
+        let (topic, event) = next_event(&mut events);
+        assert_eq!(topic, "$SYS/broker/events/disconnected");
+        assert_eq!(event["reason"], "server_disconnect");
+        assert_eq!(
+            event["reason_code"],
+            DisconnectReasonCode::SessionTakenOver as u8
+        );
+        assert_eq!(next_event(&mut events).0, "$SYS/broker/events/connected");
+
+        app.connection_closed("sensor".into(), first, DisconnectReason::ConnectionLost)
+            .await;
+        assert!(events.try_recv().is_err());
+
+        app.connection_closed("sensor".into(), second, DisconnectReason::ConnectionLost)
+            .await;
+        let (topic, event) = next_event(&mut events);
+        assert_eq!(topic, "$SYS/broker/events/disconnected");
+        assert_eq!(event["reason"], "connection_lost");
+        assert!(event.get("reason_code").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remove_retained() {
+        let mut app = App::new();
+        for topic in ["sensors/2", "sensors/1", "other"] {
+            app.publish(
+                topic.into(),
+                Bytes::from_static(b"1"),
+                QosLevel::AtMost,
+                true,
+                None,
+                PublishProperties::default(),
+                Instant::now(),
+            )
+            .await;
+        }
+        let mut rx = connect(&mut app, "client", ProtocalVersion::Four).await;
+        subscribe(&mut app, "client", "sensors/#", QosLevel::AtMost.into()).await;
+        while rx.try_recv().is_ok() {}
+
+        let topics = app
+            .retained_messages("sensors/#")
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect::<Vec<String>>();
+        assert_eq!(topics, ["sensors/1", "sensors/2"]);
+
+        // Subscribers aren't sent an empty retained message
+        assert_eq!(app.remove_retained("sensors/+"), 2);
+        assert!(rx.try_recv().is_err());
+        assert!(app.retained_messages("sensors/#").is_empty());
+        assert_eq!(app.retained_count(), 1);
+    }
+}
@@ -1,17 +1,80 @@
+use std::{collections::VecDeque, sync::Arc, time::SystemTime};
+
+use bytes::Bytes;
 use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use super::enums::ClientEvent;
+use crate::packets::enums::QosLevel;
+
+use super::enums::{ClientEvent, Connection, ConnectionId, ProtocalVersion};
 
 pub struct Session {
     pub id: u128,
     pub bridge: Sender<ClientEvent>,
+    pub protocol: ProtocalVersion,
+    /// Cancelled when the broker drops the client
+    pub disconnect: CancellationToken,
+    /// Topic filters the client is subscribed to
+    pub subscriptions: Vec<(String, QosLevel)>,
+    /// `None` for in process clients and bridges
+    pub connection: Option<Connection>,
+    /// When the client last connected
+    pub connected_at: SystemTime,
+    /// The connection currently using the session
+    pub connection_id: ConnectionId,
+    /// Set by the CONNECT of the current connection, a persistent session outlives the connection
+    pub clean_session: bool,
+    /// Messages for the client while it is offline, sent when it reconnects
+    pub offline: VecDeque<Bytes>,
+    /// A connected event was published for the current connection and its disconnected event wasn't yet
+    pub online: bool,
 }
 
 impl Session {
-    pub fn new(bridge: Sender<ClientEvent>) -> Self {
+    pub fn new(
+        bridge: Sender<ClientEvent>,
+        protocol: ProtocalVersion,
+        disconnect: CancellationToken,
+        connection: Option<Connection>,
+        connection_id: ConnectionId,
+        clean_session: bool,
+    ) -> Self {
         let id = Uuid::new_v4().as_u128();
 
-        Self { id, bridge }
+        Self {
+            id,
+            bridge,
+            protocol,
+            disconnect,
+            subscriptions: Vec::new(),
+            connection,
+            connected_at: SystemTime::now(),
+            connection_id,
+            clean_session,
+            offline: VecDeque::new(),
+            online: false,
+        }
+    }
+
+    /// Network client that connected without a username
+    pub fn is_anonymous(&self) -> bool {
+        self.connection
+            .as_ref()
+            .is_some_and(|connection| connection.anonymous)
+    }
+
+    /// Network client that connected as a bridge
+    pub fn is_bridge(&self) -> bool {
+        self.connection
+            .as_ref()
+            .is_some_and(|connection| connection.bridge)
+    }
+
+    /// Topic prefix of the client, see [`Connection::mount_point`]
+    pub fn mount_point(&self) -> Option<Arc<str>> {
+        self.connection
+            .as_ref()
+            .and_then(|connection| connection.mount_point.clone())
     }
 }
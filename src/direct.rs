@@ -0,0 +1,215 @@
+//! ### Direct Delivery
+//! A fast path for QoS 0 messages to a topic with a single subscriber, enabled with the `direct_delivery` option.
+//!
+//! The connection task of the publisher asks the command loop once for the route of a topic,
+//! and then writes the messages straight into the queue of the subscriber instead of sending them through the command loop.
+//! The command loop only gives a route when it would deliver the message the same way: the topic has one non-shared subscriber
+//! without a Subscription Identifier, there are no hooks, interceptors, message stamps or command shards,
+//! and the `slow_client_policy` is `drop_qos0`.
+//!
+//! The route is asked for after a message sent through the command loop and the connection waits for the answer,
+//! so the messages of a publisher keep their order. Any change to the subscriptions or a reload of the config
+//! drops the routes of every connection, and they are asked for again.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::{
+    core::{
+        broker_info,
+        enums::{ClientEvent, ProtocalVersion},
+    },
+    packets::{enums::QosLevel, Packet, PublishProperties},
+};
+
+/// Most topics a connection keeps routes for
+const MAX_ROUTES: usize = 1024;
+
+/// Changed every time the routes may have gone out of date
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Current generation of the routes
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Drop the routes of every connection, called when the subscriptions change
+pub fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Where the messages published to a topic go without the command loop
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Topic sent to the subscriber, without its mount point
+    pub topic: String,
+    pub protocol: ProtocalVersion,
+    pub bridge: Sender<ClientEvent>,
+    /// Generation the route was given in
+    pub generation: u64,
+}
+
+#[derive(Debug)]
+enum State {
+    Direct(Route),
+    /// The messages go through the command loop until the routes are dropped
+    Central,
+}
+
+/// Routes of the topics a connection publishes to
+#[derive(Debug, Default)]
+pub struct Routes {
+    generation: u64,
+    topics: HashMap<String, State>,
+}
+
+impl Routes {
+    /// Deliver a QoS 0 message straight to the subscriber of the topic.
+    ///
+    /// Returns false if the message has to be sent through the command loop.
+    pub fn send(
+        &mut self,
+        topic: &str,
+        payload: &Bytes,
+        properties: &PublishProperties,
+        received: Instant,
+    ) -> bool {
+        self.refresh();
+        let Some(State::Direct(route)) = self.topics.get(topic) else {
+            return false;
+        };
+
+        let packet = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            false,
+            route.topic.clone(),
+            None,
+            payload.clone(),
+            properties.clone(),
+            Vec::new(),
+            route.protocol,
+        );
+        match route.bridge.try_send(ClientEvent::Message(packet)) {
+            Ok(()) => {
+                #[cfg(feature = "topic-stats")]
+                if !topic.starts_with('$') {
+                    broker_info::topic_published(topic, payload.len());
+                }
+                broker_info::delivered(received.elapsed());
+            }
+            // Like the command loop does with the `drop_qos0` policy
+            Err(TrySendError::Full(_)) => broker_info::dropped_published(),
+            // The command loop keeps the message if the subscriber has a persistent session
+            Err(TrySendError::Closed(_)) => {
+                self.topics.remove(topic);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Should the route of the topic be asked for, after a message to it was sent through the command loop
+    pub fn should_ask(&mut self, topic: &str) -> bool {
+        self.refresh();
+        !self.topics.contains_key(topic) && self.topics.len() < MAX_ROUTES
+    }
+
+    /// Keep the answer of the command loop, an answer that went out of date while it was asked for is dropped
+    pub fn insert(&mut self, topic: String, route: Option<Route>) {
+        let current = self.refresh();
+        let state = match route {
+            Some(route) if route.generation == current => State::Direct(route),
+            Some(_) => return,
+            None => State::Central,
+        };
+        self.topics.insert(topic, state);
+    }
+
+    /// Forget the route after a message to the topic was sent through the command loop,
+    /// the next messages have to wait for it to be delivered
+    pub fn remove(&mut self, topic: &str) {
+        self.topics.remove(topic);
+    }
+
+    /// Drop the routes if they have gone out of date, returns the current generation
+    fn refresh(&mut self) -> u64 {
+        let current = generation();
+        if self.generation != current {
+            self.generation = current;
+            self.topics.clear();
+        }
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    fn route(bridge: Sender<ClientEvent>) -> Route {
+        Route {
+            topic: "a".into(),
+            protocol: ProtocalVersion::Four,
+            bridge,
+            generation: generation(),
+        }
+    }
+
+    fn send(routes: &mut Routes) -> bool {
+        routes.send(
+            "a",
+            &Bytes::from_static(b"x"),
+            &PublishProperties::default(),
+            Instant::now(),
+        )
+    }
+
+    #[test]
+    fn test_routes() {
+        let (bridge, mut rx) = channel(1);
+        let mut routes = Routes::default();
+
+        assert!(!send(&mut routes));
+        assert!(routes.should_ask("a"));
+        // no route, the messages go through the command loop
+        routes.insert("a".into(), None);
+        assert!(!send(&mut routes));
+
+        // other tests changing their subscriptions make the route go out of date, it is then asked for again
+        loop {
+            routes.remove("a");
+            routes.insert("a".into(), Some(route(bridge.clone())));
+            if send(&mut routes) {
+                break;
+            }
+        }
+        match rx.try_recv() {
+            Ok(ClientEvent::Message(packet)) => {
+                assert_eq!(&packet[..], &[0x30, 0x04, 0x00, 0x01, b'a', b'x'])
+            }
+            _ => panic!("Expected the message to be delivered"),
+        }
+
+        // a route given before the subscriptions changed is dropped
+        let stale = route(bridge.clone());
+        invalidate();
+        routes.insert("a".into(), Some(stale));
+        assert!(!send(&mut routes));
+        assert!(routes.should_ask("a"));
+
+        // the subscriber's connection has closed
+        routes.insert("a".into(), Some(route(bridge)));
+        drop(rx);
+        assert!(!send(&mut routes));
+        assert!(routes.should_ask("a"));
+    }
+}
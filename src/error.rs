@@ -1,7 +1,10 @@
 use thiserror::Error;
 use tokio::task::JoinError;
 
-use crate::core::enums::Command;
+use crate::{
+    core::enums::Command,
+    packets::enums::{ConnectReturnCode, DisconnectReasonCode},
+};
 
 #[derive(Debug, Error)]
 pub enum MqttError {
@@ -29,6 +32,12 @@ pub enum MqttError {
     #[error("Unknown protocal name")]
     UnknownProtocol,
 
+    #[error("Received a packet before the CONNECT")]
+    ProtocolNotNegotiated,
+
+    #[error("CONNECT protocol version does not match the connection")]
+    ProtocolMismatch,
+
     #[error("MalformedHeader")]
     MalformedHeader,
 
@@ -40,12 +49,23 @@ pub enum MqttError {
     MissingByte,
     #[error("Malformed Remaing Length header")]
     MalformedRemaingLength,
+    #[error("Malformed Packet: {0}")]
+    MalformedPacket(&'static str),
+    #[error("Packet of {0} bytes is larger than the Maximum Packet Size")]
+    PacketTooLarge(usize),
 
     #[error("Missing Fixed Header")]
     MissingFixedHeader,
 
+    #[error("Topic Name is invalid")]
+    TopicNameInvalid,
+    #[error("Topic Filter is invalid")]
+    TopicFilterInvalid,
+
     #[error("Client identifier is invalid")]
     ClientIdentifierRejected,
+    #[error("Client is not authorized to connect")]
+    NotAuthorized,
     #[error("Failed to get client id")]
     FailedToGetCId,
     #[error("PoisonError")]
@@ -54,4 +74,18 @@ pub enum MqttError {
     TaskJoinError(#[from] JoinError),
     #[error("RwLock error")]
     RwLockError,
+    #[error("Failed to set up logging: {0}")]
+    Logging(String),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("Invalid PROXY protocol header")]
+    InvalidProxyHeader,
+    #[error("Connection refused by remote broker: {0:?}")]
+    ConnectionRefused(ConnectReturnCode),
+    #[error("Timed out waiting for {0} from the remote broker")]
+    RemoteTimeout(&'static str),
+    #[error("Bridge disconnected by the local broker: {0:?}")]
+    BridgeDisconnected(DisconnectReasonCode),
 }
@@ -0,0 +1,204 @@
+//! ### Client Events
+//! JSON messages the broker publishes as clients connect, disconnect, subscribe and unsubscribe,
+//! enabled with the `event_topic_prefix` option.
+//!
+//! Each event is published at QoS 1 to `{prefix}/{event}`, so a service can follow the presence of
+//! devices by subscribing to `{prefix}/#` instead of polling the admin API. With the prefix `$SYS/broker/events`
+//! a client connecting and going away without a DISCONNECT is published as
+//!
+//! ```text
+//! $SYS/broker/events/connected     {"event":"connected","client_id":"sensor-1","addr":"10.0.0.7:50112","protocol":5,"clean_session":true,"keepalive":60,"timestamp":1718000000000}
+//! $SYS/broker/events/disconnected  {"event":"disconnected","client_id":"sensor-1","reason":"connection_lost","timestamp":1718000090000}
+//! ```
+//!
+//! Only clients connected over the network have events, in process clients and the broker's own bridges don't.
+
+use std::{
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use crate::{
+    core::enums::ProtocalVersion,
+    packets::enums::{DisconnectReasonCode, QosLevel},
+};
+
+/// Why the connection of a client closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client sent a DISCONNECT with the reason code
+    Client(DisconnectReasonCode),
+    /// The broker closed the connection, with the reason code sent to v5 clients
+    Server(DisconnectReasonCode),
+    /// The connection closed without a DISCONNECT
+    ConnectionLost,
+}
+
+impl DisconnectReason {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Client(_) => "client_disconnect",
+            Self::Server(_) => "server_disconnect",
+            Self::ConnectionLost => "connection_lost",
+        }
+    }
+
+    fn reason_code(self) -> Option<u8> {
+        match self {
+            Self::Client(code) | Self::Server(code) => Some(code as u8),
+            Self::ConnectionLost => None,
+        }
+    }
+}
+
+/// Flattened into the disconnected event as `reason` and `reason_code`
+impl Serialize for DisconnectReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut reason = serializer.serialize_struct("DisconnectReason", 2)?;
+        reason.serialize_field("reason", self.name())?;
+        match self.reason_code() {
+            Some(code) => reason.serialize_field("reason_code", &code)?,
+            None => reason.skip_field("reason_code")?,
+        }
+        reason.end()
+    }
+}
+
+/// Something that happened to a client, published as JSON
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected {
+        client_id: String,
+        addr: SocketAddr,
+        #[serde(serialize_with = "serialize_protocol")]
+        protocol: ProtocalVersion,
+        clean_session: bool,
+        keepalive: u16,
+    },
+    Disconnected {
+        client_id: String,
+        #[serde(flatten)]
+        reason: DisconnectReason,
+    },
+    Subscribed {
+        client_id: String,
+        filter: String,
+        #[serde(serialize_with = "serialize_qos")]
+        qos: QosLevel,
+    },
+    Unsubscribed {
+        client_id: String,
+        filter: String,
+    },
+}
+
+impl Event {
+    /// Topic of the event under `prefix`
+    pub fn topic(&self, prefix: &str) -> String {
+        let name = match self {
+            Self::Connected { .. } => "connected",
+            Self::Disconnected { .. } => "disconnected",
+            Self::Subscribed { .. } => "subscribed",
+            Self::Unsubscribed { .. } => "unsubscribed",
+        };
+        format!("{}/{}", prefix, name)
+    }
+
+    /// The event as JSON, with the time it happened in milliseconds since the Unix epoch
+    pub fn payload(&self, at: SystemTime) -> Bytes {
+        #[derive(Serialize)]
+        struct Message<'a> {
+            #[serde(flatten)]
+            event: &'a Event,
+            timestamp: u64,
+        }
+
+        let timestamp = at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        match serde_json::to_vec(&Message {
+            event: self,
+            timestamp,
+        }) {
+            Ok(json) => Bytes::from(json),
+            Err(err) => {
+                tracing::error!("Failed to serialize {:?}: {}", self, err);
+                Bytes::new()
+            }
+        }
+    }
+}
+
+fn serialize_protocol<S: Serializer>(
+    protocol: &ProtocalVersion,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u8(u8::from(*protocol))
+}
+
+fn serialize_qos<S: Serializer>(qos: &QosLevel, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u8(*qos as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn json(event: &Event) -> String {
+        let payload = event.payload(UNIX_EPOCH + Duration::from_millis(1500));
+        String::from_utf8(payload.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_event_payload() {
+        let connected = Event::Connected {
+            client_id: "sensor-1".into(),
+            addr: "10.0.0.7:50112".parse().unwrap(),
+            protocol: ProtocalVersion::Five,
+            clean_session: true,
+            keepalive: 60,
+        };
+        assert_eq!(
+            connected.topic("$SYS/broker/events"),
+            "$SYS/broker/events/connected"
+        );
+        assert_eq!(
+            json(&connected),
+            r#"{"event":"connected","client_id":"sensor-1","addr":"10.0.0.7:50112","protocol":5,"clean_session":true,"keepalive":60,"timestamp":1500}"#
+        );
+
+        let disconnected = Event::Disconnected {
+            client_id: "sensor-1".into(),
+            reason: DisconnectReason::Server(DisconnectReasonCode::KeepAliveTimeout),
+        };
+        assert_eq!(
+            json(&disconnected),
+            r#"{"event":"disconnected","client_id":"sensor-1","reason":"server_disconnect","reason_code":141,"timestamp":1500}"#
+        );
+        let lost = Event::Disconnected {
+            client_id: "sensor-1".into(),
+            reason: DisconnectReason::ConnectionLost,
+        };
+        assert_eq!(
+            json(&lost),
+            r#"{"event":"disconnected","client_id":"sensor-1","reason":"connection_lost","timestamp":1500}"#
+        );
+
+        let subscribed = Event::Subscribed {
+            client_id: "sensor-1".into(),
+            filter: "commands/#".into(),
+            qos: QosLevel::AtLeast,
+        };
+        assert_eq!(subscribed.topic("$events"), "$events/subscribed");
+        assert_eq!(
+            json(&subscribed),
+            r#"{"event":"subscribed","client_id":"sensor-1","filter":"commands/#","qos":1,"timestamp":1500}"#
+        );
+    }
+}
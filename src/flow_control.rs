@@ -0,0 +1,359 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    packet_id::PacketIdAllocator,
+    packets::{enums::QosLevel, Packet, VariableHeader},
+};
+
+/// Message counts of a connection, shared with the command loop for [`crate::core::ClientInfo`]
+#[derive(Debug, Default)]
+pub struct Inflight {
+    outgoing: AtomicUsize,
+    pending: AtomicUsize,
+    incoming: AtomicUsize,
+}
+
+impl Inflight {
+    /// Outgoing QoS 1 and QoS 2 messages waiting to be acknowledged
+    pub fn outgoing(&self) -> usize {
+        self.outgoing.load(Ordering::Relaxed)
+    }
+
+    /// Outgoing messages held back by the client's Receive Maximum
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Incoming QoS 2 messages waiting for a PUBREL
+    pub fn incoming(&self) -> usize {
+        self.incoming.load(Ordering::Relaxed)
+    }
+}
+
+/// What to do with an incoming QoS 1 or QoS 2 PUBLISH
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Received {
+    /// Forward the message
+    New,
+    /// A QoS 2 message sent again before its PUBREL, it was already forwarded so only the PUBREC is sent again
+    Duplicate,
+    /// The client has more QoS 2 messages in flight than the Receive Maximum
+    OverLimit,
+}
+
+/// ### Flow Control
+/// Tracks the QoS 1 and QoS 2 PUBLISH packets in flight on a single connection.
+///
+/// Outgoing messages are held back once the client's Receive Maximum is reached
+/// and sent as acknowledgements arrive. Incoming messages are limited by the
+/// Receive Maximum the server advertised in the CONNACK.
+///
+/// The packet identifiers of incoming QoS 2 messages are kept until the PUBREL,
+/// so a message the client sends again before then is only forwarded once.
+///
+/// [(MQTT 5) 4.3.3 QoS 2: Exactly once delivery](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901237)
+///
+/// [(MQTT 5) 4.9 Flow Control](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901251)
+#[derive(Debug)]
+pub struct FlowControl {
+    /// Receive Maximum of the client
+    send_quota: u16,
+    /// Packet identifiers of the outgoing messages in flight
+    outgoing: PacketIdAllocator,
+    /// Messages waiting for an outgoing slot
+    pending: VecDeque<Packet>,
+    /// Receive Maximum advertised to the client
+    receive_maximum: u16,
+    incoming: HashSet<u16>,
+    counts: Arc<Inflight>,
+}
+
+impl FlowControl {
+    pub fn new(receive_maximum: u16) -> Self {
+        Self {
+            send_quota: u16::MAX,
+            outgoing: PacketIdAllocator::new(),
+            pending: VecDeque::new(),
+            receive_maximum,
+            incoming: HashSet::new(),
+            counts: Arc::new(Inflight::default()),
+        }
+    }
+
+    /// Counts of the messages in flight, kept up to date as messages are sent and acknowledged
+    pub fn inflight(&self) -> Arc<Inflight> {
+        self.counts.clone()
+    }
+
+    /// Set the Receive Maximum sent by the client in the CONNECT packet
+    pub fn set_send_quota(&mut self, value: u16) {
+        self.send_quota = value;
+    }
+
+    /// Set the Receive Maximum of the server, v3.1.1 clients aren't sent one so they get `u16::MAX`
+    pub fn set_receive_maximum(&mut self, value: u16) {
+        self.receive_maximum = value;
+    }
+
+    /// Number of messages waiting to be sent
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// No outgoing messages are waiting to be sent or acknowledged
+    pub fn is_idle(&self) -> bool {
+        self.outgoing.len() == 0 && self.pending.is_empty()
+    }
+
+    /// Queue a PUBLISH for the client.
+    ///
+    /// Returns the packet with a packet identifier assigned if it can be sent now,
+    /// otherwise it is held until [`FlowControl::acknowledge`] frees a slot.
+    /// Held messages are sent in the order they were queued so a newer message never overtakes an older one.
+    pub fn publish(&mut self, packet: Packet) -> Option<Packet> {
+        if !self.requires_ack(&packet) {
+            return Some(packet);
+        }
+
+        let id = if self.pending.is_empty() {
+            self.next_packet_id()
+        } else {
+            None
+        };
+
+        match id {
+            Some(id) => Some(with_packet_id(packet, id)),
+            None => {
+                self.pending.push_back(packet);
+                self.update_counts();
+                None
+            }
+        }
+    }
+
+    /// Complete an outgoing message after the PUBACK or PUBCOMP was received.
+    ///
+    /// Returns the held back messages that can now be sent.
+    pub fn acknowledge(&mut self, packet_id: u16) -> Vec<Packet> {
+        self.outgoing.release(packet_id);
+
+        let mut ready = Vec::new();
+        while let Some(packet) = self.pending.pop_front() {
+            let Some(id) = self.next_packet_id() else {
+                self.pending.push_front(packet);
+                break;
+            };
+            ready.push(with_packet_id(packet, id));
+        }
+
+        self.update_counts();
+        ready
+    }
+
+    /// Record an incoming QoS 1 or QoS 2 PUBLISH
+    pub fn receive(&mut self, packet_id: u16, exactly_once: bool) -> Received {
+        if exactly_once && self.incoming.contains(&packet_id) {
+            return Received::Duplicate;
+        }
+
+        if self.incoming.len() >= self.receive_maximum as usize {
+            return Received::OverLimit;
+        }
+
+        // QoS 1 messages are acknowledged straight away so only QoS 2 stay in flight
+        if exactly_once {
+            self.incoming.insert(packet_id);
+            self.update_counts();
+        }
+
+        Received::New
+    }
+
+    /// Complete an incoming QoS 2 message after the PUBREL was received
+    pub fn release(&mut self, packet_id: u16) {
+        self.incoming.remove(&packet_id);
+        self.update_counts();
+    }
+
+    fn requires_ack(&self, packet: &Packet) -> bool {
+        matches!(packet.fixed.get_qos(), Ok(qos) if qos > QosLevel::AtMost)
+    }
+
+    /// A free packet identifier if the client can receive another message
+    fn next_packet_id(&mut self) -> Option<u16> {
+        if self.outgoing.len() >= self.send_quota as usize {
+            return None;
+        }
+        let id = self.outgoing.allocate();
+        self.update_counts();
+        id
+    }
+
+    fn update_counts(&self) {
+        self.counts
+            .outgoing
+            .store(self.outgoing.len(), Ordering::Relaxed);
+        self.counts
+            .pending
+            .store(self.pending.len(), Ordering::Relaxed);
+        self.counts
+            .incoming
+            .store(self.incoming.len(), Ordering::Relaxed);
+    }
+}
+
+fn with_packet_id(mut packet: Packet, id: u16) -> Packet {
+    if let VariableHeader::Publish { packet_id, .. } = &mut packet.variable {
+        *packet_id = Some(id);
+    }
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{core::enums::ProtocalVersion, packets::PublishProperties};
+
+    fn publish(qos: QosLevel) -> Packet {
+        let mut bytes = Packet::make_publish(
+            false,
+            qos,
+            false,
+            "a".into(),
+            None,
+            Bytes::from_static(b"data"),
+            PublishProperties::default(),
+            Vec::new(),
+            ProtocalVersion::Five,
+        );
+        Packet::unpack(&mut bytes, ProtocalVersion::Five).unwrap().0
+    }
+
+    fn publish_payload(payload: &'static [u8]) -> Packet {
+        let mut bytes = Packet::make_publish(
+            false,
+            QosLevel::AtLeast,
+            false,
+            "a".into(),
+            None,
+            Bytes::from_static(payload),
+            PublishProperties::default(),
+            Vec::new(),
+            ProtocalVersion::Five,
+        );
+        Packet::unpack(&mut bytes, ProtocalVersion::Five).unwrap().0
+    }
+
+    fn payload(packet: &Packet) -> &[u8] {
+        match &packet.variable {
+            VariableHeader::Publish { payload, .. } => payload,
+            _ => &[],
+        }
+    }
+
+    fn packet_id(packet: &Packet) -> Option<u16> {
+        match packet.variable {
+            VariableHeader::Publish { packet_id, .. } => packet_id,
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_send_quota() {
+        let mut flow = FlowControl::new(10);
+        flow.set_send_quota(2);
+
+        let first = flow.publish(publish(QosLevel::AtLeast)).expect("Packet");
+        let second = flow.publish(publish(QosLevel::Exactly)).expect("Packet");
+        assert_eq!(packet_id(&first), Some(1));
+        assert_eq!(packet_id(&second), Some(2));
+
+        assert!(flow.publish(publish(QosLevel::AtLeast)).is_none());
+        // QoS 0 messages are not limited
+        assert!(flow.publish(publish(QosLevel::AtMost)).is_some());
+        assert_eq!(flow.pending(), 1);
+
+        let ready = flow.acknowledge(1);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(packet_id(&ready[0]), Some(3));
+        assert_eq!(flow.pending(), 0);
+
+        flow.acknowledge(2);
+        assert!(!flow.is_idle());
+        flow.acknowledge(3);
+        assert!(flow.is_idle());
+    }
+
+    #[test]
+    fn test_pending_order() {
+        let mut flow = FlowControl::new(10);
+        flow.set_send_quota(1);
+
+        let first = flow.publish(publish_payload(b"1")).expect("Packet");
+        assert!(flow.publish(publish_payload(b"2")).is_none());
+
+        // A free slot must not let a new message overtake the held ones
+        flow.set_send_quota(2);
+        assert!(flow.publish(publish_payload(b"3")).is_none());
+
+        let ready = flow.acknowledge(packet_id(&first).unwrap());
+        let order: Vec<&[u8]> = ready.iter().map(payload).collect();
+        assert_eq!(order, vec![b"2", b"3"]);
+    }
+
+    #[test]
+    fn test_receive_maximum() {
+        let mut flow = FlowControl::new(1);
+
+        assert_eq!(flow.receive(1, true), Received::New);
+        assert_eq!(flow.receive(2, false), Received::OverLimit);
+
+        flow.release(1);
+        assert_eq!(flow.receive(2, false), Received::New);
+        assert_eq!(flow.receive(3, true), Received::New);
+    }
+
+    #[test]
+    fn test_receive_duplicate() {
+        let mut flow = FlowControl::new(10);
+
+        assert_eq!(flow.receive(1, true), Received::New);
+        // sent again before the PUBREL
+        assert_eq!(flow.receive(1, true), Received::Duplicate);
+        // a QoS 1 message is never held, so it is always forwarded
+        assert_eq!(flow.receive(2, false), Received::New);
+        assert_eq!(flow.receive(2, false), Received::New);
+
+        // the packet identifier can be used for a new message after the PUBREL
+        flow.release(1);
+        assert_eq!(flow.receive(1, true), Received::New);
+    }
+
+    #[test]
+    fn test_inflight_counts() {
+        let mut flow = FlowControl::new(10);
+        flow.set_send_quota(1);
+        let inflight = flow.inflight();
+
+        let first = flow.publish(publish(QosLevel::AtLeast)).expect("Packet");
+        flow.publish(publish(QosLevel::AtLeast));
+        flow.receive(1, true);
+        assert_eq!(inflight.outgoing(), 1);
+        assert_eq!(inflight.pending(), 1);
+        assert_eq!(inflight.incoming(), 1);
+
+        flow.acknowledge(packet_id(&first).unwrap());
+        flow.release(1);
+        assert_eq!(inflight.outgoing(), 1);
+        assert_eq!(inflight.pending(), 0);
+        assert_eq!(inflight.incoming(), 0);
+    }
+}
@@ -1,251 +1,1898 @@
-use std::time::Duration;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use log::{debug, error};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncWrite},
     select,
-    sync::mpsc::{channel, Sender},
+    sync::mpsc::{channel, Receiver, Sender},
     time::Instant,
 };
-use tokio_util::sync::CancellationToken;
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+};
+use tracing::{debug, error, field, instrument, warn, Span};
 
 use crate::{
+    client_id::{self, ClientId, ClientIdPolicy},
+    config::Config,
     core::{
         broker_info,
-        enums::{ClientEvent, Command, ProtocalVersion},
+        enums::{ClientEvent, Command, Connection, ConnectionId, LastSeen, Login, ProtocalVersion},
     },
+    direct::Routes,
     error::MqttError,
+    events::DisconnectReason,
+    flow_control::{FlowControl, Received},
+    mount_point::{self, MountPoints},
     packets::{
-        enums::{ConnectReturnCode, QosLevel, SubackReturnCode},
-        Packet, VariableHeader,
+        codec::MqttCodec,
+        enums::{
+            ConnectReturnCode, DisconnectReasonCode, QosLevel, SubackReturnCode, UnsubackReasonCode,
+        },
+        Packet, PublishProperties, ServerCapabilities, VariableHeader,
     },
+    quota::Quotas,
+    rate_limit::{RateLimiter, Throttle},
+    read_timeout::ReadTimeout,
+    topic_policy::TopicPolicy,
+    write_timeout::WriteTimeout,
 };
 
+/// Most queued messages written to a client before the writer is flushed
+const MAX_WRITE_BATCH: usize = 64;
+
+/// Per connection settings taken from the broker [`Config`]
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    /// Receive Maximum advertised to v5 clients
+    pub receive_maximum: u16,
+    /// Highest QoS clients can publish and subscribe with
+    pub max_qos: QosLevel,
+    /// Clients can publish retained messages
+    pub retain_available: bool,
+    /// Clients can subscribe to topic filters with wildcards
+    pub wildcard_subscription_available: bool,
+    /// Clients can make shared subscriptions
+    pub shared_subscription_available: bool,
+    /// Size of the client's outgoing message queue
+    pub max_queued_messages: usize,
+    /// Client ids the server accepts
+    pub client_id: ClientIdPolicy,
+    /// Use the identity of the TLS client certificate as the User Name
+    pub use_identity_as_username: bool,
+    /// Accept MQTT 3.1 clients
+    pub allow_mqtt31: bool,
+    /// Disconnect clients that publish invalid UTF-8 with the Payload Format Indicator set
+    pub strict_payload_format: bool,
+    /// How long to wait for acknowledgements before closing the connection on shutdown
+    pub shutdown_timeout: Duration,
+    /// How long a write can wait for the client before the connection is dropped
+    pub write_timeout: Option<Duration>,
+    /// How long the client can take to send the rest of a packet before the connection is dropped
+    pub packet_timeout: Option<Duration>,
+    /// Largest packet the client can send, sent to v5 clients as the Maximum Packet Size
+    pub max_packet_size: Option<usize>,
+    /// Server Reference sent to v5 clients on shutdown
+    pub server_reference: Option<String>,
+    /// Send QoS 0 messages straight to the single subscriber of their topic
+    pub direct_delivery: bool,
+    /// Limits on the size of published payloads
+    pub quotas: Arc<Quotas>,
+    /// Topics anonymous clients are confined to, checked for their will
+    pub topic_policy: Arc<TopicPolicy>,
+    /// Topic prefix of each client
+    pub mount_points: Arc<MountPoints>,
+}
+
+impl From<&Config> for ClientSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            receive_maximum: config.max_inflight_messages,
+            max_qos: config.max_qos,
+            retain_available: config.retain_available,
+            wildcard_subscription_available: config.wildcard_subscription_available,
+            shared_subscription_available: config.shared_subscription_available,
+            max_queued_messages: config.max_queued_messages,
+            client_id: ClientIdPolicy::from(config),
+            use_identity_as_username: config
+                .tls
+                .as_ref()
+                .is_some_and(|tls| tls.use_identity_as_username),
+            allow_mqtt31: config.allow_mqtt31,
+            strict_payload_format: config.strict_payload_format,
+            shutdown_timeout: config.shutdown_timeout,
+            write_timeout: config.write_timeout,
+            packet_timeout: config.packet_timeout,
+            max_packet_size: config.max_packet_size,
+            server_reference: config.server_reference.clone(),
+            direct_delivery: config.direct_delivery,
+            quotas: Arc::new(Quotas::from(config)),
+            topic_policy: Arc::new(TopicPolicy::from(config)),
+            mount_points: Arc::new(MountPoints::from(config)),
+        }
+    }
+}
+
+impl ClientSettings {
+    /// Capabilities sent in the CONNACK, only the features that are restricted are sent
+    fn capabilities(&self) -> ServerCapabilities {
+        ServerCapabilities {
+            maximum_qos: (self.max_qos < QosLevel::Exactly).then_some(self.max_qos),
+            retain_available: (!self.retain_available).then_some(false),
+            wildcard_subscription_available: (!self.wildcard_subscription_available)
+                .then_some(false),
+            shared_subscription_available: (!self.shared_subscription_available).then_some(false),
+            maximum_packet_size: self
+                .max_packet_size
+                .map(|size| u32::try_from(size).unwrap_or(u32::MAX)),
+        }
+    }
+
+    /// Reason code for a topic filter that needs a disabled feature or is over the topic limits
+    fn unsupported_filter(&self, filter: &str) -> Option<SubackReturnCode> {
+        if !self.shared_subscription_available && filter.starts_with("$share/") {
+            Some(SubackReturnCode::SharedSubscriptionsNotSupported)
+        } else if !self.wildcard_subscription_available && filter.contains(['+', '#']) {
+            Some(SubackReturnCode::WildcardSubscriptionsNotSupported)
+        } else if !self.quotas.allows_topic(filter) {
+            Some(SubackReturnCode::TopicFilterInvalid)
+        } else {
+            None
+        }
+    }
+}
+
+/// Remote end of a client connection
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Identity of the TLS client certificate, see [`crate::tls::peer_identity`]
+    pub certificate_identity: Option<String>,
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            certificate_identity: None,
+        }
+    }
+}
+
+/// Where a connection is in the CONNECT handshake
+///
+/// [(MQTT 5) 3.1 CONNECT](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901033)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    /// Only a CONNECT packet is accepted
+    AwaitingConnect,
+    /// The CONNECT packet was accepted, any other CONNECT packet is a Protocol Error
+    Connected,
+}
+
+/// Handle a single client connection, logged inside a `client` span with the address and client id.
+#[instrument(name = "client", skip_all, fields(addr = %peer.addr, client_id = field::Empty))]
 pub async fn client_handler<R, W>(
     read_stream: R,
-    mut writer: W,
+    write_stream: W,
+    peer: Peer,
     message_bridge: Sender<Command>,
+    limiter: Arc<RateLimiter>,
+    settings: ClientSettings,
     cancellation: CancellationToken,
 ) -> Result<(), MqttError>
 where
     W: AsyncWrite + Unpin,
     R: AsyncRead + Unpin,
 {
-    broker_info::client_inc();
+    let connected = ConnectedClient::new();
 
+    let addr = peer.addr;
     let mut keepalive_duration: u64 = 60;
-    let mut has_connected = false;
+    let mut state = ConnectionState::AwaitingConnect;
     let mut protocol = ProtocalVersion::Unknown;
     let mut cid = None;
     let keepalive_timer = tokio::time::sleep(Duration::from_secs(60));
-    let mut reader = tokio::io::BufReader::new(read_stream);
-    let (tx, mut rx) = channel::<ClientEvent>(100);
+    // A client can't keep the connection, or the memory of a packet, by sending a packet slowly or one larger than the limit
+    let mut reader = FramedRead::new(
+        ReadTimeout::new(read_stream, settings.packet_timeout),
+        MqttCodec::awaiting_connect().with_max_packet_size(settings.max_packet_size),
+    );
+    // A client that stops reading is dropped like a lost connection, so its will is published
+    let mut writer = FramedWrite::new(
+        WriteTimeout::new(write_stream, settings.write_timeout),
+        MqttCodec::awaiting_connect(),
+    );
+    let (tx, mut rx) = channel::<ClientEvent>(settings.max_queued_messages);
+    let disconnect = cancellation.child_token();
+    let connection_id = ConnectionId::next();
+    let mut publish_limiter = limiter.publish_limiter();
+    let mut flow = FlowControl::new(settings.receive_maximum);
+    let mut routes = Routes::default();
+    let last_seen = Arc::new(LastSeen::default());
+    let mut will: Option<Will> = None;
+    // Published in the disconnected event of the client
+    let mut close_reason = DisconnectReason::ConnectionLost;
+    // Prepended to the topics and filters of the client once it has connected
+    let mut mount: Option<Arc<str>> = None;
+    let mounted = |mount: &Option<Arc<str>>, topic: String| match mount {
+        Some(mount) => mount_point::mount(mount, &topic),
+        None => topic,
+    };
+
+    // Nothing is read from a client publishing over its rate until its next token is due,
+    // the messages for it are still sent
+    let read_pause = tokio::time::sleep(Duration::ZERO);
+    let mut reading_paused = false;
 
     tokio::pin!(keepalive_timer);
+    tokio::pin!(read_pause);
 
-    'ctrl: loop {
-        select! {
-            () = cancellation.cancelled() => {
-                break 'ctrl;
-            }
-            () = &mut keepalive_timer => {
-                break 'ctrl;
-            }
-            buffer = reader.fill_buf() => {
-                let packet = match buffer {
-                    Ok(bytes) => {
-                        let len = bytes.len();
-                        if len == 0 {
-                            continue;
+    // Every way out of the connection, including errors, ends up after this block so the will can be published
+    let result = async {
+        'ctrl: loop {
+            select! {
+                () = disconnect.cancelled() => {
+                    // Either the server is stopping or the client was dropped for not keeping up with its messages
+                    if cancellation.is_cancelled() {
+                        if tokio::time::timeout(settings.shutdown_timeout, drain(&mut reader, &mut writer, &mut rx, &mut flow, protocol)).await.is_err() {
+                            debug!("Client {:?} has {} messages pending on shutdown", cid, flow.pending());
                         }
-                        let (packet, packet_size) = Packet::unpack(bytes, protocol)?;
-                        broker_info::sent_data(packet_size);
-
-                        reader.consume(packet_size);
-                        packet
+                        close_reason = DisconnectReason::Server(DisconnectReasonCode::ServerShuttingDown);
+                        if protocol == ProtocalVersion::Five {
+                            writer.send(Packet::make_disconnect(DisconnectReasonCode::ServerShuttingDown, None, settings.server_reference.clone())).await?;
+                        }
+                    } else {
+                        close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::QuotaExceeded).await?;
                     }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionReset || e.kind() == std::io::ErrorKind::ConnectionReset => {
-                        debug!("Connection lost");
+                    break 'ctrl;
+                }
+                () = &mut keepalive_timer => {
+                    close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::KeepAliveTimeout).await?;
+                    break 'ctrl;
+                }
+                () = &mut read_pause, if reading_paused => {
+                    reading_paused = false;
+                }
+                frame = reader.next(), if !reading_paused => {
+                    let received = Instant::now();
+                    let packet = match frame {
+                        Some(Ok(packet)) => {
+                            last_seen.touch();
+                            if let Ok(packet_type) = packet.fixed.get_packet_type() {
+                                debug!(?packet_type, "Received packet");
+                            }
+                            packet
+                        }
+                        Some(Err(MqttError::Io(ref e))) if e.kind() == std::io::ErrorKind::ConnectionReset => {
+                            debug!("Connection lost");
+                            break 'ctrl;
+                        }
+                        Some(Err(MqttError::Io(ref e))) if e.kind() == std::io::ErrorKind::TimedOut => {
+                            warn!("Connection from {} dropped: packet not completed in time", addr);
+                            close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::QuotaExceeded).await?;
+                            break 'ctrl;
+                        }
+                        Some(Err(MqttError::Io(err))) => {
+                            error!("{}",err);
+                            return Err(MqttError::Io(err));
+                        }
+                        Some(Err(MqttError::UnacceptableProtocolLevel)) if state == ConnectionState::AwaitingConnect => {
+                            // The protocol level is not one the server knows, so the v3.1.1 CONNACK layout is used
+                            debug!("Connection from {} refused: unsupported protocol version", addr);
+                            writer.send(Packet::make_connack_refused(ConnectReturnCode::UnsupportedProtocolVersion, None, ProtocalVersion::Four)).await?;
+                            return Err(MqttError::UnacceptableProtocolLevel);
+                        }
+                        Some(Err(MqttError::ProtocolNotNegotiated)) => {
+                            // The first packet sent from the Client to the Server MUST be a CONNECT packet,
+                            // the protocol version is not known yet so the connection is just closed
+                            debug!("Received a packet before CONNECT");
+                            break 'ctrl;
+                        }
+                        Some(Err(err)) => {
+                            close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::from(&err)).await?;
+                            return Err(err);
+                        }
+                        None => {
+                            debug!("Connection closed");
+                            break 'ctrl;
+                        }
+                    };
+
+                    // The codec only decodes a CONNECT until the protocol version is known
+                    let is_connect = matches!(packet.variable, VariableHeader::Connect { .. });
+                    if state == ConnectionState::Connected && is_connect {
+                        // The Server MUST process a second CONNECT packet sent from a Client as a Protocol Error and close the Network Connection
+                        debug!("Received a second CONNECT packet");
+                        close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::ProtocolError).await?;
                         break 'ctrl;
                     }
-                    Err(err) => {
-                        error!("{}",err);
-                        return Err(MqttError::Io(err));
-                    }
-                };
 
-                match packet.variable {
-                        VariableHeader::Connect { flags, keepalive, client_id, protocol_version, .. } => {
-                            if has_connected {
-                                debug!("Seen connect packet two times!");
-                                //  Client can only send the CONNECT Packet once over a Network Connection.
-                                // The Server MUST process a second CONNECT Packet sent from a Client as a protocol violation and disconnect the Client
-                                let resp = Packet::make_connack(ConnectReturnCode::V4UnacceptableProtocal,false);
+                    match packet.variable {
+                            VariableHeader::Connect { flags, keepalive, client_id, username, password, will_topic, will_message, will_delay_interval, protocol_version, bridge, receive_maximum: client_receive_maximum, .. } => {
+                                // MQTT 3.1 clients are handled as v3.1.1 clients after the CONNECT
+                                let legacy = protocol_version == ProtocalVersion::Three;
+                                protocol = if legacy { ProtocalVersion::Four } else { protocol_version };
 
-                                let len = writer.write(&resp).await?;
-                                debug!("Wrote {} bytes", len);
+                                if legacy && !settings.allow_mqtt31 {
+                                    debug!("Connection from {} refused: MQTT 3.1 is disabled", addr);
+                                    let resp = Packet::make_connack_refused(ConnectReturnCode::V4UnacceptableProtocal, None, protocol);
+                                    writer.send(resp).await?;
+                                    break 'ctrl;
+                                }
 
-                                break 'ctrl;
-                            }
+                                // MQTT 3.1 client ids must be 1 to 23 characters
+                                if legacy && (client_id.is_empty() || client_id.len() > 23) {
+                                    debug!("Connection from {} refused: invalid MQTT 3.1 client id", addr);
+                                    let resp = Packet::make_connack_refused(ConnectReturnCode::V4IdentifierRejected, None, protocol);
+                                    writer.send(resp).await?;
+                                    break 'ctrl;
+                                }
 
-                            protocol = protocol_version;
-
-                            let (r_tx, r_rx) = tokio::sync::oneshot::channel::<Result<(), MqttError>>();
-                            cid = Some(client_id.clone());
-
-                            if message_bridge
-                            .send(Command::RegisterClient {
-                                id: client_id,
-                                message_channel: tx.clone(),
-                                protocol,
-                                clean_session: flags.clean_session(),
-                                callback: r_tx,
-                            }).await.is_err()
-                          {
-                                error!("Receiver dropped. Closing");
-                                break 'ctrl;
-                          }
-
-                          r_rx.await.map_err(|_| MqttError::QueuePoisonError)??;
-                          has_connected = true;
-                          keepalive_duration = (keepalive as u64) + 4;
-                          keepalive_timer.as_mut().reset(Instant::now() + Duration::from_secs(keepalive_duration));
-
-                          let resp = Packet::make_connack(ConnectReturnCode::Accepted,false);
-
-                          let len = writer.write(&resp).await?;
-                          debug!("Wrote {} bytes", len);
-                        },
-                        VariableHeader::Subscribe { packet_id, tuples,.. } => {
-                            let id = cid.as_ref().ok_or_else(|| MqttError::FailedToGetCId)?;
-                            let (r_tx, r_rx) =
-                            tokio::sync::oneshot::channel::<Result<Vec<SubackReturnCode>, MqttError>>();
-
-                            if message_bridge
-                            .send(Command::Subscribe {
-                                client: id.clone(),
-                                topics: tuples,
-                                callback: r_tx,
-                            })
-                            .await
-                            .is_err()
-                            {
-                                error!("Receiver dropped!");
-                                break 'ctrl;
-                            }
-                            let codes = r_rx.await.map_err(|_| MqttError::QueuePoisonError)??;
+                                // v5 clients are told the server does not support the Will QoS, v3.1.1 wills are downgraded
+                                if flags.will() && protocol == ProtocalVersion::Five && flags.will_qos()? > settings.max_qos {
+                                    debug!("Connection from {} refused: Will QoS is above the maximum", addr);
+                                    let resp = Packet::make_connack_refused(ConnectReturnCode::QoSNotSupported, Some(format!("Maximum QoS is {}", settings.max_qos as u8)), protocol);
+                                    writer.send(resp).await?;
+                                    break 'ctrl;
+                                }
+
+                                // Same for the Will Retain flag when retained messages are disabled
+                                if flags.will() && protocol == ProtocalVersion::Five && flags.will_retain() && !settings.retain_available {
+                                    debug!("Connection from {} refused: retained messages are disabled", addr);
+                                    let resp = Packet::make_connack_refused(ConnectReturnCode::RetainNotSupported, None, protocol);
+                                    writer.send(resp).await?;
+                                    break 'ctrl;
+                                }
+
+                                if let Err(limit) = limiter.check_connect(addr.ip()) {
+                                    debug!("Connection from {} refused: {:?}", addr, limit);
+                                    let resp = Packet::make_connack_refused(limit.return_code(protocol), None, protocol);
+                                    writer.send(resp).await?;
+                                    break 'ctrl;
+                                }
+
+                                let client_id = match settings.client_id.resolve(client_id, flags.clean_session(), protocol) {
+                                    Ok(client_id) => client_id,
+                                    Err(err) => {
+                                        debug!("Connection from {} refused: {}", addr, err);
+                                        let resp = Packet::make_connack_refused(client_id::rejected_code(protocol), None, protocol);
+                                        writer.send(resp).await?;
+                                        break 'ctrl;
+                                    }
+                                };
+
+                                // With use_identity_as_username the client certificate replaces the User Name from the packet
+                                let username = if settings.use_identity_as_username {
+                                    peer.certificate_identity.clone()
+                                } else {
+                                    username
+                                };
+                                let has_username = username.is_some();
+                                publish_limiter.set_username(username.clone());
+                                mount = settings.mount_points.for_user(username.as_deref());
+                                let will_topic = will_topic.map(|topic| mounted(&mount, topic));
+
+                                if let Some(topic) = will_topic.as_deref().filter(|topic| !settings.quotas.allows_topic(topic)) {
+                                    warn!("Connection from {} refused: will topic is over the topic limits, {} bytes", addr, topic.len());
+                                    let resp = Packet::make_connack_refused(ConnectReturnCode::TopicNameInvalid, None, protocol);
+                                    writer.send(resp).await?;
+                                    break 'ctrl;
+                                }
+
+                                // An anonymous client can't escape its sandbox with a will
+                                if let (false, true, Some(topic)) = (has_username, flags.will(), will_topic.as_deref()) {
+                                    if settings.topic_policy.confines_anonymous() && !settings.topic_policy.can_publish(topic, true) {
+                                        debug!("Connection from {} refused: will topic '{}' is not allowed", addr, topic);
+                                        let resp = Packet::make_connack_refused(ConnectReturnCode::V5NotAuthorized, None, protocol);
+                                        writer.send(resp).await?;
+                                        break 'ctrl;
+                                    }
+                                }
+
+                                let (r_tx, r_rx) = tokio::sync::oneshot::channel::<Result<bool, MqttError>>();
+                                Span::current().record("client_id", client_id.as_str());
+                                cid = Some(client_id.as_str().to_string());
+
+                                if message_bridge
+                                .send(Command::RegisterClient {
+                                    id: client_id.as_str().to_string(),
+                                    message_channel: tx.clone(),
+                                    disconnect: disconnect.clone(),
+                                    protocol,
+                                    clean_session: flags.clean_session(),
+                                    login: Some(Login { username, password }),
+                                    connection: Some(Connection {
+                                        addr,
+                                        keepalive,
+                                        anonymous: !has_username,
+                                        bridge,
+                                        mount_point: mount.clone(),
+                                        inflight: flow.inflight(),
+                                        last_seen: last_seen.clone(),
+                                    }),
+                                    connection_id,
+                                    callback: r_tx,
+                                }).await.is_err()
+                              {
+                                    error!("Receiver dropped. Closing");
+                                    break 'ctrl;
+                              }
 
-                            let resp = Packet::make_suback(packet_id, codes);
+                              let session_present = match r_rx.await.map_err(|_| MqttError::QueuePoisonError)? {
+                                  Ok(session_present) => {
+                                      limiter.auth_succeeded(addr.ip());
+                                      session_present
+                                  }
+                                  Err(MqttError::NotAuthorized) => {
+                                      debug!("Connection from {} refused: not authorized", addr);
+                                      limiter.auth_failed(addr.ip());
+                                      // Clients that sent credentials were refused because of them
+                                      let code = if has_username {
+                                          ConnectReturnCode::BadUserNameOrPassword
+                                      } else {
+                                          ConnectReturnCode::V5NotAuthorized
+                                      };
+                                      writer.send(Packet::make_connack_refused(code, None, protocol)).await?;
+                                      break 'ctrl;
+                                  }
+                                  Err(err) => return Err(err),
+                              };
+                              state = ConnectionState::Connected;
+                              // The client's packets can be traced from here on
+                              reader.decoder_mut().set_client_id(client_id.as_str().to_string());
+                              writer.encoder_mut().set_client_id(client_id.as_str().to_string());
+                              if let (true, Some(topic), Some(payload)) = (flags.will(), will_topic, will_message) {
+                                  will = Some(Will {
+                                      topic,
+                                      payload,
+                                      qos: flags.will_qos()?.min(settings.max_qos),
+                                      retain: flags.will_retain() && settings.retain_available,
+                                      delay: Duration::from_secs(will_delay_interval.unwrap_or(0).into()),
+                                  });
+                              }
+                              keepalive_duration = (keepalive as u64) + 4;
+                              keepalive_timer.as_mut().reset(Instant::now() + Duration::from_secs(keepalive_duration));
 
-                            let len = writer.write(&resp).await?;
-                            debug!("Wrote {} bytes", len);
-                        },
-                        VariableHeader::Unsubscribe { packet_id, tuples, .. } => {
-                            let id = cid.as_ref().ok_or_else(|| MqttError::FailedToGetCId)?;
+                              flow.set_send_quota(client_receive_maximum.unwrap_or(u16::MAX));
+                              if protocol != ProtocalVersion::Five {
+                                  flow.set_receive_maximum(u16::MAX);
+                              }
 
-                            let (r_tx, r_rx) = tokio::sync::oneshot::channel::<Result<(), MqttError>>();
+                              let assigned_client_identifier = match client_id {
+                                  ClientId::Assigned(id) => Some(id),
+                                  ClientId::Client(_) => None,
+                              };
+                              let resp = Packet::make_connack(ConnectReturnCode::Accepted, session_present, Some(settings.receive_maximum), settings.capabilities(), assigned_client_identifier, protocol);
 
-                            if message_bridge
-                                .send(Command::Unsubscribe {
-                                    topics: tuples,
-                                    cid: id.clone(),
+                              writer.send(resp).await?;
+                            },
+                            VariableHeader::Subscribe { packet_id, tuples, subscription_identifier, .. } => {
+                                let id = cid.as_ref().ok_or_else(|| MqttError::FailedToGetCId)?;
+                                let (r_tx, r_rx) =
+                                tokio::sync::oneshot::channel::<Result<Vec<SubackReturnCode>, MqttError>>();
+
+                                // Filters that need a disabled feature are rejected here and the rest are sent to the broker
+                                let tuples = tuples.into_iter().map(|(filter, options)| (mounted(&mount, filter), options)).collect::<Vec<_>>();
+                                let rejected = tuples.iter().map(|(filter, _)| settings.unsupported_filter(filter)).collect::<Vec<_>>();
+                                for ((filter, _), _) in tuples.iter().zip(&rejected).filter(|(_, code)| matches!(code, Some(SubackReturnCode::TopicFilterInvalid))) {
+                                    warn!("Client {:?} from {} subscribed to a filter over the topic limits, {} bytes", cid, addr, filter.len());
+                                }
+                                let topics = tuples
+                                    .into_iter()
+                                    .zip(&rejected)
+                                    .filter(|(_, code)| code.is_none())
+                                    // subscriptions are granted at most the maximum QoS
+                                    .map(|((filter, options), _)| (filter, options.with_max_qos(settings.max_qos)))
+                                    .collect();
+
+                                if message_bridge
+                                .send(Command::Subscribe {
+                                    client: id.clone(),
+                                    topics,
+                                    subscription_identifier,
                                     callback: r_tx,
                                 })
                                 .await
                                 .is_err()
-                            {
-                                error!("Receiver dropped. Closing");
-                                break 'ctrl;
-                            }
+                                {
+                                    error!("Receiver dropped!");
+                                    break 'ctrl;
+                                }
+                                let mut granted = match r_rx.await.map_err(|_| MqttError::QueuePoisonError)? {
+                                    Ok(granted) => granted.into_iter(),
+                                    Err(err @ (MqttError::ProtocolViolation | MqttError::MalformedPacket(_))) => {
+                                        debug!("Client {:?} from {} sent an invalid SUBSCRIBE", cid, addr);
+                                        close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::from(&err)).await?;
+                                        break 'ctrl;
+                                    }
+                                    Err(err) => return Err(err),
+                                };
+                                let codes = rejected
+                                    .into_iter()
+                                    .map(|code| code.or_else(|| granted.next()).unwrap_or(SubackReturnCode::Failure))
+                                    .collect();
+
+                                let resp = Packet::make_suback(packet_id, codes, None, None, protocol);
+
+                                writer.send(resp).await?;
+                            },
+                            VariableHeader::Unsubscribe { packet_id, tuples, .. } => {
+                                let id = cid.as_ref().ok_or_else(|| MqttError::FailedToGetCId)?;
+
+                                let (r_tx, r_rx) = tokio::sync::oneshot::channel::<Result<Vec<UnsubackReasonCode>, MqttError>>();
+
+                                if message_bridge
+                                    .send(Command::Unsubscribe {
+                                        topics: tuples.into_iter().map(|filter| mounted(&mount, filter)).collect(),
+                                        cid: id.clone(),
+                                        callback: r_tx,
+                                    })
+                                    .await
+                                    .is_err()
+                                {
+                                    error!("Receiver dropped. Closing");
+                                    break 'ctrl;
+                                }
 
-                            r_rx.await.map_err(|_| MqttError::QueuePoisonError)??;
+                                let codes = r_rx.await.map_err(|_| MqttError::QueuePoisonError)??;
 
-                            let resp = Packet::make_unsuback(packet_id);
-                            let len = writer.write(&resp).await?;
-                            debug!("Wrote {} bytes", len);
-                        },
-                        VariableHeader::Publish { topic, packet_id, payload, .. } => {
-                            message_bridge
-                            .send(Command::Publish {
-                                topic,
-                                payload,
-                            })
-                            .await
-                            .map_err(MqttError::ChannelError)?;
+                                let resp = Packet::make_unsuback(packet_id, codes, protocol);
+                                writer.send(resp).await?;
+                            },
+                            VariableHeader::Publish { topic, packet_id, payload, payload_format_indicator, content_type, user_property, .. } => {
+                                let topic = mounted(&mount, topic);
+                                if !settings.quotas.allows_topic(&topic) {
+                                    warn!("Client {:?} from {} published to a topic over the topic limits, {} bytes", cid, addr, topic.len());
+                                    close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::TopicNameInvalid).await?;
+                                    break 'ctrl;
+                                }
+
+                                if !settings.quotas.allows_payload(&topic, payload.len()) {
+                                    debug!("Client {:?} exceeded the payload size for '{}'", cid, topic);
+                                    close_reason = send_disconnect(&mut writer, protocol, settings.quotas.payload_limit_reason.into()).await?;
+                                    break 'ctrl;
+                                }
 
-                            let data = match packet.fixed.get_qos()? {
-                                QosLevel::AtMost => None,
-                                QosLevel::AtLeast => {
-                                    let id = packet_id.ok_or_else(|| MqttError::ProtocolViolation)?;
+                                let properties = PublishProperties {
+                                    payload_format_indicator,
+                                    content_type,
+                                    user_properties: user_property.unwrap_or_default(),
+                                };
+                                if settings.strict_payload_format && !properties.is_valid_payload(&payload) {
+                                    debug!("Client {:?} published a payload that is not UTF-8 to '{}'", cid, topic);
+                                    close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::PayloadFormatInvalid).await?;
+                                    break 'ctrl;
+                                }
 
-                                    Some(Packet::make_puback(id))
+                                if packet.fixed.get_retain() && !settings.retain_available {
+                                    debug!("Client {:?} published a retained message while they are disabled", cid);
+                                    close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::RetainNotSupported).await?;
+                                    break 'ctrl;
                                 }
-                                QosLevel::Exactly => {
-                                    let id = packet_id.ok_or_else(|| MqttError::ProtocolViolation)?;
 
-                                    Some(Packet::make_pubrec(id))
+                                let qos = packet.fixed.get_qos()?;
+                                if qos > settings.max_qos {
+                                    debug!("Client {:?} published with QoS {:?} above the maximum", cid, qos);
+                                    close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::QoSNotSupported).await?;
+                                    break 'ctrl;
+                                }
+                                if let Some(id) = packet_id.filter(|_| qos > QosLevel::AtMost) {
+                                    match flow.receive(id, qos == QosLevel::Exactly) {
+                                        Received::New => {}
+                                        Received::Duplicate => {
+                                            debug!("Client {:?} sent message {} again before the PUBREL", cid, id);
+                                            writer.send(Packet::make_pubrec(id)).await?;
+                                            continue 'ctrl;
+                                        }
+                                        Received::OverLimit => {
+                                            debug!("Client {:?} exceeded the Receive Maximum", cid);
+                                            close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::ReceiveMaximumExceeded).await?;
+                                            break 'ctrl;
+                                        }
+                                    }
+                                }
+                                // Only new messages count towards the publish rate, not resends of a QoS 2 message
+                                match publish_limiter.check(qos) {
+                                    Throttle::Allow => {}
+                                    Throttle::Drop => {
+                                        debug!("Client {:?} exceeded the publish rate, message to '{}' dropped", cid, topic);
+                                        continue 'ctrl;
+                                    }
+                                    Throttle::Delay(delay) => {
+                                        read_pause.as_mut().reset(Instant::now() + delay);
+                                        reading_paused = true;
+                                    }
+                                    Throttle::Exceeded => {
+                                        debug!("Client {:?} kept exceeding the publish rate", cid);
+                                        close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::QuotaExceeded).await?;
+                                        break 'ctrl;
+                                    }
                                 }
-                            };
+                                let direct = settings.direct_delivery && qos == QosLevel::AtMost && !packet.fixed.get_retain();
+                                if direct && routes.send(&topic, &payload, &properties, received.into_std()) {
+                                    continue 'ctrl;
+                                }
+                                // The route is asked for after the message, so it has been delivered once the route is given
+                                let ask = (direct && routes.should_ask(&topic)).then(|| topic.clone());
+                                if settings.direct_delivery && !direct {
+                                    routes.remove(&topic);
+                                }
+
+                                message_bridge
+                                .send(Command::Publish {
+                                    topic,
+                                    payload,
+                                    qos,
+                                    retain: packet.fixed.get_retain(),
+                                    client: cid.clone(),
+                                    properties,
+                                    received: received.into_std(),
+                                })
+                                .await
+                                .map_err(MqttError::ChannelError)?;
 
-                            if let Some(resp) = data {
-                                let len = writer.write(&resp).await?;
-                                debug!("Wrote {} bytes", len);
+                                if let (Some(topic), Some(client)) = (ask, cid.clone()) {
+                                    let (r_tx, r_rx) = tokio::sync::oneshot::channel();
+                                    message_bridge
+                                        .send(Command::DirectRoute { client, topic: topic.clone(), callback: r_tx })
+                                        .await
+                                        .map_err(MqttError::ChannelError)?;
+                                    routes.insert(topic, r_rx.await.map_err(|_| MqttError::QueuePoisonError)?);
+                                }
+
+                                let data = match qos {
+                                    QosLevel::AtMost => None,
+                                    QosLevel::AtLeast => {
+                                        let id = packet_id.ok_or_else(|| MqttError::ProtocolViolation)?;
+
+                                        Some(Packet::make_puback(id))
+                                    }
+                                    QosLevel::Exactly => {
+                                        let id = packet_id.ok_or_else(|| MqttError::ProtocolViolation)?;
+
+                                        Some(Packet::make_pubrec(id))
+                                    }
+                                };
+
+                                if let Some(resp) = data {
+                                    writer.send(resp).await?;
+                                }
                             }
-                        }
-                        VariableHeader::PubRec { packet_id, .. } => {
-                            let resp = Packet::make_pubrel(packet_id);
-                            let len = writer.write(&resp).await?;
-                            debug!("Wrote {} bytes", len);
-                        },
-                        VariableHeader::PubRel { packet_id, .. } => {
-                            let resp = Packet::make_pubcomp(packet_id);
-                            let len = writer.write(&resp).await?;
-                            debug!("Wrote {} bytes", len);
-                        },
-                        VariableHeader::PubComp { packet_id: _, ..} | VariableHeader::PubAck { packet_id: _, .. } => {}
-                        VariableHeader::PingReq => {
-                            keepalive_timer.as_mut().reset(Instant::now() + Duration::from_secs(keepalive_duration));
-                            let resp = Packet::make_ping_resp();
-                            let len = writer.write(&resp).await?;
-                            debug!("Wrote {} bytes", len);
-                        },
-                        VariableHeader::Disconnect { .. } => {
-                            debug!("Disconnect Called");
-                            let id = cid.as_ref().ok_or_else(|| MqttError::FailedToGetCId)?;
-                            if message_bridge
-                            .send(Command::DisconnectClient(id.clone()))
-                            .await
-                            .is_err()
-                            {
-                                error!("Receiver dropped!");
+                            // The client refused the message, so the exchange ends without a PUBREL
+                            VariableHeader::PubRec { packet_id, reason_code, .. } if reason_code.is_failure() => {
+                                debug!("Client {:?} refused message {} with {:?}", cid, packet_id, reason_code);
+                                for ready in flow.acknowledge(packet_id) {
+                                    writer.feed(ready.pack(protocol)).await?;
+                                }
+                                writer.flush().await?;
+                            }
+                            VariableHeader::PubRec { packet_id, .. } => {
+                                let resp = Packet::make_pubrel(packet_id);
+                                writer.send(resp).await?;
+                            },
+                            VariableHeader::PubRel { packet_id, .. } => {
+                                flow.release(packet_id);
+                                let resp = Packet::make_pubcomp(packet_id);
+                                writer.send(resp).await?;
+                            },
+                            VariableHeader::PubComp { packet_id, ..} | VariableHeader::PubAck { packet_id, .. } => {
+                                for ready in flow.acknowledge(packet_id) {
+                                    writer.feed(ready.pack(protocol)).await?;
+                                }
+                                writer.flush().await?;
+                            }
+                            VariableHeader::PingReq => {
+                                keepalive_timer.as_mut().reset(Instant::now() + Duration::from_secs(keepalive_duration));
+                                let resp = Packet::make_ping_resp();
+                                writer.send(resp).await?;
+                            },
+                            VariableHeader::Disconnect { reason_code, .. } => {
+                                debug!("Disconnect Called with {:?}", reason_code);
+                                close_reason = DisconnectReason::Client(reason_code);
+                                // The will is discarded when the client disconnects normally, unless it asked for it to be sent
+                                if reason_code != DisconnectReasonCode::DisconnectWithWillMessage {
+                                    will = None;
+                                }
+                                let id = cid.as_ref().ok_or_else(|| MqttError::FailedToGetCId)?;
+                                if message_bridge
+                                .send(Command::DisconnectClient { id: id.clone(), connection_id })
+                                .await
+                                .is_err()
+                                {
+                                    error!("Receiver dropped!");
+                                    break 'ctrl;
+                                }
+                                break 'ctrl;
+                            },
+                            _ => {
+                                error!("Invaild packet");
+                                close_reason = send_disconnect(&mut writer, protocol, DisconnectReasonCode::ProtocolError).await?;
                                 break 'ctrl;
                             }
-                            break 'ctrl;
-                        },
-                        _ => {
-                            error!("Invaild packet");
+                        }
+                }
+                event = rx.recv() => {
+                    if let Some(ev) = event {
+                        if let Some(reason_code) = write_events(&mut writer, &mut flow, protocol, &mut rx, ev).await? {
+                            // The new connection resumes the session, so a delayed will is never published
+                            if reason_code == DisconnectReasonCode::SessionTakenOver {
+                                will = will.filter(|will| will.delay.is_zero());
+                            }
+                            close_reason = send_disconnect(&mut writer, protocol, reason_code).await?;
                             break 'ctrl;
                         }
                     }
+
+                }
             }
-            event = rx.recv() => {
-                if let Some(ev) = event {
-                    match ev {
-                        ClientEvent::Message(msg) => {
-                            let len = writer.write(&msg).await?;
-                            debug!("Wrote {} bytes",len);
-                        },
-                        ClientEvent::Disconnect => break 'ctrl,
-                    }
+        }
+
+        Ok::<(), MqttError>(())
+    }
+    .await;
+
+    drop(connected);
+
+    if let (ConnectionState::Connected, Some(client)) = (state, &cid) {
+        if message_bridge
+            .send(Command::ConnectionClosed {
+                id: client.clone(),
+                connection_id,
+                reason: close_reason,
+            })
+            .await
+            .is_err()
+        {
+            debug!("Receiver dropped, disconnected event not published");
+        }
+    }
+
+    if let (Some(will), Some(client)) = (will, cid) {
+        publish_will(will, client, &message_bridge, &mut rx, &cancellation).await;
+    }
+
+    debug!("Client: disconnect");
+
+    result
+}
+
+/// Counts the client as connected until it is dropped, also when the handler panics
+struct ConnectedClient;
+
+impl ConnectedClient {
+    fn new() -> Self {
+        broker_info::client_inc();
+        Self
+    }
+}
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        broker_info::client_dec();
+    }
+}
+
+/// ### Will Message
+/// Published for a client when its connection closes without a normal DISCONNECT.
+///
+/// [(MQTT 5) 3.1.2.5 Will Flag](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901040)
+#[derive(Debug)]
+struct Will {
+    topic: String,
+    payload: Bytes,
+    qos: QosLevel,
+    retain: bool,
+    /// Will Delay Interval, zero for v3.1.1 clients
+    delay: Duration,
+}
+
+/// Publish a will once its Will Delay Interval has passed.
+///
+/// The will is dropped if the client connects again before then and resumes the session,
+/// when the server is shutting down it is published straight away.
+///
+/// [(MQTT 5) 3.1.3.2.2 Will Delay Interval](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901062)
+async fn publish_will(
+    will: Will,
+    client: String,
+    message_bridge: &Sender<Command>,
+    rx: &mut Receiver<ClientEvent>,
+    cancellation: &CancellationToken,
+) {
+    let delay = tokio::time::sleep(will.delay);
+    tokio::pin!(delay);
+
+    loop {
+        select! {
+            () = &mut delay => break,
+            () = cancellation.cancelled() => break,
+            event = rx.recv() => match event {
+                Some(ClientEvent::Disconnect(DisconnectReasonCode::SessionTakenOver)) => {
+                    debug!("Session resumed, will not published");
+                    return;
                 }
+                // messages for the session are dropped until the client reconnects
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
 
+    if message_bridge
+        .send(Command::Publish {
+            topic: will.topic,
+            payload: will.payload,
+            qos: will.qos,
+            retain: will.retain,
+            client: Some(client),
+            properties: PublishProperties::default(),
+            received: std::time::Instant::now(),
+        })
+        .await
+        .is_err()
+    {
+        error!("Receiver dropped, will not published");
+    }
+}
+
+/// Write an event and the others already queued for the client with a single flush,
+/// so a busy subscriber gets many packets per write instead of a write for each.
+///
+/// At most [`MAX_WRITE_BATCH`] events are written so reading from the client is not held up,
+/// and the writer is also flushed once its buffer is full.
+/// Returns the reason code if the client is to be disconnected.
+async fn write_events<W>(
+    writer: &mut FramedWrite<W, MqttCodec>,
+    flow: &mut FlowControl,
+    protocol: ProtocalVersion,
+    rx: &mut Receiver<ClientEvent>,
+    first: ClientEvent,
+) -> Result<Option<DisconnectReasonCode>, MqttError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut event = Some(first);
+    let mut written = 0;
+    while let Some(ev) = event {
+        match ev {
+            ClientEvent::Message(msg) => write_message(writer, flow, protocol, msg).await?,
+            ClientEvent::Disconnect(reason_code) => {
+                writer.flush().await?;
+                return Ok(Some(reason_code));
             }
         }
+
+        written += 1;
+        event = if written < MAX_WRITE_BATCH {
+            rx.try_recv().ok()
+        } else {
+            None
+        };
     }
 
-    broker_info::client_dec();
+    writer.flush().await?;
+    Ok(None)
+}
 
-    debug!("Client: disconnect");
+/// Buffer a queued PUBLISH to be written on the next flush,
+/// QoS 1 and 2 messages are held back once the client's Receive Maximum is reached.
+async fn write_message<W>(
+    writer: &mut FramedWrite<W, MqttCodec>,
+    flow: &mut FlowControl,
+    protocol: ProtocalVersion,
+    mut msg: Bytes,
+) -> Result<(), MqttError>
+where
+    W: AsyncWrite + Unpin,
+{
+    // QoS 0 messages are not subject to flow control
+    if msg.first().is_none_or(|header| header & 0x06 == 0) {
+        return writer.feed(msg).await;
+    }
+
+    let (packet, _) = Packet::unpack(&mut msg, protocol)?;
+    match flow.publish(packet) {
+        Some(packet) => writer.feed(packet.pack(protocol)).await?,
+        None => debug!(
+            "Receive Maximum reached, {} messages pending",
+            flow.pending()
+        ),
+    }
 
     Ok(())
 }
+
+/// Send the messages still queued for a client on shutdown
+/// and wait for it to acknowledge the QoS 1 and 2 messages in flight.
+async fn drain<R, W>(
+    reader: &mut FramedRead<R, MqttCodec>,
+    writer: &mut FramedWrite<W, MqttCodec>,
+    rx: &mut Receiver<ClientEvent>,
+    flow: &mut FlowControl,
+    protocol: ProtocalVersion,
+) -> Result<(), MqttError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Ok(event) = rx.try_recv() {
+        if let ClientEvent::Message(msg) = event {
+            write_message(writer, flow, protocol, msg).await?;
+        }
+    }
+    writer.flush().await?;
+
+    while !flow.is_idle() {
+        let Some(packet) = reader.next().await else {
+            break;
+        };
+
+        match packet?.variable {
+            VariableHeader::PubComp { packet_id, .. }
+            | VariableHeader::PubAck { packet_id, .. } => {
+                for ready in flow.acknowledge(packet_id) {
+                    writer.send(ready.pack(protocol)).await?;
+                }
+            }
+            VariableHeader::PubRec {
+                packet_id,
+                reason_code,
+                ..
+            } if reason_code.is_failure() => {
+                for ready in flow.acknowledge(packet_id) {
+                    writer.send(ready.pack(protocol)).await?;
+                }
+            }
+            VariableHeader::PubRec { packet_id, .. } => {
+                writer.send(Packet::make_pubrel(packet_id)).await?;
+            }
+            VariableHeader::PubRel { packet_id, .. } => {
+                flow.release(packet_id);
+                writer.send(Packet::make_pubcomp(packet_id)).await?;
+            }
+            // New messages are not accepted while the server is shutting down
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Notify v5 clients why the connection is being closed.
+/// A v3.1.1 Server has no DISCONNECT packet so the connection is just closed.
+///
+/// Returns the reason for the disconnected event of the client.
+async fn send_disconnect<W>(
+    writer: &mut FramedWrite<W, MqttCodec>,
+    protocol: ProtocalVersion,
+    reason_code: DisconnectReasonCode,
+) -> Result<DisconnectReason, MqttError>
+where
+    W: AsyncWrite + Unpin,
+{
+    if protocol == ProtocalVersion::Five {
+        writer
+            .send(Packet::make_disconnect(reason_code, None, None))
+            .await?;
+    }
+
+    Ok(DisconnectReason::Server(reason_code))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::client_id::ClientIdPolicy;
+
+    /// Run a client handler over an in memory stream, accepting every client registration
+    fn settings() -> ClientSettings {
+        ClientSettings {
+            receive_maximum: 20,
+            max_qos: QosLevel::Exactly,
+            retain_available: true,
+            wildcard_subscription_available: true,
+            shared_subscription_available: true,
+            max_queued_messages: 10,
+            client_id: ClientIdPolicy::default(),
+            use_identity_as_username: false,
+            allow_mqtt31: true,
+            strict_payload_format: false,
+            shutdown_timeout: Duration::from_secs(5),
+            write_timeout: None,
+            packet_timeout: None,
+            max_packet_size: None,
+            server_reference: None,
+            direct_delivery: false,
+            quotas: Arc::new(Quotas::default()),
+            topic_policy: Arc::new(TopicPolicy::default()),
+            mount_points: Arc::new(MountPoints::default()),
+        }
+    }
+
+    fn spawn_handler(
+        cancellation: CancellationToken,
+    ) -> (
+        DuplexStream,
+        tokio::task::JoinHandle<Result<(), MqttError>>,
+        tokio::sync::mpsc::Receiver<Command>,
+    ) {
+        spawn_handler_with(cancellation, settings())
+    }
+
+    fn spawn_handler_with(
+        cancellation: CancellationToken,
+        settings: ClientSettings,
+    ) -> (
+        DuplexStream,
+        tokio::task::JoinHandle<Result<(), MqttError>>,
+        tokio::sync::mpsc::Receiver<Command>,
+    ) {
+        spawn_handler_limited(
+            cancellation,
+            settings,
+            Arc::new(RateLimiter::new(None, None, None)),
+        )
+    }
+
+    fn spawn_handler_limited(
+        cancellation: CancellationToken,
+        settings: ClientSettings,
+        limiter: Arc<RateLimiter>,
+    ) -> (
+        DuplexStream,
+        tokio::task::JoinHandle<Result<(), MqttError>>,
+        tokio::sync::mpsc::Receiver<Command>,
+    ) {
+        let (client, server) = duplex(1024);
+        let (read, write) = split(server);
+        let (cmd_tx, mut cmd_rx) = channel(10);
+        let (fwd_tx, fwd_rx) = channel(10);
+
+        tokio::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                match cmd {
+                    Command::RegisterClient { callback, .. } => {
+                        let _ = callback.send(Ok(false));
+                    }
+                    cmd => {
+                        let _ = fwd_tx.send(cmd).await;
+                    }
+                }
+            }
+        });
+
+        let handle = tokio::spawn(client_handler(
+            read,
+            write,
+            Peer::from("127.0.0.1:1883".parse::<SocketAddr>().unwrap()),
+            cmd_tx,
+            limiter,
+            settings,
+            cancellation,
+        ));
+
+        (client, handle, fwd_rx)
+    }
+
+    /// Writer that counts the writes made to it
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        buf: Vec<u8>,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            self.buf.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_events_batches() {
+        let mut writer = FramedWrite::new(
+            CountingWriter::default(),
+            MqttCodec::new(ProtocalVersion::Four),
+        );
+        let mut flow = FlowControl::new(10);
+        let (tx, mut rx) = channel(10);
+
+        let message = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            false,
+            "a/b".into(),
+            None,
+            Bytes::from_static(b"1"),
+            PublishProperties::default(),
+            Vec::new(),
+            ProtocalVersion::Four,
+        );
+        for _ in 0..4 {
+            tx.send(ClientEvent::Message(message.clone()))
+                .await
+                .unwrap();
+        }
+        tx.send(ClientEvent::Disconnect(
+            DisconnectReasonCode::SessionTakenOver,
+        ))
+        .await
+        .unwrap();
+
+        let first = ClientEvent::Message(message.clone());
+        let reason_code = write_events(
+            &mut writer,
+            &mut flow,
+            ProtocalVersion::Four,
+            &mut rx,
+            first,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reason_code, Some(DisconnectReasonCode::SessionTakenOver));
+        assert_eq!(writer.get_ref().writes, 1);
+        assert_eq!(writer.get_ref().buf.len(), message.len() * 5);
+    }
+
+    const V5_CONNECT: &[u8] = &[
+        0x10, 0x0F, // Fixed Header
+        0x00, 0x04, b'M', b'Q', b'T', b'T', // MQTT
+        0x05, // version
+        0x02, // Connect Flags
+        0x00, 0x3C, // Keep Alive
+        0x00, // Properties
+        0x00, 0x02, b'c', b'1', // Client Identifier
+    ];
+
+    #[tokio::test]
+    async fn test_packet_before_connect() {
+        let (mut client, handle, mut commands) = spawn_handler(CancellationToken::new());
+
+        // PUBLISH a/b QoS 0
+        client
+            .write_all(&[0x30, 0x05, 0x00, 0x03, b'a', b'/', b'b'])
+            .await
+            .unwrap();
+
+        handle.await.unwrap().unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_protocol_version() {
+        let (mut client, handle, _commands) = spawn_handler(CancellationToken::new());
+
+        let mut connect = V5_CONNECT.to_vec();
+        connect[8] = 0x06;
+        client.write_all(&connect).await.unwrap();
+
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(MqttError::UnacceptableProtocolLevel)
+        ));
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, vec![0x20, 0x02, 0x00, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn test_second_connect() {
+        let (mut client, handle, _commands) = spawn_handler(CancellationToken::new());
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        let mut connack = [0u8; 2];
+        client.read_exact(&mut connack).await.unwrap();
+        assert_eq!(connack[0], 0x20);
+        let mut rest = vec![0u8; connack[1] as usize];
+        client.read_exact(&mut rest).await.unwrap();
+        assert_eq!(rest[1], u8::from(ConnectReturnCode::Accepted));
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        handle.await.unwrap().unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::ProtocolError as u8);
+    }
+
+    #[tokio::test]
+    async fn test_packet_too_large() {
+        let (mut client, handle, mut commands) = spawn_handler_with(
+            CancellationToken::new(),
+            ClientSettings {
+                max_packet_size: Some(64),
+                ..settings()
+            },
+        );
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        read_connack(&mut client).await;
+
+        // PUBLISH declaring a 256MB Remaining Length
+        client
+            .write_all(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F])
+            .await
+            .unwrap();
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(MqttError::PacketTooLarge(_))
+        ));
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::PacketTooLarge as u8);
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Server(DisconnectReasonCode::PacketTooLarge),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_packet_timeout() {
+        let (mut client, handle, mut commands) = spawn_handler_with(
+            CancellationToken::new(),
+            ClientSettings {
+                packet_timeout: Some(Duration::from_millis(50)),
+                ..settings()
+            },
+        );
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        read_connack(&mut client).await;
+
+        // half of a PUBLISH a/b
+        client.write_all(&[0x30, 0x06, 0x00, 0x03]).await.unwrap();
+        handle.await.unwrap().unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::QuotaExceeded as u8);
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Server(DisconnectReasonCode::QuotaExceeded),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let cancellation = CancellationToken::new();
+        let (mut client, handle, _commands) = spawn_handler(cancellation.clone());
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        let mut connack = [0u8; 2];
+        client.read_exact(&mut connack).await.unwrap();
+        let mut rest = vec![0u8; connack[1] as usize];
+        client.read_exact(&mut rest).await.unwrap();
+
+        cancellation.cancel();
+        handle.await.unwrap().unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::ServerShuttingDown as u8);
+    }
+
+    #[tokio::test]
+    async fn test_max_qos() {
+        let (mut client, handle, mut commands) = spawn_handler_with(
+            CancellationToken::new(),
+            ClientSettings {
+                max_qos: QosLevel::AtMost,
+                ..settings()
+            },
+        );
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        let mut connack = [0u8; 10];
+        client.read_exact(&mut connack).await.unwrap();
+        // Receive Maximum 20 and Maximum QoS 0
+        assert_eq!(
+            connack,
+            [0x20, 0x08, 0x00, 0x00, 0x05, 0x21, 0x00, 0x14, 0x24, 0x00]
+        );
+
+        // QoS 1 PUBLISH
+        client
+            .write_all(&[0x32, 0x06, 0x00, 0x01, b'a', 0x00, 0x01, 0x00])
+            .await
+            .unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::QoSNotSupported as u8);
+
+        handle.await.unwrap().unwrap();
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Server(DisconnectReasonCode::QoSNotSupported),
+        )
+        .await;
+        assert!(commands.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_subscription_features() {
+        let (mut client, handle, mut commands) = spawn_handler_with(
+            CancellationToken::new(),
+            ClientSettings {
+                wildcard_subscription_available: false,
+                shared_subscription_available: false,
+                ..settings()
+            },
+        );
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        read_connack(&mut client).await;
+
+        client
+            .write_all(&[
+                0x82, 0x1A, // Fixed Header
+                0x00, 0x01, // Packet Identifier
+                0x00, // Properties
+                0x00, 0x03, b'a', b'/', b'+', 0x00, // a/+
+                0x00, 0x0A, b'$', b's', b'h', b'a', b'r', b'e', b'/', b'g', b'/', b'a',
+                0x00, // $share/g/a
+                0x00, 0x01, b'a', 0x00, // a
+            ])
+            .await
+            .unwrap();
+
+        // only the filter without a disabled feature reaches the broker
+        match commands.recv().await {
+            Some(Command::Subscribe {
+                topics, callback, ..
+            }) => {
+                assert_eq!(topics.len(), 1);
+                assert_eq!(topics[0].0, "a");
+                callback
+                    .send(Ok(vec![SubackReturnCode::SuccessQosZero]))
+                    .unwrap();
+            }
+            _ => panic!("Expected a subscribe"),
+        }
+
+        let mut suback = [0u8; 8];
+        client.read_exact(&mut suback).await.unwrap();
+        assert_eq!(suback, [0x90, 0x06, 0x00, 0x01, 0x00, 0xA2, 0x9E, 0x00]);
+
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_protocol_error() {
+        let (mut client, handle, mut commands) = spawn_handler(CancellationToken::new());
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        read_connack(&mut client).await;
+
+        // No Local on a shared subscription
+        client
+            .write_all(&[
+                0x82, 0x10, // Fixed Header
+                0x00, 0x01, // Packet Identifier
+                0x00, // Properties
+                0x00, 0x0A, b'$', b's', b'h', b'a', b'r', b'e', b'/', b'g', b'/', b'a',
+                0x04, // $share/g/a
+            ])
+            .await
+            .unwrap();
+
+        match commands.recv().await {
+            Some(Command::Subscribe { callback, .. }) => {
+                callback.send(Err(MqttError::ProtocolViolation)).unwrap();
+            }
+            _ => panic!("Expected a subscribe"),
+        }
+
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::ProtocolError as u8);
+
+        handle.await.unwrap().unwrap();
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Server(DisconnectReasonCode::ProtocolError),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_malformed_options() {
+        let (mut client, handle, mut commands) = spawn_handler(CancellationToken::new());
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        read_connack(&mut client).await;
+
+        // Retain Handling 3
+        client
+            .write_all(&[
+                0x82, 0x07, // Fixed Header
+                0x00, 0x01, // Packet Identifier
+                0x00, // Properties
+                0x00, 0x01, b'a', 0x30, // a
+            ])
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::MalformedPacket as u8);
+
+        assert!(handle.await.unwrap().is_err());
+        // the filter never reaches the broker
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Server(DisconnectReasonCode::MalformedPacket),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_v4_suback_order() {
+        let (mut client, handle, mut commands) = spawn_handler_with(
+            CancellationToken::new(),
+            ClientSettings {
+                wildcard_subscription_available: false,
+                ..settings()
+            },
+        );
+
+        client
+            .write_all(&[
+                0x10, 0x0E, // Fixed Header
+                0x00, 0x04, b'M', b'Q', b'T', b'T', // MQTT
+                0x04, // version
+                0x02, // Connect Flags
+                0x00, 0x3C, // Keep Alive
+                0x00, 0x02, b'c', b'1', // Client Identifier
+            ])
+            .await
+            .unwrap();
+        read_connack(&mut client).await;
+
+        client
+            .write_all(&[
+                0x82, 0x11, // Fixed Header
+                0x00, 0x01, // Packet Identifier
+                0x00, 0x03, b'a', b'/', b'+', 0x00, // a/+
+                0x00, 0x02, b'$', b'x', 0x00, // $x
+                0x00, 0x01, b'b', 0x01, // b
+            ])
+            .await
+            .unwrap();
+
+        match commands.recv().await {
+            Some(Command::Subscribe {
+                topics, callback, ..
+            }) => {
+                assert_eq!(topics.len(), 2);
+                callback
+                    .send(Ok(vec![
+                        SubackReturnCode::NotAuthorized,
+                        SubackReturnCode::SuccessQosOne,
+                    ]))
+                    .unwrap();
+            }
+            _ => panic!("Expected a subscribe"),
+        }
+
+        // v3.1.1 clients get 0x80 for every refused filter, in the order of the filters
+        let mut suback = [0u8; 7];
+        client.read_exact(&mut suback).await.unwrap();
+        assert_eq!(suback, [0x90, 0x05, 0x00, 0x01, 0x80, 0x80, 0x01]);
+
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_topic_limits() {
+        let (mut client, handle, mut commands) = spawn_handler_with(
+            CancellationToken::new(),
+            ClientSettings {
+                quotas: Arc::new(Quotas {
+                    max_topic_levels: Some(2),
+                    ..Quotas::default()
+                }),
+                ..settings()
+            },
+        );
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        read_connack(&mut client).await;
+
+        client
+            .write_all(&[
+                0x82, 0x11, // Fixed Header
+                0x00, 0x01, // Packet Identifier
+                0x00, // Properties
+                0x00, 0x05, b'a', b'/', b'b', b'/', b'c', 0x00, // a/b/c
+                0x00, 0x03, b'a', b'/', b'b', 0x00, // a/b
+            ])
+            .await
+            .unwrap();
+
+        match commands.recv().await {
+            Some(Command::Subscribe {
+                topics, callback, ..
+            }) => {
+                assert_eq!(topics.len(), 1);
+                assert_eq!(topics[0].0, "a/b");
+                callback
+                    .send(Ok(vec![SubackReturnCode::SuccessQosZero]))
+                    .unwrap();
+            }
+            _ => panic!("Expected a subscribe"),
+        }
+
+        let mut suback = [0u8; 7];
+        client.read_exact(&mut suback).await.unwrap();
+        assert_eq!(suback, [0x90, 0x05, 0x00, 0x01, 0x00, 0x8F, 0x00]);
+
+        // QoS 0 PUBLISH to a/b/c
+        client
+            .write_all(&[0x30, 0x08, 0x00, 0x05, b'a', b'/', b'b', b'/', b'c', 0x00])
+            .await
+            .unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::TopicNameInvalid as u8);
+
+        handle.await.unwrap().unwrap();
+    }
+
+    async fn read_connack(client: &mut DuplexStream) {
+        let mut connack = [0u8; 2];
+        client.read_exact(&mut connack).await.unwrap();
+        assert_eq!(connack[0], 0x20);
+        let mut rest = vec![0u8; connack[1] as usize];
+        client.read_exact(&mut rest).await.unwrap();
+    }
+
+    async fn expect_closed(
+        commands: &mut tokio::sync::mpsc::Receiver<Command>,
+        expected: DisconnectReason,
+    ) {
+        match commands.recv().await {
+            Some(Command::ConnectionClosed { id, reason, .. }) => {
+                assert_eq!(id, "c1");
+                assert_eq!(reason, expected);
+            }
+            _ => panic!("Expected the connection to be closed"),
+        }
+    }
+
+    async fn expect_will(commands: &mut tokio::sync::mpsc::Receiver<Command>) {
+        match commands.recv().await {
+            Some(Command::Publish {
+                topic,
+                payload,
+                client,
+                ..
+            }) => {
+                assert_eq!(topic, "w");
+                assert_eq!(payload, Bytes::from_static(b"x"));
+                assert_eq!(client.as_deref(), Some("c1"));
+            }
+            _ => panic!("Expected the will to be published"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_qos2_duplicate_not_forwarded() {
+        let (mut client, handle, mut commands) = spawn_handler(CancellationToken::new());
+
+        client
+            .write_all(&[
+                0x10, 0x0E, // Fixed Header
+                0x00, 0x04, b'M', b'Q', b'T', b'T', // MQTT
+                0x04, // version
+                0x02, // Connect Flags (Clean Session)
+                0x00, 0x3C, // Keep Alive
+                0x00, 0x02, b'c', b'1', // Client Identifier
+            ])
+            .await
+            .unwrap();
+        read_connack(&mut client).await;
+
+        // QoS 2 PUBLISH to a with packet identifier 1, sent again before the PUBREL
+        let publish = [0x34, 0x06, 0x00, 0x01, b'a', 0x00, 0x01, b'x'];
+        let mut pubrec = [0u8; 4];
+        for _ in 0..2 {
+            client.write_all(&publish).await.unwrap();
+            client.read_exact(&mut pubrec).await.unwrap();
+            assert_eq!(pubrec, [0x50, 0x02, 0x00, 0x01]);
+        }
+
+        // After the PUBREL the packet identifier is free for a new message
+        client.write_all(&[0x62, 0x02, 0x00, 0x01]).await.unwrap();
+        let mut pubcomp = [0u8; 4];
+        client.read_exact(&mut pubcomp).await.unwrap();
+        assert_eq!(pubcomp, [0x70, 0x02, 0x00, 0x01]);
+        client.write_all(&publish).await.unwrap();
+        client.read_exact(&mut pubrec).await.unwrap();
+
+        drop(client);
+        handle.await.unwrap().unwrap();
+        let mut published = 0;
+        while let Some(command) = commands.recv().await {
+            if matches!(command, Command::Publish { .. }) {
+                published += 1;
+            }
+        }
+        assert_eq!(published, 2);
+    }
+
+    #[tokio::test]
+    async fn test_qos2_duplicate_not_rate_limited() {
+        let (mut client, handle, mut commands) = spawn_handler_limited(
+            CancellationToken::new(),
+            settings(),
+            Arc::new(RateLimiter::new(None, None, Some(1))),
+        );
+
+        client
+            .write_all(&[
+                0x10, 0x0E, // Fixed Header
+                0x00, 0x04, b'M', b'Q', b'T', b'T', // MQTT
+                0x04, // version
+                0x02, // Connect Flags (Clean Session)
+                0x00, 0x3C, // Keep Alive
+                0x00, 0x02, b'c', b'1', // Client Identifier
+            ])
+            .await
+            .unwrap();
+        read_connack(&mut client).await;
+
+        // QoS 2 PUBLISH to a with packet identifier 1, then resent with DUP set before the PUBREL.
+        // The resends don't take tokens so reading is never paused for them
+        let mut pubrec = [0u8; 4];
+        tokio::time::timeout(Duration::from_millis(500), async {
+            for header in [0x34, 0x3C, 0x3C, 0x3C, 0x3C] {
+                client
+                    .write_all(&[header, 0x06, 0x00, 0x01, b'a', 0x00, 0x01, b'x'])
+                    .await
+                    .unwrap();
+                client.read_exact(&mut pubrec).await.unwrap();
+                assert_eq!(pubrec, [0x50, 0x02, 0x00, 0x01]);
+            }
+        })
+        .await
+        .expect("Resent messages were throttled");
+
+        drop(client);
+        handle.await.unwrap().unwrap();
+        let mut published = 0;
+        while let Some(command) = commands.recv().await {
+            if matches!(command, Command::Publish { .. }) {
+                published += 1;
+            }
+        }
+        assert_eq!(published, 1);
+    }
+
+    #[tokio::test]
+    async fn test_receive_maximum() {
+        let (mut client, handle, mut commands) = spawn_handler_with(
+            CancellationToken::new(),
+            ClientSettings {
+                receive_maximum: 2,
+                ..settings()
+            },
+        );
+
+        client.write_all(V5_CONNECT).await.unwrap();
+        let mut connack = [0u8; 2];
+        client.read_exact(&mut connack).await.unwrap();
+        let mut rest = vec![0u8; connack[1] as usize];
+        client.read_exact(&mut rest).await.unwrap();
+        // Receive Maximum property
+        assert!(rest
+            .windows(3)
+            .any(|property| property == [0x21, 0x00, 0x02]));
+
+        let publish = |header: u8, id: u8| [header, 0x07, 0x00, 0x01, b'a', 0x00, id, 0x00, b'x'];
+        let mut ack = [0u8; 4];
+
+        // QoS 1 messages are acknowledged straight away and don't count
+        for id in 1..=3 {
+            client.write_all(&publish(0x32, id)).await.unwrap();
+            client.read_exact(&mut ack).await.unwrap();
+            assert_eq!(ack, [0x40, 0x02, 0x00, id]);
+        }
+
+        for id in 4..=5 {
+            client.write_all(&publish(0x34, id)).await.unwrap();
+            client.read_exact(&mut ack).await.unwrap();
+            assert_eq!(ack, [0x50, 0x02, 0x00, id]);
+        }
+        // The PUBREL frees a place for the next QoS 2 message
+        client.write_all(&[0x62, 0x02, 0x00, 0x04]).await.unwrap();
+        client.read_exact(&mut ack).await.unwrap();
+        assert_eq!(ack, [0x70, 0x02, 0x00, 0x04]);
+        client.write_all(&publish(0x34, 6)).await.unwrap();
+        client.read_exact(&mut ack).await.unwrap();
+        assert_eq!(ack, [0x50, 0x02, 0x00, 0x06]);
+
+        // 5 and 6 are still waiting for their PUBREL
+        client.write_all(&publish(0x34, 7)).await.unwrap();
+        handle.await.unwrap().unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xE0);
+        assert_eq!(buf[2], DisconnectReasonCode::ReceiveMaximumExceeded as u8);
+
+        for _ in 0..6 {
+            assert!(matches!(
+                commands.recv().await,
+                Some(Command::Publish { .. })
+            ));
+        }
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Server(DisconnectReasonCode::ReceiveMaximumExceeded),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_will_on_connection_lost() {
+        let (mut client, handle, mut commands) = spawn_handler(CancellationToken::new());
+
+        client
+            .write_all(&[
+                0x10, 0x14, // Fixed Header
+                0x00, 0x04, b'M', b'Q', b'T', b'T', // MQTT
+                0x04, // version
+                0x06, // Connect Flags (Will, Clean Session)
+                0x00, 0x3C, // Keep Alive
+                0x00, 0x02, b'c', b'1', // Client Identifier
+                0x00, 0x01, b'w', // Will Topic
+                0x00, 0x01, b'x', // Will Payload
+            ])
+            .await
+            .unwrap();
+        read_connack(&mut client).await;
+
+        drop(client);
+        handle.await.unwrap().unwrap();
+        expect_closed(&mut commands, DisconnectReason::ConnectionLost).await;
+        expect_will(&mut commands).await;
+    }
+
+    #[tokio::test]
+    async fn test_will_discarded_on_disconnect() {
+        let (mut client, handle, mut commands) = spawn_handler(CancellationToken::new());
+
+        client
+            .write_all(&[
+                0x10, 0x14, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x06, 0x00, 0x3C, 0x00, 0x02,
+                b'c', b'1', 0x00, 0x01, b'w', 0x00, 0x01, b'x',
+            ])
+            .await
+            .unwrap();
+        read_connack(&mut client).await;
+
+        client.write_all(&[0xE0, 0x00]).await.unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(matches!(
+            commands.recv().await,
+            Some(Command::DisconnectClient { .. })
+        ));
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Client(DisconnectReasonCode::NormalDisconnection),
+        )
+        .await;
+        assert!(commands.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_with_will_message() {
+        let (mut client, handle, mut commands) = spawn_handler(CancellationToken::new());
+
+        client
+            .write_all(&[
+                0x10, 0x16, // Fixed Header
+                0x00, 0x04, b'M', b'Q', b'T', b'T', // MQTT
+                0x05, // version
+                0x06, // Connect Flags (Will, Clean Start)
+                0x00, 0x3C, // Keep Alive
+                0x00, // Properties
+                0x00, 0x02, b'c', b'1', // Client Identifier
+                0x00, // Will Properties
+                0x00, 0x01, b'w', // Will Topic
+                0x00, 0x01, b'x', // Will Payload
+            ])
+            .await
+            .unwrap();
+        read_connack(&mut client).await;
+
+        // Reason Code 0x04 Disconnect with Will Message
+        client.write_all(&[0xE0, 0x01, 0x04]).await.unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(matches!(
+            commands.recv().await,
+            Some(Command::DisconnectClient { .. })
+        ));
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Client(DisconnectReasonCode::DisconnectWithWillMessage),
+        )
+        .await;
+        expect_will(&mut commands).await;
+    }
+
+    #[tokio::test]
+    async fn test_delayed_will_on_shutdown() {
+        let cancellation = CancellationToken::new();
+        let (mut client, handle, mut commands) = spawn_handler(cancellation.clone());
+
+        client
+            .write_all(&[
+                0x10, 0x1B, // Fixed Header
+                0x00, 0x04, b'M', b'Q', b'T', b'T', // MQTT
+                0x05, // version
+                0x06, // Connect Flags (Will, Clean Start)
+                0x00, 0x3C, // Keep Alive
+                0x00, // Properties
+                0x00, 0x02, b'c', b'1', // Client Identifier
+                0x05, 0x18, 0x00, 0x00, 0x0E,
+                0x10, // Will Properties (Will Delay Interval 3600)
+                0x00, 0x01, b'w', // Will Topic
+                0x00, 0x01, b'x', // Will Payload
+            ])
+            .await
+            .unwrap();
+        read_connack(&mut client).await;
+
+        // the server shutting down does not wait for the Will Delay Interval
+        cancellation.cancel();
+        handle.await.unwrap().unwrap();
+        expect_closed(
+            &mut commands,
+            DisconnectReason::Server(DisconnectReasonCode::ServerShuttingDown),
+        )
+        .await;
+        expect_will(&mut commands).await;
+    }
+}
@@ -0,0 +1,249 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use bytes::Bytes;
+
+use crate::{
+    packets::{enums::QosLevel, PublishProperties},
+    utils::topic_matches,
+};
+
+/// Future returned by a [`BrokerHook`] callback
+pub type HookFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// ### Broker Hook
+/// Callbacks that are run by the broker as clients connect, publish and subscribe.
+///
+/// Every callback has a default no-op implementation so only the needed callbacks have to be implemented.
+/// Hooks are run in the order they were registered and are awaited by the broker,
+/// so long running work should be moved to a separate task.
+///
+/// ```
+/// use bytes::Bytes;
+/// use mqtt_broker::hooks::{BrokerHook, HookFuture};
+///
+/// struct DenyAdmin;
+///
+/// impl BrokerHook for DenyAdmin {
+///     fn on_publish<'a>(&'a self, topic: &'a str, _payload: &'a Bytes) -> HookFuture<'a, bool> {
+///         Box::pin(async move { !topic.starts_with("admin/") })
+///     }
+/// }
+/// ```
+pub trait BrokerHook: Send + Sync {
+    /// A client has connected
+    fn on_connect<'a>(&'a self, _client_id: &'a str) -> HookFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// A client has disconnected
+    fn on_disconnect<'a>(&'a self, _client_id: &'a str) -> HookFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// A message has been published.
+    /// Return `false` to drop the message before it is delivered to any subscriber.
+    fn on_publish<'a>(&'a self, _topic: &'a str, _payload: &'a Bytes) -> HookFuture<'a, bool> {
+        Box::pin(async { true })
+    }
+
+    /// A client has subscribed to a topic filter
+    fn on_subscribe<'a>(
+        &'a self,
+        _client_id: &'a str,
+        _filter: &'a str,
+        _qos: QosLevel,
+    ) -> HookFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// A message has been handed to a subscriber
+    fn on_message_delivered<'a>(
+        &'a self,
+        _client_id: &'a str,
+        _topic: &'a str,
+        _payload: &'a Bytes,
+    ) -> HookFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// The broker is shutting down and every client has disconnected.
+    /// Any state that should outlive the broker can be saved here.
+    fn on_shutdown(&self) -> HookFuture<'_, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// A published message on its way to the subscribers, which a [`MessageInterceptor`] can change
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterceptedMessage {
+    pub(crate) topic: String,
+    pub(crate) client: Option<String>,
+    pub payload: Bytes,
+    pub qos: QosLevel,
+    pub retain: bool,
+    pub properties: PublishProperties,
+}
+
+impl InterceptedMessage {
+    pub fn new(
+        topic: String,
+        payload: Bytes,
+        qos: QosLevel,
+        retain: bool,
+        client: Option<String>,
+        properties: PublishProperties,
+    ) -> Self {
+        Self {
+            topic,
+            client,
+            payload,
+            qos,
+            retain,
+            properties,
+        }
+    }
+
+    /// Topic the message was published to, it can't be changed
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Client id of the publisher, `None` for messages published in process
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+}
+
+/// ### Message Interceptor
+/// Changes or drops published messages before they are retained and delivered,
+/// like removing fields from a JSON payload or refusing payloads that don't match a schema.
+///
+/// An interceptor is registered for a topic filter and only sees the messages published to matching topics.
+/// Interceptors are run before the [`BrokerHook::on_publish`] hooks, by their order and then in the order
+/// they were registered, each one is given the message as changed by the ones before it.
+///
+/// ```
+/// use mqtt_broker::hooks::{HookFuture, InterceptedMessage, MessageInterceptor};
+///
+/// struct DropEmpty;
+///
+/// impl MessageInterceptor for DropEmpty {
+///     fn intercept<'a>(&'a self, message: &'a mut InterceptedMessage) -> HookFuture<'a, bool> {
+///         Box::pin(async move { !message.payload.is_empty() || message.retain })
+///     }
+/// }
+/// ```
+pub trait MessageInterceptor: Send + Sync {
+    /// Change the message in place.
+    /// Return `false` to drop the message, the interceptors after this one are not run.
+    fn intercept<'a>(&'a self, message: &'a mut InterceptedMessage) -> HookFuture<'a, bool>;
+}
+
+#[derive(Clone)]
+struct Scoped {
+    filter: String,
+    order: i32,
+    interceptor: Arc<dyn MessageInterceptor>,
+}
+
+/// The registered [`MessageInterceptor`]s, kept in the order they are run
+#[derive(Clone, Default)]
+pub struct Interceptors(Vec<Scoped>);
+
+impl std::fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|scoped| (&scoped.filter, scoped.order)))
+            .finish()
+    }
+}
+
+impl Interceptors {
+    /// Register an interceptor for the messages published to topics matching `filter`.
+    ///
+    /// Lower orders are run first, interceptors with the same order are run in the order they were added.
+    pub fn add(&mut self, filter: String, order: i32, interceptor: Arc<dyn MessageInterceptor>) {
+        let index = self.0.partition_point(|scoped| scoped.order <= order);
+        self.0.insert(
+            index,
+            Scoped {
+                filter,
+                order,
+                interceptor,
+            },
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run the interceptors matching the topic of the message, returns `false` if one of them dropped it
+    pub async fn run(&self, message: &mut InterceptedMessage) -> bool {
+        for scoped in &self.0 {
+            if !topic_matches(&scoped.filter, &message.topic) {
+                continue;
+            }
+            if !scoped.interceptor.intercept(message).await {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends its name to the payload, drops payloads starting with `drop`
+    struct Append(&'static str);
+
+    impl MessageInterceptor for Append {
+        fn intercept<'a>(&'a self, message: &'a mut InterceptedMessage) -> HookFuture<'a, bool> {
+            Box::pin(async move {
+                if message.payload.starts_with(b"drop") {
+                    return false;
+                }
+                let mut payload = message.payload.to_vec();
+                payload.extend_from_slice(self.0.as_bytes());
+                message.payload = Bytes::from(payload);
+                true
+            })
+        }
+    }
+
+    fn message(topic: &str, payload: &'static [u8]) -> InterceptedMessage {
+        InterceptedMessage::new(
+            topic.into(),
+            Bytes::from_static(payload),
+            QosLevel::AtMost,
+            false,
+            None,
+            PublishProperties::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_interceptors() {
+        let mut interceptors = Interceptors::default();
+        interceptors.add("sensors/#".into(), 0, Arc::new(Append("b")));
+        interceptors.add("#".into(), 10, Arc::new(Append("d")));
+        interceptors.add("sensors/+/temp".into(), -1, Arc::new(Append("a")));
+        interceptors.add("sensors/#".into(), 0, Arc::new(Append("c")));
+
+        // run by order, then in the order they were added
+        let mut temp = message("sensors/1/temp", b"");
+        assert!(interceptors.run(&mut temp).await);
+        assert_eq!(temp.payload, Bytes::from_static(b"abcd"));
+
+        // only the interceptors of matching filters are run
+        let mut other = message("lights/1", b"");
+        assert!(interceptors.run(&mut other).await);
+        assert_eq!(other.payload, Bytes::from_static(b"d"));
+
+        let mut dropped = message("sensors/1/temp", b"drop");
+        assert!(!interceptors.run(&mut dropped).await);
+        assert_eq!(dropped.payload, Bytes::from_static(b"drop"));
+    }
+}
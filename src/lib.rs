@@ -0,0 +1,68 @@
+//! An MQTT broker that can run standalone or be embedded in process.
+//!
+//! See [`Broker`] to get started.
+
+// Version 5 https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901021
+// Version 3.1.1 + Errata http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/mqtt-v3.1.1.html
+
+// https://hivemq.github.io/mqtt-cli/docs/installation/
+// C impl example
+// https://codepr.github.io/posts/sol-mqtt-broker/
+
+// Code
+// https://blog.graysonhead.net/posts/rust-tcp/
+// https://patshaughnessy.net/2018/3/15/how-rust-implements-tagged-unions
+// https://locka99.gitbooks.io/a-guide-to-porting-c-to-rust/content/features_of_rust/types.html
+// https://towardsdev.com/bitwise-operation-and-tricks-in-rust-5aea318c99b7
+// https://c-for-dummies.com/blog/?p=1848
+
+mod admin;
+pub mod auth;
+pub mod bridge;
+mod broker;
+mod client_id;
+pub mod cluster;
+pub mod config;
+pub mod core;
+mod direct;
+pub mod error;
+pub mod events;
+mod flow_control;
+mod handler;
+pub mod hooks;
+mod local_handle;
+pub mod logging;
+mod mount_point;
+mod packet_id;
+mod packet_trace;
+pub mod packets;
+mod privileges;
+mod proxy_protocol;
+pub mod quota;
+mod rate_limit;
+mod read_timeout;
+mod retained;
+#[cfg(all(windows, feature = "windows-service"))]
+pub mod service;
+mod shard;
+mod socket;
+mod stamp;
+mod sys;
+mod systemd;
+pub mod tls;
+// public for the benchmarks in `benches/`
+#[doc(hidden)]
+pub mod topic_heir;
+mod topic_policy;
+mod utils;
+mod write_timeout;
+
+pub use broker::{
+    Broker, BrokerBuilder, BrokerHandle, BrokerStats, Health, Message, Rates, Subscription,
+    HEALTH_TIMEOUT,
+};
+pub use packet_trace::TraceFilter;
+pub use rate_limit::{Ban, BanReason};
+pub use retained::{RetainedMessage, RetainedStats};
+pub use topic_heir::SubscriptionStats;
+pub use topic_policy::FilterNormalization;
@@ -0,0 +1,195 @@
+//! ### Local Handle
+//! How the broker's own subsystems, like the `$SYS` task, bridges and cluster links, publish and subscribe.
+//!
+//! Their messages go through the same command loop as the messages of connected clients,
+//! so they get the same QoS, retain, no local and retained message handling.
+
+use std::time::Instant;
+
+use bytes::Bytes;
+use tokio::sync::{mpsc::Sender, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    core::enums::{ClientEvent, Command, ConnectionId, ProtocalVersion, Responder},
+    error::MqttError,
+    packets::{
+        enums::{QosLevel, SubackReturnCode},
+        PublishProperties, SubscriptionOptions,
+    },
+};
+
+/// Cloneable handle on the command loop for the subsystems inside the broker
+#[derive(Debug, Clone)]
+pub struct LocalHandle {
+    message_bridge: Sender<Command>,
+}
+
+impl LocalHandle {
+    pub fn new(message_bridge: Sender<Command>) -> Self {
+        Self { message_bridge }
+    }
+
+    /// Publish a message from the broker itself
+    pub async fn publish(
+        &self,
+        topic: String,
+        payload: Bytes,
+        qos: QosLevel,
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        self.send_publish(None, topic, payload, qos, retain).await
+    }
+
+    /// Publish a message for an in process client, so it is not sent back to a No Local subscription of that client
+    pub async fn publish_as(
+        &self,
+        client_id: &str,
+        topic: String,
+        payload: Bytes,
+        qos: QosLevel,
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        self.send_publish(Some(client_id.to_string()), topic, payload, qos, retain)
+            .await
+    }
+
+    async fn send_publish(
+        &self,
+        client: Option<String>,
+        topic: String,
+        payload: Bytes,
+        qos: QosLevel,
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        self.message_bridge
+            .send(Command::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+                client,
+                properties: PublishProperties::default(),
+                received: Instant::now(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Register an in process v3.1.1 client with a clean session that is sent its messages on `channel`,
+    /// and subscribe it to `filters`. Returns a return code for each filter.
+    ///
+    /// The client is dropped by the broker when `disconnect` is cancelled.
+    pub async fn subscribe(
+        &self,
+        client_id: &str,
+        filters: Vec<(String, SubscriptionOptions)>,
+        channel: Sender<ClientEvent>,
+        disconnect: CancellationToken,
+        connection_id: ConnectionId,
+    ) -> Result<Vec<SubackReturnCode>, MqttError> {
+        self.request(|callback| Command::RegisterClient {
+            id: client_id.to_string(),
+            message_channel: channel,
+            disconnect,
+            protocol: ProtocalVersion::Four,
+            clean_session: true,
+            login: None,
+            connection: None,
+            connection_id,
+            callback,
+        })
+        .await??;
+
+        if filters.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.request(|callback| Command::Subscribe {
+            client: client_id.to_string(),
+            topics: filters,
+            subscription_identifier: None,
+            callback,
+        })
+        .await?
+    }
+
+    /// Remove the session of an in process client, unless another connection has since taken it over
+    pub async fn disconnect(
+        &self,
+        client_id: &str,
+        connection_id: ConnectionId,
+    ) -> Result<(), MqttError> {
+        self.message_bridge
+            .send(Command::DisconnectClient {
+                id: client_id.to_string(),
+                connection_id,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Send a command and wait for its response
+    pub async fn request<T>(
+        &self,
+        command: impl FnOnce(Responder<T>) -> Command,
+    ) -> Result<T, MqttError> {
+        let (tx, rx) = oneshot::channel();
+        self.message_bridge.send(command(tx)).await?;
+        rx.await.map_err(|_| MqttError::QueuePoisonError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let (tx, mut rx) = channel(1);
+        let handle = LocalHandle::new(tx);
+
+        handle
+            .publish_as(
+                "local.bridge.a",
+                "a/b".into(),
+                Bytes::from_static(b"1"),
+                QosLevel::AtLeast,
+                true,
+            )
+            .await
+            .unwrap();
+
+        match rx.recv().await {
+            Some(Command::Publish {
+                topic,
+                qos,
+                retain,
+                client,
+                ..
+            }) => {
+                assert_eq!(topic, "a/b");
+                assert_eq!(qos, QosLevel::AtLeast);
+                assert!(retain);
+                assert_eq!(client.as_deref(), Some("local.bridge.a"));
+            }
+            _ => panic!("Expected a publish"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let (tx, mut rx) = channel(1);
+        let handle = LocalHandle::new(tx);
+
+        tokio::spawn(async move {
+            if let Some(Command::RetainedCount(callback)) = rx.recv().await {
+                let _ = callback.send(3);
+            }
+        });
+
+        assert_eq!(handle.request(Command::RetainedCount).await.unwrap(), 3);
+    }
+}
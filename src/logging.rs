@@ -0,0 +1,109 @@
+//! Log output for the broker.
+//!
+//! The broker logs with [`tracing`], connection events are inside a `client` span with the
+//! remote address and client id so a client's lifecycle can be followed.
+//! Programs embedding the broker can install their own subscriber instead of calling [`init`].
+
+use std::{str::FromStr, sync::OnceLock};
+
+use tracing::{error, level_filters::LevelFilter};
+use tracing_subscriber::{
+    fmt::{self, MakeWriter},
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+    Registry,
+};
+
+use crate::error::MqttError;
+
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Format of the log output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors like ELK or Loki
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = MqttError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(MqttError::InvalidConfig(format!(
+                "Invalid value '{}' for 'log_format'",
+                value
+            ))),
+        }
+    }
+}
+
+/// Install the global log subscriber writing to stdout.
+pub fn init(format: LogFormat, level: LevelFilter) -> Result<(), MqttError> {
+    init_with(format, level, std::io::stdout, true)
+}
+
+/// Install the global log subscriber writing to `writer` without ANSI colors, like a file or the Windows event log.
+pub fn init_with_writer<W>(
+    format: LogFormat,
+    level: LevelFilter,
+    writer: W,
+) -> Result<(), MqttError>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    init_with(format, level, writer, false)
+}
+
+fn init_with<W>(
+    format: LogFormat,
+    level: LevelFilter,
+    writer: W,
+    ansi: bool,
+) -> Result<(), MqttError>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let (filter, handle) = reload::Layer::new(level);
+    let registry = tracing_subscriber::registry().with(filter);
+    let layer = fmt::layer().with_ansi(ansi).with_writer(writer);
+
+    let result = match format {
+        LogFormat::Text => registry.with(layer).try_init(),
+        LogFormat::Json => registry.with(layer.json()).try_init(),
+    };
+    result.map_err(|err| MqttError::Logging(err.to_string()))?;
+
+    // try_init only succeeds once so the handle is always unset here
+    let _ = LEVEL.set(handle);
+
+    Ok(())
+}
+
+/// Change the maximum log level of the subscriber installed by [`init`].
+///
+/// Returns false if the program installed its own subscriber, which keeps its level.
+pub fn set_level(level: LevelFilter) -> bool {
+    let Some(handle) = LEVEL.get() else {
+        return false;
+    };
+
+    match handle.reload(level) {
+        Ok(()) => true,
+        Err(err) => {
+            error!("Failed to change log level: {}", err);
+            false
+        }
+    }
+}
+
+/// Maximum log level of the subscriber installed by [`init`], `None` if the program installed its own.
+pub fn level() -> Option<LevelFilter> {
+    LEVEL.get()?.clone_current()
+}
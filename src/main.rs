@@ -1,122 +1,79 @@
-use config::ConfigBuilder;
-
-mod config;
-mod core;
-mod error;
-mod handler;
-mod packets;
-mod topic_heir;
-mod utils;
-
-use crate::core::{enums::Command, App};
-use crate::error::MqttError;
-use crate::handler::client_handler;
-
-use log::{debug, info};
-use tokio::{select, sync::mpsc::channel};
-
-use tokio_util::sync::CancellationToken;
-
-// Version 5 https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901021
-// Version 3.1.1 + Errata http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/mqtt-v3.1.1.html
-
-// https://hivemq.github.io/mqtt-cli/docs/installation/
-// C impl example
-// https://codepr.github.io/posts/sol-mqtt-broker/
-
-// Code
-// https://blog.graysonhead.net/posts/rust-tcp/
-// https://patshaughnessy.net/2018/3/15/how-rust-implements-tagged-unions
-// https://locka99.gitbooks.io/a-guide-to-porting-c-to-rust/content/features_of_rust/types.html
-// https://towardsdev.com/bitwise-operation-and-tricks-in-rust-5aea318c99b7
-// https://c-for-dummies.com/blog/?p=1848
-
-#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
-async fn main() -> Result<(), MqttError> {
-    env_logger::Builder::new()
-        .filter(None, log::LevelFilter::Trace)
-        .init();
+use mqtt_broker::{error::MqttError, logging, Broker};
+use tracing::level_filters::LevelFilter;
+
+fn main() -> Result<(), MqttError> {
+    let mut config_path = None;
+    #[cfg(all(windows, feature = "windows-service"))]
+    let mut service_action = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| MqttError::InvalidConfig("Missing config file path".into()))?;
+                config_path = Some(path);
+            }
+            #[cfg(all(windows, feature = "windows-service"))]
+            "--service" | "--install-service" | "--uninstall-service" => {
+                service_action = Some(arg);
+            }
+            _ => {}
+        }
+    }
 
-    let config = ConfigBuilder::new()
-        .set_port(1883)
-        .set_sys_interval(0)
-        .build()
-        .expect("Failed to start: Invalid config");
-    info!("Starting MQTT Broker at: {}", config.socket_addr);
+    #[cfg(all(windows, feature = "windows-service"))]
+    match service_action.as_deref() {
+        Some("--service") => return mqtt_broker::service::run(config_path),
+        Some("--install-service") => {
+            return mqtt_broker::service::install(config_path.as_deref().map(std::path::Path::new))
+        }
+        Some("--uninstall-service") => return mqtt_broker::service::uninstall(),
+        _ => {}
+    }
 
-    let listener = tokio::net::TcpListener::bind(config.socket_addr)
-        .await
-        .map_err(MqttError::Io)?;
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(10)
+        .enable_all()
+        .build()?
+        .block_on(run(config_path))
+}
 
-    let tracker = tokio_util::task::TaskTracker::new();
-    let token = CancellationToken::new();
-    let (tx, mut rx) = channel::<Command>(100);
+async fn run(config_path: Option<String>) -> Result<(), MqttError> {
+    let mut builder = Broker::builder();
+    if let Some(path) = &config_path {
+        builder = builder.config_file(path)?;
+    }
 
-    tracker.spawn(async move {
-        let mut context = App::new();
-        while let Some(command) = rx.recv().await {
-            match command {
-                Command::RegisterClient {
-                    id,
-                    message_channel,
-                    protocol,
-                    clean_session,
-                    callback,
-                } => {
-                    context
-                        .connect(id, message_channel, protocol, clean_session, callback)
-                        .await
-                }
-                Command::Subscribe {
-                    client,
-                    topics,
-                    callback,
-                } => context.subscribe(client, topics, callback),
+    let broker = builder.build()?;
 
-                Command::Publish { topic, payload } => context.publish(topic, payload).await,
-                Command::Unsubscribe {
-                    topics,
-                    cid,
-                    callback,
-                } => context.unsubscribe(cid, topics, callback),
-                Command::DisconnectClient(cid) => context.disconnect(cid),
-                Command::Exit => break,
-            }
-        }
+    let config = broker.config();
+    logging::init(
+        config.log_format,
+        config.log_level.unwrap_or(LevelFilter::INFO),
+    )?;
 
-        debug!("Exiting Command loop");
-    });
+    let handle = broker.handle();
 
-    loop {
-        select! {
-            res = listener.accept() => {
-                if let Ok((stream,addr)) = res {
-                    log::debug!("Connection Start: {:?}",addr);
-                    let cancellation = token.clone();
-                    let message_brige = tx.clone();
-                    tracker.spawn(async move {
-                        let (reader, writer) = tokio::io::split(stream);
-                        if let Err(err) = client_handler(reader,writer,message_brige,cancellation).await {
-                           log::error!("{}", err);
-                        }
-                        log::debug!("Exited TCP handler");
-                    });
+    #[cfg(unix)]
+    if let Some(path) = config_path {
+        let handle = handle.clone();
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if let Err(err) = handle.reload_file(&path).await {
+                    tracing::error!("Failed to reload config: {}", err);
                 }
             }
-            _ = tokio::signal::ctrl_c() => {
-                break;
-            }
-        }
-    }
-
-    log::info!("Exiting");
-    if tx.send(Command::Exit).await.is_err() {
-        log::error!("Failed to exit message loop");
+        });
     }
-    token.cancel();
-    tracker.close();
 
-    tracker.wait().await;
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            handle.shutdown();
+        }
+    });
 
-    Ok(())
+    broker.run().await
 }
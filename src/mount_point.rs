@@ -0,0 +1,113 @@
+//! ### Mount Points
+//! Topic prefixes that keep tenants sharing one broker apart, set with the `mount_point` and `user_mount_point` options.
+//!
+//! The mount point of a client is prepended to the topics it publishes to, the filters it subscribes and
+//! unsubscribes with and its will topic, and removed again from the topics of the messages it is sent.
+//! A client with the mount point `tenant-a/` that subscribes to `sensors/#` is subscribed to `tenant-a/sensors/#`,
+//! so it only sees the messages of its own tenant. A shared subscription keeps `$share/{ShareName}/` in front.
+//!
+//! [mosquitto.conf(5)](https://mosquitto.org/man/mosquitto-conf-5.html)
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::config::Config;
+
+/// Mount point of each client, from its username
+#[derive(Debug, Default, Clone)]
+pub struct MountPoints {
+    /// Mount point of the clients without one of their own
+    default: Option<Arc<str>>,
+    users: HashMap<String, Arc<str>>,
+}
+
+impl From<&Config> for MountPoints {
+    fn from(config: &Config) -> Self {
+        Self {
+            default: config.mount_point.as_deref().map(Arc::from),
+            users: config
+                .user_mount_points
+                .iter()
+                .map(|(user, mount_point)| (user.clone(), Arc::from(mount_point.as_str())))
+                .collect(),
+        }
+    }
+}
+
+impl MountPoints {
+    /// Mount point of a client, the one for its username before the one for the listener
+    pub fn for_user(&self, username: Option<&str>) -> Option<Arc<str>> {
+        username
+            .and_then(|username| self.users.get(username))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Can be used as a mount point, a topic name prefix that doesn't start with `$`
+pub fn is_valid(mount_point: &str) -> bool {
+    !mount_point.is_empty()
+        && !mount_point.starts_with('$')
+        && !mount_point.contains(['+', '#', '\0'])
+}
+
+/// Prepend a mount point to a topic or topic filter, after the `$share/{ShareName}/` of a shared subscription
+pub fn mount(mount_point: &str, topic: &str) -> String {
+    match topic
+        .strip_prefix("$share/")
+        .and_then(|rest| rest.split_once('/'))
+    {
+        Some((share_name, filter)) => format!("$share/{}/{}{}", share_name, mount_point, filter),
+        None => format!("{}{}", mount_point, topic),
+    }
+}
+
+/// Remove the mount point from a topic, `None` if the topic is outside of it
+pub fn unmount<'a>(mount_point: &str, topic: &'a str) -> Option<&'a str> {
+    topic
+        .strip_prefix(mount_point)
+        .filter(|topic| !topic.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount() {
+        assert_eq!(mount("tenant/", "sensors/temp"), "tenant/sensors/temp");
+        assert_eq!(
+            mount("tenant/", "$share/group/sensors/#"),
+            "$share/group/tenant/sensors/#"
+        );
+        assert_eq!(
+            unmount("tenant/", "tenant/sensors/temp"),
+            Some("sensors/temp")
+        );
+        assert_eq!(unmount("tenant/", "other/sensors/temp"), None);
+        assert_eq!(unmount("tenant/", "tenant/"), None);
+
+        assert!(is_valid("tenant/"));
+        assert!(!is_valid("$SYS/"));
+        assert!(!is_valid("tenant/+/"));
+        assert!(!is_valid(""));
+    }
+
+    #[test]
+    fn test_for_user() {
+        let mount_points = MountPoints {
+            default: Some(Arc::from("public/")),
+            users: HashMap::from([("acme".to_string(), Arc::from("acme/"))]),
+        };
+
+        assert_eq!(
+            mount_points.for_user(Some("acme")).as_deref(),
+            Some("acme/")
+        );
+        assert_eq!(
+            mount_points.for_user(Some("other")).as_deref(),
+            Some("public/")
+        );
+        assert_eq!(mount_points.for_user(None).as_deref(), Some("public/"));
+        assert_eq!(MountPoints::default().for_user(Some("acme")), None);
+    }
+}
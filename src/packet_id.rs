@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+
+/// ### Packet Identifier
+/// Hands out the packet identifiers for packets the broker sends that need an acknowledgement.
+///
+/// Identifiers are non-zero and an identifier is not reused until it is released,
+/// once the PUBACK, PUBCOMP, SUBACK or UNSUBACK for it has been received.
+///
+/// [(MQTT 5) 2.2.1 Packet Identifier](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901026)
+#[derive(Debug, Default)]
+pub struct PacketIdAllocator {
+    last: u16,
+    in_flight: HashSet<u16>,
+}
+
+impl PacketIdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Next free packet identifier, `None` if all 65,535 are in flight
+    pub fn allocate(&mut self) -> Option<u16> {
+        if self.in_flight.len() >= u16::MAX as usize {
+            return None;
+        }
+
+        loop {
+            self.last = self.last.wrapping_add(1).max(1);
+            if self.in_flight.insert(self.last) {
+                return Some(self.last);
+            }
+        }
+    }
+
+    /// Free a packet identifier once its packet has been acknowledged
+    pub fn release(&mut self, packet_id: u16) -> bool {
+        self.in_flight.remove(&packet_id)
+    }
+
+    /// Free every packet identifier that `keep` returns false for
+    pub fn retain(&mut self, keep: impl FnMut(&u16) -> bool) {
+        self.in_flight.retain(keep);
+    }
+
+    /// Number of packet identifiers in flight
+    pub fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_skips_in_flight() {
+        let mut ids = PacketIdAllocator::new();
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(2));
+        assert_eq!(ids.allocate(), Some(3));
+
+        assert!(ids.release(1));
+        assert!(!ids.release(1));
+        ids.last = u16::MAX;
+
+        // wraps around without using 0 or the ids still in flight
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(4));
+        assert_eq!(ids.len(), 4);
+    }
+
+    #[test]
+    fn test_allocate_exhausted() {
+        let mut ids = PacketIdAllocator::new();
+        for _ in 0..u16::MAX {
+            assert!(ids.allocate().is_some());
+        }
+        assert_eq!(ids.allocate(), None);
+
+        ids.release(42);
+        assert_eq!(ids.allocate(), Some(42));
+    }
+}
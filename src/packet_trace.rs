@@ -0,0 +1,159 @@
+//! ### Packet Trace
+//! Log the packets of chosen clients or topics while the broker is running, for debugging a single device in a fleet.
+//!
+//! Filters are added and removed with [`crate::BrokerHandle::trace`] or the admin API.
+//! Every packet a traced client sends or receives, and every PUBLISH to a traced topic,
+//! is logged at the info level with its direction, type, QoS, topic and size in bytes.
+//! A client is only traced once its CONNECT has been accepted.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    RwLock,
+};
+
+use tracing::info;
+
+use crate::{
+    error::MqttError,
+    packets::{
+        enums::{PacketType, QosLevel},
+        topic,
+    },
+    utils::topic_matches,
+};
+
+static FILTERS: RwLock<Vec<TraceFilter>> = RwLock::new(Vec::new());
+/// Set while there are filters so untraced packets only cost a load
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// What to trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceFilter {
+    /// Every packet to and from a client
+    ClientId(String),
+    /// PUBLISH packets with a topic matching the topic filter
+    Topic(String),
+}
+
+impl TraceFilter {
+    fn matches(&self, client_id: &str, topic: Option<&str>) -> bool {
+        match self {
+            Self::ClientId(id) => id == client_id,
+            Self::Topic(filter) => topic.is_some_and(|topic| topic_matches(filter, topic)),
+        }
+    }
+}
+
+/// Direction of a traced packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client
+    Inbound,
+    /// Sent to the client
+    Outbound,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Inbound => "in",
+            Self::Outbound => "out",
+        }
+    }
+}
+
+/// Start tracing, returns false if the filter was already added
+pub fn enable(filter: TraceFilter) -> Result<bool, MqttError> {
+    if let TraceFilter::Topic(topic_filter) = &filter {
+        topic::validate_topic_filter(topic_filter)?;
+    }
+
+    let mut filters = FILTERS.write().unwrap_or_else(|err| err.into_inner());
+    if filters.contains(&filter) {
+        return Ok(false);
+    }
+    filters.push(filter);
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(true)
+}
+
+/// Stop tracing, returns false if the filter was not added
+pub fn disable(filter: &TraceFilter) -> bool {
+    let mut filters = FILTERS.write().unwrap_or_else(|err| err.into_inner());
+    let len = filters.len();
+    filters.retain(|item| item != filter);
+    ENABLED.store(!filters.is_empty(), Ordering::Relaxed);
+    filters.len() != len
+}
+
+/// Filters currently being traced
+pub fn filters() -> Vec<TraceFilter> {
+    FILTERS
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn is_traced(client_id: &str, topic: Option<&str>) -> bool {
+    is_enabled()
+        && FILTERS
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .any(|filter| filter.matches(client_id, topic))
+}
+
+/// Log a packet if it matches a filter, `header` is the first byte of its fixed header
+pub fn record(direction: Direction, client_id: &str, header: u8, topic: Option<&str>, size: usize) {
+    if !is_traced(client_id, topic) {
+        return;
+    }
+
+    let packet_type = PacketType::try_from(header >> 4).ok();
+    let qos = match packet_type {
+        Some(PacketType::Publish) => QosLevel::try_from((header & 0x06) >> 1).ok(),
+        _ => None,
+    };
+
+    info!(
+        client_id,
+        direction = direction.as_str(),
+        ?packet_type,
+        ?qos,
+        topic,
+        size,
+        "Traced packet"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_filters() {
+        let client = TraceFilter::ClientId("trace-test-client".into());
+        let topic = TraceFilter::Topic("trace-test/+/status".into());
+
+        assert!(enable(client.clone()).unwrap());
+        assert!(!enable(client.clone()).unwrap());
+        assert!(enable(topic.clone()).unwrap());
+        assert!(enable(TraceFilter::Topic("trace/#/bad".into())).is_err());
+        assert!(is_enabled());
+
+        assert!(is_traced("trace-test-client", None));
+        assert!(is_traced("other", Some("trace-test/device-1/status")));
+        assert!(!is_traced("other", Some("trace-test/device-1/config")));
+        assert!(!is_traced("other", None));
+
+        assert!(disable(&client));
+        assert!(!disable(&client));
+        assert!(!is_traced("trace-test-client", None));
+        assert!(filters().contains(&topic));
+        assert!(disable(&topic));
+    }
+}
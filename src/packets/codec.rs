@@ -0,0 +1,313 @@
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    core::{broker_info, enums::ProtocalVersion},
+    error::MqttError,
+    packet_trace::{self, Direction},
+};
+
+use super::{utils::decode_length, Packet, VariableHeader};
+
+/// ### MQTT Codec
+/// Frames a byte stream into MQTT Control Packets.
+///
+/// A packet is only decoded once all of the bytes given by the Remaining Length
+/// of the fixed header have been received, so partial reads and multiple packets
+/// in a single read are handled by the [`tokio_util::codec::FramedRead`] buffer.
+/// That buffer is kept for the whole connection and each frame is split off of it without copying,
+/// so its memory is reused for later reads once the packets from it have been dropped.
+///
+/// Packets are encoded with the `Packet::make_*` functions so the encoder writes the bytes as is.
+///
+/// The layout of most packets depends on the protocol version, so a codec for the server side of a connection
+/// only decodes a CONNECT until the version is known, and takes it from that CONNECT.
+/// Every later packet is decoded with that version, and a CONNECT for another version is an error.
+///
+/// With a maximum packet size, a packet whose Remaining Length is over it is an error as soon as
+/// the fixed header has been read, before any room is reserved for it.
+///
+/// Every packet decoded and encoded is counted in [`broker_info`] for the `$SYS` topics,
+/// and logged by [`packet_trace`] once the client id is set.
+#[derive(Debug)]
+pub struct MqttCodec {
+    /// `None` until the CONNECT has been decoded
+    protocol: Option<ProtocalVersion>,
+    client_id: Option<String>,
+    max_packet_size: Option<usize>,
+}
+
+impl MqttCodec {
+    /// Codec for a connection using `protocol`, eg. one opened to another broker
+    pub fn new(protocol: ProtocalVersion) -> Self {
+        Self {
+            protocol: Some(protocol),
+            client_id: None,
+            max_packet_size: None,
+        }
+    }
+
+    /// Codec for a connection accepted from a client, the protocol version is set by its CONNECT
+    pub fn awaiting_connect() -> Self {
+        Self {
+            protocol: None,
+            client_id: None,
+            max_packet_size: None,
+        }
+    }
+
+    /// Refuse packets larger than `max_packet_size` bytes, fixed header included
+    pub fn with_max_packet_size(mut self, max_packet_size: Option<usize>) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Protocol version packets are decoded with, `None` until the CONNECT has been decoded
+    pub fn protocol(&self) -> Option<ProtocalVersion> {
+        self.protocol
+    }
+
+    /// Set the id of the connected client so its packets can be traced
+    pub fn set_client_id(&mut self, client_id: String) {
+        self.client_id = Some(client_id);
+    }
+}
+
+impl Decoder for MqttCodec {
+    type Item = Packet;
+    type Error = MqttError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+
+        let mut header = &src[1..];
+        let (remaining_len, len_bytes) = match decode_length(&mut header) {
+            Ok(result) => result,
+            // the remaining length has not been fully received yet
+            Err(MqttError::RequiredByteMissing(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let packet_len = 1 + len_bytes + remaining_len;
+        if self.max_packet_size.is_some_and(|max| packet_len > max) {
+            return Err(MqttError::PacketTooLarge(packet_len));
+        }
+        if src.len() < packet_len {
+            src.reserve(packet_len - src.len());
+            return Ok(None);
+        }
+
+        let mut frame = src.split_to(packet_len).freeze();
+        let header = frame[0];
+        // Without a protocol version only a CONNECT can be unpacked
+        let (packet, _) = Packet::unpack(
+            &mut frame,
+            self.protocol.unwrap_or(ProtocalVersion::Unknown),
+        )?;
+
+        if let VariableHeader::Connect {
+            protocol_version, ..
+        } = &packet.variable
+        {
+            match self.protocol {
+                None => self.protocol = Some(*protocol_version),
+                Some(protocol) if protocol != *protocol_version => {
+                    return Err(MqttError::ProtocolMismatch)
+                }
+                Some(_) => {}
+            }
+        }
+
+        broker_info::received_data(packet_len);
+        let topic = match &packet.variable {
+            VariableHeader::Publish { topic, .. } => {
+                broker_info::received_published();
+                Some(topic.as_str())
+            }
+            _ => None,
+        };
+
+        if let (Some(client_id), true) = (&self.client_id, packet_trace::is_enabled()) {
+            packet_trace::record(Direction::Inbound, client_id, header, topic, packet_len);
+        }
+
+        Ok(Some(packet))
+    }
+}
+
+impl Encoder<Bytes> for MqttCodec {
+    type Error = MqttError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        broker_info::sent_data(item.len());
+        // PUBLISH is packet type 3
+        let is_publish = item.first().is_some_and(|header| header >> 4 == 3);
+        if is_publish {
+            broker_info::sent_published();
+        }
+
+        if let (Some(client_id), Some(header), true) =
+            (&self.client_id, item.first(), packet_trace::is_enabled())
+        {
+            let topic = is_publish.then(|| publish_topic(&item)).flatten();
+            packet_trace::record(Direction::Outbound, client_id, *header, topic, item.len());
+        }
+
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+/// Read the Topic Name of an encoded PUBLISH packet
+fn publish_topic(packet: &[u8]) -> Option<&str> {
+    let mut header = packet.get(1..)?;
+    let (_, len_bytes) = decode_length(&mut header).ok()?;
+    let start = 1 + len_bytes;
+    let len = u16::from_be_bytes([*packet.get(start)?, *packet.get(start + 1)?]) as usize;
+    std::str::from_utf8(packet.get(start + 2..start + 2 + len)?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use tokio_util::codec::Decoder;
+
+    use crate::{
+        core::enums::ProtocalVersion,
+        error::MqttError,
+        packets::{Packet, PublishProperties, VariableHeader},
+    };
+
+    use super::{publish_topic, MqttCodec};
+
+    #[test]
+    fn test_decode_partial_packet() {
+        let mut codec = MqttCodec::new(ProtocalVersion::Four);
+        let packet = Packet::make_publish(
+            false,
+            crate::packets::enums::QosLevel::AtMost,
+            false,
+            "info".into(),
+            None,
+            "Cedalo".into(),
+            PublishProperties::default(),
+            Vec::new(),
+            ProtocalVersion::Four,
+        );
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(&packet[..5]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.put_slice(&packet[5..]);
+        let packet = codec.decode(&mut buf).unwrap().expect("Packet");
+        assert!(matches!(packet.variable, VariableHeader::Publish { .. }));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_publish_topic() {
+        let packet = Packet::make_publish(
+            false,
+            crate::packets::enums::QosLevel::AtLeast,
+            false,
+            "devices/1/status".into(),
+            Some(1),
+            vec![0; 200].into(),
+            PublishProperties::default(),
+            Vec::new(),
+            ProtocalVersion::Five,
+        );
+
+        assert_eq!(publish_topic(&packet), Some("devices/1/status"));
+        assert_eq!(publish_topic(&packet[..5]), None);
+    }
+
+    #[test]
+    fn test_decode_coalesced_packets() {
+        let mut codec = MqttCodec::new(ProtocalVersion::Four);
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(&Packet::make_ping_req());
+        buf.put_slice(&Packet::make_puback(1));
+
+        let first = codec.decode(&mut buf).unwrap().expect("Packet");
+        assert!(matches!(first.variable, VariableHeader::PingReq));
+
+        let second = codec.decode(&mut buf).unwrap().expect("Packet");
+        assert!(matches!(
+            second.variable,
+            VariableHeader::PubAck { packet_id: 1, .. }
+        ));
+
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_incomplete_remaining_length() {
+        let mut codec = MqttCodec::new(ProtocalVersion::Four);
+
+        let mut buf = BytesMut::from(&[0x30, 0xC1][..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 2);
+    }
+
+    #[test]
+    fn test_decode_max_packet_size() {
+        let mut codec = MqttCodec::new(ProtocalVersion::Four).with_max_packet_size(Some(64));
+
+        let mut buf = BytesMut::from(&Packet::make_ping_req()[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+
+        // refused from the fixed header, before the rest arrives
+        let mut buf = BytesMut::from(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(MqttError::PacketTooLarge(268435460))
+        ));
+        assert!(buf.capacity() < 64);
+    }
+
+    #[test]
+    fn test_decode_negotiates_protocol() {
+        // Nothing but a CONNECT can be decoded before the protocol version is known
+        let mut codec = MqttCodec::awaiting_connect();
+        let mut buf = BytesMut::from(&Packet::make_puback(1)[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(MqttError::ProtocolNotNegotiated)
+        ));
+        assert_eq!(codec.protocol(), None);
+
+        let mut codec = MqttCodec::awaiting_connect();
+        let mut buf = BytesMut::new();
+        buf.put_slice(&Packet::make_connect(
+            "c1".into(),
+            60,
+            true,
+            None,
+            None,
+            false,
+        ));
+        buf.put_slice(&Packet::make_puback(1));
+        let connect = codec.decode(&mut buf).unwrap().expect("Packet");
+        assert!(matches!(connect.variable, VariableHeader::Connect { .. }));
+        assert_eq!(codec.protocol(), Some(ProtocalVersion::Four));
+        let puback = codec.decode(&mut buf).unwrap().expect("Packet");
+        assert!(matches!(puback.variable, VariableHeader::PubAck { .. }));
+
+        // A v5 CONNECT on the v3.1.1 connection
+        let mut buf = BytesMut::from(
+            &[
+                0x10, 0x0E, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x02, 0x00, 0x3c, 0x00, 0x00,
+                0x01, b'a',
+            ][..],
+        );
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(MqttError::ProtocolMismatch)
+        ));
+    }
+}
@@ -0,0 +1,701 @@
+//! ### Conformance Test Vectors
+//! Packets the way mosquitto_pub and mosquitto_sub, paho and rumqttc put them on the wire, for v3.1.1 and v5,
+//! written out byte for byte so every change to the codec is checked against what real clients send.
+//!
+//! Every vector is unpacked with [`Packet::unpack`] and packed again with [`Packet::pack`].
+//! The encoder only writes the properties the broker uses, so not every packet comes back byte-exact,
+//! see [`Reencode`] for what is checked for each one.
+//!
+//! [(MQTT 3.1.1) 2 MQTT Control Packet format](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/errata01/os/mqtt-v3.1.1-errata01-os-complete.html#_Toc442180832)
+//! [(MQTT 5) 2 MQTT Control Packet format](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901019)
+
+use bytes::Bytes;
+
+use crate::core::enums::ProtocalVersion;
+
+use super::Packet;
+
+/// What packing an unpacked vector again has to give
+#[derive(Debug, Clone, Copy)]
+enum Reencode {
+    /// The same bytes
+    Exact,
+    /// Different bytes, without the properties the broker does not keep or with the optional fields filled in,
+    /// that unpack to a packet packing to the same bytes again
+    Canonical,
+    /// Only unpacked, the encoder writes no v5 CONNECT properties
+    DecodeOnly,
+}
+
+struct Vector {
+    name: &'static str,
+    protocol: ProtocalVersion,
+    reencode: Reencode,
+    bytes: &'static [u8],
+}
+
+#[rustfmt::skip]
+const VECTORS: &[Vector] = &[
+    Vector {
+        name: "mosquitto_sub v3.1.1 CONNECT",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x10, 0x21, // Fixed Header
+            0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // Protocol Name
+            0x04, // Protocol Level
+            0x02, // Connect Flags (Clean Session)
+            0x00, 0x3C, // Keep Alive
+            0x00, 0x15, 0x6D, 0x6F, 0x73, 0x71, 0x2D, 0x46, 0x71, 0x33, 0x6C, 0x4B, 0x31, 0x70, 0x44, 0x74, // Client Identifier
+            0x55, 0x53, 0x41, 0x78, 0x36, 0x63, 0x6B,
+        ],
+    },
+    Vector {
+        name: "paho v3.1.1 CONNECT with will and credentials",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x10, 0x51, // Fixed Header
+            0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // Protocol Name
+            0x04, // Protocol Level
+            0xEE, // Connect Flags (User Name, Password, Will Retain, Will QoS 1, Will, Clean Session)
+            0x00, 0x3C, // Keep Alive
+            0x00, 0x0D, 0x70, 0x61, 0x68, 0x6F, 0x2D, 0x73, 0x65, 0x6E, 0x73, 0x6F, 0x72, 0x2D, 0x37, // Client Identifier
+            0x00, 0x1C, 0x63, 0x6C, 0x69, 0x65, 0x6E, 0x74, 0x73, 0x2F, 0x70, 0x61, 0x68, 0x6F, 0x2D, 0x73, // Will Topic
+            0x65, 0x6E, 0x73, 0x6F, 0x72, 0x2D, 0x37, 0x2F, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73,
+            0x00, 0x07, 0x6F, 0x66, 0x66, 0x6C, 0x69, 0x6E, 0x65, // Will Message
+            0x00, 0x06, 0x73, 0x65, 0x6E, 0x73, 0x6F, 0x72, // User Name
+            0x00, 0x07, 0x68, 0x75, 0x6E, 0x74, 0x65, 0x72, 0x32, // Password
+        ],
+    },
+    Vector {
+        name: "rumqttc v3.1.1 CONNECT",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x10, 0x17, // Fixed Header
+            0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // Protocol Name
+            0x04, // Protocol Level
+            0x00, // Connect Flags (persistent session)
+            0x00, 0x05, // Keep Alive
+            0x00, 0x0B, 0x72, 0x75, 0x6D, 0x71, 0x74, 0x74, 0x2D, 0x73, 0x79, 0x6E, 0x63, // Client Identifier
+        ],
+    },
+    Vector {
+        name: "mosquitto_pub v3.1 CONNECT",
+        protocol: ProtocalVersion::Three,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x10, 0x1F, // Fixed Header
+            0x00, 0x06, 0x4D, 0x51, 0x49, 0x73, 0x64, 0x70, // Protocol Name
+            0x03, // Protocol Level
+            0x02, // Connect Flags (Clean Session)
+            0x00, 0x3C, // Keep Alive
+            0x00, 0x11, 0x6D, 0x6F, 0x73, 0x71, 0x70, 0x75, 0x62, 0x7C, 0x34, 0x37, 0x31, 0x31, 0x2D, 0x68, // Client Identifier
+            0x6F, 0x73, 0x74,
+        ],
+    },
+    Vector {
+        name: "mosquitto bridge v3.1.1 CONNECT",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x10, 0x1D, // Fixed Header
+            0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // Protocol Name
+            0x84, // Protocol Level (bridge)
+            0x00, // Connect Flags
+            0x00, 0x3C, // Keep Alive
+            0x00, 0x11, 0x65, 0x64, 0x67, 0x65, 0x2E, 0x6C, 0x6F, 0x63, 0x61, 0x6C, 0x2E, 0x75, 0x70, 0x6C, // Client Identifier
+            0x69, 0x6E, 0x6B,
+        ],
+    },
+    Vector {
+        name: "v3.1.1 CONNACK",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x20, 0x02, // Fixed Header
+            0x00, // Acknowledge Flags
+            0x00, // Return Code (Accepted)
+        ],
+    },
+    Vector {
+        name: "v3.1.1 CONNACK session present",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x20, 0x02, // Fixed Header
+            0x01, // Acknowledge Flags (Session Present)
+            0x00, // Return Code (Accepted)
+        ],
+    },
+    Vector {
+        name: "v3.1.1 CONNACK not authorized",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x20, 0x02, // Fixed Header
+            0x00, // Acknowledge Flags
+            0x05, // Return Code (Not authorized)
+        ],
+    },
+    Vector {
+        name: "mosquitto_pub v3.1.1 PUBLISH QoS 0",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x30, 0x0B, // Fixed Header
+            0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // Topic Name
+            0x68, 0x65, 0x6C, 0x6C, 0x6F, // Payload
+        ],
+    },
+    Vector {
+        name: "mosquitto_pub v3.1.1 PUBLISH QoS 1 retained",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x33, 0x19, // Fixed Header
+            0x00, 0x11, 0x68, 0x6F, 0x6D, 0x65, 0x2F, 0x6B, 0x69, 0x74, 0x63, 0x68, 0x65, 0x6E, 0x2F, 0x74, // Topic Name
+            0x65, 0x6D, 0x70,
+            0x00, 0x01, // Packet Identifier
+            0x32, 0x31, 0x2E, 0x35, // Payload
+        ],
+    },
+    Vector {
+        name: "paho v3.1.1 PUBLISH QoS 2 duplicate",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x3C, 0x12, // Fixed Header
+            0x00, 0x03, 0x61, 0x2F, 0x62, // Topic Name
+            0x01, 0x02, // Packet Identifier
+            0x7B, 0x22, 0x6F, 0x6E, 0x22, 0x3A, 0x74, 0x72, 0x75, 0x65, 0x7D, // Payload
+        ],
+    },
+    Vector {
+        name: "rumqttc v3.1.1 PUBLISH empty payload",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x31, 0x0D, // Fixed Header
+            0x00, 0x0B, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2F, 0x77, 0x6F, 0x72, 0x6C, 0x64, // Topic Name
+        ],
+    },
+    Vector {
+        name: "v3.1.1 PUBACK",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x40, 0x02, // Fixed Header
+            0x00, 0x01, // Packet Identifier
+        ],
+    },
+    Vector {
+        name: "v3.1.1 PUBREC",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x50, 0x02, // Fixed Header
+            0x01, 0x02, // Packet Identifier
+        ],
+    },
+    Vector {
+        name: "v3.1.1 PUBREL",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x62, 0x02, // Fixed Header
+            0x01, 0x02, // Packet Identifier
+        ],
+    },
+    Vector {
+        name: "v3.1.1 PUBCOMP",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x70, 0x02, // Fixed Header
+            0x01, 0x02, // Packet Identifier
+        ],
+    },
+    Vector {
+        name: "mosquitto_sub v3.1.1 SUBSCRIBE",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x82, 0x06, // Fixed Header
+            0x00, 0x01, // Packet Identifier
+            0x00, 0x01, 0x23, // Topic Filter
+            0x00, // QoS 0
+        ],
+    },
+    Vector {
+        name: "paho v3.1.1 SUBSCRIBE several filters",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x82, 0x40, // Fixed Header
+            0x00, 0x02, // Packet Identifier
+            0x00, 0x0E, 0x73, 0x65, 0x6E, 0x73, 0x6F, 0x72, 0x73, 0x2F, 0x2B, 0x2F, 0x74, 0x65, 0x6D, 0x70, // Topic Filter
+            0x01, // QoS 1
+            0x00, 0x12, 0x24, 0x53, 0x59, 0x53, 0x2F, 0x62, 0x72, 0x6F, 0x6B, 0x65, 0x72, 0x2F, 0x75, 0x70, // Topic Filter
+            0x74, 0x69, 0x6D, 0x65,
+            0x00, // QoS 0
+            0x00, 0x15, 0x24, 0x73, 0x68, 0x61, 0x72, 0x65, 0x2F, 0x77, 0x6F, 0x72, 0x6B, 0x65, 0x72, 0x73, // Topic Filter
+            0x2F, 0x6A, 0x6F, 0x62, 0x73, 0x2F, 0x23,
+            0x02, // QoS 2
+        ],
+    },
+    Vector {
+        name: "v3.1.1 SUBACK",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x90, 0x05, // Fixed Header
+            0x00, 0x02, // Packet Identifier
+            0x01, 0x00, 0x80, // Return Codes (QoS 1, QoS 0, Failure)
+        ],
+    },
+    Vector {
+        name: "paho v3.1.1 UNSUBSCRIBE",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xA2, 0x17, // Fixed Header
+            0x00, 0x03, // Packet Identifier
+            0x00, 0x0E, 0x73, 0x65, 0x6E, 0x73, 0x6F, 0x72, 0x73, 0x2F, 0x2B, 0x2F, 0x74, 0x65, 0x6D, 0x70, // Topic Filter
+            0x00, 0x03, 0x61, 0x2F, 0x62, // Topic Filter
+        ],
+    },
+    Vector {
+        name: "v3.1.1 UNSUBACK",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xB0, 0x02, // Fixed Header
+            0x00, 0x03, // Packet Identifier
+        ],
+    },
+    Vector {
+        name: "v3.1.1 PINGREQ",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xC0, 0x00, // Fixed Header
+        ],
+    },
+    Vector {
+        name: "v3.1.1 PINGRESP",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xD0, 0x00, // Fixed Header
+        ],
+    },
+    Vector {
+        name: "v3.1.1 DISCONNECT",
+        protocol: ProtocalVersion::Four,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xE0, 0x00, // Fixed Header
+        ],
+    },
+    Vector {
+        name: "mosquitto_sub v5 CONNECT",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::DecodeOnly,
+        bytes: &[
+            0x10, 0x22, // Fixed Header
+            0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // Protocol Name
+            0x05, // Protocol Level
+            0x02, // Connect Flags (Clean Start)
+            0x00, 0x3C, // Keep Alive
+            0x00, // Properties
+            0x00, 0x15, 0x6D, 0x6F, 0x73, 0x71, 0x2D, 0x70, 0x63, 0x45, 0x35, 0x79, 0x52, 0x30, 0x76, 0x63, // Client Identifier
+            0x4D, 0x38, 0x6C, 0x47, 0x44, 0x58, 0x70,
+        ],
+    },
+    Vector {
+        name: "paho v5 CONNECT with properties and will",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::DecodeOnly,
+        bytes: &[
+            0x10, 0x53, // Fixed Header
+            0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // Protocol Name
+            0x05, // Protocol Level
+            0xC6, // Connect Flags (User Name, Password, Will, Clean Start)
+            0x00, 0x1E, // Keep Alive
+            0x0B, 0x11, 0x00, 0x00, 0x0E, 0x10, 0x21, 0x00, 0x14, 0x22, 0x00, 0x0A, // Properties (Session Expiry 3600, Receive Maximum 20, Topic Alias Maximum 10)
+            0x00, 0x07, 0x70, 0x61, 0x68, 0x6F, 0x2D, 0x76, 0x35, // Client Identifier
+            0x07, 0x18, 0x00, 0x00, 0x00, 0x05, 0x01, 0x01, // Will Properties (Will Delay 5, UTF-8 payload)
+            0x00, 0x16, 0x63, 0x6C, 0x69, 0x65, 0x6E, 0x74, 0x73, 0x2F, 0x70, 0x61, 0x68, 0x6F, 0x2D, 0x76, // Will Topic
+            0x35, 0x2F, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73,
+            0x00, 0x04, 0x67, 0x6F, 0x6E, 0x65, // Will Payload
+            0x00, 0x04, 0x75, 0x73, 0x65, 0x72, // User Name
+            0x00, 0x06, 0x73, 0x65, 0x63, 0x72, 0x65, 0x74, // Password
+        ],
+    },
+    Vector {
+        name: "rumqttc v5 CONNECT",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::DecodeOnly,
+        bytes: &[
+            0x10, 0x1E, // Fixed Header
+            0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, // Protocol Name
+            0x05, // Protocol Level
+            0x02, // Connect Flags (Clean Start)
+            0x00, 0x3C, // Keep Alive
+            0x05, 0x27, 0x00, 0x00, 0x28, 0x00, // Properties (Maximum Packet Size 10240)
+            0x00, 0x0C, 0x72, 0x75, 0x6D, 0x71, 0x74, 0x74, 0x2D, 0x61, 0x73, 0x79, 0x6E, 0x63, // Client Identifier
+        ],
+    },
+    Vector {
+        name: "v5 CONNACK",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x20, 0x03, // Fixed Header
+            0x00, // Acknowledge Flags
+            0x00, // Reason Code (Success)
+            0x00, // Properties
+        ],
+    },
+    Vector {
+        name: "v5 CONNACK with server properties",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0x20, 0x15, // Fixed Header
+            0x00, // Acknowledge Flags
+            0x00, // Reason Code (Success)
+            0x12, 0x22, 0x00, 0x0A, 0x21, 0x00, 0x64, 0x12, 0x00, 0x09, 0x61, 0x75, 0x74, 0x6F, 0x2D, 0x33, // Properties (Topic Alias Maximum 10, Receive Maximum 100, Assigned Client Identifier)
+            0x46, 0x32, 0x41,
+        ],
+    },
+    Vector {
+        name: "mosquitto_pub v5 PUBLISH QoS 0",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x30, 0x0C, // Fixed Header
+            0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // Topic Name
+            0x00, // Properties
+            0x68, 0x65, 0x6C, 0x6C, 0x6F, // Payload
+        ],
+    },
+    Vector {
+        name: "mosquitto_pub v5 PUBLISH QoS 1 with properties",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x32, 0x28, // Fixed Header
+            0x00, 0x04, 0x74, 0x65, 0x73, 0x74, // Topic Name
+            0x00, 0x01, // Packet Identifier
+            0x1D, 0x01, 0x01, 0x03, 0x00, 0x0A, 0x74, 0x65, 0x78, 0x74, 0x2F, 0x70, 0x6C, 0x61, 0x69, 0x6E, // Properties (UTF-8 payload, Content Type, User Property)
+            0x26, 0x00, 0x06, 0x6F, 0x72, 0x69, 0x67, 0x69, 0x6E, 0x00, 0x03, 0x63, 0x6C, 0x69,
+            0x68, 0x69, // Payload
+        ],
+    },
+    Vector {
+        name: "paho v5 PUBLISH request",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0x32, 0x2A, // Fixed Header
+            0x00, 0x0B, 0x72, 0x70, 0x63, 0x2F, 0x72, 0x65, 0x71, 0x75, 0x65, 0x73, 0x74, // Topic Name
+            0x00, 0x07, // Packet Identifier
+            0x16, 0x02, 0x00, 0x00, 0x00, 0x3C, 0x08, 0x00, 0x09, 0x72, 0x70, 0x63, 0x2F, 0x72, 0x65, 0x70, // Properties (Message Expiry 60, Response Topic, Correlation Data)
+            0x6C, 0x79, 0x09, 0x00, 0x02, 0x00, 0x2A,
+            0x70, 0x69, 0x6E, 0x67, // Payload
+        ],
+    },
+    Vector {
+        name: "v5 PUBLISH with subscription identifiers",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x34, 0x0E, // Fixed Header
+            0x00, 0x03, 0x61, 0x2F, 0x62, // Topic Name
+            0x00, 0x09, // Packet Identifier
+            0x05, 0x0B, 0x01, 0x0B, 0xAC, 0x02, // Properties (Subscription Identifiers 1 and 300)
+            0x78, // Payload
+        ],
+    },
+    Vector {
+        name: "v5 PUBACK",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x40, 0x02, // Fixed Header
+            0x00, 0x01, // Packet Identifier
+        ],
+    },
+    Vector {
+        name: "v5 PUBACK no matching subscribers",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x40, 0x03, // Fixed Header
+            0x00, 0x01, // Packet Identifier
+            0x10, // Reason Code (No matching subscribers)
+        ],
+    },
+    Vector {
+        name: "v5 PUBREC with properties",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x50, 0x09, // Fixed Header
+            0x00, 0x09, // Packet Identifier
+            0x00, // Reason Code (Success)
+            0x05, 0x1F, 0x00, 0x02, 0x6F, 0x6B, // Properties (Reason String)
+        ],
+    },
+    Vector {
+        name: "v5 PUBREL",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x62, 0x02, // Fixed Header
+            0x00, 0x09, // Packet Identifier
+        ],
+    },
+    Vector {
+        name: "v5 PUBCOMP",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x70, 0x02, // Fixed Header
+            0x00, 0x09, // Packet Identifier
+        ],
+    },
+    Vector {
+        name: "v5 PUBREL packet identifier not found",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x62, 0x03, // Fixed Header
+            0x00, 0x09, // Packet Identifier
+            0x92, // Reason Code (Packet Identifier not found)
+        ],
+    },
+    Vector {
+        name: "v5 PUBACK with empty properties",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0x40, 0x04, // Fixed Header
+            0x00, 0x01, // Packet Identifier
+            0x97, // Reason Code (Quota exceeded)
+            0x00, // Properties
+        ],
+    },
+    Vector {
+        name: "v5 PUBCOMP success with empty properties",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0x70, 0x04, // Fixed Header
+            0x00, 0x09, // Packet Identifier
+            0x00, // Reason Code (Success)
+            0x00, // Properties
+        ],
+    },
+    Vector {
+        name: "mosquitto_sub v5 SUBSCRIBE",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x82, 0x07, // Fixed Header
+            0x00, 0x01, // Packet Identifier
+            0x00, // Properties
+            0x00, 0x01, 0x23, // Topic Filter
+            0x00, // Subscription Options (QoS 0)
+        ],
+    },
+    Vector {
+        name: "paho v5 SUBSCRIBE with options",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x82, 0x1A, // Fixed Header
+            0x00, 0x02, // Packet Identifier
+            0x00, // Properties
+            0x00, 0x09, 0x63, 0x68, 0x61, 0x74, 0x2F, 0x72, 0x6F, 0x6F, 0x6D, // Topic Filter
+            0x0D, // Subscription Options (QoS 1, No Local, Retain As Published)
+            0x00, 0x08, 0x63, 0x6F, 0x6E, 0x66, 0x69, 0x67, 0x2F, 0x23, // Topic Filter
+            0x22, // Subscription Options (QoS 2, Do not send retained)
+        ],
+    },
+    Vector {
+        name: "v5 SUBSCRIBE with subscription identifier",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0x82, 0x0C, // Fixed Header
+            0x00, 0x03, // Packet Identifier
+            0x03, 0x0B, 0xAC, 0x02, // Properties (Subscription Identifier 300)
+            0x00, 0x03, 0x61, 0x2F, 0x2B, // Topic Filter
+            0x11, // Subscription Options (QoS 1, Send retained if new)
+        ],
+    },
+    Vector {
+        name: "v5 SUBACK",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0x90, 0x06, // Fixed Header
+            0x00, 0x02, // Packet Identifier
+            0x00, // Properties
+            0x01, 0x02, 0x87, // Reason Codes (QoS 1, QoS 2, Not authorized)
+        ],
+    },
+    Vector {
+        name: "v5 SUBACK with reason string",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0x90, 0x0D, // Fixed Header
+            0x00, 0x02, // Packet Identifier
+            0x09, 0x1F, 0x00, 0x06, 0x64, 0x65, 0x6E, 0x69, 0x65, 0x64, // Properties (Reason String)
+            0x8F, // Reason Codes (Topic Filter invalid)
+        ],
+    },
+    Vector {
+        name: "paho v5 UNSUBSCRIBE",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xA2, 0x0E, // Fixed Header
+            0x00, 0x04, // Packet Identifier
+            0x00, // Properties
+            0x00, 0x09, 0x63, 0x68, 0x61, 0x74, 0x2F, 0x72, 0x6F, 0x6F, 0x6D, // Topic Filter
+        ],
+    },
+    Vector {
+        name: "v5 UNSUBACK",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xB0, 0x05, // Fixed Header
+            0x00, 0x04, // Packet Identifier
+            0x00, // Properties
+            0x00, 0x11, // Reason Codes (Success, No subscription existed)
+        ],
+    },
+    Vector {
+        name: "v5 PINGREQ",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xC0, 0x00, // Fixed Header
+        ],
+    },
+    Vector {
+        name: "v5 DISCONNECT",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xE0, 0x00, // Fixed Header
+        ],
+    },
+    Vector {
+        name: "v5 DISCONNECT with reason code",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0xE0, 0x01, // Fixed Header
+            0x00, // Reason Code (Normal disconnection)
+        ],
+    },
+    Vector {
+        name: "v5 DISCONNECT with session expiry",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0xE0, 0x07, // Fixed Header
+            0x00, // Reason Code (Normal disconnection)
+            0x05, 0x11, 0x00, 0x00, 0x00, 0x00, // Properties (Session Expiry 0)
+        ],
+    },
+    Vector {
+        name: "v5 DISCONNECT with will message",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0xE0, 0x01, // Fixed Header
+            0x04, // Reason Code (Disconnect with Will Message)
+        ],
+    },
+    Vector {
+        name: "v5 DISCONNECT from the server",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xE0, 0x08, // Fixed Header
+            0x8B, // Reason Code (Server shutting down)
+            0x06, 0x1F, 0x00, 0x03, 0x62, 0x79, 0x65, // Properties (Reason String)
+        ],
+    },
+    Vector {
+        name: "v5 AUTH",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Exact,
+        bytes: &[
+            0xF0, 0x00, // Fixed Header
+        ],
+    },
+    Vector {
+        name: "v5 AUTH continue",
+        protocol: ProtocalVersion::Five,
+        reencode: Reencode::Canonical,
+        bytes: &[
+            0xF0, 0x19, // Fixed Header
+            0x18, // Reason Code (Continue authentication)
+            0x17, 0x15, 0x00, 0x0B, 0x53, 0x43, 0x52, 0x41, 0x4D, 0x2D, 0x53, 0x48, 0x41, 0x2D, 0x31, 0x16, // Properties (Authentication Method, Authentication Data)
+            0x00, 0x06, 0x63, 0x3D, 0x62, 0x69, 0x77, 0x73,
+        ],
+    },
+];
+
+#[test]
+fn test_conformance_vectors() {
+    for vector in VECTORS {
+        let bytes = Bytes::from_static(vector.bytes);
+        let mut data = bytes.clone();
+        let (packet, len) = Packet::unpack(&mut data, vector.protocol)
+            .unwrap_or_else(|err| panic!("{}: failed to unpack: {:?}", vector.name, err));
+        assert_eq!(len, bytes.len(), "{}: unpacked length", vector.name);
+        assert!(data.is_empty(), "{}: bytes left over", vector.name);
+
+        match vector.reencode {
+            Reencode::Exact => {
+                assert_eq!(
+                    packet.pack(vector.protocol),
+                    bytes,
+                    "{}: not re-encoded byte-exact",
+                    vector.name
+                );
+            }
+            Reencode::Canonical => {
+                let packed = packet.pack(vector.protocol);
+                assert_ne!(packed, bytes, "{}: re-encoded byte-exact", vector.name);
+
+                let mut data = packed.clone();
+                let (packet, _) =
+                    Packet::unpack(&mut data, vector.protocol).unwrap_or_else(|err| {
+                        panic!(
+                            "{}: failed to unpack the re-encoding: {:?}",
+                            vector.name, err
+                        )
+                    });
+                assert_eq!(
+                    packet.pack(vector.protocol),
+                    packed,
+                    "{}: re-encoding is not stable",
+                    vector.name
+                );
+            }
+            Reencode::DecodeOnly => {}
+        }
+    }
+}
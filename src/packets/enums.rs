@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-use crate::error::MqttError;
+use crate::{core::enums::ProtocalVersion, error::MqttError};
 
 /// ### MQTT Control Packet type
 /// Represented as a 4-bit unsigned value, the values are shown below.
@@ -82,7 +82,7 @@ impl TryFrom<u8> for PacketType {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1..=14 => unsafe { Ok(std::mem::transmute(value)) },
+            1..=14 => unsafe { Ok(std::mem::transmute::<u8, PacketType>(value)) },
             _ => Err(MqttError::Convertion(
                 value.to_string(),
                 "PacketType".into(),
@@ -149,10 +149,17 @@ impl TryFrom<u8> for QosLevel {
     }
 }
 
+/// ### Connect Return Code
+/// Sent in the CONNACK, v3.1.1 has return codes 0 to 5 and v5 has its own Reason Codes from 0x80.
+/// Codes are converted with [`ConnectReturnCode::for_protocol`] when the CONNACK is packed,
+/// so either version can be used for any client.
+///
+/// [(MQTT 5) 3.2.2.2 Connect Reason Code](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901079)
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum ConnectReturnCode {
     /// Connection accepted
+    #[default]
     Accepted,
     /// The Server does not support the level of the MQTT protocol requested by the Client
     V4UnacceptableProtocal,
@@ -216,6 +223,38 @@ impl From<ConnectReturnCode> for u8 {
     }
 }
 
+impl ConnectReturnCode {
+    /// The closest code the client's protocol version has.
+    /// v3.1.1 reports the v5 codes without an equivalent as Server Unavailable.
+    pub fn for_protocol(self, protocol: ProtocalVersion) -> Self {
+        match protocol {
+            ProtocalVersion::Five => match self {
+                Self::V4UnacceptableProtocal => Self::UnsupportedProtocolVersion,
+                Self::V4IdentifierRejected => Self::ClientIdentifierNotValid,
+                Self::V4ServerUnavailable => Self::V5ServerUnavailable,
+                Self::V4BadUserNameOrPassword => Self::BadUserNameOrPassword,
+                Self::V4NotAuthorized => Self::V5NotAuthorized,
+                code => code,
+            },
+            _ => match self {
+                Self::Accepted
+                | Self::V4UnacceptableProtocal
+                | Self::V4IdentifierRejected
+                | Self::V4ServerUnavailable
+                | Self::V4BadUserNameOrPassword
+                | Self::V4NotAuthorized => self,
+                Self::UnsupportedProtocolVersion => Self::V4UnacceptableProtocal,
+                Self::ClientIdentifierNotValid => Self::V4IdentifierRejected,
+                Self::BadUserNameOrPassword => Self::V4BadUserNameOrPassword,
+                Self::V5NotAuthorized | Self::Banned | Self::BadAuthenticationMethod => {
+                    Self::V4NotAuthorized
+                }
+                _ => Self::V4ServerUnavailable,
+            },
+        }
+    }
+}
+
 impl TryFrom<u8> for ConnectReturnCode {
     type Error = MqttError;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
@@ -234,6 +273,27 @@ impl TryFrom<&u8> for ConnectReturnCode {
             3 => Ok(ConnectReturnCode::V4ServerUnavailable),
             4 => Ok(ConnectReturnCode::V4BadUserNameOrPassword),
             5 => Ok(ConnectReturnCode::V4NotAuthorized),
+            0x80 => Ok(ConnectReturnCode::UnspecifiedError),
+            0x81 => Ok(ConnectReturnCode::MalformedPacket),
+            0x82 => Ok(ConnectReturnCode::ProtocolError),
+            0x83 => Ok(ConnectReturnCode::ImplementationSpecificError),
+            0x84 => Ok(ConnectReturnCode::UnsupportedProtocolVersion),
+            0x85 => Ok(ConnectReturnCode::ClientIdentifierNotValid),
+            0x86 => Ok(ConnectReturnCode::BadUserNameOrPassword),
+            0x87 => Ok(ConnectReturnCode::V5NotAuthorized),
+            0x88 => Ok(ConnectReturnCode::V5ServerUnavailable),
+            0x89 => Ok(ConnectReturnCode::ServerBusy),
+            0x8A => Ok(ConnectReturnCode::Banned),
+            0x8C => Ok(ConnectReturnCode::BadAuthenticationMethod),
+            0x90 => Ok(ConnectReturnCode::TopicNameInvalid),
+            0x95 => Ok(ConnectReturnCode::PacketTooLarge),
+            0x97 => Ok(ConnectReturnCode::QuotaExceeded),
+            0x99 => Ok(ConnectReturnCode::PayloadFormatInvalid),
+            0x9A => Ok(ConnectReturnCode::RetainNotSupported),
+            0x9B => Ok(ConnectReturnCode::QoSNotSupported),
+            0x9C => Ok(ConnectReturnCode::UseAnotherServer),
+            0x9D => Ok(ConnectReturnCode::ServerMoved),
+            0x9F => Ok(ConnectReturnCode::ConnectionRateExceeded),
             _ => Err(MqttError::Convertion(
                 value.to_string(),
                 "ConnectReturnCode".into(),
@@ -242,19 +302,26 @@ impl TryFrom<&u8> for ConnectReturnCode {
     }
 }
 
-impl Default for ConnectReturnCode {
-    fn default() -> Self {
-        Self::Accepted
-    }
-}
-
 #[repr(u8)]
 #[derive(Debug)]
 pub enum SubackReturnCode {
     SuccessQosZero = 0x00,
     SuccessQosOne = 0x01,
     SuccessQosTwo = 0x02,
+    /// Unspecified error in v5, the only failure return code in v3.1.1.
     Failure = 0x80,
+    /// (v5) The SUBSCRIBE is valid but the Server does not accept it.
+    ImplementationSpecificError = 0x83,
+    /// (v5) The Client is not authorized to make this subscription.
+    NotAuthorized = 0x87,
+    /// (v5) The Topic Filter is malformed or not allowed for this Client.
+    TopicFilterInvalid = 0x8F,
+    /// (v5) An implementation or administrative imposed limit has been exceeded.
+    QuotaExceeded = 0x97,
+    /// (v5) The Server does not support Shared Subscriptions for this Client.
+    SharedSubscriptionsNotSupported = 0x9E,
+    /// (v5) The Server does not support Wildcard Subscriptions for this Client.
+    WildcardSubscriptionsNotSupported = 0xA2,
 }
 
 impl From<SubackReturnCode> for u8 {
@@ -264,6 +331,22 @@ impl From<SubackReturnCode> for u8 {
 }
 
 impl SubackReturnCode {
+    pub fn is_failure(&self) -> bool {
+        !matches!(
+            self,
+            Self::SuccessQosZero | Self::SuccessQosOne | Self::SuccessQosTwo
+        )
+    }
+
+    /// v3.1.1 only has a single failure return code
+    pub fn for_protocol(self, protocol: ProtocalVersion) -> Self {
+        match protocol {
+            ProtocalVersion::Five => self,
+            _ if self.is_failure() => Self::Failure,
+            _ => self,
+        }
+    }
+
     #[deprecated]
     pub fn to_u8(&self) -> u8 {
         match self {
@@ -271,6 +354,12 @@ impl SubackReturnCode {
             Self::SuccessQosOne => 0x01,
             Self::SuccessQosTwo => 0x02,
             Self::Failure => 0x80,
+            Self::ImplementationSpecificError => 0x83,
+            Self::NotAuthorized => 0x87,
+            Self::TopicFilterInvalid => 0x8F,
+            Self::QuotaExceeded => 0x97,
+            Self::SharedSubscriptionsNotSupported => 0x9E,
+            Self::WildcardSubscriptionsNotSupported => 0xA2,
         }
     }
 }
@@ -292,6 +381,12 @@ impl TryFrom<&u8> for SubackReturnCode {
             0x01 => Ok(Self::SuccessQosOne),
             0x02 => Ok(Self::SuccessQosTwo),
             0x80 => Ok(Self::Failure),
+            0x83 => Ok(Self::ImplementationSpecificError),
+            0x87 => Ok(Self::NotAuthorized),
+            0x8F => Ok(Self::TopicFilterInvalid),
+            0x97 => Ok(Self::QuotaExceeded),
+            0x9E => Ok(Self::SharedSubscriptionsNotSupported),
+            0xA2 => Ok(Self::WildcardSubscriptionsNotSupported),
             _ => Err(MqttError::Convertion(
                 value.to_string(),
                 "ReturnCode".into(),
@@ -299,3 +394,217 @@ impl TryFrom<&u8> for SubackReturnCode {
         }
     }
 }
+
+/// ### Unsuback Reason Code
+/// One for each Topic Filter of the UNSUBSCRIBE packet, only sent to v5 clients.
+///
+/// [(MQTT 5) 3.11.3 UNSUBACK Payload](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901194)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsubackReasonCode {
+    /// The subscription is deleted.
+    Success = 0x00,
+    /// No matching Topic Filter is being used by the Client.
+    NoSubscriptionExisted = 0x11,
+    /// The unsubscribe could not be completed and the Server either does not wish to reveal the reason or none of the other Reason Codes apply.
+    UnspecifiedError = 0x80,
+    /// The Client is not authorized to unsubscribe.
+    NotAuthorized = 0x87,
+    /// The Topic Filter is correctly formed but is not allowed for this Client.
+    TopicFilterInvalid = 0x8F,
+}
+
+impl From<UnsubackReasonCode> for u8 {
+    fn from(value: UnsubackReasonCode) -> Self {
+        value as u8
+    }
+}
+
+impl TryFrom<u8> for UnsubackReasonCode {
+    type Error = MqttError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Success),
+            0x11 => Ok(Self::NoSubscriptionExisted),
+            0x80 => Ok(Self::UnspecifiedError),
+            0x87 => Ok(Self::NotAuthorized),
+            0x8F => Ok(Self::TopicFilterInvalid),
+            _ => Err(MqttError::Convertion(
+                value.to_string(),
+                "UnsubackReasonCode".into(),
+            )),
+        }
+    }
+}
+
+/// ### Disconnect Reason Code
+/// Sent by the Server in a v5 DISCONNECT packet to tell the Client why the Network Connection is being closed.
+///
+/// [(MQTT 5) 3.14.2.1 Disconnect Reason Code](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901208)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisconnectReasonCode {
+    /// Close the connection normally. Do not send the Will Message.
+    #[default]
+    NormalDisconnection = 0x00,
+    /// The Client wishes to disconnect but requires that the Server also publishes its Will Message.
+    DisconnectWithWillMessage = 0x04,
+    /// The Connection is closed but the sender either does not wish to reveal the reason, or none of the other Reason Codes apply.
+    UnspecifiedError = 0x80,
+    /// The received packet does not conform to this specification.
+    MalformedPacket = 0x81,
+    /// An unexpected or out of order packet was received.
+    ProtocolError = 0x82,
+    /// The packet received is valid but cannot be processed by this implementation.
+    ImplementationSpecificError = 0x83,
+    /// The request is not authorized.
+    NotAuthorized = 0x87,
+    /// The Server is busy and cannot continue processing requests from this Client.
+    ServerBusy = 0x89,
+    /// The Server is shutting down.
+    ServerShuttingDown = 0x8B,
+    /// The Connection is closed because no packet has been received for 1.5 times the Keepalive time.
+    KeepAliveTimeout = 0x8D,
+    /// Another Connection using the same ClientID has connected causing this Connection to be closed.
+    SessionTakenOver = 0x8E,
+    /// The Topic Filter is correctly formed, but is not accepted by this Sever.
+    TopicFilterInvalid = 0x8F,
+    /// The Topic Name is correctly formed, but is not accepted by this Client or Server.
+    TopicNameInvalid = 0x90,
+    /// The Client or Server has received more than Receive Maximum publication for which it has not sent PUBACK or PUBCOMP.
+    ReceiveMaximumExceeded = 0x93,
+    /// The Client or Server has received a PUBLISH packet containing a Topic Alias which is greater than the Maximum Topic Alias it sent in the CONNECT or CONNACK packet.
+    TopicAliasInvalid = 0x94,
+    /// The packet size is greater than Maximum Packet Size for this Client or Server.
+    PacketTooLarge = 0x95,
+    /// The received data rate is too high.
+    MessageRateTooHigh = 0x96,
+    /// An implementation or administrative imposed limit has been exceeded.
+    QuotaExceeded = 0x97,
+    /// The Connection is closed due to an administrative action.
+    AdministrativeAction = 0x98,
+    /// The payload format does not match the one specified by the Payload Format Indicator.
+    PayloadFormatInvalid = 0x99,
+    /// The Server has does not support retained messages.
+    RetainNotSupported = 0x9A,
+    /// The Client specified a QoS greater than the QoS specified in a Maximum QoS in the CONNACK.
+    QoSNotSupported = 0x9B,
+    /// The Client should temporarily change its Server.
+    UseAnotherServer = 0x9C,
+    /// The Server is moved and the Client should permanently change its server location.
+    ServerMoved = 0x9D,
+    /// The Server does not support Shared Subscriptions.
+    SharedSubscriptionsNotSupported = 0x9E,
+    /// This connection is closed because the connection rate is too high.
+    ConnectionRateExceeded = 0x9F,
+    /// The maximum connection time authorized for this connection has been exceeded.
+    MaximumConnectTime = 0xA0,
+    /// The Server does not support Subscription Identifiers; the subscription is not accepted.
+    SubscriptionIdentifiersNotSupported = 0xA1,
+    /// The Server does not support Wildcard Subscriptions; the subscription is not accepted.
+    WildcardSubscriptionsNotSupported = 0xA2,
+}
+
+impl From<DisconnectReasonCode> for u8 {
+    fn from(value: DisconnectReasonCode) -> Self {
+        value as u8
+    }
+}
+
+impl TryFrom<u8> for DisconnectReasonCode {
+    type Error = MqttError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x00 => Self::NormalDisconnection,
+            0x04 => Self::DisconnectWithWillMessage,
+            0x80 => Self::UnspecifiedError,
+            0x81 => Self::MalformedPacket,
+            0x82 => Self::ProtocolError,
+            0x83 => Self::ImplementationSpecificError,
+            0x87 => Self::NotAuthorized,
+            0x89 => Self::ServerBusy,
+            0x8B => Self::ServerShuttingDown,
+            0x8D => Self::KeepAliveTimeout,
+            0x8E => Self::SessionTakenOver,
+            0x8F => Self::TopicFilterInvalid,
+            0x90 => Self::TopicNameInvalid,
+            0x93 => Self::ReceiveMaximumExceeded,
+            0x94 => Self::TopicAliasInvalid,
+            0x95 => Self::PacketTooLarge,
+            0x96 => Self::MessageRateTooHigh,
+            0x97 => Self::QuotaExceeded,
+            0x98 => Self::AdministrativeAction,
+            0x99 => Self::PayloadFormatInvalid,
+            0x9A => Self::RetainNotSupported,
+            0x9B => Self::QoSNotSupported,
+            0x9C => Self::UseAnotherServer,
+            0x9D => Self::ServerMoved,
+            0x9E => Self::SharedSubscriptionsNotSupported,
+            0x9F => Self::ConnectionRateExceeded,
+            0xA0 => Self::MaximumConnectTime,
+            0xA1 => Self::SubscriptionIdentifiersNotSupported,
+            0xA2 => Self::WildcardSubscriptionsNotSupported,
+            _ => {
+                return Err(MqttError::Convertion(
+                    value.to_string(),
+                    "DisconnectReasonCode".into(),
+                ))
+            }
+        })
+    }
+}
+
+impl From<&MqttError> for DisconnectReasonCode {
+    fn from(value: &MqttError) -> Self {
+        match value {
+            MqttError::ProtocolViolation
+            | MqttError::UnacceptableProtocolLevel
+            | MqttError::UnknownProtocol
+            | MqttError::ProtocolNotNegotiated
+            | MqttError::ProtocolMismatch => Self::ProtocolError,
+            MqttError::MalformedString(_)
+            | MqttError::ReservedPacketType
+            | MqttError::RequiredByteMissing(_)
+            | MqttError::MalformedHeader
+            | MqttError::Convertion(_, _)
+            | MqttError::MissingByte
+            | MqttError::MalformedRemaingLength
+            | MqttError::MalformedPacket(_)
+            | MqttError::MissingFixedHeader => Self::MalformedPacket,
+            MqttError::TopicNameInvalid => Self::TopicNameInvalid,
+            MqttError::TopicFilterInvalid => Self::TopicFilterInvalid,
+            MqttError::PacketTooLarge(_) => Self::PacketTooLarge,
+            _ => Self::UnspecifiedError,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_return_code_round_trip() {
+        let codes: Vec<ConnectReturnCode> = (0..=u8::MAX)
+            .filter_map(|value| ConnectReturnCode::try_from(value).ok())
+            .collect();
+        // 6 v3.1.1 return codes and 21 v5 Reason Codes
+        assert_eq!(codes.len(), 27);
+
+        for value in 0..=u8::MAX {
+            if let Ok(code) = ConnectReturnCode::try_from(value) {
+                assert_eq!(u8::from(code), value);
+            }
+        }
+        assert_eq!(
+            ConnectReturnCode::try_from(0x8A).unwrap(),
+            ConnectReturnCode::Banned
+        );
+
+        assert!(ConnectReturnCode::try_from(6).is_err());
+        assert!(ConnectReturnCode::try_from(0x8B).is_err());
+        assert!(ConnectReturnCode::try_from(0xA0).is_err());
+    }
+}
@@ -1,10 +1,10 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 
 use crate::{
     error::MqttError,
     packets::{
         enums::{PacketType, QosLevel},
-        utils::decode_length,
+        utils::{decode_length, unpack_u8},
     },
 };
 
@@ -147,17 +147,13 @@ impl FixedHeader {
     pub fn get_rl_len(&self) -> usize {
         self.remaining_length_bytes
     }
-    pub fn from_bytes<'a, I>(iter: &mut I, _: Option<&FixedHeader>) -> Result<Self, MqttError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
+    pub fn from_bytes<B: Buf>(buf: &mut B, _: Option<&FixedHeader>) -> Result<Self, MqttError> {
         let mut header = FixedHeader::from(
-            *iter
-                .next()
-                .ok_or_else(|| MqttError::RequiredByteMissing("Missing Fixed header byte"))?,
+            unpack_u8(buf)
+                .map_err(|_| MqttError::RequiredByteMissing("Missing Fixed header byte"))?,
         );
 
-        let (len, bytes) = decode_length(iter)?;
+        let (len, bytes) = decode_length(buf)?;
         header.remaining_len = len;
         header.remaining_length_bytes = bytes;
 
@@ -201,6 +197,8 @@ impl Default for FixedHeader {
 
 #[cfg(test)]
 mod tests {
+    use bytes::Bytes;
+
     use super::*;
 
     #[test]
@@ -208,9 +206,7 @@ mod tests {
         // Fixed Header
         // length: 0,
 
-        let data: [u8; 2] = [0x10, 0x07];
-
-        let mut iter = data.iter();
+        let mut iter = Bytes::from_static(&[0x10, 0x07]);
 
         let header = FixedHeader::from_bytes(&mut iter, None).expect("Failed to parse header");
 
@@ -1,3 +1,4 @@
 pub mod connack;
 pub mod connect;
 pub mod fixed_header;
+pub mod subscribe;
@@ -0,0 +1,146 @@
+use crate::{error::MqttError, packets::enums::QosLevel};
+
+/// ### Subscription Options
+/// Each Topic Filter of a SUBSCRIBE packet is followed by a Subscription Options byte.
+/// For v3.1.1 clients only the QoS bits are used and the rest of the byte is reserved.
+///
+/// |Bit|    7-6   |       5-4        |           3           |    2     |   1-0   |
+/// | - | - | - | - | - | - |
+/// |   | Reserved | Retain Handling  | Retain As Published   | No Local | QoS     |
+///
+/// [(MQTT 5) 3.8.3.1 Subscription Options](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901169)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionOptions(u8);
+
+impl SubscriptionOptions {
+    pub fn new(qos: QosLevel, no_local: bool, retain_as_published: bool) -> Self {
+        let mut bit: u8 = 0x00;
+        bit |= qos as u8;
+        bit |= (no_local as u8) << 2;
+        bit |= (retain_as_published as u8) << 3;
+
+        Self(bit)
+    }
+
+    /// Maximum QoS level at which the Server can send Application Messages to the Client.
+    pub fn qos(&self) -> Result<QosLevel, MqttError> {
+        QosLevel::try_from(self.0 & 0x03)
+    }
+
+    /// The options with the QoS lowered to `max`, the Server may grant a lower QoS than the Client requested.
+    pub fn with_max_qos(self, max: QosLevel) -> Self {
+        match self.qos() {
+            Ok(qos) if qos > max => Self((self.0 & !0x03) | max as u8),
+            _ => self,
+        }
+    }
+
+    /// #### No Local
+    /// If 1, Application Messages MUST NOT be forwarded to a connection with a ClientID equal to the ClientID of the publishing connection.
+    pub fn no_local(&self) -> bool {
+        (self.0 & 0x04) >> 2 == 1
+    }
+
+    /// #### Retain As Published
+    /// If 1, Application Messages forwarded using this subscription keep the RETAIN flag they were published with.
+    /// If 0, Application Messages forwarded using this subscription have the RETAIN flag set to 0.
+    /// Retained messages sent when the subscription is established have the RETAIN flag set to 1.
+    pub fn retain_as_published(&self) -> bool {
+        (self.0 & 0x08) >> 3 == 1
+    }
+
+    /// #### Retain Handling
+    /// Whether retained messages are sent when the subscription is established.
+    /// A Retain Handling value of 3 makes the SUBSCRIBE malformed.
+    pub fn retain_handling(&self) -> Result<RetainHandling, MqttError> {
+        RetainHandling::try_from((self.0 & 0x30) >> 4)
+    }
+}
+
+/// ### Retain Handling
+/// Bits 4 and 5 of the Subscription Options, always 0 for v3.1.1 clients.
+///
+/// [(MQTT 5) 3.8.3.1 Subscription Options](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901169)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RetainHandling {
+    /// Send retained messages at the time of the subscribe
+    #[default]
+    SendOnSubscribe = 0,
+    /// Send retained messages at subscribe only if the subscription does not currently exist
+    SendIfNew = 1,
+    /// Do not send retained messages at the time of the subscribe
+    DoNotSend = 2,
+}
+
+impl TryFrom<u8> for RetainHandling {
+    type Error = MqttError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::SendOnSubscribe),
+            1 => Ok(Self::SendIfNew),
+            2 => Ok(Self::DoNotSend),
+            _ => Err(MqttError::ProtocolViolation),
+        }
+    }
+}
+
+impl From<QosLevel> for SubscriptionOptions {
+    fn from(value: QosLevel) -> Self {
+        Self::new(value, false, false)
+    }
+}
+
+impl From<SubscriptionOptions> for u8 {
+    fn from(value: SubscriptionOptions) -> Self {
+        value.0
+    }
+}
+
+impl From<u8> for SubscriptionOptions {
+    fn from(value: u8) -> Self {
+        Self(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscription_options() {
+        let options = SubscriptionOptions::from(0x09);
+
+        assert_eq!(options.qos().unwrap(), QosLevel::AtLeast);
+        assert!(!options.no_local());
+        assert!(options.retain_as_published());
+
+        let options = SubscriptionOptions::new(QosLevel::AtMost, true, false);
+        assert_eq!(u8::from(options), 0x04);
+        assert!(options.no_local());
+
+        let options = SubscriptionOptions::from(QosLevel::Exactly);
+        assert_eq!(u8::from(options), 0x02);
+        assert!(!options.retain_as_published());
+
+        let options = SubscriptionOptions::new(QosLevel::Exactly, true, true);
+        assert_eq!(u8::from(options.with_max_qos(QosLevel::AtLeast)), 0x0D);
+        assert_eq!(options.with_max_qos(QosLevel::Exactly), options);
+        assert_eq!(
+            options.retain_handling().unwrap(),
+            RetainHandling::SendOnSubscribe
+        );
+
+        let options = SubscriptionOptions::from(0x21);
+        assert_eq!(options.qos().unwrap(), QosLevel::AtLeast);
+        assert_eq!(
+            options.retain_handling().unwrap(),
+            RetainHandling::DoNotSend
+        );
+        assert_eq!(
+            SubscriptionOptions::from(0x10).retain_handling().unwrap(),
+            RetainHandling::SendIfNew
+        );
+        assert!(SubscriptionOptions::from(0x30).retain_handling().is_err());
+    }
+}
@@ -1,6 +1,6 @@
-use std::mem::size_of;
+use std::cell::RefCell;
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::{
     core::enums::ProtocalVersion,
@@ -9,22 +9,79 @@ use crate::{
 };
 
 use self::{
-    enums::{ConnectReturnCode, QosLevel, SubackReturnCode},
+    enums::{
+        ConnectReturnCode, DisconnectReasonCode, QosLevel, SubackReturnCode, UnsubackReasonCode,
+    },
     headers::{connack::AcknowledgeFlags, connect::Flags},
-    utils::{encode_length, unpack_bytes, unpack_properties, unpack_string, unpack_u16},
+    utils::{
+        encode_length, length_size, unpack_bytes, unpack_properties, unpack_string, unpack_u16,
+        unpack_u8, Props,
+    },
 };
 
 #[repr(u8)]
-#[derive(Debug)]
-enum PayloadFormat {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
     Unspecified,
     EncodedUTF8,
 }
 
+/// ### Publish Properties
+/// The v5 properties of a PUBLISH that are forwarded to the subscribers unchanged.
+///
+/// [(MQTT 5) 3.3.2.3 PUBLISH Properties](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901109)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PublishProperties {
+    /// `None` when the publisher did not send a Payload Format Indicator
+    pub payload_format_indicator: Option<PayloadFormat>,
+    /// Description of the payload, eg. a MIME type
+    pub content_type: Option<String>,
+    /// User Properties in the order they were sent by the publisher
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl PublishProperties {
+    /// Does the payload match the Payload Format Indicator, only UTF-8 payloads are checked.
+    pub fn is_valid_payload(&self, payload: &[u8]) -> bool {
+        self.payload_format_indicator != Some(PayloadFormat::EncodedUTF8)
+            || std::str::from_utf8(payload).is_ok()
+    }
+}
+
+/// Bytes reserved at a time for the packets split off of the output buffer
+const OUTPUT_CAPACITY: usize = 8 * 1024;
+
+/// Set in the protocol level of a CONNECT from a bridge, eg. `0x84` for a v3.1.1 bridge
+const BRIDGE_PROTOCOL_BIT: u8 = 0x80;
+
+thread_local! {
+    /// Output buffer of [`Packet::pack`]
+    static OUTPUT: RefCell<BytesMut> = RefCell::new(BytesMut::with_capacity(OUTPUT_CAPACITY));
+}
+
+/// ### Server Capabilities
+/// Optional features the Server tells v5 clients about in the CONNACK.
+/// A property is only sent when it is `Some`, a missing property means the feature is available.
+///
+/// [(MQTT 5) 3.2.2.3 CONNACK Properties](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901080)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    pub maximum_qos: Option<QosLevel>,
+    pub retain_available: Option<bool>,
+    pub wildcard_subscription_available: Option<bool>,
+    pub shared_subscription_available: Option<bool>,
+    pub maximum_packet_size: Option<u32>,
+}
+
+/// ### PUBACK and PUBREC Reason Code
+/// Only sent by v5 clients and servers, omitted when it is Success and there are no properties.
+///
+/// [(MQTT 5) 3.4.2.1 PUBACK Reason Code](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901124)
 #[repr(u8)]
-#[derive(Debug)]
-enum PubRecReasonCode {
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PubRecReasonCode {
     /// The message is accepted. Publication of the QoS 2 message proceeds.
+    #[default]
     Success = 0x00,
     /// The message is accepted but there are no subscribers.
     /// This is sent only by the Server. If the Server knows that there are no matching subscribers,
@@ -46,20 +103,87 @@ enum PubRecReasonCode {
     PayloadFormatInvalid = 0x99,
 }
 
+impl PubRecReasonCode {
+    /// The message was not accepted, a PUBREC with a failure ends the QoS 2 exchange without a PUBREL
+    pub fn is_failure(&self) -> bool {
+        *self as u8 >= 0x80
+    }
+}
+
+impl From<PubRecReasonCode> for u8 {
+    fn from(value: PubRecReasonCode) -> Self {
+        value as u8
+    }
+}
+
+impl TryFrom<u8> for PubRecReasonCode {
+    type Error = MqttError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Success),
+            0x10 => Ok(Self::NoMatchingSubscribers),
+            0x80 => Ok(Self::UnspecifiedError),
+            0x83 => Ok(Self::ImplementationSpecificError),
+            0x87 => Ok(Self::NotAuthorized),
+            0x90 => Ok(Self::TopicNameInvalid),
+            0x91 => Ok(Self::PacketIdentifierInUse),
+            0x97 => Ok(Self::QuotaExceeded),
+            0x99 => Ok(Self::PayloadFormatInvalid),
+            _ => Err(MqttError::Convertion(
+                value.to_string(),
+                "PubRecReasonCode".into(),
+            )),
+        }
+    }
+}
+
+/// ### PUBREL and PUBCOMP Reason Code
+/// Only sent by v5 clients and servers, omitted when it is Success and there are no properties.
+///
+/// [(MQTT 5) 3.6.2.1 PUBREL Reason Code](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901144)
 #[repr(u8)]
-#[derive(Debug)]
-enum PubReasonCode {
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PubReasonCode {
     /// Message released.
+    #[default]
     Success = 0x00,
     /// The Packet Identifier is not known. This is not an error during recovery,
     /// but at other times indicates a mismatch between the Session State on the Client and Server.
     PacketIdentifierNotFound = 0x92,
 }
 
+impl From<PubReasonCode> for u8 {
+    fn from(value: PubReasonCode) -> Self {
+        value as u8
+    }
+}
+
+impl TryFrom<u8> for PubReasonCode {
+    type Error = MqttError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Success),
+            0x92 => Ok(Self::PacketIdentifierNotFound),
+            _ => Err(MqttError::Convertion(
+                value.to_string(),
+                "PubReasonCode".into(),
+            )),
+        }
+    }
+}
+
+pub mod codec;
+#[cfg(test)]
+mod conformance;
 pub mod enums;
 mod headers;
+pub mod topic;
 mod utils;
 
+pub use self::headers::subscribe::{RetainHandling, SubscriptionOptions};
+
 #[derive(Debug)]
 pub enum VariableHeader {
     Connect {
@@ -72,8 +196,13 @@ pub enum VariableHeader {
         username: Option<String>,
         password: Option<String>,
         will_topic: Option<String>,
-        will_message: Option<String>,
+        will_message: Option<Bytes>,
+        /// Seconds to wait before publishing the will, v5 only
+        will_delay_interval: Option<u32>,
         protocol_version: ProtocalVersion,
+        /// The top bit of the protocol level is set, sent by bridges like mosquitto's `try_private`
+        /// so the server does not send their own messages back to them
+        bridge: bool,
 
         session_expiry_interval: Option<u32>,
         receive_maximum: Option<u16>,
@@ -111,12 +240,12 @@ pub enum VariableHeader {
         // Header
         packet_id: u16,
         // Properties
-        subscription_identifier: Option<String>,
+        subscription_identifier: Option<u32>,
         user_property: Option<Vec<(String, String)>>,
         // payload
         // V4 (topic,qos)
         // V5 (topic, (Retain,RAP,NL,QOS))
-        tuples: Vec<(String, QosLevel)>,
+        tuples: Vec<(String, SubscriptionOptions)>,
     },
     Unsubscribe {
         packet_id: u16,
@@ -137,7 +266,8 @@ pub enum VariableHeader {
         response_topic: Option<String>,
         correlation_data: Option<Bytes>,
         user_property: Option<Vec<(String, String)>>,
-        subscription_identifier: Option<u32>,
+        /// Identifiers of the matching subscriptions, only sent to v5 clients
+        subscription_identifiers: Vec<u32>,
         content_type: Option<String>,
         // End V5 Props
         payload: Bytes,
@@ -153,6 +283,7 @@ pub enum VariableHeader {
     },
     PubAck {
         packet_id: u16,
+        reason_code: PubRecReasonCode, // V5
         reason_string: Option<String>,
         user_property: Option<Vec<(String, String)>>,
     },
@@ -185,12 +316,13 @@ pub enum VariableHeader {
         reason_string: Option<String>,
         user_property: Option<Vec<(String, String)>>,
 
-        reason_codes: Vec<u8>,
+        /// One for each Topic Filter of the UNSUBSCRIBE, empty for v3.1.1
+        reason_codes: Vec<UnsubackReasonCode>,
     },
     PingReq,
     PingResp,
     Disconnect {
-        reason_code: u8,
+        reason_code: DisconnectReasonCode,
         session_expiry_interval: Option<u32>,
         reason_string: Option<String>,
         user_property: Option<Vec<(String, String)>>,
@@ -206,36 +338,200 @@ pub enum VariableHeader {
 }
 
 impl VariableHeader {
-    fn pack(self) -> Bytes {
-        let mut bytes = BytesMut::new();
+    /// Number of bytes [`VariableHeader::pack`] writes, the Remaining Length of the packet
+    fn size(&self, protocol: ProtocalVersion) -> usize {
+        let is_v5 = protocol == ProtocalVersion::Five;
+        let properties = self.properties_size();
+        // The Property Length and the properties, for the packets that always have them in v5
+        let v5_properties = if is_v5 {
+            length_size(properties) + properties
+        } else {
+            0
+        };
 
         match self {
             VariableHeader::Connect {
                 flags,
-                keepalive,
                 client_id,
                 username,
                 password,
                 will_topic,
                 will_message,
                 protocol_version,
-                session_expiry_interval,
+                ..
+            } => {
+                let name = match protocol_version {
+                    ProtocalVersion::Three => 6,
+                    _ => 4,
+                };
+                // protocol name, level, flags, keep alive and client id
+                let mut size = 2 + name + 1 + 1 + 2 + 2 + client_id.len();
+                if flags.will() {
+                    size += will_topic.as_ref().map_or(0, |topic| 2 + topic.len());
+                    size += will_message.as_ref().map_or(0, |message| 2 + message.len());
+                }
+                if flags.has_username() {
+                    size += username.as_ref().map_or(0, |usr| 2 + usr.len());
+                }
+                if flags.has_password() {
+                    size += password.as_ref().map_or(0, |psd| 2 + psd.len());
+                }
+                size
+            }
+            VariableHeader::ConnAck { .. } => 2 + v5_properties,
+            VariableHeader::Subscribe { tuples, .. } => {
+                2 + v5_properties
+                    + tuples
+                        .iter()
+                        .map(|(topic, _)| 2 + topic.len() + 1)
+                        .sum::<usize>()
+            }
+            VariableHeader::Unsubscribe { tuples, .. } => {
+                2 + v5_properties + tuples.iter().map(|topic| 2 + topic.len()).sum::<usize>()
+            }
+            VariableHeader::Publish {
+                topic,
+                packet_id,
+                payload,
+                ..
+            } => 2 + topic.len() + packet_id.map_or(0, |_| 2) + v5_properties + payload.len(),
+            VariableHeader::SubAck { return_codes, .. } => 2 + v5_properties + return_codes.len(),
+            VariableHeader::Disconnect { reason_code, .. } => {
+                if *reason_code != DisconnectReasonCode::NormalDisconnection || properties > 0 {
+                    1 + length_size(properties) + properties
+                } else {
+                    0
+                }
+            }
+            VariableHeader::UnsubAck { reason_codes, .. } => {
+                2 + if is_v5 { 1 + reason_codes.len() } else { 0 }
+            }
+            VariableHeader::PubAck { reason_code, .. }
+            | VariableHeader::PubRec { reason_code, .. } => {
+                ack_size(u8::from(*reason_code), properties, is_v5)
+            }
+            VariableHeader::PubRel { reason_code, .. }
+            | VariableHeader::PubComp { reason_code, .. } => {
+                ack_size(u8::from(*reason_code), properties, is_v5)
+            }
+            VariableHeader::Auth { .. } | VariableHeader::PingReq | VariableHeader::PingResp => 0,
+        }
+    }
+
+    /// Number of bytes of the v5 properties [`VariableHeader::pack`] writes, without the Property Length
+    fn properties_size(&self) -> usize {
+        match self {
+            VariableHeader::ConnAck {
                 receive_maximum,
+                maximum_qos,
+                retain_available,
                 maximum_packet_size,
-                topic_alias_maximum,
-                request_response_info,
-                request_problem_info,
-                user_properties,
-                auth_method,
-                auth_data,
+                wildcard_subscription_available,
+                shared_subscription_available,
+                assigned_client_identifier,
+                reason_string,
+                user_property,
+                server_refernce,
+                ..
+            } => {
+                receive_maximum.map_or(0, |_| 3)
+                    + maximum_qos.map_or(0, |_| 2)
+                    + retain_available.map_or(0, |_| 2)
+                    + maximum_packet_size.map_or(0, |_| 5)
+                    + wildcard_subscription_available.map_or(0, |_| 2)
+                    + shared_subscription_available.map_or(0, |_| 2)
+                    + string_property_size(assigned_client_identifier.as_deref())
+                    + string_property_size(reason_string.as_deref())
+                    + user_properties_size(user_property.as_deref())
+                    + string_property_size(server_refernce.as_deref())
+            }
+            VariableHeader::Publish {
+                payload_format_indicator,
+                content_type,
+                user_property,
+                subscription_identifiers,
+                ..
+            } => {
+                payload_format_indicator.map_or(0, |_| 2)
+                    + string_property_size(content_type.as_deref())
+                    + user_properties_size(user_property.as_deref())
+                    + subscription_identifiers
+                        .iter()
+                        .map(|id| 1 + length_size(*id as usize))
+                        .sum::<usize>()
+            }
+            VariableHeader::SubAck {
+                reason_string,
+                user_property,
+                ..
+            }
+            | VariableHeader::PubAck {
+                reason_string,
+                user_property,
+                ..
+            }
+            | VariableHeader::PubRec {
+                reason_string,
+                user_property,
+                ..
+            }
+            | VariableHeader::PubRel {
+                reason_string,
+                user_property,
+                ..
+            }
+            | VariableHeader::PubComp {
+                reason_string,
+                user_property,
+                ..
+            } => {
+                string_property_size(reason_string.as_deref())
+                    + user_properties_size(user_property.as_deref())
+            }
+            VariableHeader::Disconnect {
+                reason_string,
+                server_reference,
+                ..
+            } => {
+                string_property_size(reason_string.as_deref())
+                    + string_property_size(server_reference.as_deref())
+            }
+            _ => 0,
+        }
+    }
+
+    /// Write the variable header and payload to `bytes`, [`VariableHeader::size`] bytes in all.
+    ///
+    /// The properties are written straight after their Property Length, which is known from [`VariableHeader::properties_size`].
+    fn pack(self, protocol: ProtocalVersion, bytes: &mut BytesMut) {
+        let is_v5 = protocol == ProtocalVersion::Five;
+        let properties = self.properties_size();
+
+        match self {
+            VariableHeader::Connect {
+                flags,
+                keepalive,
+                client_id,
+                username,
+                password,
+                will_topic,
+                will_message,
+                protocol_version,
+                bridge,
+                ..
             } => {
                 let will = flags.will();
                 let has_psd = flags.has_password();
                 let has_usr = flags.has_username();
 
-                bytes.put_u16(4); // str len
-                bytes.put_slice(b"MQTT");
-                bytes.put_u8(protocol_version.into()); // protocal version
+                let name: &[u8] = match protocol_version {
+                    ProtocalVersion::Three => b"MQIsdp",
+                    _ => b"MQTT",
+                };
+                bytes.put_u16(name.len() as u16); // str len
+                bytes.put_slice(name);
+                let bridge_bit = if bridge { BRIDGE_PROTOCOL_BIT } else { 0 };
+                bytes.put_u8(u8::from(protocol_version) | bridge_bit); // protocal version
                 bytes.put_u8(flags.into());
                 bytes.put_u16(keepalive);
                 bytes.put_u16(client_id.len() as u16);
@@ -248,7 +544,7 @@ impl VariableHeader {
                     }
                     if let Some(wm) = will_message {
                         bytes.put_u16(wm.len() as u16);
-                        bytes.put(wm.as_bytes());
+                        bytes.put(wm);
                     }
                 }
 
@@ -269,19 +565,66 @@ impl VariableHeader {
             VariableHeader::ConnAck {
                 acknowledge_flags,
                 return_code,
+                receive_maximum,
+                maximum_qos,
+                retain_available,
+                maximum_packet_size,
+                wildcard_subscription_available,
+                shared_subscription_available,
+                assigned_client_identifier,
+                reason_string,
+                user_property,
+                server_refernce,
                 ..
             } => {
                 bytes.put_u8(acknowledge_flags.into());
-                bytes.put_u8(return_code.into());
+                bytes.put_u8(return_code.for_protocol(protocol).into());
+
+                if is_v5 {
+                    encode_length(properties, bytes);
+                    if let Some(value) = receive_maximum {
+                        bytes.put_u8(0x21);
+                        bytes.put_u16(value);
+                    }
+                    if let Some(qos) = maximum_qos {
+                        bytes.put_u8(0x24);
+                        bytes.put_u8(qos as u8);
+                    }
+                    if let Some(available) = retain_available {
+                        bytes.put_u8(0x25);
+                        bytes.put_u8(available.into());
+                    }
+                    if let Some(size) = maximum_packet_size {
+                        bytes.put_u8(0x27);
+                        bytes.put_u32(size);
+                    }
+                    if let Some(available) = wildcard_subscription_available {
+                        bytes.put_u8(0x28);
+                        bytes.put_u8(available.into());
+                    }
+                    if let Some(available) = shared_subscription_available {
+                        bytes.put_u8(0x2A);
+                        bytes.put_u8(available.into());
+                    }
+                    put_string_property(bytes, 0x12, assigned_client_identifier);
+                    put_string_property(bytes, 0x1F, reason_string);
+                    put_user_properties(bytes, user_property);
+                    put_string_property(bytes, 0x1C, server_refernce);
+                }
             }
             VariableHeader::Subscribe {
                 packet_id, tuples, ..
             } => {
                 bytes.put_u16(packet_id);
-                for (topic, qos) in tuples {
+
+                if is_v5 {
+                    encode_length(0, bytes);
+                }
+
+                for (topic, options) in tuples {
                     bytes.put_u16(topic.len() as u16);
                     bytes.put(topic.as_bytes());
-                    bytes.put_u8(qos.into());
+                    bytes.put_u8(options.into());
                 }
             }
             VariableHeader::Unsubscribe {
@@ -289,6 +632,10 @@ impl VariableHeader {
             } => {
                 bytes.put_u16(packet_id);
 
+                if is_v5 {
+                    encode_length(0, bytes);
+                }
+
                 for x in tuples {
                     bytes.put_u16(x.len() as u16);
                     bytes.put(x.as_bytes());
@@ -298,6 +645,10 @@ impl VariableHeader {
                 topic,
                 packet_id,
                 payload,
+                subscription_identifiers,
+                payload_format_indicator,
+                content_type,
+                user_property,
                 ..
             } => {
                 bytes.put_u16(topic.len() as u16);
@@ -307,118 +658,206 @@ impl VariableHeader {
                     bytes.put_u16(id);
                 }
 
+                if is_v5 {
+                    encode_length(properties, bytes);
+                    if let Some(format) = payload_format_indicator {
+                        bytes.put_u8(0x01);
+                        bytes.put_u8(format as u8);
+                    }
+                    put_string_property(bytes, 0x03, content_type);
+                    put_user_properties(bytes, user_property);
+                    for id in subscription_identifiers {
+                        bytes.put_u8(0x0B);
+                        encode_length(id as usize, bytes);
+                    }
+                }
+
                 bytes.put(payload);
             }
             VariableHeader::SubAck {
                 packet_id,
                 return_codes,
-                ..
+                reason_string,
+                user_property,
             } => {
                 bytes.put_u16(packet_id);
+
+                if is_v5 {
+                    encode_length(properties, bytes);
+                    put_string_property(bytes, 0x1F, reason_string);
+                    put_user_properties(bytes, user_property);
+                }
+
                 for code in return_codes {
-                    bytes.put_u8(code.into());
+                    bytes.put_u8(code.for_protocol(protocol).into());
+                }
+            }
+            VariableHeader::Disconnect {
+                reason_code,
+                reason_string,
+                server_reference,
+                ..
+            } => {
+                // The Reason Code and Property Length can be omitted if the Reason Code is 0x00 (Normal disconnecton) and there are no Properties.
+                if reason_code != DisconnectReasonCode::NormalDisconnection || properties > 0 {
+                    bytes.put_u8(reason_code.into());
+                    encode_length(properties, bytes);
+                    put_string_property(bytes, 0x1F, reason_string);
+                    put_string_property(bytes, 0x1C, server_reference);
                 }
             }
-            VariableHeader::Disconnect { .. } => {}
             VariableHeader::Auth { .. } => {}
-            VariableHeader::UnsubAck { packet_id, .. }
-            | VariableHeader::PubComp { packet_id, .. }
-            | VariableHeader::PubRel { packet_id, .. }
-            | VariableHeader::PubRec { packet_id, .. }
-            | VariableHeader::PubAck { packet_id, .. } => {
+            VariableHeader::UnsubAck {
+                packet_id,
+                reason_codes,
+                ..
+            } => {
                 bytes.put_u16(packet_id);
+
+                if is_v5 {
+                    encode_length(0, bytes);
+                    for code in reason_codes {
+                        bytes.put_u8(code.into());
+                    }
+                }
+            }
+            VariableHeader::PubAck {
+                packet_id,
+                reason_code,
+                reason_string,
+                user_property,
+            }
+            | VariableHeader::PubRec {
+                packet_id,
+                reason_code,
+                reason_string,
+                user_property,
+            } => pack_ack(
+                bytes,
+                packet_id,
+                reason_code.into(),
+                properties,
+                reason_string,
+                user_property,
+                is_v5,
+            ),
+            VariableHeader::PubRel {
+                packet_id,
+                reason_code,
+                reason_string,
+                user_property,
             }
+            | VariableHeader::PubComp {
+                packet_id,
+                reason_code,
+                reason_string,
+                user_property,
+            } => pack_ack(
+                bytes,
+                packet_id,
+                reason_code.into(),
+                properties,
+                reason_string,
+                user_property,
+                is_v5,
+            ),
 
             VariableHeader::PingReq | VariableHeader::PingResp => {}
         }
-
-        bytes.freeze()
     }
-    fn unpack<'a, I>(
-        iter: &mut I,
+    /// Unpack the variable header and payload from the body of a packet.
+    ///
+    /// `body` must contain exactly the Remaining Length bytes of the packet,
+    /// slices of it (like the PUBLISH payload) are kept without copying.
+    fn unpack(
+        body: &mut Bytes,
         fixed: &FixedHeader,
-        _protocal: ProtocalVersion,
-    ) -> Result<Self, MqttError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
-        match fixed.get_packet_type()? {
+        protocal: ProtocalVersion,
+    ) -> Result<Self, MqttError> {
+        let is_v5 = protocal == ProtocalVersion::Five;
+
+        let packet_type = fixed.get_packet_type()?;
+        // Every packet but the CONNECT is laid out for the protocol version the CONNECT set
+        if protocal == ProtocalVersion::Unknown && packet_type != PacketType::Connect {
+            return Err(MqttError::ProtocolNotNegotiated);
+        }
+
+        match packet_type {
             PacketType::Connect => {
                 //  ===== Start Connect header =======
 
-                let protocal_name = unpack_string(iter)?;
-                if &protocal_name != "MQTT" {
+                // MQTT 3.1 uses the protocol name MQIsdp with level 3
+                let protocal_name = unpack_string(body)?;
+                if protocal_name != "MQTT" && protocal_name != "MQIsdp" {
                     return Err(MqttError::UnknownProtocol);
                 }
 
-                let protocol_version = ProtocalVersion::from(
-                    *iter
-                        .next()
-                        .ok_or_else(|| MqttError::RequiredByteMissing("Missing protocal byte"))?,
-                );
+                let level = unpack_u8(body)
+                    .map_err(|_| MqttError::RequiredByteMissing("Missing protocal byte"))?;
+                let bridge = level & BRIDGE_PROTOCOL_BIT != 0;
+                let protocol_version = ProtocalVersion::from(level & !BRIDGE_PROTOCOL_BIT);
 
-                if protocol_version != ProtocalVersion::Four
-                /*|| protocol_version != ProtocalVersion::Five*/
-                {
-                    return Err(MqttError::UnacceptableProtocolLevel);
+                match (protocal_name.as_str(), protocol_version) {
+                    ("MQTT", ProtocalVersion::Four | ProtocalVersion::Five)
+                    | ("MQIsdp", ProtocalVersion::Three) => {}
+                    _ => return Err(MqttError::UnacceptableProtocolLevel),
                 }
 
                 let flags = Flags::from(
-                    iter.next()
-                        .ok_or_else(|| MqttError::RequiredByteMissing("Missing connect flags"))?,
+                    unpack_u8(body)
+                        .map_err(|_| MqttError::RequiredByteMissing("Missing connect flags"))?,
                 );
 
                 if flags.validate_flags() {
                     return Err(MqttError::MalformedHeader);
                 }
 
-                let keepalive = unpack_u16(iter)?;
+                let keepalive = unpack_u16(body)?;
 
                 let props = if protocol_version == ProtocalVersion::Five {
-                    Some(unpack_properties(iter)?)
+                    Some(unpack_properties(body)?)
                 } else {
                     None
                 };
 
+                let receive_maximum = props.as_ref().and_then(|props| props.reveive_maximum);
+                // It is a Protocol Error to include the Receive Maximum value more than once or for it to have the value 0.
+                if receive_maximum == Some(0) {
+                    return Err(MqttError::ProtocolViolation);
+                }
+
                 //  ===== End Connect header =======
                 //  ===== Start Connect Payload =====
 
-                let client_id = {
-                    let id = unpack_string(iter)?;
-
-                    if id.is_empty() {
-                        if !flags.clean_session() {
-                            return Err(MqttError::ClientIdentifierRejected);
-                        }
-                        uuid::Uuid::new_v4().to_string()
-                    } else {
-                        id
-                    }
-                };
+                // An empty client id is resolved by the server's client id policy
+                let client_id = unpack_string(body)?;
 
                 let (will_topic, will_message, will_props) = if flags.will() {
                     let props = if protocol_version == ProtocalVersion::Five {
-                        Some(unpack_properties(iter)?)
+                        Some(unpack_properties(body)?)
                     } else {
                         None
                     };
 
-                    let will_topic = unpack_string(iter)?;
-                    let will_payload = unpack_string(iter)?;
+                    let will_topic = unpack_string(body)?;
+                    // The Will Payload is Binary Data
+                    let len = unpack_u16(body)?;
+                    let will_payload = unpack_bytes(body, len as usize)?;
 
                     (Some(will_topic), Some(will_payload), props)
                 } else {
                     (None, None, None)
                 };
+                let will_delay_interval = will_props.and_then(|props| props.will_delay_interval);
 
                 let username = if flags.has_username() {
-                    Some(unpack_string(iter)?)
+                    Some(unpack_string(body)?)
                 } else {
                     None
                 };
 
                 let password = if flags.has_password() {
-                    Some(unpack_string(iter)?)
+                    Some(unpack_string(body)?)
                 } else {
                     None
                 };
@@ -431,9 +870,11 @@ impl VariableHeader {
                     password,
                     will_topic,
                     will_message,
+                    will_delay_interval,
                     protocol_version,
+                    bridge,
                     session_expiry_interval: None,
-                    receive_maximum: None,
+                    receive_maximum,
                     maximum_packet_size: None,
                     topic_alias_maximum: None,
                     request_response_info: None,
@@ -444,11 +885,14 @@ impl VariableHeader {
                 })
             }
             PacketType::Connack => {
-                let flags_byte = iter.next().ok_or_else(|| MqttError::MissingByte)?;
-                let flags = AcknowledgeFlags::from(*flags_byte);
+                let flags = AcknowledgeFlags::from(unpack_u8(body)?);
 
-                let rc_byte = iter.next().ok_or_else(|| MqttError::MissingByte)?;
-                let rc = ConnectReturnCode::try_from(rc_byte)?;
+                let rc = ConnectReturnCode::try_from(unpack_u8(body)?)?;
+                let props = if is_v5 {
+                    unpack_properties(body)?
+                } else {
+                    Props::default()
+                };
 
                 Ok(Self::ConnAck {
                     acknowledge_flags: flags,
@@ -460,8 +904,8 @@ impl VariableHeader {
                     maximum_packet_size: None,
                     assigned_client_identifier: None,
                     topic_alias_maximum: None,
-                    reason_string: None,
-                    user_property: None,
+                    reason_string: props.reason_string,
+                    user_property: props.user_property,
                     wildcard_subscription_available: None,
                     subscription_identifiers_available: None,
                     shared_subscription_available: None,
@@ -473,105 +917,135 @@ impl VariableHeader {
                 })
             }
             PacketType::Publish => {
-                let topic = unpack_string(iter)?;
-
-                /*
-                 * Message len is calculated subtracting the length of the variable header
-                 * from the Remaining Length field that is in the Fixed Header
-                 */
-                let mut len = fixed.get_remaing_len();
+                let topic = unpack_string(body)?;
+                topic::validate_topic_name(&topic)?;
 
                 let packet_id = if fixed.get_qos()? > QosLevel::AtMost {
-                    let id = Some(unpack_u16(iter)?);
-                    len -= size_of::<u16>();
-                    id
+                    Some(unpack_u16(body)?)
                 } else {
                     None
                 };
 
-                len -= size_of::<u16>() + topic.len();
+                let props = if is_v5 {
+                    unpack_properties(body)?
+                } else {
+                    Props::default()
+                };
+                let subscription_identifiers = props
+                    .subscription_identifers
+                    .into_iter()
+                    .map(|id| id as u32)
+                    .collect();
+                let payload_format_indicator = props.payload_format_indicator.map(|utf8| {
+                    if utf8 {
+                        PayloadFormat::EncodedUTF8
+                    } else {
+                        PayloadFormat::Unspecified
+                    }
+                });
 
-                let payload = unpack_bytes(iter, len)?;
+                // The rest of the packet is the message, this is a view into the read buffer.
+                let payload = unpack_bytes(body, body.remaining())?;
 
                 Ok(Self::Publish {
                     topic,
                     packet_id,
                     payload,
-                    payload_format_indicator: None,
+                    payload_format_indicator,
                     message_expiry_interval: None,
                     topic_alias: None,
                     response_topic: None,
                     correlation_data: None,
-                    user_property: None,
-                    subscription_identifier: None,
-                    content_type: None,
+                    user_property: props.user_property,
+                    subscription_identifiers,
+                    content_type: props.content_type,
                 })
             }
             PacketType::Puback => {
-                let id = unpack_u16(iter)?;
+                let id = unpack_u16(body)?;
+                let (reason_code, props) = unpack_ack(body, is_v5)?;
                 Ok(Self::PubAck {
                     packet_id: id,
-                    reason_string: None,
-                    user_property: None,
+                    reason_code: PubRecReasonCode::try_from(reason_code)?,
+                    reason_string: props.reason_string,
+                    user_property: props.user_property,
                 })
             }
             PacketType::Pubrec => {
-                let id = unpack_u16(iter)?;
+                let id = unpack_u16(body)?;
+                let (reason_code, props) = unpack_ack(body, is_v5)?;
                 Ok(Self::PubRec {
                     packet_id: id,
-                    reason_code: PubRecReasonCode::Success,
-                    reason_string: None,
-                    user_property: None,
+                    reason_code: PubRecReasonCode::try_from(reason_code)?,
+                    reason_string: props.reason_string,
+                    user_property: props.user_property,
                 })
             }
             PacketType::Pubrel => {
-                let id = unpack_u16(iter)?;
+                let id = unpack_u16(body)?;
+                let (reason_code, props) = unpack_ack(body, is_v5)?;
                 Ok(Self::PubRel {
                     packet_id: id,
-                    reason_code: PubReasonCode::Success,
-                    reason_string: None,
-                    user_property: None,
+                    reason_code: PubReasonCode::try_from(reason_code)?,
+                    reason_string: props.reason_string,
+                    user_property: props.user_property,
                 })
             }
             PacketType::Pubcomp => {
-                let id = unpack_u16(iter)?;
+                let id = unpack_u16(body)?;
+                let (reason_code, props) = unpack_ack(body, is_v5)?;
                 Ok(Self::PubComp {
                     packet_id: id,
-                    reason_code: PubReasonCode::Success,
-                    reason_string: None,
-                    user_property: None,
+                    reason_code: PubReasonCode::try_from(reason_code)?,
+                    reason_string: props.reason_string,
+                    user_property: props.user_property,
                 })
             }
             PacketType::Subscribe => {
                 if !fixed.get_dup() && fixed.get_qos()? != QosLevel::AtLeast && fixed.get_retain() {
                     return Err(MqttError::MalformedHeader);
                 }
-                let mut len = fixed.get_remaing_len();
-
                 // # Variable header
 
-                let packet_id = unpack_u16(iter)?;
-                len -= size_of::<u16>();
+                let packet_id = unpack_u16(body)?;
+
+                let subscription_identifier = if is_v5 {
+                    match unpack_properties(body)?.subscription_identifers[..] {
+                        [] => None,
+                        // The Subscription Identifier can have the value of 1 to 268,435,455
+                        [id] if id > 0 => Some(id as u32),
+                        _ => return Err(MqttError::ProtocolViolation),
+                    }
+                } else {
+                    None
+                };
 
                 // # Payload
                 /*
-                 * Read in a loop all remaining bytes specified by len of the Fixed Header.
+                 * Read in a loop all remaining bytes of the packet body.
                  * From now on the payload consists of 3-tuples formed by:
                  *  - topic filter (string)
-                 *  - qos
+                 *  - qos (v5 subscription options)
                  */
                 let mut tuples = Vec::new();
-                while len > 0 {
-                    let topic = unpack_string(iter)?;
-                    len -= topic.len() + size_of::<u16>();
-
-                    let qos = QosLevel::try_from(
-                        *iter.next().ok_or_else(|| MqttError::MalformedHeader)?,
-                    )?;
+                while body.has_remaining() {
+                    let topic = unpack_string(body)?;
+
+                    let byte = unpack_u8(body).map_err(|_| MqttError::MalformedHeader)?;
+                    // bits other than QoS are reserved in v3.1.1
+                    let reserved = if is_v5 { 0xC0 } else { 0xFC };
+                    if byte & reserved != 0 {
+                        return Err(MqttError::MalformedHeader);
+                    }
 
-                    len -= size_of::<u8>();
+                    // a QoS or Retain Handling of 3 makes the packet malformed like the reserved bits,
+                    // the connection is closed rather than the filter refused
+                    let options = SubscriptionOptions::from(byte);
+                    if options.qos().is_err() || options.retain_handling().is_err() {
+                        return Err(MqttError::MalformedPacket("Invalid Subscription Options"));
+                    }
 
-                    tuples.push((topic, qos));
+                    tuples.push((topic, options));
                 }
 
                 if tuples.is_empty() {
@@ -581,22 +1055,19 @@ impl VariableHeader {
                 Ok(Self::Subscribe {
                     packet_id,
                     tuples,
-                    subscription_identifier: None,
+                    subscription_identifier,
                     user_property: None,
                 })
             }
             PacketType::Suback => {
-                let packet_id = unpack_u16(iter)?;
-
-                let mut len = fixed.get_remaing_len() - size_of::<u16>();
+                let packet_id = unpack_u16(body)?;
+                if is_v5 {
+                    unpack_properties(body)?;
+                }
 
                 let mut return_codes = Vec::new();
-                while len > 0 {
-                    let byte = iter.next().ok_or_else(|| MqttError::MissingByte)?;
-
-                    return_codes.push(SubackReturnCode::try_from(byte)?);
-
-                    len -= size_of::<u8>()
+                while body.has_remaining() {
+                    return_codes.push(SubackReturnCode::try_from(unpack_u8(body)?)?);
                 }
 
                 Ok(Self::SubAck {
@@ -607,18 +1078,17 @@ impl VariableHeader {
                 })
             }
             PacketType::Unsubscribe => {
-                let mut len = fixed.get_remaing_len();
                 let mut tuples = Vec::<String>::new();
-                let packet_id = unpack_u16(iter)?;
-                len -= size_of::<u16>();
-
-                while len > 0 {
-                    len -= size_of::<u16>();
+                let packet_id = unpack_u16(body)?;
 
-                    let topic = unpack_string(iter)?;
-                    len -= topic.len();
+                let _props = if is_v5 {
+                    Some(unpack_properties(body)?)
+                } else {
+                    None
+                };
 
-                    tuples.push(topic);
+                while body.has_remaining() {
+                    tuples.push(unpack_string(body)?);
                 }
 
                 Ok(Self::Unsubscribe {
@@ -628,34 +1098,167 @@ impl VariableHeader {
                 })
             }
             PacketType::Unsuback => {
-                let id = unpack_u16(iter)?;
+                let id = unpack_u16(body)?;
+
+                let mut reason_codes = Vec::new();
+                if is_v5 {
+                    unpack_properties(body)?;
+                    while body.has_remaining() {
+                        reason_codes.push(UnsubackReasonCode::try_from(unpack_u8(body)?)?);
+                    }
+                }
+
                 Ok(Self::UnsubAck {
                     packet_id: id,
                     reason_string: None,
                     user_property: None,
-                    reason_codes: Vec::default(),
+                    reason_codes,
                 })
             }
             PacketType::PingReq => Ok(Self::PingReq),
             PacketType::PingResp => Ok(Self::PingResp),
-            PacketType::Disconnect => Ok(Self::Disconnect {
-                reason_code: 0,
-                session_expiry_interval: None,
-                reason_string: None,
-                user_property: None,
-                server_reference: None,
-            }),
-            PacketType::Auth => Ok(Self::Auth {
-                reason_code: 0,
-                authentication_method: None,
-                authentication_data: None,
-                reason_string: None,
-                user_property: None,
-            }),
+            PacketType::Disconnect => {
+                // The Reason Code can be omitted for Normal disconnection and the Property Length when there are no properties
+                if !is_v5 || !body.has_remaining() {
+                    return Ok(Self::Disconnect {
+                        reason_code: DisconnectReasonCode::NormalDisconnection,
+                        session_expiry_interval: None,
+                        reason_string: None,
+                        user_property: None,
+                        server_reference: None,
+                    });
+                }
+
+                let reason_code = DisconnectReasonCode::try_from(unpack_u8(body)?)?;
+                let props = if body.has_remaining() {
+                    unpack_properties(body)?
+                } else {
+                    Props::default()
+                };
+
+                Ok(Self::Disconnect {
+                    reason_code,
+                    session_expiry_interval: props.session_expiry_interval,
+                    reason_string: props.reason_string,
+                    user_property: props.user_property,
+                    server_reference: props.server_reference,
+                })
+            }
+            PacketType::Auth => {
+                // The Reason Code and Property Length can be omitted for Success
+                let reason_code = if body.has_remaining() {
+                    unpack_u8(body)?
+                } else {
+                    0
+                };
+                let props = if body.has_remaining() {
+                    unpack_properties(body)?
+                } else {
+                    Props::default()
+                };
+
+                Ok(Self::Auth {
+                    reason_code,
+                    authentication_method: None,
+                    authentication_data: None,
+                    reason_string: props.reason_string,
+                    user_property: props.user_property,
+                })
+            }
         }
     }
 }
 
+/// Unpack the Reason Code and Properties of a v5 PUBACK, PUBREC, PUBREL or PUBCOMP.
+///
+/// Both can be left out, the Reason Code is then Success and there are no properties.
+fn unpack_ack(body: &mut Bytes, is_v5: bool) -> Result<(u8, Props), MqttError> {
+    if !is_v5 || !body.has_remaining() {
+        return Ok((0x00, Props::default()));
+    }
+
+    let reason_code = unpack_u8(body)?;
+    let props = if body.has_remaining() {
+        unpack_properties(body)?
+    } else {
+        Props::default()
+    };
+    Ok((reason_code, props))
+}
+
+/// Number of bytes [`pack_ack`] writes
+fn ack_size(reason_code: u8, properties: usize, is_v5: bool) -> usize {
+    if !is_v5 {
+        2
+    } else if properties > 0 {
+        2 + 1 + length_size(properties) + properties
+    } else if reason_code != 0x00 {
+        2 + 1
+    } else {
+        2
+    }
+}
+
+/// Pack the variable header of a PUBACK, PUBREC, PUBREL or PUBCOMP.
+/// The Reason Code and Property Length are left out when they are not needed, like every v5 client and server does.
+fn pack_ack(
+    bytes: &mut BytesMut,
+    packet_id: u16,
+    reason_code: u8,
+    properties: usize,
+    reason_string: Option<String>,
+    user_property: Option<Vec<(String, String)>>,
+    is_v5: bool,
+) {
+    bytes.put_u16(packet_id);
+    if !is_v5 {
+        return;
+    }
+
+    // The Reason Code 0x00 (Success) can be omitted when there are no properties,
+    // and the Property Length when the Remaining Length is less than 4
+    if reason_code != 0x00 || properties > 0 {
+        bytes.put_u8(reason_code);
+    }
+    if properties > 0 {
+        encode_length(properties, bytes);
+        put_string_property(bytes, 0x1F, reason_string);
+        put_user_properties(bytes, user_property);
+    }
+}
+
+/// Number of bytes of a UTF-8 Encoded String property, with its identifier
+fn string_property_size(value: Option<&str>) -> usize {
+    value.map_or(0, |value| 1 + 2 + value.len())
+}
+
+/// Number of bytes of the User Properties, with the identifier of each
+fn user_properties_size(properties: Option<&[(String, String)]>) -> usize {
+    properties
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| 1 + 2 + key.len() + 2 + value.len())
+        .sum()
+}
+
+fn put_string_property(bytes: &mut BytesMut, identifier: u8, value: Option<String>) {
+    if let Some(value) = value {
+        bytes.put_u8(identifier);
+        bytes.put_u16(value.len() as u16);
+        bytes.put(value.as_bytes());
+    }
+}
+
+fn put_user_properties(bytes: &mut BytesMut, properties: Option<Vec<(String, String)>>) {
+    for (key, value) in properties.into_iter().flatten() {
+        bytes.put_u8(0x26);
+        bytes.put_u16(key.len() as u16);
+        bytes.put(key.as_bytes());
+        bytes.put_u16(value.len() as u16);
+        bytes.put(value.as_bytes());
+    }
+}
+
 #[derive(Debug)]
 pub struct Packet {
     pub fixed: FixedHeader,
@@ -669,6 +1272,7 @@ impl Packet {
             variable,
         }
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn make_publish(
         dup: bool,
         qos: QosLevel,
@@ -676,24 +1280,34 @@ impl Packet {
         topic: String,
         packet_id: Option<u16>,
         payload: Bytes,
+        properties: PublishProperties,
+        subscription_identifiers: Vec<u32>,
+        protocol: ProtocalVersion,
     ) -> Bytes {
+        // QoS 1 and 2 messages for clients get their packet identifier from the connection's flow control,
+        // reserve the field so the packet can be unpacked again.
+        let packet_id = match qos {
+            QosLevel::AtMost => None,
+            _ => Some(packet_id.unwrap_or(0)),
+        };
+
         Self {
             fixed: FixedHeader::new(PacketType::Publish, dup, qos, retain, 0),
             variable: VariableHeader::Publish {
                 topic,
                 packet_id,
                 payload,
-                payload_format_indicator: None,
+                payload_format_indicator: properties.payload_format_indicator,
                 message_expiry_interval: None,
                 topic_alias: None,
                 response_topic: None,
                 correlation_data: None,
-                user_property: None,
-                subscription_identifier: None,
-                content_type: None,
+                user_property: Some(properties.user_properties).filter(|props| !props.is_empty()),
+                subscription_identifiers,
+                content_type: properties.content_type,
             },
         }
-        .pack()
+        .pack(protocol)
     }
     pub fn make_pubcomp(packet_id: u16) -> Bytes {
         Self {
@@ -705,7 +1319,7 @@ impl Packet {
                 user_property: None,
             },
         }
-        .pack()
+        .pack(ProtocalVersion::Four)
     }
     pub fn make_pubrel(packet_id: u16) -> Bytes {
         Self {
@@ -717,7 +1331,7 @@ impl Packet {
                 user_property: None,
             },
         }
-        .pack()
+        .pack(ProtocalVersion::Four)
     }
     pub fn make_pubrec(packet_id: u16) -> Bytes {
         Self {
@@ -729,55 +1343,76 @@ impl Packet {
                 user_property: None,
             },
         }
-        .pack()
+        .pack(ProtocalVersion::Four)
     }
     pub fn make_puback(packet_id: u16) -> Bytes {
         Self {
             fixed: FixedHeader::new(PacketType::Puback, false, QosLevel::AtMost, false, 0),
             variable: VariableHeader::PubAck {
                 packet_id,
+                reason_code: PubRecReasonCode::Success,
                 reason_string: None,
                 user_property: None,
             },
         }
-        .pack()
+        .pack(ProtocalVersion::Four)
     }
-    pub fn make_unsuback(packet_id: u16) -> Bytes {
+    /// The reason codes are only sent to v5 clients
+    pub fn make_unsuback(
+        packet_id: u16,
+        reason_codes: Vec<UnsubackReasonCode>,
+        protocol: ProtocalVersion,
+    ) -> Bytes {
         Self {
             fixed: FixedHeader::new(PacketType::Unsuback, false, QosLevel::AtMost, false, 0),
             variable: VariableHeader::UnsubAck {
                 packet_id,
                 reason_string: None,
                 user_property: None,
-                reason_codes: Vec::default(),
+                reason_codes,
             },
         }
-        .pack()
+        .pack(protocol)
     }
     pub fn make_ping_resp() -> Bytes {
         Self {
             fixed: FixedHeader::new(PacketType::PingResp, false, QosLevel::AtMost, false, 0),
             variable: VariableHeader::PingResp,
         }
-        .pack()
+        .pack(ProtocalVersion::Four)
     }
-    pub fn make_suback(packet_id: u16, rc: Vec<SubackReturnCode>) -> Bytes {
-        Self {
-            fixed: FixedHeader::new(PacketType::Suback, false, QosLevel::AtMost, false, 0),
+    /// SUBACK for the client, the v5 failure codes are sent as 0x80 to v3.1.1 clients
+    /// and `reason_string` and `user_property` are only sent to v5 clients.
+    pub fn make_suback(
+        packet_id: u16,
+        rc: Vec<SubackReturnCode>,
+        reason_string: Option<String>,
+        user_property: Option<Vec<(String, String)>>,
+        protocol: ProtocalVersion,
+    ) -> Bytes {
+        Self {
+            fixed: FixedHeader::new(PacketType::Suback, false, QosLevel::AtMost, false, 0),
             variable: VariableHeader::SubAck {
                 packet_id,
                 return_codes: rc,
-                reason_string: None,
-                user_property: None,
+                reason_string,
+                user_property,
             },
         }
-        .pack()
+        .pack(protocol)
     }
-    pub fn make_connack(rc: ConnectReturnCode, session_present: bool) -> Bytes {
+    /// CONNACK refusing a connection.
+    ///
+    /// The return code is converted to the client's protocol version, v5 clients get a Reason Code from 0x80 and the Reason String.
+    pub fn make_connack_refused(
+        rc: ConnectReturnCode,
+        reason_string: Option<String>,
+        protocol: ProtocalVersion,
+    ) -> Bytes {
         Self {
             fixed: FixedHeader::new(PacketType::Connack, false, QosLevel::AtMost, false, 0),
             variable: VariableHeader::ConnAck {
-                acknowledge_flags: AcknowledgeFlags::new(session_present),
+                acknowledge_flags: AcknowledgeFlags::new(false),
                 return_code: rc,
                 session_expiry_interval: None,
                 receive_maximum: None,
@@ -786,7 +1421,7 @@ impl Packet {
                 maximum_packet_size: None,
                 assigned_client_identifier: None,
                 topic_alias_maximum: None,
-                reason_string: None,
+                reason_string,
                 user_property: None,
                 wildcard_subscription_available: None,
                 subscription_identifiers_available: None,
@@ -798,30 +1433,203 @@ impl Packet {
                 authentication_data: None,
             },
         }
-        .pack()
+        .pack(protocol)
+    }
+    /// CONNACK for the client, `receive_maximum` and `assigned_client_identifier` are only sent to v5 clients.
+    pub fn make_connack(
+        rc: ConnectReturnCode,
+        session_present: bool,
+        receive_maximum: Option<u16>,
+        capabilities: ServerCapabilities,
+        assigned_client_identifier: Option<String>,
+        protocol: ProtocalVersion,
+    ) -> Bytes {
+        Self {
+            fixed: FixedHeader::new(PacketType::Connack, false, QosLevel::AtMost, false, 0),
+            variable: VariableHeader::ConnAck {
+                acknowledge_flags: AcknowledgeFlags::new(session_present),
+                return_code: rc,
+                session_expiry_interval: None,
+                receive_maximum,
+                maximum_qos: capabilities.maximum_qos,
+                retain_available: capabilities.retain_available,
+                maximum_packet_size: capabilities.maximum_packet_size,
+                assigned_client_identifier,
+                topic_alias_maximum: None,
+                reason_string: None,
+                user_property: None,
+                wildcard_subscription_available: capabilities.wildcard_subscription_available,
+                subscription_identifiers_available: None,
+                shared_subscription_available: capabilities.shared_subscription_available,
+                server_keep_alive: None,
+                response_inormation: None,
+                server_refernce: None,
+                authentication_method: None,
+                authentication_data: None,
+            },
+        }
+        .pack(protocol)
+    }
+    /// CONNECT for a v3.1.1 client, `bridge` sets the top bit of the protocol level
+    pub fn make_connect(
+        client_id: String,
+        keepalive: u16,
+        clean_session: bool,
+        username: Option<String>,
+        password: Option<String>,
+        bridge: bool,
+    ) -> Bytes {
+        Self {
+            fixed: FixedHeader::new(PacketType::Connect, false, QosLevel::AtMost, false, 0),
+            variable: VariableHeader::Connect {
+                flags: Flags::new(
+                    clean_session,
+                    false,
+                    QosLevel::AtMost,
+                    false,
+                    password.is_some(),
+                    username.is_some(),
+                ),
+                keepalive,
+                client_id,
+                username,
+                password,
+                will_topic: None,
+                will_message: None,
+                will_delay_interval: None,
+                protocol_version: ProtocalVersion::Four,
+                bridge,
+                session_expiry_interval: None,
+                receive_maximum: None,
+                maximum_packet_size: None,
+                topic_alias_maximum: None,
+                request_response_info: None,
+                request_problem_info: None,
+                user_properties: None,
+                auth_method: None,
+                auth_data: None,
+            },
+        }
+        .pack(ProtocalVersion::Four)
+    }
+    pub fn make_subscribe(packet_id: u16, tuples: Vec<(String, QosLevel)>) -> Bytes {
+        Self {
+            fixed: FixedHeader::new(PacketType::Subscribe, false, QosLevel::AtLeast, false, 0),
+            variable: VariableHeader::Subscribe {
+                packet_id,
+                tuples: tuples
+                    .into_iter()
+                    .map(|(topic, qos)| (topic, SubscriptionOptions::from(qos)))
+                    .collect(),
+                subscription_identifier: None,
+                user_property: None,
+            },
+        }
+        .pack(ProtocalVersion::Four)
+    }
+    pub fn make_unsubscribe(packet_id: u16, tuples: Vec<String>) -> Bytes {
+        Self {
+            fixed: FixedHeader::new(PacketType::Unsubscribe, false, QosLevel::AtLeast, false, 0),
+            variable: VariableHeader::Unsubscribe {
+                packet_id,
+                user_property: None,
+                tuples,
+            },
+        }
+        .pack(ProtocalVersion::Four)
+    }
+    /// DISCONNECT sent by the Server. Only valid for v5 clients.
+    /// `server_reference` tells the client another server to use.
+    pub fn make_disconnect(
+        reason_code: DisconnectReasonCode,
+        reason_string: Option<String>,
+        server_reference: Option<String>,
+    ) -> Bytes {
+        Self {
+            fixed: FixedHeader::new(PacketType::Disconnect, false, QosLevel::AtMost, false, 0),
+            variable: VariableHeader::Disconnect {
+                reason_code,
+                session_expiry_interval: None,
+                reason_string,
+                user_property: None,
+                server_reference,
+            },
+        }
+        .pack(ProtocalVersion::Five)
+    }
+    pub fn make_ping_req() -> Bytes {
+        Self {
+            fixed: FixedHeader::new(PacketType::PingReq, false, QosLevel::AtMost, false, 0),
+            variable: VariableHeader::PingReq,
+        }
+        .pack(ProtocalVersion::Four)
     }
 
-    pub fn pack(self) -> Bytes {
-        let mut buffer = BytesMut::new();
-
-        self.fixed.as_byte(&mut buffer);
-
-        let variable = self.variable.pack();
-
-        encode_length(variable.len(), &mut buffer);
+    /// Pack the packet into the bytes written to the socket.
+    ///
+    /// The size of the packet is worked out first, so it is written straight into a shared output buffer
+    /// and split off of it without a second copy. Small packets like PUBACK don't allocate each time,
+    /// the output buffer's memory is reused once every packet split off of it has been dropped.
+    pub fn pack(self, protocol: ProtocalVersion) -> Bytes {
+        let remaining = self.variable.size(protocol);
+        // one byte of fixed header and the Remaining Length
+        let len = 1 + length_size(remaining) + remaining;
+
+        OUTPUT.with_borrow_mut(|output| {
+            if output.capacity() < len {
+                output.reserve(len.max(OUTPUT_CAPACITY));
+            }
 
-        buffer.put(variable);
+            self.fixed.as_byte(output);
+            encode_length(remaining, output);
+            self.variable.pack(protocol, output);
+            debug_assert_eq!(
+                output.len(),
+                len,
+                "Packed size does not match its Remaining Length"
+            );
 
-        buffer.freeze()
+            output.split().freeze()
+        })
     }
-    pub fn unpack(bytes: &[u8], protocal: ProtocalVersion) -> Result<(Self, usize), MqttError> {
-        let mut iter = bytes.iter();
+    /// Unpack a single packet from the front of `bytes`.
+    ///
+    /// The packet is split off of `bytes` so payloads share the same buffer.
+    /// `protocal` is the version set by the CONNECT of the connection,
+    /// with [`ProtocalVersion::Unknown`] only a CONNECT can be unpacked.
+    /// Returns the packet and the number of bytes it used.
+    pub fn unpack(
+        bytes: &mut Bytes,
+        protocal: ProtocalVersion,
+    ) -> Result<(Self, usize), MqttError> {
+        let fixed = FixedHeader::from_bytes(bytes, None)?;
+
+        let remaining = fixed.get_remaing_len();
+        if bytes.remaining() < remaining {
+            return Err(MqttError::RequiredByteMissing(
+                "Packet is shorter than its remaining length",
+            ));
+        }
 
-        let fixed = FixedHeader::from_bytes(&mut iter, None)?;
+        let len = remaining + fixed.get_rl_len() + 1;
 
-        let len = fixed.get_remaing_len() + fixed.get_rl_len() + 1;
+        let mut body = bytes.split_to(remaining);
+        // The whole body has been read, so a field running past its end means a length in the packet is wrong
+        let variable =
+            VariableHeader::unpack(&mut body, &fixed, protocal).map_err(|err| match err {
+                MqttError::RequiredByteMissing(_) | MqttError::MissingByte => {
+                    MqttError::MalformedHeader
+                }
+                err => err,
+            })?;
+
+        // Every field has been read, so bytes left over mean the Remaining Length is longer than the packet
+        if body.has_remaining() {
+            return Err(MqttError::MalformedPacket(
+                "Remaining Length is longer than the packet",
+            ));
+        }
 
-        let variable = VariableHeader::unpack(&mut iter, &fixed, protocal)?;
         Ok((Self { fixed, variable }, len))
     }
 }
@@ -830,21 +1638,467 @@ impl Packet {
 mod tests {
     use bytes::Bytes;
 
-    use crate::{core::enums::ProtocalVersion, packets::enums::QosLevel};
-
-    use super::{headers::fixed_header::FixedHeader, Packet, VariableHeader};
+    use crate::{
+        core::enums::ProtocalVersion,
+        error::MqttError,
+        packets::enums::{
+            ConnectReturnCode, DisconnectReasonCode, PacketType, QosLevel, SubackReturnCode,
+            UnsubackReasonCode,
+        },
+    };
+
+    use super::{
+        headers::fixed_header::FixedHeader, Packet, PayloadFormat, PubReasonCode, PubRecReasonCode,
+        PublishProperties, RetainHandling, ServerCapabilities, VariableHeader,
+    };
     // https://cedalo.com/blog/mqtt-packet-guide/
 
     #[test]
     fn test_pack_connack_packet() {
-        let bytes = Packet::make_connack(crate::packets::enums::ConnectReturnCode::Accepted, false);
+        let bytes = Packet::make_connack(
+            crate::packets::enums::ConnectReturnCode::Accepted,
+            false,
+            None,
+            ServerCapabilities::default(),
+            None,
+            ProtocalVersion::Four,
+        );
 
         println!("{:#?}", bytes.to_vec());
     }
 
+    #[test]
+    fn test_pack_connack_refused() {
+        // v5 clients get the v5 Reason Code and the Reason String
+        let bytes = Packet::make_connack_refused(
+            ConnectReturnCode::V4NotAuthorized,
+            Some("no".into()),
+            ProtocalVersion::Five,
+        );
+        assert_eq!(
+            bytes.to_vec(),
+            vec![0x20, 0x08, 0x00, 0x87, 0x05, 0x1F, 0x00, 0x02, b'n', b'o']
+        );
+
+        // v3.1.1 clients get the closest return code and no properties
+        for (code, expected) in [
+            (ConnectReturnCode::UnsupportedProtocolVersion, 0x01),
+            (ConnectReturnCode::BadUserNameOrPassword, 0x04),
+            (ConnectReturnCode::V5NotAuthorized, 0x05),
+            (ConnectReturnCode::PacketTooLarge, 0x03),
+            (ConnectReturnCode::V4IdentifierRejected, 0x02),
+        ] {
+            let bytes =
+                Packet::make_connack_refused(code, Some("no".into()), ProtocalVersion::Four);
+            assert_eq!(bytes.to_vec(), vec![0x20, 0x02, 0x00, expected]);
+        }
+
+        assert_eq!(
+            ConnectReturnCode::V4BadUserNameOrPassword.for_protocol(ProtocalVersion::Five),
+            ConnectReturnCode::BadUserNameOrPassword
+        );
+        assert_eq!(
+            ConnectReturnCode::PacketTooLarge.for_protocol(ProtocalVersion::Five),
+            ConnectReturnCode::PacketTooLarge
+        );
+    }
+
+    #[test]
+    fn test_unpack_v5_connack_refused() {
+        let mut data = Packet::make_connack_refused(
+            ConnectReturnCode::Banned,
+            Some("no".into()),
+            ProtocalVersion::Five,
+        );
+
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Five).expect("Failed to unpack");
+
+        match packet.variable {
+            VariableHeader::ConnAck { return_code, .. } => {
+                assert_eq!(return_code, ConnectReturnCode::Banned)
+            }
+            _ => panic!("Expected a CONNACK packet"),
+        }
+    }
+
+    #[test]
+    fn test_pack_v5_connack_receive_maximum() {
+        let bytes = Packet::make_connack(
+            crate::packets::enums::ConnectReturnCode::Accepted,
+            false,
+            Some(20),
+            ServerCapabilities::default(),
+            None,
+            ProtocalVersion::Five,
+        );
+
+        assert_eq!(
+            bytes.to_vec(),
+            vec![0x20, 0x06, 0x00, 0x00, 0x03, 0x21, 0x00, 0x14]
+        );
+    }
+
+    #[test]
+    fn test_pack_v5_connack_capabilities() {
+        let bytes = Packet::make_connack(
+            crate::packets::enums::ConnectReturnCode::Accepted,
+            false,
+            None,
+            ServerCapabilities {
+                maximum_qos: Some(QosLevel::AtMost),
+                retain_available: Some(false),
+                wildcard_subscription_available: None,
+                shared_subscription_available: Some(false),
+                maximum_packet_size: Some(1024),
+            },
+            None,
+            ProtocalVersion::Five,
+        );
+
+        assert_eq!(
+            bytes.to_vec(),
+            vec![
+                0x20, 0x0E, 0x00, 0x00, 0x0B, 0x24, 0x00, 0x25, 0x00, 0x27, 0x00, 0x00, 0x04, 0x00,
+                0x2A, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pack_v5_connack_assigned_client_identifier() {
+        let bytes = Packet::make_connack(
+            crate::packets::enums::ConnectReturnCode::Accepted,
+            false,
+            None,
+            ServerCapabilities::default(),
+            Some("auto-1".into()),
+            ProtocalVersion::Five,
+        );
+
+        assert_eq!(
+            bytes.to_vec(),
+            vec![
+                0x20, 0x0C, 0x00, 0x00, 0x09, 0x12, 0x00, 0x06, b'a', b'u', b't', b'o', b'-', b'1'
+            ]
+        );
+
+        // v3.1.1 has no properties
+        let bytes = Packet::make_connack(
+            crate::packets::enums::ConnectReturnCode::Accepted,
+            false,
+            None,
+            ServerCapabilities::default(),
+            Some("auto-1".into()),
+            ProtocalVersion::Four,
+        );
+        assert_eq!(bytes.to_vec(), vec![0x20, 0x02, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_unpack_v5_connect_receive_maximum() {
+        let mut data = Bytes::from_static(&[
+            0x10, 0x11, // Fixed Header
+            0x00, 0x04, 0x4d, 0x51, 0x54, 0x54, // MQTT
+            0x05, // version
+            0x02, // Connect Flags
+            0x00, 0x3c, // keepalive (60)
+            0x03, 0x21, 0x00, 0x0a, // Properties (Receive Maximum 10)
+            0x00, 0x01, 0x61, // Client Id
+        ]);
+
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Four).expect("Failed to unpack");
+
+        match packet.variable {
+            VariableHeader::Connect {
+                receive_maximum, ..
+            } => assert_eq!(receive_maximum, Some(10)),
+            _ => panic!("Expected a CONNECT packet"),
+        }
+    }
+
+    #[test]
+    fn test_unpack_v3_connect() {
+        let mut data = Bytes::from_static(&[
+            0x10, 0x10, // Fixed Header
+            0x00, 0x06, b'M', b'Q', b'I', b's', b'd', b'p', // MQIsdp
+            0x03, // version
+            0x02, // Connect Flags
+            0x00, 0x3c, // keepalive (60)
+            0x00, 0x02, b'c', b'1', // Client Id
+        ]);
+
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Four).expect("Failed to unpack");
+
+        match packet.variable {
+            VariableHeader::Connect {
+                protocol_version,
+                client_id,
+                ..
+            } => {
+                assert_eq!(protocol_version, ProtocalVersion::Three);
+                assert_eq!(client_id, "c1");
+            }
+            _ => panic!("Expected a CONNECT packet"),
+        }
+
+        // MQIsdp is only valid with level 3
+        let mut data = Bytes::from_static(&[
+            0x10, 0x10, 0x00, 0x06, b'M', b'Q', b'I', b's', b'd', b'p', 0x04, 0x02, 0x00, 0x3c,
+            0x00, 0x02, b'c', b'1',
+        ]);
+        assert!(matches!(
+            Packet::unpack(&mut data, ProtocalVersion::Four),
+            Err(MqttError::UnacceptableProtocolLevel)
+        ));
+    }
+
+    #[test]
+    fn test_bridge_connect() {
+        let mut data = Packet::make_connect("bridge.a".into(), 60, false, None, None, true);
+        // MQTT, then the protocol level with the top bit set
+        assert_eq!(data[8], 0x84);
+
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Four).expect("Failed to unpack");
+        match packet.variable {
+            VariableHeader::Connect {
+                protocol_version,
+                bridge,
+                ..
+            } => {
+                assert_eq!(protocol_version, ProtocalVersion::Four);
+                assert!(bridge);
+            }
+            _ => panic!("Expected a CONNECT packet"),
+        }
+    }
+
+    #[test]
+    fn test_unpack_lying_lengths() {
+        let packets: [&[u8]; 4] = [
+            // PUBLISH topic length past the end of the packet
+            &[0x30, 0x04, 0x00, 0x10, b'a', b'b'],
+            // QoS 1 PUBLISH without a packet id
+            &[0x32, 0x03, 0x00, 0x01, b'a'],
+            // SUBSCRIBE filter without its options byte
+            &[0x82, 0x05, 0x00, 0x01, 0x00, 0x01, b'a'],
+            // UNSUBSCRIBE filter length past the end of the packet
+            &[0xA2, 0x05, 0x00, 0x01, 0x00, 0x08, b'a'],
+        ];
+
+        for packet in packets {
+            let mut data = Bytes::copy_from_slice(packet);
+            assert!(
+                matches!(
+                    Packet::unpack(&mut data, ProtocalVersion::Four),
+                    Err(MqttError::MalformedHeader)
+                ),
+                "{:02x?}",
+                packet
+            );
+        }
+
+        // v5 properties longer than the packet
+        let mut data = Bytes::from_static(&[0x32, 0x06, 0x00, 0x01, b'a', 0x00, 0x01, 0x7f]);
+        assert!(matches!(
+            Packet::unpack(&mut data, ProtocalVersion::Five),
+            Err(MqttError::MalformedHeader)
+        ));
+    }
+
+    #[test]
+    fn test_unpack_remaining_length_mismatch() {
+        // Real packets with their Remaining Length cut short or padded with an extra byte
+        let packets: [&[u8]; 4] = [
+            // CONNECT v3.1.1 with client id c1
+            &[
+                0x10, 0x0E, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x02,
+                b'c', b'1',
+            ],
+            // PUBACK
+            &[0x40, 0x02, 0x00, 0x01],
+            // SUBSCRIBE a/b QoS 1
+            &[0x82, 0x08, 0x00, 0x01, 0x00, 0x03, b'a', b'/', b'b', 0x01],
+            // UNSUBACK
+            &[0xB0, 0x02, 0x00, 0x01],
+        ];
+
+        for packet in packets {
+            for len in 0..packet[1] {
+                let mut truncated = packet[..2 + len as usize].to_vec();
+                truncated[1] = len;
+                let mut data = Bytes::from(truncated);
+                assert!(
+                    Packet::unpack(&mut data, ProtocalVersion::Four).is_err(),
+                    "{:02x?} cut to {}",
+                    packet,
+                    len
+                );
+            }
+
+            let mut padded = packet.to_vec();
+            padded[1] += 1;
+            padded.push(0x00);
+            let mut data = Bytes::from(padded);
+            let err = Packet::unpack(&mut data, ProtocalVersion::Four)
+                .expect_err("Padded packet should not unpack");
+            assert_eq!(
+                DisconnectReasonCode::from(&err),
+                DisconnectReasonCode::MalformedPacket,
+                "{:02x?} padded",
+                packet
+            );
+        }
+
+        // PINGREQ and v3.1.1 DISCONNECT have no body
+        for packet in [[0xC0, 0x01, 0x00], [0xE0, 0x01, 0x00]] {
+            let mut data = Bytes::copy_from_slice(&packet);
+            assert!(matches!(
+                Packet::unpack(&mut data, ProtocalVersion::Four),
+                Err(MqttError::MalformedPacket(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unpack_v5_puback_reason() {
+        // Reason Code 0x10 No matching subscribers with a Reason String
+        let mut data = Bytes::from_static(&[
+            0x40, 0x09, 0x00, 0x01, 0x10, 0x05, 0x1F, 0x00, 0x02, b'o', b'k',
+        ]);
+        match Packet::unpack(&mut data, ProtocalVersion::Five).map(|(packet, _)| packet.variable) {
+            Ok(VariableHeader::PubAck {
+                packet_id,
+                reason_code,
+                reason_string,
+                ..
+            }) => {
+                assert_eq!(packet_id, 1);
+                assert_eq!(reason_code, PubRecReasonCode::NoMatchingSubscribers);
+                assert_eq!(reason_string.as_deref(), Some("ok"));
+            }
+            other => panic!("Expected a PUBACK, got {:?}", other),
+        }
+
+        // The properties can be left out
+        let mut data = Bytes::from_static(&[0x50, 0x03, 0x00, 0x01, 0x97]);
+        assert!(matches!(
+            Packet::unpack(&mut data, ProtocalVersion::Five).map(|(packet, _)| packet.variable),
+            Ok(VariableHeader::PubRec {
+                reason_code: PubRecReasonCode::QuotaExceeded,
+                ..
+            })
+        ));
+
+        // 0x92 is only a reason code of PUBREL and PUBCOMP
+        let mut data = Bytes::from_static(&[0x40, 0x03, 0x00, 0x01, 0x92]);
+        assert!(Packet::unpack(&mut data, ProtocalVersion::Five).is_err());
+    }
+
+    #[test]
+    fn test_pack_v5_ack_reason() {
+        let pack = |variable| {
+            Packet::new(
+                FixedHeader::new(PacketType::Pubrel, false, QosLevel::AtLeast, false, 0),
+                variable,
+            )
+            .pack(ProtocalVersion::Five)
+        };
+
+        // Success without properties is just the Packet Identifier
+        let packed = pack(VariableHeader::PubRel {
+            packet_id: 7,
+            reason_code: PubReasonCode::Success,
+            reason_string: None,
+            user_property: None,
+        });
+        assert_eq!(&packed[..], &[0x62, 0x02, 0x00, 0x07]);
+
+        // Other reason codes are sent without a Property Length
+        let packed = pack(VariableHeader::PubRel {
+            packet_id: 7,
+            reason_code: PubReasonCode::PacketIdentifierNotFound,
+            reason_string: None,
+            user_property: None,
+        });
+        assert_eq!(&packed[..], &[0x62, 0x03, 0x00, 0x07, 0x92]);
+
+        let packed = pack(VariableHeader::PubRel {
+            packet_id: 7,
+            reason_code: PubReasonCode::Success,
+            reason_string: None,
+            user_property: Some(vec![("k".into(), "v".into())]),
+        });
+        assert_eq!(
+            &packed[..],
+            &[0x62, 0x0A, 0x00, 0x07, 0x00, 0x07, 0x26, 0x00, 0x01, b'k', 0x00, 0x01, b'v']
+        );
+
+        // v3.1.1 has no reason codes
+        let packed = Packet::new(
+            FixedHeader::new(PacketType::Pubrel, false, QosLevel::AtLeast, false, 0),
+            VariableHeader::PubRel {
+                packet_id: 7,
+                reason_code: PubReasonCode::PacketIdentifierNotFound,
+                reason_string: None,
+                user_property: None,
+            },
+        )
+        .pack(ProtocalVersion::Four);
+        assert_eq!(&packed[..], &[0x62, 0x02, 0x00, 0x07]);
+    }
+
+    #[test]
+    fn test_unpack_v5_disconnect() {
+        let reason_code = |packet: &'static [u8]| {
+            let mut data = Bytes::from_static(packet);
+            match Packet::unpack(&mut data, ProtocalVersion::Five)
+                .map(|(packet, _)| packet.variable)
+            {
+                Ok(VariableHeader::Disconnect { reason_code, .. }) => Ok(reason_code),
+                Ok(packet) => panic!("Expected a DISCONNECT, got {:?}", packet),
+                Err(err) => Err(err),
+            }
+        };
+
+        // Reason Code and Property Length omitted
+        assert_eq!(
+            reason_code(&[0xE0, 0x00]).unwrap(),
+            DisconnectReasonCode::NormalDisconnection
+        );
+        // Property Length omitted
+        assert_eq!(
+            reason_code(&[0xE0, 0x01, 0x04]).unwrap(),
+            DisconnectReasonCode::DisconnectWithWillMessage
+        );
+        assert!(reason_code(&[0xE0, 0x01, 0x05]).is_err());
+
+        let mut data = Packet::make_disconnect(
+            DisconnectReasonCode::ProtocolError,
+            Some("error".into()),
+            None,
+        );
+        let (packet, _) = Packet::unpack(&mut data, ProtocalVersion::Five).unwrap();
+        assert!(matches!(
+            packet.variable,
+            VariableHeader::Disconnect {
+                reason_code: DisconnectReasonCode::ProtocolError,
+                reason_string: Some(reason),
+                ..
+            } if reason == "error"
+        ));
+
+        // v3.1.1 DISCONNECT has no variable header
+        let mut data = Bytes::from_static(&[0xE0, 0x00]);
+        assert!(Packet::unpack(&mut data, ProtocalVersion::Four).is_ok());
+    }
+
     #[test]
     fn test_unpack_connect_packet() {
-        let data = vec![
+        let mut data = Bytes::from_static(&[
             0x10, // Fixed Header
             0x1e, // Length
             0x00, 0x04, 0x4d, 0x51, 0x54, 0x54, // MQTT
@@ -855,10 +2109,10 @@ mod tests {
             0x00, 0x06, 0x63, 0x6c, 0x69, 0x65, 0x6e,
             0x74, // Username with length (6,"client")
             0x00, 0x04, 0x70, 0x61, 0x73, 0x73, // Password with length (4,"pass")
-        ];
+        ]);
 
-        let (packet, _) =
-            Packet::unpack(&data, ProtocalVersion::Four).expect("Failed to parse connect packet");
+        let (packet, _) = Packet::unpack(&mut data, ProtocalVersion::Four)
+            .expect("Failed to parse connect packet");
 
         if let VariableHeader::Connect {
             flags,
@@ -905,29 +2159,22 @@ mod tests {
 
     #[test]
     fn test_unpack_publish_packet() {
-        let data = vec![
+        let mut data = Bytes::from_static(&[
             0x33, // Fixed Header QOS 1, Retain 1
             0x0E, // Length 14
             0x00, 0x04, 0x69, 0x6e, 0x66, 0x6f, // topic "info"
             0x00, 0x02, // packet_id: 2
             0x43, 0x65, 0x64, 0x61, 0x6c, 0x6f, // Message "Cedalo"
-        ];
+        ]);
 
-        let (packet, _) =
-            Packet::unpack(&data, ProtocalVersion::Four).expect("Failed to parse connect packet");
+        let (packet, _) = Packet::unpack(&mut data, ProtocalVersion::Four)
+            .expect("Failed to parse connect packet");
 
         if let VariableHeader::Publish {
             packet_id,
             payload,
             topic,
-            payload_format_indicator,
-            message_expiry_interval,
-            topic_alias,
-            response_topic,
-            correlation_data,
-            user_property,
-            subscription_identifier,
-            content_type,
+            ..
         } = packet.variable
         {
             assert_eq!(packet.fixed.get_remaing_len(), 14);
@@ -955,23 +2202,20 @@ mod tests {
 
     #[test]
     fn test_unpack_subscribe_packet() {
-        let data = vec![
+        let mut data = Bytes::from_static(&[
             0x82, // Header
             0x0C, // Len
             0x00, 0x01, // pkt id
             // Start Tubles
             0x00, 0x07, 0x6d, 0x79, 0x74, 0x6f, 0x70, 0x69, 0x63, // String "mytopic"
             0x01, // Qos
-        ];
+        ]);
 
-        let (packet, _) =
-            Packet::unpack(&data, ProtocalVersion::Four).expect("Failed to parse connect packet");
+        let (packet, _) = Packet::unpack(&mut data, ProtocalVersion::Four)
+            .expect("Failed to parse connect packet");
 
         if let VariableHeader::Subscribe {
-            packet_id,
-            tuples,
-            subscription_identifier,
-            user_property,
+            packet_id, tuples, ..
         } = packet.variable
         {
             assert_eq!(packet.fixed.get_remaing_len(), 12);
@@ -980,28 +2224,292 @@ mod tests {
 
             assert_eq!(tuples.len(), 1);
             assert_eq!(tuples[0].0, "mytopic");
-            assert_eq!(tuples[0].1, QosLevel::AtLeast);
+            assert_eq!(tuples[0].1.qos().unwrap(), QosLevel::AtLeast);
+        } else {
+            panic!("Invalid packet");
+        }
+    }
+
+    #[test]
+    fn test_unpack_v5_subscribe_packet() {
+        let mut data = Bytes::from_static(&[
+            0x82, // Header
+            0x0D, // Len
+            0x00, 0x01, // pkt id
+            0x00, // properties length
+            0x00, 0x07, 0x6d, 0x79, 0x74, 0x6f, 0x70, 0x69, 0x63, // String "mytopic"
+            0x09, // Options: RAP, Qos 1
+        ]);
+
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Five).expect("Failed to parse packet");
+
+        if let VariableHeader::Subscribe { tuples, .. } = packet.variable {
+            assert_eq!(tuples[0].0, "mytopic");
+            assert_eq!(tuples[0].1.qos().unwrap(), QosLevel::AtLeast);
+            assert!(tuples[0].1.retain_as_published());
+        } else {
+            panic!("Invalid packet");
+        }
+    }
+
+    #[test]
+    fn test_unpack_v5_subscribe_retain_handling() {
+        let packet = |options: u8| {
+            Bytes::from(vec![
+                0x82, 0x0D, 0x00, 0x01, 0x00, 0x00, 0x07, 0x6d, 0x79, 0x74, 0x6f, 0x70, 0x69, 0x63,
+                options,
+            ])
+        };
+
+        let (packet_two, _) = Packet::unpack(&mut packet(0x2C), ProtocalVersion::Five)
+            .expect("Failed to parse packet");
+        if let VariableHeader::Subscribe { tuples, .. } = packet_two.variable {
+            assert_eq!(tuples[0].1.qos().unwrap(), QosLevel::AtMost);
+            assert!(tuples[0].1.no_local());
+            assert!(tuples[0].1.retain_as_published());
+            assert_eq!(
+                tuples[0].1.retain_handling().unwrap(),
+                RetainHandling::DoNotSend
+            );
         } else {
             panic!("Invalid packet");
         }
+
+        // Retain Handling 3, QoS 3 and the reserved bits make the packet malformed
+        for options in [0x30, 0x03, 0x40, 0x80] {
+            assert!(matches!(
+                Packet::unpack(&mut packet(options), ProtocalVersion::Five),
+                Err(MqttError::MalformedPacket(_) | MqttError::MalformedHeader)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unpack_v4_subscribe_reserved_options() {
+        let mut data = Bytes::from_static(&[
+            0x82, 0x0C, 0x00, 0x01, 0x00, 0x07, 0x6d, 0x79, 0x74, 0x6f, 0x70, 0x69, 0x63,
+            0x09, // RAP is reserved in v3.1.1
+        ]);
+
+        Packet::unpack(&mut data, ProtocalVersion::Four).expect_err("Reserved bits are set");
+    }
+
+    #[test]
+    fn test_unpack_v5_publish_packet() {
+        let mut data = Bytes::from_static(&[
+            0x30, // Fixed Header QOS 0
+            0x0A, // Length 10
+            0x00, 0x04, 0x69, 0x6e, 0x66, 0x6f, // topic "info"
+            0x02, 0x01, 0x01, // properties: payload format indicator
+            0x68, // Message "h"
+        ]);
+
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Five).expect("Failed to parse packet");
+
+        if let VariableHeader::Publish { topic, payload, .. } = packet.variable {
+            assert_eq!(&topic, "info");
+            assert_eq!(&payload[..], b"h");
+        } else {
+            panic!("Invalid packet type");
+        }
+    }
+
+    #[test]
+    fn test_pack_v5_publish_packet() {
+        let packet = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            true,
+            "info".into(),
+            None,
+            Bytes::from_static(b"h"),
+            PublishProperties::default(),
+            Vec::new(),
+            ProtocalVersion::Five,
+        );
+
+        assert_eq!(
+            packet.to_vec(),
+            vec![0x31, 0x08, 0x00, 0x04, 0x69, 0x6e, 0x66, 0x6f, 0x00, 0x68]
+        );
+    }
+
+    #[test]
+    fn test_pack_v5_publish_subscription_identifiers() {
+        let packet = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            false,
+            "info".into(),
+            None,
+            Bytes::from_static(b"h"),
+            PublishProperties::default(),
+            vec![1, 200],
+            ProtocalVersion::Five,
+        );
+
+        assert_eq!(
+            packet.to_vec(),
+            vec![
+                0x30, 0x0D, 0x00, 0x04, 0x69, 0x6e, 0x66, 0x6f, // topic "info"
+                0x05, // properties length
+                0x0B, 0x01, // subscription identifier 1
+                0x0B, 0xC8, 0x01, // subscription identifier 200
+                0x68
+            ]
+        );
+
+        // subscription identifiers are not sent to v3.1.1 clients
+        let packet = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            false,
+            "info".into(),
+            None,
+            Bytes::from_static(b"h"),
+            PublishProperties::default(),
+            vec![1],
+            ProtocalVersion::Four,
+        );
+        assert_eq!(packet.len(), 9);
+    }
+
+    #[test]
+    fn test_v5_publish_format_properties() {
+        let properties = PublishProperties {
+            payload_format_indicator: Some(PayloadFormat::EncodedUTF8),
+            content_type: Some("text/plain".into()),
+            ..Default::default()
+        };
+        let mut data = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            false,
+            "info".into(),
+            None,
+            Bytes::from_static(b"h"),
+            properties.clone(),
+            Vec::new(),
+            ProtocalVersion::Five,
+        );
+
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Five).expect("Failed to unpack");
+        match packet.variable {
+            VariableHeader::Publish {
+                payload_format_indicator,
+                content_type,
+                ..
+            } => {
+                assert_eq!(
+                    payload_format_indicator,
+                    properties.payload_format_indicator
+                );
+                assert_eq!(content_type, properties.content_type);
+            }
+            _ => panic!("Expected a PUBLISH packet"),
+        }
+
+        assert!(properties.is_valid_payload(b"text"));
+        assert!(!properties.is_valid_payload(&[0xff, 0xfe]));
+        assert!(PublishProperties::default().is_valid_payload(&[0xff, 0xfe]));
+    }
+
+    #[test]
+    fn test_v5_publish_user_properties() {
+        let user_properties = vec![
+            ("b".to_string(), "1".to_string()),
+            ("a".to_string(), "2".to_string()),
+            ("b".to_string(), "3".to_string()),
+        ];
+        let properties = PublishProperties {
+            user_properties: user_properties.clone(),
+            ..Default::default()
+        };
+
+        let mut data = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            false,
+            "info".into(),
+            None,
+            Bytes::from_static(b"h"),
+            properties.clone(),
+            Vec::new(),
+            ProtocalVersion::Five,
+        );
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Five).expect("Failed to unpack");
+        match packet.variable {
+            VariableHeader::Publish { user_property, .. } => {
+                // repeated keys are kept in order
+                assert_eq!(user_property, Some(user_properties));
+            }
+            _ => panic!("Expected a PUBLISH packet"),
+        }
+
+        // user properties are not sent to v3.1.1 clients
+        let packet = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            false,
+            "info".into(),
+            None,
+            Bytes::from_static(b"h"),
+            properties,
+            Vec::new(),
+            ProtocalVersion::Four,
+        );
+        assert_eq!(packet.len(), 9);
+    }
+
+    #[test]
+    fn test_unpack_v5_subscribe_identifier() {
+        let mut data = Bytes::from_static(&[
+            0x82, // Header
+            0x0B, // Len
+            0x00, 0x01, // pkt id
+            0x02, 0x0B, 0x07, // properties: subscription identifier 7
+            0x00, 0x03, 0x61, 0x2f, 0x62, // String "a/b"
+            0x00, // Options
+        ]);
+
+        let (packet, _) =
+            Packet::unpack(&mut data, ProtocalVersion::Five).expect("Failed to parse packet");
+
+        if let VariableHeader::Subscribe {
+            subscription_identifier,
+            ..
+        } = packet.variable
+        {
+            assert_eq!(subscription_identifier, Some(7));
+        } else {
+            panic!("Invalid packet");
+        }
+
+        let mut data = Bytes::from_static(&[
+            0x82, 0x0B, 0x00, 0x01, 0x02, 0x0B, 0x00, // subscription identifier 0
+            0x00, 0x03, 0x61, 0x2f, 0x62, 0x00,
+        ]);
+        Packet::unpack(&mut data, ProtocalVersion::Five).expect_err("Identifier can not be 0");
     }
 
     #[test]
     fn test_unpack_unsubscribe_packet() {
-        let data = vec![
+        let mut data = Bytes::from_static(&[
             0xA2, // Fixed Header
             0x08, // length
             0x00, 0x01, // pkt_id = 1
             0x00, 0x04, 0x69, 0x6e, 0x66, 0x6f, // string "info"
-        ];
+        ]);
 
-        let (packet, _) =
-            Packet::unpack(&data, ProtocalVersion::Four).expect("Failed to parse connect packet");
+        let (packet, _) = Packet::unpack(&mut data, ProtocalVersion::Four)
+            .expect("Failed to parse connect packet");
 
         if let VariableHeader::Unsubscribe {
-            packet_id,
-            tuples,
-            user_property,
+            packet_id, tuples, ..
         } = packet.variable
         {
             assert_eq!(packet.fixed.get_remaing_len(), 8, "remaing packet length");
@@ -1015,6 +2523,87 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_unpack_truncated_publish_packet() {
+        let mut data = Bytes::from_static(&[
+            0x30, // Fixed Header QOS 0
+            0x0A, // Length 10
+            0x00, 0x04, 0x69, 0x6e, 0x66, 0x6f, // topic "info"
+            0x43, 0x65, // Message missing bytes
+        ]);
+
+        Packet::unpack(&mut data, ProtocalVersion::Four).expect_err("Packet is truncated");
+    }
+
+    #[test]
+    fn test_unpack_publish_wildcard_topic() {
+        let mut data = Bytes::from_static(&[
+            0x30, // Fixed Header QOS 0
+            0x05, // Length 5
+            0x00, 0x03, 0x61, 0x2f, 0x23, // topic "a/#"
+        ]);
+
+        let err = Packet::unpack(&mut data, ProtocalVersion::Four).expect_err("Invalid topic");
+        assert_eq!(
+            DisconnectReasonCode::from(&err),
+            DisconnectReasonCode::TopicNameInvalid
+        );
+    }
+
+    #[test]
+    fn test_unpack_multiple_packets() {
+        let mut data = Bytes::from_static(&[
+            0x30, 0x08, 0x00, 0x04, 0x69, 0x6e, 0x66, 0x6f, 0x68, 0x69, // publish "info" "hi"
+            0xC0, 0x00, // pingreq
+        ]);
+        let start = data.as_ptr();
+
+        let (packet, len) = Packet::unpack(&mut data, ProtocalVersion::Four).unwrap();
+        assert_eq!(len, 10);
+
+        if let VariableHeader::Publish { payload, .. } = packet.variable {
+            assert_eq!(&payload[..], b"hi");
+            // the payload is a view into the original buffer
+            assert_eq!(payload.as_ptr(), start.wrapping_add(8));
+        } else {
+            panic!("Invalid packet type");
+        }
+
+        let (packet, len) = Packet::unpack(&mut data, ProtocalVersion::Four).unwrap();
+        assert_eq!(len, 2);
+        assert!(matches!(packet.variable, VariableHeader::PingReq));
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_pack_disconnect_packet() {
+        let packet = Packet::make_disconnect(DisconnectReasonCode::NormalDisconnection, None, None);
+        assert_eq!(packet.to_vec(), vec![0xE0, 0x00]);
+
+        let packet = Packet::make_disconnect(DisconnectReasonCode::KeepAliveTimeout, None, None);
+        assert_eq!(packet.to_vec(), vec![0xE0, 0x02, 0x8D, 0x00]);
+
+        let packet = Packet::make_disconnect(
+            DisconnectReasonCode::ProtocolError,
+            Some("error".into()),
+            None,
+        );
+        assert_eq!(
+            packet.to_vec(),
+            vec![0xE0, 0x0A, 0x82, 0x08, 0x1F, 0x00, 0x05, 0x65, 0x72, 0x72, 0x6f, 0x72]
+        );
+
+        let packet = Packet::make_disconnect(
+            DisconnectReasonCode::ServerShuttingDown,
+            None,
+            Some("b:1883".into()),
+        );
+        assert_eq!(
+            packet.to_vec(),
+            vec![0xE0, 0x0B, 0x8B, 0x09, 0x1C, 0x00, 0x06, b'b', b':', b'1', b'8', b'8', b'3']
+        );
+    }
+
     #[test]
     fn test_pack_puback() {
         let puback = Packet::make_puback(0);
@@ -1022,6 +2611,117 @@ mod tests {
         println!("{:?}", puback.to_vec());
     }
 
+    #[test]
+    fn test_pack_reuses_buffers() {
+        let pubacks = (0..1000).map(Packet::make_puback).collect::<Vec<_>>();
+        let large = Packet::make_publish(
+            false,
+            QosLevel::AtMost,
+            false,
+            "large".into(),
+            None,
+            Bytes::from(vec![0xAB; 100_000]),
+            PublishProperties::default(),
+            Vec::new(),
+            ProtocalVersion::Four,
+        );
+        let after = Packet::make_puback(7);
+
+        // earlier packets are not changed by the ones packed after them
+        for (packet_id, puback) in pubacks.iter().enumerate() {
+            let id = (packet_id as u16).to_be_bytes();
+            assert_eq!(puback.to_vec(), vec![0x40, 0x02, id[0], id[1]]);
+        }
+        assert_eq!(large.len(), 1 + 3 + 2 + 5 + 100_000);
+        assert!(large[11..].iter().all(|byte| *byte == 0xAB));
+        assert_eq!(after.to_vec(), vec![0x40, 0x02, 0x00, 0x07]);
+    }
+
+    #[test]
+    fn test_pack_size() {
+        let headers = || {
+            vec![
+                VariableHeader::Publish {
+                    topic: "a/b".into(),
+                    packet_id: Some(1),
+                    payload_format_indicator: Some(PayloadFormat::EncodedUTF8),
+                    message_expiry_interval: None,
+                    topic_alias: None,
+                    response_topic: None,
+                    correlation_data: None,
+                    user_property: Some(vec![("k".into(), "v".repeat(200))]),
+                    subscription_identifiers: vec![1, 200, 268_435_455],
+                    content_type: Some("text/plain".into()),
+                    payload: Bytes::from(vec![0; 20_000]),
+                },
+                VariableHeader::SubAck {
+                    packet_id: 1,
+                    reason_string: Some("quota".into()),
+                    user_property: None,
+                    return_codes: vec![SubackReturnCode::SuccessQosZero],
+                },
+                VariableHeader::PubAck {
+                    packet_id: 1,
+                    reason_code: PubRecReasonCode::NoMatchingSubscribers,
+                    reason_string: None,
+                    user_property: None,
+                },
+                VariableHeader::PubRel {
+                    packet_id: 1,
+                    reason_code: PubReasonCode::Success,
+                    reason_string: None,
+                    user_property: Some(vec![("k".into(), "v".into())]),
+                },
+                VariableHeader::UnsubAck {
+                    packet_id: 1,
+                    reason_string: None,
+                    user_property: None,
+                    reason_codes: vec![UnsubackReasonCode::Success],
+                },
+                VariableHeader::Disconnect {
+                    reason_code: DisconnectReasonCode::NormalDisconnection,
+                    session_expiry_interval: None,
+                    reason_string: None,
+                    user_property: None,
+                    server_reference: None,
+                },
+                VariableHeader::Disconnect {
+                    reason_code: DisconnectReasonCode::UseAnotherServer,
+                    session_expiry_interval: None,
+                    reason_string: None,
+                    user_property: None,
+                    server_reference: Some("other:1883".into()),
+                },
+                VariableHeader::PingResp,
+            ]
+        };
+
+        for protocol in [ProtocalVersion::Four, ProtocalVersion::Five] {
+            for header in headers() {
+                let size = header.size(protocol);
+                let mut bytes = bytes::BytesMut::new();
+                header.pack(protocol, &mut bytes);
+                assert_eq!(size, bytes.len());
+            }
+        }
+
+        let connack = Packet::make_connack(
+            ConnectReturnCode::Accepted,
+            false,
+            Some(10),
+            ServerCapabilities {
+                maximum_qos: Some(QosLevel::AtLeast),
+                retain_available: Some(false),
+                wildcard_subscription_available: Some(false),
+                shared_subscription_available: Some(false),
+                maximum_packet_size: Some(1024),
+            },
+            Some("auto-1".into()),
+            ProtocalVersion::Five,
+        );
+        assert_eq!(connack[1] as usize, connack.len() - 2);
+    }
+
     #[test]
     fn test_pack_connect_packet() {
         let header = FixedHeader::new(
@@ -1049,7 +2749,9 @@ mod tests {
             password: Some("pass".into()),
             will_topic: None,
             will_message: None,
+            will_delay_interval: None,
             protocol_version: ProtocalVersion::Four,
+            bridge: false,
             session_expiry_interval: None,
             receive_maximum: None,
             maximum_packet_size: None,
@@ -1063,7 +2765,7 @@ mod tests {
 
         let packet = Packet::new(header, variable);
 
-        let bytes = packet.pack();
+        let bytes = packet.pack(ProtocalVersion::Four);
 
         let data: [u8; 32] = [
             0x10, // Fixed Header
@@ -1092,6 +2794,9 @@ mod tests {
             "info".into(),
             Some(2),
             Bytes::copy_from_slice(&payload),
+            PublishProperties::default(),
+            Vec::new(),
+            ProtocalVersion::Four,
         );
 
         let data: [u8; 16] = [
@@ -1117,12 +2822,12 @@ mod tests {
 
         let v = VariableHeader::Subscribe {
             packet_id: 1,
-            tuples: vec![("mytopic".into(), QosLevel::AtLeast)],
+            tuples: vec![("mytopic".into(), QosLevel::AtLeast.into())],
             subscription_identifier: None,
             user_property: None,
         };
 
-        let packet = Packet::new(header, v).pack();
+        let packet = Packet::new(header, v).pack(ProtocalVersion::Four);
 
         let data: [u8; 14] = [
             0x82, // Header
@@ -1136,6 +2841,76 @@ mod tests {
         assert_eq!(data.to_vec(), packet);
     }
 
+    #[test]
+    fn test_pack_v5_suback() {
+        let codes = || {
+            vec![
+                SubackReturnCode::SuccessQosOne,
+                SubackReturnCode::NotAuthorized,
+                SubackReturnCode::ImplementationSpecificError,
+            ]
+        };
+
+        let bytes = Packet::make_suback(
+            1,
+            codes(),
+            Some("no".into()),
+            Some(vec![("k".into(), "v".into())]),
+            ProtocalVersion::Five,
+        );
+        assert_eq!(
+            bytes.to_vec(),
+            vec![
+                0x90, 0x12, 0x00, 0x01, // packet id
+                0x0C, // properties length
+                0x1F, 0x00, 0x02, b'n', b'o', // reason string
+                0x26, 0x00, 0x01, b'k', 0x00, 0x01, b'v', // user property
+                0x01, 0x87, 0x83,
+            ]
+        );
+
+        // v3.1.1 only has 0x80 and no properties
+        let bytes = Packet::make_suback(1, codes(), Some("no".into()), None, ProtocalVersion::Four);
+        assert_eq!(
+            bytes.to_vec(),
+            vec![0x90, 0x05, 0x00, 0x01, 0x01, 0x80, 0x80]
+        );
+    }
+
+    #[test]
+    fn test_pack_v5_unsuback() {
+        let codes = vec![
+            UnsubackReasonCode::Success,
+            UnsubackReasonCode::NoSubscriptionExisted,
+            UnsubackReasonCode::NotAuthorized,
+        ];
+
+        let mut bytes = Packet::make_unsuback(3, codes.clone(), ProtocalVersion::Five);
+        assert_eq!(
+            bytes.to_vec(),
+            vec![0xB0, 0x06, 0x00, 0x03, 0x00, 0x00, 0x11, 0x87]
+        );
+
+        let (packet, _) = Packet::unpack(&mut bytes, ProtocalVersion::Five).unwrap();
+        match packet.variable {
+            VariableHeader::UnsubAck {
+                packet_id,
+                reason_codes,
+                ..
+            } => {
+                assert_eq!(packet_id, 3);
+                assert_eq!(reason_codes, codes);
+            }
+            _ => panic!("Expected an UNSUBACK"),
+        }
+
+        // v3.1.1 has no reason codes
+        assert_eq!(
+            Packet::make_unsuback(3, codes, ProtocalVersion::Four).to_vec(),
+            vec![0xB0, 0x02, 0x00, 0x03]
+        );
+    }
+
     #[test]
     fn test_pack_unsubscribe_packet() {
         let header = FixedHeader::new(
@@ -1152,7 +2927,7 @@ mod tests {
             user_property: None,
         };
 
-        let packet = Packet::new(header, v).pack();
+        let packet = Packet::new(header, v).pack(ProtocalVersion::Four);
 
         let data: [u8; 10] = [
             0xA2, // Fixed Header
@@ -0,0 +1,106 @@
+use crate::error::MqttError;
+
+/// Topic Names and Topic Filters are UTF-8 Encoded Strings so can't be longer than 65,535 bytes.
+const MAX_TOPIC_LEN: usize = u16::MAX as usize;
+
+/// ### Topic Name
+/// A Topic Name must be at least one character long, can't contain wildcard characters or the null character U+0000.
+///
+/// [(MQTT 5) 4.7.3 Topic semantic and usage](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901247)
+pub fn validate_topic_name(topic: &str) -> Result<(), MqttError> {
+    if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+        return Err(MqttError::TopicNameInvalid);
+    }
+
+    if topic.contains(['+', '#', '\0']) {
+        return Err(MqttError::TopicNameInvalid);
+    }
+
+    Ok(())
+}
+
+/// ### Topic Filter
+/// A Topic Filter must be at least one character long and can't contain the null character U+0000.
+/// - `#` must be the last level of the filter and occupy the whole level.
+/// - `+` must occupy the whole level.
+/// - A shared subscription `$share/{ShareName}/{filter}` needs a ShareName without wildcards and a filter.
+///
+/// [(MQTT 5) 4.7.1 Topic wildcards](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901242)
+///
+/// [(MQTT 5) 4.8.2 Shared Subscriptions](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901250)
+pub fn validate_topic_filter(filter: &str) -> Result<(), MqttError> {
+    if filter.is_empty() || filter.len() > MAX_TOPIC_LEN || filter.contains('\0') {
+        return Err(MqttError::TopicFilterInvalid);
+    }
+
+    let filter = match filter.strip_prefix("$share/") {
+        Some(shared) => {
+            let (share_name, filter) = shared
+                .split_once('/')
+                .ok_or(MqttError::TopicFilterInvalid)?;
+
+            if share_name.is_empty() || share_name.contains(['+', '#']) || filter.is_empty() {
+                return Err(MqttError::TopicFilterInvalid);
+            }
+
+            filter
+        }
+        None => filter,
+    };
+
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            "#" if levels.peek().is_some() => return Err(MqttError::TopicFilterInvalid),
+            "#" | "+" => {}
+            _ if level.contains(['+', '#']) => return Err(MqttError::TopicFilterInvalid),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_topic_name() {
+        assert!(validate_topic_name("sport/tennis/player1").is_ok());
+        assert!(validate_topic_name("/").is_ok());
+        assert!(validate_topic_name("").is_err());
+        assert!(validate_topic_name("sport/+").is_err());
+        assert!(validate_topic_name("sport/#").is_err());
+        assert!(validate_topic_name("sport\0").is_err());
+        assert!(validate_topic_name(&"a".repeat(65536)).is_err());
+    }
+
+    #[test]
+    fn test_validate_topic_filter() {
+        for filter in [
+            "#",
+            "+",
+            "sport/#",
+            "+/tennis/#",
+            "/+",
+            "a//b",
+            "$share/group/a/+",
+        ] {
+            assert!(validate_topic_filter(filter).is_ok(), "{}", filter);
+        }
+
+        for filter in [
+            "",
+            "foo/#/bar",
+            "foo+",
+            "foo/bar#",
+            "a\0",
+            "$share/group",
+            "$share//a",
+            "$share/gr+oup/a",
+        ] {
+            assert!(validate_topic_filter(filter).is_err(), "{}", filter);
+        }
+    }
+}
@@ -1,53 +1,42 @@
-use bytes::{BufMut, Bytes, BytesMut};
-use log::error;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::error::MqttError;
 use std::mem::size_of;
 const MAX_ENCODED_SIZE: usize = 128 * 128 * 128;
 const MAX_ENCODED_BYTES: usize = 4;
 
-fn format_unpack_u16_error(e: Vec<u8>) -> MqttError {
-    error!(
-        "Failed to unpack u16: length is too short recived: {} expected {}",
-        e.len(),
-        size_of::<u16>()
-    );
-    MqttError::RequiredByteMissing("Missing u16 byte(s)")
+/// Check that the buffer has enough bytes left to read `len` bytes
+fn ensure_remaining<B: Buf>(buf: &B, len: usize) -> Result<(), MqttError> {
+    if buf.remaining() < len {
+        return Err(MqttError::RequiredByteMissing(
+            "Packet is shorter than expected",
+        ));
+    }
+    Ok(())
+}
+
+/// Unpack a single byte
+pub fn unpack_u8<B: Buf>(buf: &mut B) -> Result<u8, MqttError> {
+    ensure_remaining(buf, size_of::<u8>())?;
+    Ok(buf.get_u8())
 }
 
 /// Unpack a bytes into a u16
 ///
 /// Integer data values are 16 bits in big-endian order: the high order byte precedes the lower order byte.
 /// This means that a 16-bit word is presented on the network as Most Significant Byte (MSB), followed by Least Significant Byte (LSB).
-pub fn unpack_u16<'a, I>(iter: &mut I) -> Result<u16, MqttError>
-where
-    I: Iterator<Item = &'a u8>,
-{
-    let bytes: [u8; size_of::<u16>()] = iter
-        .take(size_of::<u16>())
-        .copied()
-        .collect::<Vec<u8>>()
-        .try_into()
-        .map_err(format_unpack_u16_error)?;
-
-    Ok(u16::from_be_bytes(bytes))
+pub fn unpack_u16<B: Buf>(buf: &mut B) -> Result<u16, MqttError> {
+    ensure_remaining(buf, size_of::<u16>())
+        .map_err(|_| MqttError::RequiredByteMissing("Missing u16 byte(s)"))?;
+    Ok(buf.get_u16())
 }
 
 /// Unpack bytes into a u32
 ///
 /// Four Byte Integer data values are 32-bit unsigned integers in big-endian order: the high order byte precedes the successively lower order bytes. This means that a 32-bit word is presented on the network as Most Significant Byte (MSB), followed by the next most Significant Byte (MSB), followed by the next most Significant Byte (MSB), followed by Least Significant Byte (LSB).
-pub fn unpack_u32<'a, I>(iter: &mut I) -> Result<u32, MqttError>
-where
-    I: Iterator<Item = &'a u8>,
-{
-    let bytes: [u8; size_of::<u32>()] = iter
-        .take(size_of::<u32>())
-        .copied()
-        .collect::<Vec<u8>>()
-        .try_into()
-        .map_err(|_| MqttError::MissingByte)?;
-
-    Ok(u32::from_be_bytes(bytes))
+pub fn unpack_u32<B: Buf>(buf: &mut B) -> Result<u32, MqttError> {
+    ensure_remaining(buf, size_of::<u32>()).map_err(|_| MqttError::MissingByte)?;
+    Ok(buf.get_u32())
 }
 
 /// ### UTF-8 Encoded String
@@ -69,49 +58,41 @@ where
 ///
 /// [(MQTT 3.1.1) 1.5.3 UTF-8 encoded strings](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/errata01/os/mqtt-v3.1.1-errata01-os-complete.html#_Toc442180829)<br/>
 /// [(MQTT 5) 1.5.4 UTF-8 Encoded String](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901010)
-pub fn unpack_string<'a, I>(iter: &mut I) -> Result<String, MqttError>
-where
-    I: Iterator<Item = &'a u8>,
-{
-    let len = usize::from(unpack_u16(iter)?);
+pub fn unpack_string<B: Buf>(buf: &mut B) -> Result<String, MqttError> {
+    let len = usize::from(unpack_u16(buf)?);
 
-    unpack_string_with_len(iter, len)
+    unpack_string_with_len(buf, len)
 }
 
-/// Read a set of bytes
-pub fn unpack_bytes<'a, I>(iter: &mut I, len: usize) -> Result<bytes::Bytes, MqttError>
-where
-    I: Iterator<Item = &'a u8>,
-{
-    Ok(Bytes::from_iter(iter.take(len).copied()))
+/// Read a set of bytes.
+///
+/// When reading from [`Bytes`] the returned bytes are a view into the same buffer and no data is copied.
+pub fn unpack_bytes<B: Buf>(buf: &mut B, len: usize) -> Result<Bytes, MqttError> {
+    ensure_remaining(buf, len)?;
+    Ok(buf.copy_to_bytes(len))
 }
 
-pub fn unpack_string_with_len<'a, I>(iter: &mut I, len: usize) -> Result<String, MqttError>
-where
-    I: Iterator<Item = &'a u8>,
-{
+pub fn unpack_string_with_len<B: Buf>(buf: &mut B, len: usize) -> Result<String, MqttError> {
     if len == 0 {
         return Ok(String::default());
     }
 
-    let chars: Vec<u8> = iter.take(len).copied().collect();
-
-    let result = String::from_utf8(chars).map_err(MqttError::MalformedString)?;
+    let chars = unpack_bytes(buf, len)?;
 
-    Ok(result)
+    Ok(String::from_utf8(chars.to_vec())?)
 }
 
-pub fn decode_length<'a, I>(iter: &mut I) -> Result<(usize, usize), MqttError>
-where
-    I: Iterator<Item = &'a u8>,
-{
+pub fn decode_length<B: Buf>(buf: &mut B) -> Result<(usize, usize), MqttError> {
     let mut multiplier: usize = 1;
     let mut value = 0;
     let mut bytes = 0;
     loop {
-        let byte = iter
-            .next()
-            .ok_or_else(|| MqttError::RequiredByteMissing("Missing variable length byte"))?;
+        if !buf.has_remaining() {
+            return Err(MqttError::RequiredByteMissing(
+                "Missing variable length byte",
+            ));
+        }
+        let byte = buf.get_u8();
 
         bytes += 1;
 
@@ -131,10 +112,21 @@ where
     Ok((value, bytes))
 }
 
+/// Number of bytes [`encode_length`] writes for `len`
+pub fn length_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => MAX_ENCODED_BYTES,
+    }
+}
+
 pub fn encode_length(len: usize, bytes: &mut BytesMut) {
     let mut mlen = len;
+    let start = bytes.len();
     loop {
-        if bytes.len() + 1 > MAX_ENCODED_BYTES {
+        if bytes.len() - start + 1 > MAX_ENCODED_BYTES {
             return;
         }
 
@@ -160,117 +152,112 @@ pub fn encode_length(len: usize, bytes: &mut BytesMut) {
 where
     I: Iterator<Item = &'a u8>,
 {
-    let key = unpack_string(iter)?;
-    let value = unpack_string(iter)?;
+    let key = unpack_string(&mut iter)?;
+    let value = unpack_string(&mut iter)?;
 
     Ok((key, value))
 }*/
 
 #[derive(Debug, Default)]
 pub struct Props {
-    payload_format_indicator: Option<bool>,
+    pub payload_format_indicator: Option<bool>,
     message_expriy_interval: Option<u32>,
-    content_type: Option<String>,
+    pub content_type: Option<String>,
     response_topic: Option<String>,
     correlation_data: Option<Bytes>,
-    session_expiry_interval: Option<u32>,
+    pub session_expiry_interval: Option<u32>,
     assigned_client_identifier: Option<String>,
     server_keep_alive: Option<u16>,
     authentication_method: Option<String>,
     authenication_data: Option<Bytes>,
     request_problem_infomation: Option<bool>,
-    will_delay_interval: Option<u32>,
+    pub will_delay_interval: Option<u32>,
     request_response_information: Option<bool>,
     response_infomation: Option<String>,
-    server_reference: Option<String>,
-    reason_string: Option<String>,
-    reveive_maximum: Option<u16>,
+    pub server_reference: Option<String>,
+    pub reason_string: Option<String>,
+    pub reveive_maximum: Option<u16>,
     topic_alias_maximum: Option<u16>,
     topic_alias: Option<u16>,
     maximum_qos: Option<u8>,
     retain_available: Option<bool>,
-    user_property: Option<Vec<(String, String)>>,
+    pub user_property: Option<Vec<(String, String)>>,
     maximum_packet_size: Option<u32>,
     wildcard_subscription_available: Option<bool>,
     subscription_identifier_available: Option<bool>,
     shared_subscription_available: Option<bool>,
-    subscription_identifer: Option<usize>,
+    pub subscription_identifers: Vec<usize>,
 }
 
-pub fn unpack_properties<'a, I>(iter: &mut I) -> Result<Props, MqttError>
-where
-    I: Iterator<Item = &'a u8>,
-{
+pub fn unpack_properties<B: Buf>(buf: &mut B) -> Result<Props, MqttError> {
     let mut props = Props::default();
-    let mut props_len = decode_length(iter)?.0;
+    let (props_len, _) = decode_length(buf)?;
+    let mut iter = unpack_bytes(buf, props_len)?;
 
-    while props_len > 0 {
-        let byte = iter.next().ok_or_else(|| MqttError::MissingByte)?;
+    while iter.has_remaining() {
+        let byte = iter.get_u8();
 
         match byte {
             // Byte
             0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2A => {
-                let data = iter.next().ok_or_else(|| MqttError::MissingByte)?;
+                let data = unpack_u8(&mut iter)?;
 
                 match byte {
                     0x01 => {
                         if props.payload_format_indicator.is_some() {
                             return Err(MqttError::ProtocolViolation);
                         }
-                        props.payload_format_indicator = Some(*data == 1);
+                        props.payload_format_indicator = Some(data == 1);
                     }
                     0x17 => {
-                        if *data > 1 || props.request_problem_infomation.is_some() {
+                        if data > 1 || props.request_problem_infomation.is_some() {
                             return Err(MqttError::ProtocolViolation);
                         }
 
-                        props.request_problem_infomation = Some(*data == 1);
+                        props.request_problem_infomation = Some(data == 1);
                     }
                     0x19 => {
-                        if *data > 1 || props.request_response_information.is_some() {
+                        if data > 1 || props.request_response_information.is_some() {
                             return Err(MqttError::ProtocolViolation);
                         }
-                        props.request_response_information = Some(*data == 1);
+                        props.request_response_information = Some(data == 1);
                     }
                     0x24 => {
                         if props.maximum_qos.is_some() {
                             return Err(MqttError::ProtocolViolation);
                         }
-                        props.maximum_qos = Some(*data);
+                        props.maximum_qos = Some(data);
                     }
                     0x25 => {
                         if props.retain_available.is_some() {
                             return Err(MqttError::ProtocolViolation);
                         }
-                        props.retain_available = Some(*data == 1);
+                        props.retain_available = Some(data == 1);
                     }
                     0x28 => {
                         if props.wildcard_subscription_available.is_some() {
                             return Err(MqttError::ProtocolViolation);
                         }
-                        props.wildcard_subscription_available = Some(*data == 1);
+                        props.wildcard_subscription_available = Some(data == 1);
                     }
                     0x29 => {
                         if props.subscription_identifier_available.is_some() {
                             return Err(MqttError::ProtocolViolation);
                         }
-                        props.subscription_identifier_available = Some(*data == 1);
+                        props.subscription_identifier_available = Some(data == 1);
                     }
                     0x2A => {
                         if props.shared_subscription_available.is_some() {
                             return Err(MqttError::ProtocolViolation);
                         }
-                        props.shared_subscription_available = Some(*data == 1);
+                        props.shared_subscription_available = Some(data == 1);
                     }
                     _ => return Err(MqttError::MalformedHeader),
                 }
-
-                props_len -= 1;
             }
             // Two Byte Integer
             0x13 | 0x21 | 0x22 | 0x23 => {
-                let data = unpack_u16(iter)?;
-                props_len -= size_of::<u16>();
+                let data = unpack_u16(&mut iter)?;
 
                 match byte {
                     0x13 => {
@@ -302,8 +289,7 @@ where
             }
             // Four Byte Integer
             0x02 | 0x11 | 0x18 | 0x27 => {
-                let data = unpack_u32(iter)?;
-                props_len -= size_of::<u32>();
+                let data = unpack_u32(&mut iter)?;
                 match byte {
                     0x02 => {
                         if props.message_expriy_interval.is_some() {
@@ -334,20 +320,15 @@ where
             }
             // Variable Byte Integer
             0x0B => {
-                let (value, len) = decode_length(iter)?;
-                props_len -= len;
+                let (value, _) = decode_length(&mut iter)?;
 
-                if props.subscription_identifer.is_some() {
-                    return Err(MqttError::ProtocolViolation);
-                }
-
-                props.subscription_identifer = Some(value);
+                // a PUBLISH can have one for each matching subscription
+                props.subscription_identifers.push(value);
             }
             // Binary Data
             0x09 | 0x16 => {
-                let len = unpack_u16(iter)?;
-                let data = unpack_bytes(iter, len as usize)?;
-                props_len -= size_of::<u16>() + data.len();
+                let len = unpack_u16(&mut iter)?;
+                let data = unpack_bytes(&mut iter, len as usize)?;
 
                 match byte {
                     0x09 => {
@@ -367,8 +348,7 @@ where
             }
             // UTF-8 Encoded String
             0x03 | 0x08 | 0x12 | 0x15 | 0x1A | 0x1C | 0x1F => {
-                let data = unpack_string(iter)?;
-                props_len -= size_of::<u16>() + data.len();
+                let data = unpack_string(&mut iter)?;
 
                 match byte {
                     0x03 => {
@@ -418,9 +398,8 @@ where
             }
             // UTF-8 String Pair
             0x26 => {
-                let key = unpack_string(iter)?;
-                let value = unpack_string(iter)?;
-                props_len -= (size_of::<u16>() * 2) + value.len() + key.len();
+                let key = unpack_string(&mut iter)?;
+                let value = unpack_string(&mut iter)?;
 
                 if let Some(property) = props.user_property.as_mut() {
                     property.push((key, value));
@@ -437,11 +416,31 @@ where
 
 #[cfg(test)]
 mod tests {
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
 
     use crate::packets::utils::unpack_u32;
 
-    use super::{decode_length, encode_length, unpack_string, unpack_u16};
+    use super::{
+        decode_length, encode_length, length_size, unpack_bytes, unpack_string, unpack_u16,
+    };
+
+    #[test]
+    fn test_length_size() {
+        for len in [
+            0,
+            127,
+            128,
+            16_383,
+            16_384,
+            2_097_151,
+            2_097_152,
+            268_435_455,
+        ] {
+            let mut bytes = BytesMut::new();
+            encode_length(len, &mut bytes);
+            assert_eq!(length_size(len), bytes.len(), "{}", len);
+        }
+    }
 
     #[test]
     fn test_encode_single_byte() {
@@ -471,9 +470,7 @@ mod tests {
 
     #[test]
     fn test_decode_single_byte() {
-        let byte: [u8; 1] = [0x1e];
-
-        let mut iter = byte.iter();
+        let mut iter = Bytes::from_static(&[0x1e]);
 
         let (value, len) = decode_length(&mut iter).expect("Failed to decode");
 
@@ -483,9 +480,7 @@ mod tests {
 
     #[test]
     fn test_decode_two_bytes() {
-        let bytes: [u8; 2] = [193, 2];
-
-        let mut iter = bytes.iter();
+        let mut iter = Bytes::from_static(&[193, 2]);
 
         let (value, len) = decode_length(&mut iter).unwrap();
         assert_eq!(value, 321);
@@ -494,9 +489,7 @@ mod tests {
 
     #[test]
     fn test_decode_two_bytes_with_extra() {
-        let bytes: [u8; 3] = [193, 2, 123];
-
-        let mut iter = bytes.iter();
+        let mut iter = Bytes::from_static(&[193, 2, 123]);
 
         let (value, len) = decode_length(&mut iter).unwrap();
         assert_eq!(value, 321);
@@ -505,9 +498,7 @@ mod tests {
 
     #[test]
     fn test_unpack_u32() {
-        let input: [u8; 4] = [0x00, 0x01, 0xD9, 0xF6];
-
-        let mut iter = input.iter();
+        let mut iter = Bytes::from_static(&[0x00, 0x01, 0xD9, 0xF6]);
 
         let result = unpack_u32(&mut iter).unwrap();
 
@@ -516,9 +507,7 @@ mod tests {
 
     #[test]
     fn test_unpack_u16() {
-        let input: [u8; 2] = [0x00, 0xC];
-
-        let mut iter = input.iter();
+        let mut iter = Bytes::from_static(&[0x00, 0xC]);
 
         let result = unpack_u16(&mut iter).unwrap();
 
@@ -527,9 +516,7 @@ mod tests {
 
     #[test]
     fn test_unpack_a() {
-        let input: [u8; 2] = [0x00, 0x0a];
-
-        let mut iter = input.iter();
+        let mut iter = Bytes::from_static(&[0x00, 0x0a]);
 
         let result = unpack_u16(&mut iter).expect("Failed to parse");
 
@@ -538,9 +525,7 @@ mod tests {
 
     #[test]
     fn test_unpack_error() {
-        let input: [u8; 1] = [0xC];
-
-        let mut iter = input.iter();
+        let mut iter = Bytes::from_static(&[0xC]);
 
         unpack_u16(&mut iter).expect_err("Testing Expect Error");
     }
@@ -548,14 +533,30 @@ mod tests {
     #[test]
     fn test_unpack_string() {
         //  'Hello, World' UTF8 string with u16 length header, in big endian order
-        let input: [u8; 14] = [
+        let mut iter = Bytes::from_static(&[
             0x00, 0xC, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x2c, 0x20, 0x57, 0x6f, 0x72, 0x6c, 0x64,
-        ];
-
-        let mut iter = input.iter();
+        ]);
 
         let result = unpack_string(&mut iter).unwrap();
 
         assert_eq!(result, "Hello, World".to_string())
     }
+
+    #[test]
+    fn test_unpack_string_too_short() {
+        let mut iter = Bytes::from_static(&[0x00, 0x05, 0x48, 0x65]);
+
+        unpack_string(&mut iter).expect_err("String is longer than the buffer");
+    }
+
+    #[test]
+    fn test_unpack_bytes_zero_copy() {
+        let data = Bytes::from_static(&[0x01, 0x02, 0x03, 0x04]);
+        let mut iter = data.clone();
+
+        let result = unpack_bytes(&mut iter, 3).unwrap();
+
+        assert_eq!(result.as_ptr(), data.as_ptr());
+        assert_eq!(iter.len(), 1);
+    }
 }
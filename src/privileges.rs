@@ -0,0 +1,158 @@
+//! ### Dropping Privileges
+//! Switch to an unprivileged user once the client listeners are bound, set with the `user` and `group` options.
+//!
+//! The broker can then be started as root to listen on ports below 1024 without serving clients as root.
+//! Privileges are only dropped when running as root, and only on Unix.
+//!
+//! [setuid(2)](https://man7.org/linux/man-pages/man2/setuid.2.html)
+
+use std::io;
+
+use tracing::{info, warn};
+
+/// Switch the process to `user`, and to `group` or else the primary group of the user.
+#[cfg(unix)]
+pub fn drop_privileges(user: &str, group: Option<&str>) -> io::Result<()> {
+    use std::ffi::CString;
+
+    // SAFETY: geteuid has no preconditions
+    if unsafe { libc::geteuid() } != 0 {
+        warn!(
+            "Not running as root, staying the current user instead of '{}'",
+            user
+        );
+        return Ok(());
+    }
+
+    let name = CString::new(user).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let (uid, primary_gid) = lookup_user(&name)?;
+    let gid = match group {
+        Some(group) => {
+            lookup_group(&CString::new(group).map_err(|_| io::ErrorKind::InvalidInput)?)?
+        }
+        None => primary_gid,
+    };
+
+    // The supplementary groups go first, they can't be changed once the user is switched
+    // SAFETY: `name` is a valid C string and the group lists are only read during the calls
+    let result = unsafe {
+        match group {
+            Some(_) => libc::setgroups(1, &gid),
+            None => libc::initgroups(name.as_ptr(), gid as _),
+        }
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: setgid and setuid have no preconditions, the gid has to change before the uid
+    if unsafe { libc::setgid(gid) } != 0 || unsafe { libc::setuid(uid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Root can't be regained if the uid was really dropped
+    // SAFETY: as above
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::other(
+            "Privileges could be regained after dropping them",
+        ));
+    }
+
+    info!("Dropped privileges to user '{}' ({}:{})", user, uid, gid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(user: &str, _group: Option<&str>) -> io::Result<()> {
+    warn!(
+        "Dropping privileges is only supported on Unix, staying the current user instead of '{}'",
+        user
+    );
+    Ok(())
+}
+
+/// Uid and primary gid of a user
+#[cfg(unix)]
+fn lookup_user(name: &std::ffi::CStr) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let mut buf = vec![0; 4096];
+    loop {
+        // SAFETY: passwd is plain data that getpwnam_r fills in
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        // SAFETY: every pointer is valid for the call and `buf.len()` is the size of `buf`
+        let code = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match code {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if result.is_null() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Unknown user '{}'", name.to_string_lossy()),
+                ))
+            }
+            0 => return Ok((passwd.pw_uid, passwd.pw_gid)),
+            code => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+/// Gid of a group
+#[cfg(unix)]
+fn lookup_group(name: &std::ffi::CStr) -> io::Result<libc::gid_t> {
+    let mut buf = vec![0; 4096];
+    loop {
+        // SAFETY: group is plain data that getgrnam_r fills in
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        // SAFETY: every pointer is valid for the call and `buf.len()` is the size of `buf`
+        let code = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut group,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match code {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if result.is_null() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Unknown group '{}'", name.to_string_lossy()),
+                ))
+            }
+            0 => return Ok(group.gr_gid),
+            code => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let root = CString::new("root").unwrap();
+        assert_eq!(lookup_user(&root).unwrap().0, 0);
+
+        let unknown = CString::new("mqtt-broker-no-such-user").unwrap();
+        assert_eq!(
+            lookup_user(&unknown).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            lookup_group(&unknown).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::MqttError;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// A v1 header is at most 107 bytes including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// ### PROXY protocol
+/// Read the PROXY protocol v1 or v2 header a load balancer sends at the start of a connection.
+///
+/// Returns the address of the client, or `None` if the header does not carry one
+/// (`UNKNOWN`, `LOCAL` or a non IP address family), in which case the address of the socket should be used.
+/// Only the header is read from the stream so the MQTT packets that follow are left untouched.
+///
+/// [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, MqttError>
+where
+    S: AsyncRead + Unpin,
+{
+    // The shortest v1 header `PROXY UNKNOWN\r\n` is longer than the v2 signature
+    let mut signature = [0u8; 12];
+    stream.read_exact(&mut signature).await?;
+
+    if &signature == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if signature.starts_with(V1_PREFIX) {
+        read_v1(stream, &signature).await
+    } else {
+        Err(MqttError::InvalidProxyHeader)
+    }
+}
+
+async fn read_v1<S>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>, MqttError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(MqttError::InvalidProxyHeader);
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line =
+        std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| MqttError::InvalidProxyHeader)?;
+    let mut parts = line.split(' ').skip(1);
+
+    match parts.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4") | Some("TCP6") => {
+            let ip = parts
+                .next()
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .ok_or(MqttError::InvalidProxyHeader)?;
+            let port = parts
+                .nth(1)
+                .and_then(|port| port.parse::<u16>().ok())
+                .ok_or(MqttError::InvalidProxyHeader)?;
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(MqttError::InvalidProxyHeader),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>, MqttError>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(MqttError::InvalidProxyHeader);
+    }
+
+    // LOCAL connections are health checks from the proxy itself
+    match version_command & 0x0F {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(MqttError::InvalidProxyHeader),
+    }
+
+    match family >> 4 {
+        // AF_INET: src addr, dst addr, src port, dst port
+        1 => {
+            let addr = body.get(..12).ok_or(MqttError::InvalidProxyHeader)?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        2 => {
+            let addr = body.get(..36).ok_or(MqttError::InvalidProxyHeader)?;
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addr[..16]);
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_v1() {
+        let mut data: &[u8] = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n\x10";
+        let addr = read_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        // The CONNECT packet is left in the stream
+        assert_eq!(data, b"\x10");
+
+        let mut data: &[u8] = b"PROXY TCP6 ::1 ::1 4000 1883\r\n";
+        let addr = read_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("[::1]:4000".parse().unwrap()));
+
+        let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut data).await.unwrap(), None);
+
+        let mut data: &[u8] = b"PROXY TCP4 bad 192.168.0.11 56324 1883\r\n";
+        assert!(read_header(&mut data).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        header.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        header.extend_from_slice(&[0xC3, 0x50, 0x07, 0x5B]);
+        header.push(0x10);
+
+        let mut data = header.as_slice();
+        let addr = read_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("10.0.0.1:50000".parse().unwrap()));
+        assert_eq!(data, b"\x10");
+
+        // LOCAL
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read_header(&mut header.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_header() {
+        let mut data: &[u8] = b"\x10\x0F\x00\x04MQTT\x04\x02\x00\x3C";
+        assert!(read_header(&mut data).await.is_err());
+    }
+}
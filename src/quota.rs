@@ -0,0 +1,247 @@
+use std::str::FromStr;
+
+use crate::{
+    config::Config, error::MqttError, packets::enums::DisconnectReasonCode, utils::topic_matches,
+};
+
+/// Maximum payload size for the topics matching a filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadLimit {
+    /// Topic filter the limit applies to, `None` for every topic
+    pub filter: Option<String>,
+    /// Maximum payload size in bytes
+    pub max_size: usize,
+}
+
+/// Reason code a client is disconnected with when it publishes a payload over the limit for its topic
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadLimitReason {
+    /// Quota Exceeded (0x97)
+    #[default]
+    QuotaExceeded,
+    /// Packet Too Large (0x95), like a packet over the Maximum Packet Size
+    PacketTooLarge,
+}
+
+impl From<PayloadLimitReason> for DisconnectReasonCode {
+    fn from(value: PayloadLimitReason) -> Self {
+        match value {
+            PayloadLimitReason::QuotaExceeded => Self::QuotaExceeded,
+            PayloadLimitReason::PacketTooLarge => Self::PacketTooLarge,
+        }
+    }
+}
+
+impl FromStr for PayloadLimitReason {
+    type Err = MqttError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "quota_exceeded" => Ok(Self::QuotaExceeded),
+            "packet_too_large" => Ok(Self::PacketTooLarge),
+            _ => Err(MqttError::InvalidConfig(format!(
+                "Invalid value '{}' for 'payload_limit_reason'",
+                value
+            ))),
+        }
+    }
+}
+
+/// ### Quotas
+/// Per client limits that keep a single client from growing the broker's memory without bound.
+///
+/// A SUBSCRIBE over the subscription quota is answered with the Quota Exceeded reason code,
+/// a PUBLISH with a payload over the limit for its topic disconnects the client with the [`PayloadLimitReason`].
+/// Topic names and filters with too many levels or bytes are refused with Topic Name Invalid or Topic Filter Invalid,
+/// so a client can't make the subscription and retained message trees arbitrarily deep.
+/// The number of in-flight and queued messages is limited by `max_inflight_messages` and `max_queued_messages`.
+///
+/// [(MQTT 5) 3.9.3 SUBACK Payload](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901178)
+#[derive(Debug, Default, Clone)]
+pub struct Quotas {
+    /// Maximum topic filters a client can be subscribed to, `None` is unlimited.
+    pub max_subscriptions: Option<usize>,
+    /// Payload limits in the order they were configured
+    pub payload_limits: Vec<PayloadLimit>,
+    pub payload_limit_reason: PayloadLimitReason,
+    /// Maximum levels of a topic name or filter, `None` is unlimited.
+    pub max_topic_levels: Option<usize>,
+    /// Maximum length of a topic name or filter in bytes, `None` is unlimited.
+    pub max_topic_length: Option<usize>,
+}
+
+impl From<&Config> for Quotas {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_subscriptions: config.max_subscriptions_per_client,
+            payload_limits: config.payload_limits.clone(),
+            payload_limit_reason: config.payload_limit_reason,
+            max_topic_levels: config.max_topic_levels,
+            max_topic_length: config.max_topic_length,
+        }
+    }
+}
+
+impl Quotas {
+    /// Can a client with `current` subscriptions subscribe to another topic filter
+    pub fn allows_subscription(&self, current: usize) -> bool {
+        self.max_subscriptions.is_none_or(|max| current < max)
+    }
+
+    /// Maximum payload size for a topic.
+    ///
+    /// The first limit with a matching filter is used, then the limit without a filter.
+    pub fn max_payload_size(&self, topic: &str) -> Option<usize> {
+        self.payload_limits
+            .iter()
+            .find(|limit| {
+                limit
+                    .filter
+                    .as_deref()
+                    .is_some_and(|filter| topic_matches(filter, topic))
+            })
+            .or_else(|| self.payload_limits.iter().find(|l| l.filter.is_none()))
+            .map(|limit| limit.max_size)
+    }
+
+    /// Is the payload within the limit for the topic
+    pub fn allows_payload(&self, topic: &str, len: usize) -> bool {
+        self.max_payload_size(topic).is_none_or(|max| len <= max)
+    }
+
+    /// Is a topic name or filter within the level and length limits.
+    /// The levels of a shared subscription are counted without the `$share/{ShareName}/` prefix.
+    pub fn allows_topic(&self, topic: &str) -> bool {
+        let filter = topic
+            .strip_prefix("$share/")
+            .and_then(|rest| rest.split_once('/'))
+            .map_or(topic, |(_, filter)| filter);
+
+        self.max_topic_length.is_none_or(|max| topic.len() <= max)
+            && self
+                .max_topic_levels
+                .is_none_or(|max| filter.split('/').nth(max).is_none())
+    }
+}
+
+/// Maximum number of retained messages with a topic matching a filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetainedFilterLimit {
+    pub filter: String,
+    pub max_messages: usize,
+}
+
+/// What to do with a retained message that would go over the retained limits
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RetainedPolicy {
+    /// Keep the stored messages, the new message is still sent to subscribers but not retained
+    #[default]
+    Reject,
+    /// Remove the least recently set or sent messages until the new message fits
+    EvictLru,
+}
+
+impl FromStr for RetainedPolicy {
+    type Err = MqttError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(Self::Reject),
+            "evict_lru" => Ok(Self::EvictLru),
+            _ => Err(MqttError::InvalidConfig(format!(
+                "Invalid value '{}' for 'retained_policy'",
+                value
+            ))),
+        }
+    }
+}
+
+/// ### Retained Limits
+/// Limits on the retained message store, so clients can't make the broker keep an unbounded number of messages.
+///
+/// The size of a message is the length of its topic and payload. Every filter limit matching a topic applies,
+/// as well as the limits on the whole store. Topics starting with `$` such as `$SYS` don't count towards the limits.
+#[derive(Debug, Default, Clone)]
+pub struct RetainedLimits {
+    /// Maximum number of retained messages, `None` is unlimited.
+    pub max_messages: Option<usize>,
+    /// Maximum size of all retained messages in bytes, `None` is unlimited.
+    pub max_bytes: Option<usize>,
+    pub filter_limits: Vec<RetainedFilterLimit>,
+    pub policy: RetainedPolicy,
+}
+
+impl From<&Config> for RetainedLimits {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_messages: config.max_retained_messages,
+            max_bytes: config.max_retained_bytes,
+            filter_limits: config.retained_filter_limits.clone(),
+            policy: config.retained_policy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_limits() {
+        let quotas = Quotas {
+            max_subscriptions: None,
+            payload_limits: vec![
+                PayloadLimit {
+                    filter: None,
+                    max_size: 100,
+                },
+                PayloadLimit {
+                    filter: Some("firmware/#".into()),
+                    max_size: 1000,
+                },
+            ],
+            payload_limit_reason: PayloadLimitReason::default(),
+            max_topic_levels: None,
+            max_topic_length: None,
+        };
+
+        assert_eq!(quotas.max_payload_size("firmware/v2"), Some(1000));
+        assert_eq!(quotas.max_payload_size("sensors/temp"), Some(100));
+        assert!(quotas.allows_payload("firmware/v2", 500));
+        assert!(!quotas.allows_payload("sensors/temp", 500));
+        assert!(Quotas::default().allows_payload("sensors/temp", usize::MAX));
+    }
+
+    #[test]
+    fn test_subscription_quota() {
+        let quotas = Quotas {
+            max_subscriptions: Some(2),
+            payload_limits: Vec::new(),
+            payload_limit_reason: PayloadLimitReason::default(),
+            max_topic_levels: None,
+            max_topic_length: None,
+        };
+
+        assert!(quotas.allows_subscription(1));
+        assert!(!quotas.allows_subscription(2));
+    }
+
+    #[test]
+    fn test_topic_limits() {
+        let quotas = Quotas {
+            max_topic_levels: Some(3),
+            max_topic_length: Some(16),
+            ..Quotas::default()
+        };
+
+        assert!(quotas.allows_topic("a/b/c"));
+        assert!(quotas.allows_topic("a/+/#"));
+        assert!(!quotas.allows_topic("a/b/c/d"));
+        assert!(quotas.allows_topic("a/b/"));
+        assert!(!quotas.allows_topic("a/b/c/"));
+        assert!(!quotas.allows_topic("sensors/temperature"));
+        // The share name is not a level of the filter
+        assert!(quotas.allows_topic("$share/g/a/b/c"));
+        assert!(Quotas::default().allows_topic(&"a/".repeat(10_000)));
+    }
+}
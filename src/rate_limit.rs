@@ -0,0 +1,812 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::{
+    config::Config,
+    core::{broker_info, enums::ProtocalVersion},
+    packets::enums::{ConnectReturnCode, QosLevel},
+};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Fixed one second window counter
+#[derive(Debug)]
+struct RateWindow {
+    start: Instant,
+    count: u32,
+}
+
+impl RateWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            count: 0,
+        }
+    }
+
+    /// Record a hit, returns false if the limit has been exceeded for the current window.
+    fn hit(&mut self, now: Instant, limit: u32) -> bool {
+        if now.duration_since(self.start) >= WINDOW {
+            self.start = now;
+            self.count = 0;
+        }
+
+        self.count += 1;
+
+        self.count <= limit
+    }
+}
+
+/// Tokens refilled at a steady rate up to the size of the bucket
+#[derive(Debug)]
+struct TokenBucket {
+    /// Goes below zero when tokens are taken before they have been refilled
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket, the tokens are capped to the burst on the first refill
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: f64::INFINITY,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant, rate: u32, burst: u32) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * f64::from(rate)).min(f64::from(burst));
+    }
+
+    /// Take a token if there is one
+    fn try_take(&mut self) -> bool {
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Take the next token before it has been refilled, returns how long until it is
+    fn reserve(&mut self, rate: u32) -> Duration {
+        self.tokens -= 1.0;
+        Duration::from_secs_f64(-self.tokens.min(0.0) / f64::from(rate))
+    }
+}
+
+/// Reason a connection was refused by the [`RateLimiter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// Too many CONNECT packets from the same address
+    ConnectionRate,
+    /// The broker has reached `max_connections`
+    MaxConnections,
+    /// The address is on the ban list
+    Banned,
+}
+
+impl LimitExceeded {
+    /// The CONNACK return code to send for the given protocol.
+    /// v3.1.1 has no specific codes so the server is reported as unavailable,
+    /// apart from a ban which is sent as Not Authorized.
+    pub fn return_code(&self, protocol: ProtocalVersion) -> ConnectReturnCode {
+        match (self, protocol) {
+            (Self::Banned, _) => ConnectReturnCode::Banned.for_protocol(protocol),
+            (_, ProtocalVersion::Three | ProtocalVersion::Four | ProtocalVersion::Unknown) => {
+                ConnectReturnCode::V4ServerUnavailable
+            }
+            (Self::ConnectionRate, ProtocalVersion::Five) => {
+                ConnectReturnCode::ConnectionRateExceeded
+            }
+            (Self::MaxConnections, ProtocalVersion::Five) => ConnectReturnCode::QuotaExceeded,
+        }
+    }
+}
+
+/// Why an address was banned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanReason {
+    /// Failed authentication `ban_auth_failures` times in a row
+    AuthFailures,
+    /// Exceeded `max_connection_rate` `ban_connection_floods` times
+    ConnectionFlood,
+}
+
+impl BanReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AuthFailures => "auth_failures",
+            Self::ConnectionFlood => "connection_flood",
+        }
+    }
+}
+
+/// An address on the ban list, see [`RateLimiter::bans`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub addr: IpAddr,
+    pub reason: BanReason,
+    /// Time left until the ban is lifted
+    pub remaining: Duration,
+    /// Times the address has been banned, each ban lasts twice as long as the one before
+    pub count: u32,
+}
+
+/// When to ban an address and for how long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanPolicy {
+    pub auth_failures: Option<u32>,
+    pub connection_floods: Option<u32>,
+    pub time: Duration,
+    pub max_time: Duration,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        Self {
+            auth_failures: None,
+            connection_floods: None,
+            time: Duration::from_secs(60),
+            max_time: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl From<&Config> for BanPolicy {
+    fn from(config: &Config) -> Self {
+        Self {
+            auth_failures: config.ban_auth_failures,
+            connection_floods: config.ban_connection_floods,
+            time: config.ban_time,
+            max_time: config.ban_time_max,
+        }
+    }
+}
+
+impl BanPolicy {
+    /// Length of the `count`th ban of an address, doubling up to `max_time`
+    fn ban_time(&self, count: u32) -> Duration {
+        self.time
+            .saturating_mul(1 << count.saturating_sub(1).min(16))
+            .min(self.max_time)
+    }
+}
+
+/// Strikes and bans of a single address
+#[derive(Debug)]
+struct Offender {
+    auth_failures: u32,
+    connection_floods: u32,
+    bans: u32,
+    banned: Option<(Instant, BanReason)>,
+    last_seen: Instant,
+}
+
+impl Offender {
+    fn new(now: Instant) -> Self {
+        Self {
+            auth_failures: 0,
+            connection_floods: 0,
+            bans: 0,
+            banned: None,
+            last_seen: now,
+        }
+    }
+
+    fn banned_until(&self, now: Instant) -> Option<(Instant, BanReason)> {
+        self.banned.filter(|(until, _)| *until > now)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    max_connections: Option<usize>,
+    max_connection_rate: Option<u32>,
+    bans: BanPolicy,
+}
+
+/// PUBLISH rates of the clients
+#[derive(Debug, Clone)]
+struct PublishLimits {
+    rate: Option<u32>,
+    user_rates: HashMap<String, Option<u32>>,
+    burst: Option<u32>,
+    flood_timeout: Duration,
+}
+
+impl PublishLimits {
+    /// Rate and burst of a client, `None` is unlimited
+    fn for_user(&self, username: Option<&str>) -> Option<(u32, u32)> {
+        let rate = username
+            .and_then(|username| self.user_rates.get(username).copied())
+            .unwrap_or(self.rate)?;
+        let burst = match self.burst {
+            Some(burst) => burst.max(1),
+            None => rate,
+        };
+        Some((rate, burst))
+    }
+}
+
+impl From<&Config> for PublishLimits {
+    fn from(config: &Config) -> Self {
+        Self {
+            rate: config.max_publish_rate,
+            user_rates: config.user_publish_rates.clone(),
+            burst: config.max_publish_burst,
+            flood_timeout: config.publish_flood_timeout,
+        }
+    }
+}
+
+/// What to do with a PUBLISH, see [`PublishLimiter::check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttle {
+    /// The client is within its rate
+    Allow,
+    /// A QoS 0 message over the rate, it is dropped
+    Drop,
+    /// A QoS 1 or 2 message over the rate, nothing more is read from the client until the delay is over so it is slowed down to its rate
+    Delay(Duration),
+    /// The client has been publishing over its rate for longer than the `publish_flood_timeout`
+    Exceeded,
+}
+
+/// ### Rate Limiter
+/// Shared between all client handlers to throttle inbound connections and the PUBLISH packets of each client.
+/// The limits can be changed while the broker is running with [`RateLimiter::reload`].
+///
+/// Each client has a token bucket refilled at its `max_publish_rate`, holding up to `max_publish_burst` tokens.
+/// A QoS 0 message without a token is dropped, a QoS 1 or 2 message takes its token in advance
+/// and the client is not read from until it is due. A client that keeps publishing before its tokens are due
+/// for longer than the `publish_flood_timeout` is disconnected.
+///
+/// Addresses that fail authentication or flood CONNECT packets too often are banned for a while,
+/// and banned again for twice as long if they keep at it.
+/// An address is forgotten once it has behaved for `ban_time_max`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RwLock<Limits>,
+    publish: RwLock<PublishLimits>,
+    connections: Mutex<HashMap<IpAddr, RateWindow>>,
+    offenders: Mutex<HashMap<IpAddr, Offender>>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        max_connections: Option<usize>,
+        max_connection_rate: Option<u32>,
+        max_publish_rate: Option<u32>,
+    ) -> Self {
+        Self {
+            limits: RwLock::new(Limits {
+                max_connections,
+                max_connection_rate,
+                bans: BanPolicy::default(),
+            }),
+            publish: RwLock::new(PublishLimits {
+                rate: max_publish_rate,
+                user_rates: HashMap::new(),
+                burst: None,
+                flood_timeout: Duration::from_secs(10),
+            }),
+            connections: Mutex::new(HashMap::new()),
+            offenders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ban addresses with the given policy
+    pub fn with_ban_policy(mut self, policy: BanPolicy) -> Self {
+        self.limits
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner())
+            .bans = policy;
+        self
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let mut limiter = Self::new(
+            config.max_connections,
+            config.max_connection_rate,
+            config.max_publish_rate,
+        )
+        .with_ban_policy(BanPolicy::from(config));
+        *limiter
+            .publish
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner()) = PublishLimits::from(config);
+        limiter
+    }
+
+    /// Apply the limits of a reloaded config, connected clients use the new limits straight away.
+    pub fn reload(&self, config: &Config) {
+        *self.limits.write().unwrap_or_else(|err| err.into_inner()) = Limits {
+            max_connections: config.max_connections,
+            max_connection_rate: config.max_connection_rate,
+            bans: BanPolicy::from(config),
+        };
+        *self.publish.write().unwrap_or_else(|err| err.into_inner()) = PublishLimits::from(config);
+    }
+
+    fn limits(&self) -> Limits {
+        *self.limits.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Check if a CONNECT from the given address is allowed.
+    pub fn check_connect(&self, addr: IpAddr) -> Result<(), LimitExceeded> {
+        if self.is_banned(addr) {
+            return Err(LimitExceeded::Banned);
+        }
+
+        let limits = self.limits();
+        if let Some(max) = limits.max_connections {
+            // the connecting client has already been counted
+            if broker_info::clients_connected() > max {
+                return Err(LimitExceeded::MaxConnections);
+            }
+        }
+
+        if let Some(limit) = limits.max_connection_rate {
+            let now = Instant::now();
+            let mut connections = self
+                .connections
+                .lock()
+                .unwrap_or_else(|err| err.into_inner());
+
+            connections.retain(|_, window| now.duration_since(window.start) < WINDOW);
+
+            if !connections
+                .entry(addr)
+                .or_insert_with(|| RateWindow::new(now))
+                .hit(now, limit)
+            {
+                drop(connections);
+                self.strike(addr, BanReason::ConnectionFlood);
+                return Err(LimitExceeded::ConnectionRate);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record a failed authentication from the address
+    pub fn auth_failed(&self, addr: IpAddr) {
+        self.strike(addr, BanReason::AuthFailures);
+    }
+
+    /// Reset the failed authentications of the address
+    pub fn auth_succeeded(&self, addr: IpAddr) {
+        if let Some(offender) = self
+            .offenders
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .get_mut(&addr)
+        {
+            offender.auth_failures = 0;
+        }
+    }
+
+    fn is_banned(&self, addr: IpAddr) -> bool {
+        self.offenders
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(&addr)
+            .and_then(|offender| offender.banned_until(Instant::now()))
+            .is_some()
+    }
+
+    /// Count a strike against the address, banning it once it reaches the limit for the reason
+    fn strike(&self, addr: IpAddr, reason: BanReason) {
+        let policy = self.limits().bans;
+        let limit = match reason {
+            BanReason::AuthFailures => policy.auth_failures,
+            BanReason::ConnectionFlood => policy.connection_floods,
+        };
+        let Some(limit) = limit else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut offenders = self.offenders.lock().unwrap_or_else(|err| err.into_inner());
+        offenders.retain(|_, offender| {
+            offender.banned_until(now).is_some()
+                || now.duration_since(offender.last_seen) < policy.max_time
+        });
+
+        let offender = offenders.entry(addr).or_insert_with(|| Offender::new(now));
+        offender.last_seen = now;
+        let strikes = match reason {
+            BanReason::AuthFailures => &mut offender.auth_failures,
+            BanReason::ConnectionFlood => &mut offender.connection_floods,
+        };
+        *strikes += 1;
+        if *strikes < limit {
+            return;
+        }
+
+        offender.auth_failures = 0;
+        offender.connection_floods = 0;
+        offender.bans += 1;
+        let time = policy.ban_time(offender.bans);
+        offender.banned = Some((now + time, reason));
+        info!("Banned {} for {:?}: {}", addr, time, reason.as_str());
+    }
+
+    /// Addresses that are currently banned
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        self.offenders
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .filter_map(|(addr, offender)| {
+                offender.banned_until(now).map(|(until, reason)| Ban {
+                    addr: *addr,
+                    reason,
+                    remaining: until - now,
+                    count: offender.bans,
+                })
+            })
+            .collect()
+    }
+
+    /// Lift the ban on an address and forget its strikes, returns false if it was not banned
+    pub fn unban(&self, addr: IpAddr) -> bool {
+        self.offenders
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&addr)
+            .is_some_and(|offender| offender.banned_until(Instant::now()).is_some())
+    }
+
+    /// Create a limiter for the PUBLISH packets of a single client
+    pub fn publish_limiter(self: &Arc<Self>) -> PublishLimiter {
+        PublishLimiter {
+            limiter: self.clone(),
+            username: None,
+            bucket: TokenBucket::new(Instant::now()),
+            due: None,
+            throttled_since: None,
+        }
+    }
+}
+
+/// Per client PUBLISH rate limit
+#[derive(Debug)]
+pub struct PublishLimiter {
+    limiter: Arc<RateLimiter>,
+    /// Picks the `user_publish_rate` of the client
+    username: Option<String>,
+    bucket: TokenBucket,
+    /// When the last token taken in advance is refilled
+    due: Option<Instant>,
+    /// When the client was first held back, reset once the tokens it took in advance have been refilled
+    throttled_since: Option<Instant>,
+}
+
+impl PublishLimiter {
+    /// Use the rate of the user once the client has logged in
+    pub fn set_username(&mut self, username: Option<String>) {
+        self.username = username;
+    }
+
+    /// Record a PUBLISH with the given QoS, the throttled messages are counted in the broker stats.
+    pub fn check(&mut self, qos: QosLevel) -> Throttle {
+        let throttle = self.check_at(Instant::now(), qos);
+        match throttle {
+            Throttle::Drop => broker_info::publish_throttled_dropped(),
+            Throttle::Delay(_) => broker_info::publish_throttled_delayed(),
+            Throttle::Allow | Throttle::Exceeded => {}
+        }
+        throttle
+    }
+
+    fn check_at(&mut self, now: Instant, qos: QosLevel) -> Throttle {
+        let (limits, flood_timeout) = {
+            let publish = self
+                .limiter
+                .publish
+                .read()
+                .unwrap_or_else(|err| err.into_inner());
+            (
+                publish.for_user(self.username.as_deref()),
+                publish.flood_timeout,
+            )
+        };
+        let Some((rate, burst)) = limits else {
+            return Throttle::Allow;
+        };
+
+        self.bucket.refill(now, rate, burst);
+        // Back to a balance of zero or more, the client has kept to its rate since it was held back
+        if self.due.is_none_or(|due| now >= due) {
+            self.throttled_since = None;
+        }
+        if self.bucket.try_take() {
+            return Throttle::Allow;
+        }
+        if qos == QosLevel::AtMost {
+            return Throttle::Drop;
+        }
+        let since = *self.throttled_since.get_or_insert(now);
+        if rate == 0 || now.duration_since(since) >= flood_timeout {
+            return Throttle::Exceeded;
+        }
+        let delay = self.bucket.reserve(rate);
+        self.due = Some(now + delay);
+        Throttle::Delay(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_window() {
+        let now = Instant::now();
+        let mut window = RateWindow::new(now);
+
+        assert!(window.hit(now, 2));
+        assert!(window.hit(now, 2));
+        assert!(!window.hit(now, 2));
+
+        assert!(window.hit(now + WINDOW, 2));
+    }
+
+    #[test]
+    fn test_connection_rate() {
+        let limiter = RateLimiter::new(None, Some(1), None);
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        assert!(limiter.check_connect(addr).is_ok());
+        assert_eq!(
+            limiter.check_connect(addr),
+            Err(LimitExceeded::ConnectionRate)
+        );
+        assert!(limiter
+            .check_connect(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_ban_auth_failures() {
+        let limiter = RateLimiter::new(None, None, None).with_ban_policy(BanPolicy {
+            auth_failures: Some(2),
+            ..BanPolicy::default()
+        });
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        // a successful login resets the count
+        limiter.auth_failed(addr);
+        limiter.auth_succeeded(addr);
+        limiter.auth_failed(addr);
+        assert!(limiter.check_connect(addr).is_ok());
+
+        limiter.auth_failed(addr);
+        assert_eq!(limiter.check_connect(addr), Err(LimitExceeded::Banned));
+        assert!(limiter
+            .check_connect(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+            .is_ok());
+        assert_eq!(
+            LimitExceeded::Banned.return_code(ProtocalVersion::Four),
+            ConnectReturnCode::V4NotAuthorized
+        );
+
+        let bans = limiter.bans();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].addr, addr);
+        assert_eq!(bans[0].reason, BanReason::AuthFailures);
+        assert!(bans[0].remaining <= Duration::from_secs(60));
+
+        assert!(limiter.unban(addr));
+        assert!(!limiter.unban(addr));
+        assert!(limiter.check_connect(addr).is_ok());
+    }
+
+    #[test]
+    fn test_ban_connection_flood() {
+        let limiter = RateLimiter::new(None, Some(1), None).with_ban_policy(BanPolicy {
+            connection_floods: Some(2),
+            ..BanPolicy::default()
+        });
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        assert!(limiter.check_connect(addr).is_ok());
+        assert_eq!(
+            limiter.check_connect(addr),
+            Err(LimitExceeded::ConnectionRate)
+        );
+        assert_eq!(
+            limiter.check_connect(addr),
+            Err(LimitExceeded::ConnectionRate)
+        );
+        assert_eq!(limiter.check_connect(addr), Err(LimitExceeded::Banned));
+        assert_eq!(limiter.bans()[0].reason, BanReason::ConnectionFlood);
+    }
+
+    #[test]
+    fn test_ban_backoff() {
+        let policy = BanPolicy {
+            auth_failures: Some(1),
+            time: Duration::from_secs(10),
+            max_time: Duration::from_secs(30),
+            ..BanPolicy::default()
+        };
+        assert_eq!(policy.ban_time(1), Duration::from_secs(10));
+        assert_eq!(policy.ban_time(2), Duration::from_secs(20));
+        assert_eq!(policy.ban_time(3), Duration::from_secs(30));
+        assert_eq!(policy.ban_time(u32::MAX), Duration::from_secs(30));
+
+        let limiter = RateLimiter::new(None, None, None).with_ban_policy(policy);
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        limiter.auth_failed(addr);
+        assert_eq!(limiter.bans()[0].count, 1);
+
+        // once the ban is over the next one is twice as long
+        limiter
+            .offenders
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .banned = None;
+        assert!(limiter.bans().is_empty());
+        limiter.auth_failed(addr);
+
+        let bans = limiter.bans();
+        assert_eq!(bans[0].count, 2);
+        assert!(bans[0].remaining > Duration::from_secs(10));
+    }
+
+    fn config(config: &str) -> Config {
+        crate::config::ConfigBuilder::new()
+            .parse(config)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(now);
+
+        // starts full
+        bucket.refill(now, 2, 2);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        // refilled at the rate, up to the burst
+        bucket.refill(now + Duration::from_millis(500), 2, 2);
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        bucket.refill(now + Duration::from_secs(10), 2, 2);
+        assert_eq!(bucket.tokens, 2.0);
+
+        // a reserved token is taken from the next refill
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.reserve(2), Duration::from_millis(500));
+        assert_eq!(bucket.reserve(2), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_publish_limiter_unlimited() {
+        let limiter = Arc::new(RateLimiter::new(None, None, None));
+        let mut publish = limiter.publish_limiter();
+
+        assert!((0..1000).all(|_| publish.check(QosLevel::AtMost) == Throttle::Allow));
+    }
+
+    #[test]
+    fn test_publish_limiter() {
+        let limiter = Arc::new(RateLimiter::new(None, None, Some(3)));
+        let mut publish = limiter.publish_limiter();
+        let now = Instant::now();
+
+        // QoS 0 messages over the rate are dropped
+        assert_eq!(
+            (0..5)
+                .filter(|_| publish.check_at(now, QosLevel::AtMost) == Throttle::Allow)
+                .count(),
+            3
+        );
+        assert_eq!(publish.check_at(now, QosLevel::AtMost), Throttle::Drop);
+
+        // QoS 1 and 2 messages wait for their token
+        assert_eq!(
+            publish.check_at(now, QosLevel::AtLeast),
+            Throttle::Delay(Duration::from_secs_f64(1.0 / 3.0))
+        );
+        let later = now + Duration::from_secs(1);
+        assert_eq!(publish.check_at(later, QosLevel::Exactly), Throttle::Allow);
+
+        // a client that keeps publishing before its tokens are due is disconnected after the flood timeout
+        publish.bucket.tokens = 0.0;
+        let mut at = later;
+        while let Throttle::Delay(delay) = publish.check_at(at, QosLevel::AtLeast) {
+            at += delay / 2;
+        }
+        assert!(at >= later + Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_publish_limiter_keeps_rate() {
+        let limiter = Arc::new(RateLimiter::new(None, None, Some(3)));
+        let mut publish = limiter.publish_limiter();
+        let now = Instant::now();
+
+        // a burst, then a client that waits for each token is never disconnected
+        for _ in 0..3 {
+            assert_eq!(publish.check_at(now, QosLevel::AtLeast), Throttle::Allow);
+        }
+        let mut at = now;
+        for _ in 0..100 {
+            match publish.check_at(at, QosLevel::AtLeast) {
+                Throttle::Delay(delay) => at += delay,
+                throttle => panic!("Expected the message to be delayed, got {:?}", throttle),
+            }
+        }
+        assert!(at > now + Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_publish_limiter_users() {
+        let limiter = Arc::new(RateLimiter::from_config(&config(
+            "max_publish_rate 1\nuser_publish_rate sensor-1 3\nuser_publish_rate admin -1\n",
+        )));
+        let now = Instant::now();
+        let allowed = |username: Option<&str>| {
+            let mut publish = limiter.publish_limiter();
+            publish.set_username(username.map(str::to_string));
+            (0..100)
+                .filter(|_| publish.check_at(now, QosLevel::AtMost) == Throttle::Allow)
+                .count()
+        };
+
+        assert_eq!(allowed(None), 1);
+        assert_eq!(allowed(Some("other")), 1);
+        assert_eq!(allowed(Some("sensor-1")), 3);
+        assert_eq!(allowed(Some("admin")), 100);
+    }
+
+    #[test]
+    fn test_publish_burst() {
+        let limiter = Arc::new(RateLimiter::from_config(&config(
+            "max_publish_rate 0\nmax_publish_burst 2\n",
+        )));
+        let mut publish = limiter.publish_limiter();
+        let now = Instant::now();
+
+        // a rate of 0 only allows the burst, and never refills it
+        assert_eq!(publish.check_at(now, QosLevel::AtLeast), Throttle::Allow);
+        assert_eq!(publish.check_at(now, QosLevel::AtLeast), Throttle::Allow);
+        assert_eq!(
+            publish.check_at(now + Duration::from_secs(60), QosLevel::AtLeast),
+            Throttle::Exceeded
+        );
+    }
+
+    #[test]
+    fn test_reload() {
+        let limiter = Arc::new(RateLimiter::new(None, None, Some(1)));
+        let mut publish = limiter.publish_limiter();
+        assert_eq!(publish.check(QosLevel::AtMost), Throttle::Allow);
+        assert_eq!(publish.check(QosLevel::AtMost), Throttle::Drop);
+
+        limiter.reload(&config("max_publish_rate -1\n"));
+
+        assert_eq!(publish.check(QosLevel::AtMost), Throttle::Allow);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::Sleep,
+};
+
+/// Where the reader is in the stream of packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// Between packets
+    Idle,
+    /// Reading the Remaining Length, `shift` bits of it have been read
+    Length { remaining: usize, shift: u32 },
+    /// This many bytes of the packet are still to come
+    Body(usize),
+}
+
+impl Framing {
+    /// Follow the packets in `bytes`, returns true if a packet was completed
+    fn advance(&mut self, mut bytes: &[u8]) -> bool {
+        let mut completed = false;
+        while let Some(&byte) = bytes.first() {
+            *self = match *self {
+                Self::Idle => {
+                    bytes = &bytes[1..];
+                    Self::Length {
+                        remaining: 0,
+                        shift: 0,
+                    }
+                }
+                Self::Length { remaining, shift } => {
+                    bytes = &bytes[1..];
+                    let remaining = remaining | usize::from(byte & 0x7F) << shift;
+                    // A Remaining Length over 4 bytes is malformed, the codec rejects the packet
+                    if byte & 0x80 != 0 && shift < 21 {
+                        Self::Length {
+                            remaining,
+                            shift: shift + 7,
+                        }
+                    } else if remaining == 0 {
+                        completed = true;
+                        Self::Idle
+                    } else {
+                        Self::Body(remaining)
+                    }
+                }
+                Self::Body(remaining) => {
+                    let read = remaining.min(bytes.len());
+                    bytes = &bytes[read..];
+                    if read == remaining {
+                        completed = true;
+                        Self::Idle
+                    } else {
+                        Self::Body(remaining - read)
+                    }
+                }
+            };
+        }
+        completed
+    }
+}
+
+/// ### Read Timeout
+/// Fails a read with [`io::ErrorKind::TimedOut`] once a packet has been coming in for longer than the timeout.
+///
+/// The packets are followed by their Remaining Length, the timeout starts with the first byte of a packet
+/// and is reset once its last byte has been read. Waiting for the next packet never times out, that is up to the Keep Alive.
+/// A client that drip-feeds the bytes of a packet, or stops halfway through one, would otherwise keep
+/// its connection and the buffered part of the packet for as long as it likes.
+#[derive(Debug)]
+pub struct ReadTimeout<R> {
+    inner: R,
+    timeout: Option<Duration>,
+    framing: Framing,
+    /// Started with the first byte of a packet, reset once the packet is complete
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> ReadTimeout<R> {
+    /// Wrap a reader, `None` never times out
+    pub fn new(inner: R, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            framing: Framing::Idle,
+            deadline: None,
+        }
+    }
+
+    fn track(&mut self, bytes: &[u8]) {
+        let Some(timeout) = self.timeout else {
+            return;
+        };
+
+        if self.framing.advance(bytes) || self.framing == Framing::Idle {
+            self.deadline = None;
+        }
+        if self.framing != Framing::Idle && self.deadline.is_none() {
+            self.deadline = Some(Box::pin(tokio::time::sleep(timeout)));
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ReadTimeout<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                this.track(&buf.filled()[filled..]);
+                return Poll::Ready(Ok(()));
+            }
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => {}
+        }
+
+        let Some(deadline) = &mut this.deadline else {
+            return Poll::Pending;
+        };
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                this.deadline = None;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Packet read timed out",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_framing() {
+        let mut framing = Framing::Idle;
+        // a PINGREQ
+        assert!(framing.advance(&[0xC0, 0x00]));
+        assert_eq!(framing, Framing::Idle);
+
+        // a PUBLISH with a Remaining Length of 200, split over reads
+        assert!(!framing.advance(&[0x30, 0xC8]));
+        assert_eq!(
+            framing,
+            Framing::Length {
+                remaining: 0x48,
+                shift: 7
+            }
+        );
+        assert!(!framing.advance(&[0x01, 0x00, 0x01]));
+        assert_eq!(framing, Framing::Body(198));
+        assert!(framing.advance(&[0; 198]));
+        assert_eq!(framing, Framing::Idle);
+
+        // the end of one packet and the start of the next
+        framing.advance(&[0xC0]);
+        assert!(framing.advance(&[0x00, 0x30]));
+        assert_eq!(
+            framing,
+            Framing::Length {
+                remaining: 0,
+                shift: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let (mut client, server) = duplex(64);
+        let mut reader = ReadTimeout::new(server, Some(Duration::from_millis(50)));
+        let mut buf = [0; 4];
+
+        // waiting between packets doesn't time out
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(&[0xC0, 0x00, 0x30, 0x05]).await.unwrap();
+            // the PUBLISH never completes
+            std::future::pending::<()>().await;
+        });
+        reader.read_exact(&mut buf).await.unwrap();
+
+        let err = reader.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
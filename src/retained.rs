@@ -0,0 +1,550 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+
+use crate::{
+    packets::{enums::QosLevel, PublishProperties},
+    quota::{RetainedLimits, RetainedPolicy},
+    utils::topic_matches,
+};
+
+/// A retained message and the QoS and properties it was published with
+#[derive(Debug, Clone)]
+pub struct RetainedMessage {
+    pub qos: QosLevel,
+    pub payload: Bytes,
+    pub properties: PublishProperties,
+}
+
+/// Size of the retained store and the messages refused or dropped by its limits
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RetainedStats {
+    /// Number of topics with a retained message
+    pub messages: usize,
+    /// Size of the topics and payloads of every retained message
+    pub bytes: usize,
+    /// Messages removed to make room for newer ones
+    pub evicted: usize,
+    /// Messages that were not retained because they were over the limits
+    pub rejected: usize,
+}
+
+/// ### Retained Messages
+/// The last retained message of each topic, stored in a tree of topic levels so a
+/// topic filter with wildcards can find all matching messages without visiting every topic.
+///
+/// Filters starting with a wildcard don't match topics starting with `$`.
+///
+/// The store is kept within the [`RetainedLimits`], by refusing new messages or by evicting
+/// the least recently set or sent messages. Topics starting with `$` don't count towards the limits.
+///
+/// [(MQTT 5) 3.3.1.3 RETAIN](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901104)
+#[derive(Debug, Default)]
+pub struct RetainedMessages {
+    root: Node,
+    len: usize,
+    bytes: usize,
+    limits: RetainedLimits,
+    /// Topics that count towards the limits by when their message was last used, oldest first
+    lru: BTreeMap<u64, String>,
+    /// Size of the messages in `lru`
+    limited_bytes: usize,
+    clock: u64,
+    evicted: usize,
+    rejected: usize,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    message: Option<RetainedMessage>,
+    /// Key of the message in the LRU order
+    used: u64,
+    children: HashMap<String, Node>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.message.is_none() && self.children.is_empty()
+    }
+
+    /// Add this node's message and every message below it
+    fn collect_all(&self, topic: &mut Vec<String>, out: &mut Vec<(String, RetainedMessage)>) {
+        if let Some(message) = &self.message {
+            out.push((topic.join("/"), message.clone()));
+        }
+
+        for (level, child) in &self.children {
+            topic.push(level.clone());
+            child.collect_all(topic, out);
+            topic.pop();
+        }
+    }
+
+    fn collect(
+        &self,
+        filter: &[&str],
+        topic: &mut Vec<String>,
+        out: &mut Vec<(String, RetainedMessage)>,
+    ) {
+        let Some((level, rest)) = filter.split_first() else {
+            if let Some(message) = &self.message {
+                out.push((topic.join("/"), message.clone()));
+            }
+            return;
+        };
+
+        // A wildcard in the first level does not match topics starting with `$`
+        let skip_reserved = topic.is_empty();
+
+        match *level {
+            // `#` also matches the parent level, `sport/#` matches `sport`
+            "#" => {
+                if let Some(message) = &self.message {
+                    if !topic.is_empty() {
+                        out.push((topic.join("/"), message.clone()));
+                    }
+                }
+
+                for (name, child) in &self.children {
+                    if skip_reserved && name.starts_with('$') {
+                        continue;
+                    }
+                    topic.push(name.clone());
+                    child.collect_all(topic, out);
+                    topic.pop();
+                }
+            }
+            "+" => {
+                for (name, child) in &self.children {
+                    if skip_reserved && name.starts_with('$') {
+                        continue;
+                    }
+                    topic.push(name.clone());
+                    child.collect(rest, topic, out);
+                    topic.pop();
+                }
+            }
+            name => {
+                if let Some(child) = self.children.get(name) {
+                    topic.push(name.to_string());
+                    child.collect(rest, topic, out);
+                    topic.pop();
+                }
+            }
+        }
+    }
+
+    fn get(&self, topic: &str) -> Option<&Node> {
+        topic
+            .split('/')
+            .try_fold(self, |node, level| node.children.get(level))
+    }
+
+    fn get_mut(&mut self, topic: &str) -> Option<&mut Node> {
+        topic
+            .split('/')
+            .try_fold(self, |node, level| node.children.get_mut(level))
+    }
+
+    /// Remove the message of a topic, pruning nodes left empty. Returns the removed message and its LRU key.
+    fn remove(&mut self, levels: &[&str]) -> Option<(RetainedMessage, u64)> {
+        let Some((level, rest)) = levels.split_first() else {
+            return self.message.take().map(|message| (message, self.used));
+        };
+
+        let child = self.children.get_mut(*level)?;
+
+        let removed = child.remove(rest);
+        if child.is_empty() {
+            self.children.remove(*level);
+        }
+
+        removed
+    }
+}
+
+impl RetainedMessages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the limits, a smaller limit applies from the next message that is retained
+    pub fn set_limits(&mut self, limits: RetainedLimits) {
+        self.limits = limits;
+    }
+
+    /// Number of topics with a retained message
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn stats(&self) -> RetainedStats {
+        RetainedStats {
+            messages: self.len,
+            bytes: self.bytes,
+            evicted: self.evicted,
+            rejected: self.rejected,
+        }
+    }
+
+    /// Replace the retained message of a topic.
+    ///
+    /// Returns `false` if the message was refused because it doesn't fit within the limits.
+    pub fn insert(
+        &mut self,
+        topic: &str,
+        qos: QosLevel,
+        payload: Bytes,
+        properties: PublishProperties,
+    ) -> bool {
+        let size = topic.len() + payload.len();
+        let limited = !topic.starts_with('$');
+        if limited && !self.make_room(topic, size) {
+            self.rejected += 1;
+            return false;
+        }
+
+        let node = topic.split('/').fold(&mut self.root, |node, level| {
+            node.children.entry(level.to_string()).or_default()
+        });
+
+        let replaced = node.message.replace(RetainedMessage {
+            qos,
+            payload,
+            properties,
+        });
+        match replaced {
+            Some(replaced) => {
+                let replaced_size = topic.len() + replaced.payload.len();
+                self.bytes -= replaced_size;
+                if limited {
+                    self.limited_bytes -= replaced_size;
+                    self.lru.remove(&node.used);
+                }
+            }
+            None => self.len += 1,
+        }
+
+        self.bytes += size;
+        if limited {
+            self.clock += 1;
+            node.used = self.clock;
+            self.lru.insert(self.clock, topic.to_string());
+            self.limited_bytes += size;
+        }
+        true
+    }
+
+    /// Remove the retained message of a topic
+    pub fn remove(&mut self, topic: &str) {
+        let levels = topic.split('/').collect::<Vec<&str>>();
+        if let Some((message, used)) = self.root.remove(&levels) {
+            let size = topic.len() + message.payload.len();
+            self.len -= 1;
+            self.bytes -= size;
+            if self.lru.remove(&used).is_some() {
+                self.limited_bytes -= size;
+            }
+        }
+    }
+
+    /// Mark the message of a topic as recently used, so it is evicted after the messages that were not
+    pub fn touch(&mut self, topic: &str) {
+        let Some(node) = self.root.get_mut(topic) else {
+            return;
+        };
+        if let Some(topic) = self.lru.remove(&node.used) {
+            self.clock += 1;
+            node.used = self.clock;
+            self.lru.insert(self.clock, topic);
+        }
+    }
+
+    /// Evict messages until a message of `size` bytes for `topic` fits within the limits,
+    /// returns `false` if it can't fit or the policy is to refuse it.
+    fn make_room(&mut self, topic: &str, size: usize) -> bool {
+        // Nothing can be evicted to fit a message over a limit on its own
+        if self.limits.max_messages == Some(0)
+            || self.limits.max_bytes.is_some_and(|max| size > max)
+            || self
+                .limits
+                .filter_limits
+                .iter()
+                .any(|limit| limit.max_messages == 0 && topic_matches(&limit.filter, topic))
+        {
+            return false;
+        }
+
+        while let Some(filter) = self.exceeded(topic, size) {
+            if self.limits.policy == RetainedPolicy::Reject {
+                return false;
+            }
+
+            // The message being replaced is already accounted for
+            let Some(oldest) = self
+                .lru
+                .values()
+                .find(|other| {
+                    *other != topic
+                        && filter
+                            .as_deref()
+                            .is_none_or(|filter| topic_matches(filter, other))
+                })
+                .cloned()
+            else {
+                return false;
+            };
+            self.remove(&oldest);
+            self.evicted += 1;
+        }
+
+        true
+    }
+
+    /// The limit a message of `size` bytes for `topic` would go over, `Some(None)` for the limits on the whole store
+    fn exceeded(&self, topic: &str, size: usize) -> Option<Option<String>> {
+        let replaced = self
+            .root
+            .get(topic)
+            .and_then(|node| node.message.as_ref())
+            .map(|message| topic.len() + message.payload.len());
+        let len = self.lru.len() + usize::from(replaced.is_none());
+        let bytes = self.limited_bytes - replaced.unwrap_or(0) + size;
+        if self.limits.max_messages.is_some_and(|max| len > max)
+            || self.limits.max_bytes.is_some_and(|max| bytes > max)
+        {
+            return Some(None);
+        }
+
+        self.limits
+            .filter_limits
+            .iter()
+            .filter(|limit| topic_matches(&limit.filter, topic))
+            .find(|limit| {
+                let others = self
+                    .lru
+                    .values()
+                    .filter(|other| *other != topic && topic_matches(&limit.filter, other))
+                    .count();
+                others + 1 > limit.max_messages
+            })
+            .map(|limit| Some(limit.filter.clone()))
+    }
+
+    /// All retained messages with a topic matching the topic filter
+    pub fn matches(&self, filter: &str) -> Vec<(String, RetainedMessage)> {
+        let levels = filter.split('/').collect::<Vec<&str>>();
+        let mut out = Vec::new();
+        self.root.collect(&levels, &mut Vec::new(), &mut out);
+        out
+    }
+
+    /// Remove the retained messages with a topic matching the topic filter, returns how many were removed
+    pub fn remove_matching(&mut self, filter: &str) -> usize {
+        let matches = self.matches(filter);
+        for (topic, _) in &matches {
+            self.remove(topic);
+        }
+        matches.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quota::RetainedFilterLimit;
+
+    use super::*;
+
+    fn topics(retained: &RetainedMessages, filter: &str) -> Vec<String> {
+        let mut topics = retained
+            .matches(filter)
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect::<Vec<String>>();
+        topics.sort();
+        topics
+    }
+
+    #[test]
+    fn test_retained_matches() {
+        let mut retained = RetainedMessages::new();
+        for topic in [
+            "sensors",
+            "sensors/1/temp",
+            "sensors/2/temp",
+            "sensors/2/humidity",
+            "/sensors",
+            "$SYS/broker/uptime",
+        ] {
+            retained.insert(
+                topic,
+                QosLevel::AtMost,
+                Bytes::from_static(b"1"),
+                PublishProperties::default(),
+            );
+        }
+        assert_eq!(retained.len(), 6);
+
+        assert_eq!(topics(&retained, "sensors/1/temp"), ["sensors/1/temp"]);
+        assert_eq!(
+            topics(&retained, "sensors/+/temp"),
+            ["sensors/1/temp", "sensors/2/temp"]
+        );
+        assert_eq!(
+            topics(&retained, "sensors/#"),
+            [
+                "sensors",
+                "sensors/1/temp",
+                "sensors/2/humidity",
+                "sensors/2/temp"
+            ]
+        );
+        assert_eq!(topics(&retained, "+/sensors"), ["/sensors"]);
+        assert_eq!(topics(&retained, "#").len(), 5);
+        assert!(topics(&retained, "+/broker/uptime").is_empty());
+        assert_eq!(topics(&retained, "$SYS/#"), ["$SYS/broker/uptime"]);
+        assert!(topics(&retained, "sensors/+").is_empty());
+    }
+
+    #[test]
+    fn test_retained_remove() {
+        let mut retained = RetainedMessages::new();
+        retained.insert(
+            "a/b",
+            QosLevel::AtLeast,
+            Bytes::from_static(b"1"),
+            PublishProperties::default(),
+        );
+        retained.insert(
+            "a/b",
+            QosLevel::AtLeast,
+            Bytes::from_static(b"2"),
+            PublishProperties::default(),
+        );
+        retained.insert(
+            "a/b/c",
+            QosLevel::AtLeast,
+            Bytes::from_static(b"3"),
+            PublishProperties::default(),
+        );
+        assert_eq!(retained.len(), 2);
+        assert_eq!(
+            retained.matches("a/b")[0].1.payload,
+            Bytes::from_static(b"2")
+        );
+
+        retained.remove("a/b");
+        retained.remove("a/x");
+        assert_eq!(retained.len(), 1);
+        assert!(retained.matches("a/b").is_empty());
+        assert_eq!(topics(&retained, "a/#"), ["a/b/c"]);
+
+        retained.remove("a/b/c");
+        assert_eq!(retained.len(), 0);
+        assert_eq!(retained.stats().bytes, 0);
+        assert!(retained.root.is_empty());
+    }
+
+    fn insert(retained: &mut RetainedMessages, topic: &str, payload: &'static [u8]) -> bool {
+        retained.insert(
+            topic,
+            QosLevel::AtMost,
+            Bytes::from_static(payload),
+            PublishProperties::default(),
+        )
+    }
+
+    #[test]
+    fn test_retained_remove_matching() {
+        let mut retained = RetainedMessages::new();
+        for topic in [
+            "sensors/1/temp",
+            "sensors/2/temp",
+            "sensors/2/humidity",
+            "other",
+        ] {
+            insert(&mut retained, topic, b"1");
+        }
+
+        assert_eq!(retained.remove_matching("sensors/+/temp"), 2);
+        assert_eq!(topics(&retained, "#"), ["other", "sensors/2/humidity"]);
+        assert_eq!(retained.remove_matching("sensors/+/temp"), 0);
+
+        assert_eq!(retained.remove_matching("#"), 2);
+        assert_eq!(retained.len(), 0);
+        assert_eq!(retained.stats().bytes, 0);
+        assert!(retained.root.is_empty());
+    }
+
+    #[test]
+    fn test_retained_limits_reject() {
+        let mut retained = RetainedMessages::new();
+        retained.set_limits(RetainedLimits {
+            max_messages: Some(2),
+            max_bytes: Some(16),
+            ..RetainedLimits::default()
+        });
+
+        assert!(insert(&mut retained, "a", b"1"));
+        assert!(insert(&mut retained, "b", b"1"));
+        assert!(!insert(&mut retained, "c", b"1"));
+        // Replacing a message doesn't add one
+        assert!(insert(&mut retained, "a", b"2"));
+        assert!(!insert(&mut retained, "a", b"too large for the limit"));
+        assert!(insert(&mut retained, "$SYS/broker/uptime", b"1"));
+
+        assert_eq!(topics(&retained, "#"), ["a", "b"]);
+        assert_eq!(
+            retained.stats(),
+            RetainedStats {
+                messages: 3,
+                bytes: 4 + 19,
+                evicted: 0,
+                rejected: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_retained_limits_evict() {
+        let mut retained = RetainedMessages::new();
+        retained.set_limits(RetainedLimits {
+            max_messages: Some(3),
+            policy: RetainedPolicy::EvictLru,
+            ..RetainedLimits::default()
+        });
+
+        assert!(insert(&mut retained, "a", b"1"));
+        assert!(insert(&mut retained, "b", b"1"));
+        assert!(insert(&mut retained, "c", b"1"));
+        retained.touch("a");
+        assert!(insert(&mut retained, "d", b"1"));
+        assert_eq!(topics(&retained, "#"), ["a", "c", "d"]);
+        assert_eq!(
+            retained.stats(),
+            RetainedStats {
+                messages: 3,
+                bytes: 6,
+                evicted: 1,
+                rejected: 0,
+            }
+        );
+
+        // Only the messages within the filter are evicted to make room for it
+        let mut retained = RetainedMessages::new();
+        retained.set_limits(RetainedLimits {
+            filter_limits: vec![RetainedFilterLimit {
+                filter: "devices/+/status".into(),
+                max_messages: 1,
+            }],
+            policy: RetainedPolicy::EvictLru,
+            ..RetainedLimits::default()
+        });
+
+        assert!(insert(&mut retained, "a", b"1"));
+        assert!(insert(&mut retained, "devices/1/status", b"1"));
+        assert!(insert(&mut retained, "devices/2/status", b"1"));
+        assert_eq!(topics(&retained, "#"), ["a", "devices/2/status"]);
+        assert_eq!(retained.stats().evicted, 1);
+    }
+}
@@ -0,0 +1,279 @@
+//! ### Windows Service
+//! Run the broker as a Windows service, enabled with the `windows-service` feature.
+//!
+//! `mqtt_broker --install-service -c C:\mqtt\broker.conf` registers a service that starts with Windows
+//! and runs `mqtt_broker --service` with the same config file, `--uninstall-service` removes it again.
+//! Stopping the service shuts the broker down like `ctrl_c`, and the log goes to the Application event log.
+//!
+//! [Windows services](https://learn.microsoft.com/en-us/windows/win32/services/services)
+
+use std::{
+    ffi::OsString,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use tracing::{level_filters::LevelFilter, Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+};
+
+use crate::{error::MqttError, logging, Broker};
+
+/// Name of the service and of its event log source
+pub const SERVICE_NAME: &str = "mqtt_broker";
+
+/// Config file given to `--service`, read once the service manager starts the service
+static CONFIG_PATH: OnceLock<Option<String>> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_error(err: windows_service::Error) -> MqttError {
+    MqttError::Io(io::Error::other(err))
+}
+
+/// Register the current executable as a service started with Windows
+pub fn install(config_path: Option<&Path>) -> Result<(), MqttError> {
+    let manager =
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)
+            .map_err(service_error)?;
+
+    let mut launch_arguments = vec![OsString::from("--service")];
+    if let Some(path) = config_path {
+        // The service starts in the system directory, not the current one
+        launch_arguments.push("--config".into());
+        launch_arguments.push(std::fs::canonicalize(path)?.into_os_string());
+    }
+
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "MQTT Broker".into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(service_error)?;
+    service
+        .set_description("MQTT 3.1.1 and 5 broker")
+        .map_err(service_error)?;
+
+    Ok(())
+}
+
+/// Stop and remove the service
+pub fn uninstall() -> Result<(), MqttError> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(service_error)?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(service_error)?;
+
+    if service.query_status().map_err(service_error)?.current_state != ServiceState::Stopped {
+        service.stop().map_err(service_error)?;
+    }
+    service.delete().map_err(service_error)
+}
+
+/// Hand the process to the service manager, returns once the service has stopped.
+///
+/// Fails if the process was not started by the service manager.
+pub fn run(config_path: Option<String>) -> Result<(), MqttError> {
+    let _ = CONFIG_PATH.set(config_path);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(service_error)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        tracing::error!("Service failed: {}", err);
+    }
+}
+
+fn run_service() -> Result<(), MqttError> {
+    let mut builder = Broker::builder();
+    if let Some(path) = CONFIG_PATH.get().and_then(Option::as_deref) {
+        builder = builder.config_file(path)?;
+    }
+    let broker = builder.build()?;
+
+    let config = broker.config();
+    logging::init_with_writer(
+        config.log_format,
+        config.log_level.unwrap_or(LevelFilter::INFO),
+        EventLogWriter(Arc::new(EventLog::register()?)),
+    )?;
+
+    let handle = broker.handle();
+    let status = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            handle.shutdown();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .map_err(service_error)?;
+
+    set_state(
+        &status,
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    )?;
+
+    let result = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(broker.run());
+
+    set_state(
+        &status,
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+    )?;
+    result
+}
+
+fn set_state(
+    status: &ServiceStatusHandle,
+    state: ServiceState,
+    controls_accepted: ServiceControlAccept,
+) -> Result<(), MqttError> {
+    status
+        .set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+        .map_err(service_error)
+}
+
+/// UTF-16 with a trailing nul for the Windows API
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(Some(0)).collect()
+}
+
+/// Event source the service logs to
+struct EventLog(isize);
+
+impl EventLog {
+    fn register() -> io::Result<Self> {
+        let name = wide(SERVICE_NAME);
+        // SAFETY: `name` is a nul terminated UTF-16 string that outlives the call
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+        if handle == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(handle))
+    }
+
+    fn report(&self, event_type: REPORT_EVENT_TYPE, message: &str) {
+        let message = wide(message);
+        let strings = [message.as_ptr()];
+        // SAFETY: the handle is open until drop and `strings` holds one nul terminated string
+        unsafe {
+            ReportEventW(
+                self.0,
+                event_type,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            );
+        }
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        // SAFETY: the handle was returned by RegisterEventSourceW and is only closed here
+        unsafe {
+            DeregisterEventSource(self.0);
+        }
+    }
+}
+
+/// Writes each log line as an event, with the event type from the level of the line
+#[derive(Clone)]
+struct EventLogWriter(Arc<EventLog>);
+
+impl<'a> MakeWriter<'a> for EventLogWriter {
+    type Writer = EventLogLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventLogLine {
+            log: self.0.clone(),
+            event_type: EVENTLOG_INFORMATION_TYPE,
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let event_type = match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        EventLogLine {
+            event_type,
+            ..self.make_writer()
+        }
+    }
+}
+
+/// A log line, reported to the event log when it is dropped
+struct EventLogLine {
+    log: Arc<EventLog>,
+    event_type: REPORT_EVENT_TYPE,
+    buf: Vec<u8>,
+}
+
+impl Write for EventLogLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventLogLine {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.buf);
+        let line = line.trim_end();
+        if !line.is_empty() {
+            self.log.report(self.event_type, line);
+        }
+    }
+}
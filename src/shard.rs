@@ -0,0 +1,336 @@
+//! ### Command Shards
+//! Splits the state of the broker over several command loops so it can use more than one core,
+//! enabled with the `command_shards` option.
+//!
+//! Each shard is an [`App`] with its own command loop, and owns the sessions and subscriptions of
+//! the clients whose id hashes to it. The commands of a client always go to the same shard, in the order they were sent.
+//! Messages published in process, like the `$SYS` topics, go to the shard of the first level of their topic.
+//!
+//! A message is checked against the topic policy and the hooks by the shard it was published to,
+//! which sends it on to the other shards so each delivers it to its own subscribers.
+//! The members of a share group can be on several shards, so the shard the message was published to
+//! picks the member of each matching group from a tree of the shared subscriptions of every shard,
+//! and only the shard of that member delivers it.
+//! Those sends are unbounded so two shards publishing to each other can't wait on each other's full queue.
+//! The retained messages are kept in one store shared by the shards.
+//!
+//! Commands about the whole broker, like listing the clients, are asked of every shard and their answers merged.
+
+use std::{
+    collections::{btree_map::Entry, hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use tokio::sync::{
+    mpsc::{unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tracing::{debug, error};
+
+use crate::{
+    core::{
+        enums::{Command, Responder},
+        App,
+    },
+    packets::{enums::QosLevel, PublishProperties},
+    topic_heir::{SharePick, SubscriptionStats, SubscriptionTree},
+};
+
+/// A message published to another shard, delivered to the subscribers of this one
+#[derive(Debug, Clone)]
+pub struct Dispatch {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QosLevel,
+    pub retain: bool,
+    /// Client id of the publisher, for No Local
+    pub client: Option<String>,
+    pub properties: PublishProperties,
+    pub received: Instant,
+    /// Members of the matching share groups, picked by the shard the message was published to.
+    /// `None` without shards, each app then picks from its own groups
+    pub shared: Option<Vec<SharePick>>,
+}
+
+/// Shard owning the session of a client
+pub fn client_shard(client_id: &str, shards: usize) -> usize {
+    hash_shard(client_id, shards)
+}
+
+/// Shard a message published in process is checked on, by the first level of its topic
+pub fn topic_shard(topic: &str, shards: usize) -> usize {
+    hash_shard(topic.split('/').next().unwrap_or_default(), shards)
+}
+
+fn hash_shard(key: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// Link the apps as shards, each is returned with the receiver of the messages published on the others.
+///
+/// The first app runs the shutdown hooks and its retained store is shared with the rest,
+/// the shared subscriptions of every app are also kept in one tree to pick the members of the share groups from.
+pub fn link(apps: Vec<App>) -> Vec<(App, UnboundedReceiver<Dispatch>)> {
+    let (senders, receivers): (Vec<_>, Vec<_>) =
+        apps.iter().map(|_| unbounded_channel::<Dispatch>()).unzip();
+    let Some(retained) = apps.first().map(App::retained_store) else {
+        return Vec::new();
+    };
+    let shares = Arc::new(Mutex::new(SubscriptionTree::new()));
+
+    apps.into_iter()
+        .zip(receivers)
+        .enumerate()
+        .map(|(index, (app, dispatched))| {
+            let others = senders
+                .iter()
+                .enumerate()
+                .filter(|(other, _)| *other != index)
+                .map(|(_, sender)| sender.clone())
+                .collect();
+            let app = app.with_shard(index == 0, others, retained.clone(), shares.clone());
+            (app, dispatched)
+        })
+        .collect()
+}
+
+/// Route each command to the shard of its client or topic, and ask every shard for the commands about the whole broker.
+///
+/// Exits with [`Command::Exit`] once it has been sent on to every shard.
+pub async fn route_loop(mut rx: Receiver<Command>, shards: Vec<Sender<Command>>) {
+    let count = shards.len();
+    while let Some(command) = rx.recv().await {
+        let shard = match &command {
+            Command::RegisterClient { id, .. }
+            | Command::DisconnectClient { id, .. }
+            | Command::ConnectionClosed { id, .. }
+            | Command::GetClientInfo { id, .. } => client_shard(id, count),
+            Command::Subscribe { client, .. }
+            | Command::ClientSubscriptions { client, .. }
+            | Command::DirectRoute { client, .. } => client_shard(client, count),
+            Command::Unsubscribe { cid, .. } => client_shard(cid, count),
+            Command::AdminDisconnect { client_id, .. }
+            | Command::AdminUnsubscribe { client_id, .. } => client_shard(client_id, count),
+            Command::Publish {
+                client: Some(client),
+                ..
+            } => client_shard(client, count),
+            Command::Publish { topic, .. } => topic_shard(topic, count),
+            // The retained store is shared
+            Command::RetainedStats(_)
+            | Command::ListRetained { .. }
+            | Command::RemoveRetained { .. } => 0,
+            Command::Reload(config) => {
+                for shard in &shards {
+                    let _ = shard.send(Command::Reload(config.clone())).await;
+                }
+                continue;
+            }
+            Command::ListClients { .. }
+            | Command::RetainedCount(_)
+            | Command::SubscriptionStats(_)
+            | Command::ClusterFilters(_) => {
+                gather(command, &shards).await;
+                continue;
+            }
+            Command::Exit => {
+                for shard in &shards {
+                    let _ = shard.send(Command::Exit).await;
+                }
+                break;
+            }
+        };
+
+        if shards[shard].send(command).await.is_err() {
+            error!("Shard {} has exited", shard);
+        }
+    }
+
+    debug!("Exiting Routing loop");
+}
+
+/// Ask every shard and merge the answers off of the routing loop
+async fn gather(command: Command, shards: &[Sender<Command>]) {
+    match command {
+        Command::ListClients { callback } => {
+            let answers = ask(shards, |callback| Command::ListClients { callback }).await;
+            respond(callback, answers, |clients| clients.concat());
+        }
+        // Every shard answers so the health check notices a shard that is stuck
+        Command::RetainedCount(callback) => {
+            let answers = ask(shards, Command::RetainedCount).await;
+            respond(callback, answers, |counts| {
+                counts.first().copied().unwrap_or_default()
+            });
+        }
+        Command::SubscriptionStats(callback) => {
+            let answers = ask(shards, Command::SubscriptionStats).await;
+            respond(callback, answers, merge_stats);
+        }
+        Command::ClusterFilters(callback) => {
+            let answers = ask(shards, Command::ClusterFilters).await;
+            respond(callback, answers, |filters| {
+                filters.into_iter().flatten().collect::<HashSet<_>>()
+            });
+        }
+        command => error!("{:?} can't be asked of every shard", command),
+    }
+}
+
+/// Add up the subscription trees of the shards.
+///
+/// The roots are left out of the per-shard counts, and a first level found on
+/// several shards is counted once, as it would be in a single tree.
+/// Deeper levels found on several shards are still counted by each.
+fn merge_stats(stats: Vec<SubscriptionStats>) -> SubscriptionStats {
+    stats
+        .into_iter()
+        .fold(SubscriptionStats::default(), |mut total, stats| {
+            total.nodes += stats.nodes;
+            total.subscriptions += stats.subscriptions;
+            for (level, count) in stats.first_levels {
+                match total.first_levels.entry(level) {
+                    Entry::Occupied(mut entry) => {
+                        total.nodes -= 1;
+                        *entry.get_mut() += count;
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(count);
+                    }
+                }
+            }
+            total
+        })
+}
+
+/// Send a request to every shard, in the order of the routing loop
+async fn ask<T>(
+    shards: &[Sender<Command>],
+    request: impl Fn(Responder<T>) -> Command,
+) -> Vec<oneshot::Receiver<T>> {
+    let mut answers = Vec::with_capacity(shards.len());
+    for shard in shards {
+        let (tx, rx) = oneshot::channel();
+        if shard.send(request(tx)).await.is_ok() {
+            answers.push(rx);
+        }
+    }
+    answers
+}
+
+/// Wait for the answers of the shards on their own task, so the routing loop goes on
+fn respond<T: Send + 'static, R: Send + 'static>(
+    callback: Responder<R>,
+    answers: Vec<oneshot::Receiver<T>>,
+    merge: impl FnOnce(Vec<T>) -> R + Send + 'static,
+) {
+    tokio::spawn(async move {
+        let mut merged = Vec::with_capacity(answers.len());
+        for answer in answers {
+            match answer.await {
+                Ok(answer) => merged.push(answer),
+                Err(_) => return,
+            }
+        }
+        if callback.send(merge(merged)).is_err() {
+            error!("receiver dropped");
+        }
+    });
+}
+
+/// Send a message on to the other shards
+pub fn forward(shards: &[UnboundedSender<Dispatch>], message: &Dispatch) {
+    for shard in shards {
+        if shard.send(message.clone()).is_err() {
+            debug!("Shard has exited, message for its subscribers dropped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_keys() {
+        for shards in 1..8 {
+            assert!(client_shard("sensor-1", shards) < shards);
+            assert_eq!(
+                client_shard("sensor-1", shards),
+                client_shard("sensor-1", shards)
+            );
+        }
+        // Topics are spread by their first level
+        assert_eq!(topic_shard("sensors/1/temp", 4), topic_shard("sensors", 4));
+        assert_eq!(
+            topic_shard("$SYS/broker/uptime", 4),
+            topic_shard("$SYS/a", 4)
+        );
+    }
+
+    #[test]
+    fn test_merge_stats() {
+        let shard = |nodes, first_levels: &[(&str, usize)]| SubscriptionStats {
+            nodes,
+            subscriptions: first_levels.iter().map(|(_, count)| count).sum(),
+            first_levels: first_levels
+                .iter()
+                .map(|(level, count)| (level.to_string(), *count))
+                .collect(),
+        };
+        // sensors, sensors/temp and alerts on one shard, sensors and sensors/humidity on the other
+        let stats = merge_stats(vec![
+            shard(3, &[("sensors", 1), ("alerts", 1)]),
+            shard(2, &[("sensors", 1)]),
+        ]);
+        assert_eq!(stats.nodes, 4);
+        assert_eq!(stats.subscriptions, 3);
+        assert_eq!(stats.first_levels.get("sensors"), Some(&2));
+        assert_eq!(stats.first_levels.get("alerts"), Some(&1));
+
+        assert_eq!(merge_stats(Vec::new()), SubscriptionStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_route_loop() {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let (shard_txs, mut shard_rxs): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| tokio::sync::mpsc::channel(10)).unzip();
+        tokio::spawn(route_loop(rx, shard_txs));
+
+        let (callback, _rx) = oneshot::channel();
+        tx.send(Command::GetClientInfo {
+            id: "sensor-1".into(),
+            callback,
+        })
+        .await
+        .unwrap();
+        let shard = client_shard("sensor-1", 3);
+        assert!(matches!(
+            shard_rxs[shard].recv().await,
+            Some(Command::GetClientInfo { .. })
+        ));
+
+        // every shard is asked and the answers are merged
+        let (callback, count) = oneshot::channel();
+        tx.send(Command::RetainedCount(callback)).await.unwrap();
+        for shard in &mut shard_rxs {
+            match shard.recv().await {
+                Some(Command::RetainedCount(callback)) => callback.send(2).unwrap(),
+                _ => panic!("Expected the retained count to be asked"),
+            }
+        }
+        assert_eq!(count.await.unwrap(), 2);
+
+        tx.send(Command::Exit).await.unwrap();
+        for shard in &mut shard_rxs {
+            assert!(matches!(shard.recv().await, Some(Command::Exit)));
+            assert!(shard.recv().await.is_none());
+        }
+    }
+}
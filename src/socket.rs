@@ -0,0 +1,194 @@
+use std::{net::SocketAddr, time::Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::config::Config;
+
+/// Pending connections the listener queues before they are accepted
+const BACKLOG: u32 = 1024;
+
+/// ### Socket Options
+/// Options for the client listener and the sockets it accepts.
+///
+/// TCP_NODELAY is on by default so small packets like PUBACK are not held back by Nagle's algorithm,
+/// which together with delayed ACKs can add up to hundreds of milliseconds of latency per packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Set TCP_NODELAY on accepted sockets
+    pub nodelay: bool,
+    /// Set SO_REUSEADDR on the listener so the broker can restart while old connections are in TIME_WAIT
+    pub reuse_address: bool,
+    /// Idle time before TCP keepalive probes are sent, `None` leaves keepalive off
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes
+    pub keepalive_interval: Option<Duration>,
+    /// Unanswered keepalive probes before the connection is dropped
+    pub keepalive_retries: Option<u32>,
+    /// SO_SNDBUF in bytes, `None` keeps the system default
+    pub send_buffer_size: Option<u32>,
+    /// SO_RCVBUF in bytes, `None` keeps the system default
+    pub recv_buffer_size: Option<u32>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            reuse_address: true,
+            keepalive: None,
+            keepalive_interval: None,
+            keepalive_retries: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl From<&Config> for SocketOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            nodelay: config.tcp_nodelay,
+            reuse_address: config.reuse_address,
+            keepalive: config.tcp_keepalive,
+            keepalive_interval: config.tcp_keepalive_interval,
+            keepalive_retries: config.tcp_keepalive_retries,
+            send_buffer_size: config.socket_send_buffer,
+            recv_buffer_size: config.socket_recv_buffer,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Bind a client listener to each address.
+    ///
+    /// An IPv6 listener is dual-stack and also accepts IPv4 connections,
+    /// unless an IPv4 listener uses the same port, then it is IPv6 only so both can bind.
+    pub fn bind_all(&self, addrs: &[SocketAddr]) -> std::io::Result<Vec<TcpListener>> {
+        addrs
+            .iter()
+            .map(|addr| {
+                let only_v6 = addr.is_ipv6()
+                    && addrs
+                        .iter()
+                        .any(|other| other.is_ipv4() && other.port() == addr.port());
+                self.bind(*addr, only_v6)
+            })
+            .collect()
+    }
+
+    /// Bind a client listener, `only_v6` sets IPV6_V6ONLY on an IPv6 socket
+    pub fn bind(&self, addr: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => {
+                let socket = TcpSocket::new_v6()?;
+                SockRef::from(&socket).set_only_v6(only_v6)?;
+                socket
+            }
+        };
+
+        socket.set_reuseaddr(self.reuse_address)?;
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        socket.bind(addr)?;
+        socket.listen(BACKLOG)
+    }
+
+    /// Apply the options to an accepted socket
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+
+        let socket = SockRef::from(stream);
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size as usize)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size as usize)?;
+        }
+
+        if let Some(time) = self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            #[cfg(unix)]
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply() {
+        let options = SocketOptions {
+            keepalive: Some(Duration::from_secs(30)),
+            keepalive_interval: Some(Duration::from_secs(5)),
+            ..SocketOptions::default()
+        };
+
+        let listener = options
+            .bind("127.0.0.1:0".parse().unwrap(), false)
+            .expect("Failed to bind");
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        options.apply(&stream).expect("Failed to set options");
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bind_all() {
+        let options = SocketOptions::default();
+
+        // Find a free port to share between the IPv4 and IPv6 listeners
+        let port = options
+            .bind("127.0.0.1:0".parse().unwrap(), false)
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addrs = [
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            SocketAddr::from(([0u16; 8], port)),
+        ];
+
+        let listeners = match options.bind_all(&addrs) {
+            Ok(listeners) => listeners,
+            // No IPv6 in this environment
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::AddrNotAvailable | std::io::ErrorKind::Unsupported
+                ) =>
+            {
+                return
+            }
+            Err(err) => panic!("Failed to bind: {}", err),
+        };
+        assert_eq!(listeners.len(), 2);
+        assert!(SockRef::from(&listeners[1]).only_v6().unwrap());
+
+        for listener in &listeners {
+            let _client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            listener.accept().await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! ### Message Stamps
+//! User Properties the broker adds to every published message, enabled with the `stamp_messages` option.
+//!
+//! - `broker_timestamp` is when the broker received the message, in milliseconds since the Unix epoch
+//! - `broker_sequence` counts the messages published to the topic, starting from 1
+//!
+//! A subscriber can tell a message was lost when the sequence of its topic skips a number,
+//! and that a message was replayed or reordered when the sequence goes back.
+//! Properties with these names sent by the publisher are removed first, so they can't be forged.
+//!
+//! The sequences start again when the broker restarts. Only MQTT 5 subscribers receive User Properties.
+
+use std::{
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+
+use crate::packets::PublishProperties;
+
+pub const TIMESTAMP_PROPERTY: &str = "broker_timestamp";
+pub const SEQUENCE_PROPERTY: &str = "broker_sequence";
+
+/// Stamps the messages, shared by the shards so a topic has a single sequence
+#[derive(Debug, Clone, Default)]
+pub struct Stamper {
+    /// Last sequence number of each topic
+    sequences: Arc<DashMap<String, u64>>,
+}
+
+impl Stamper {
+    /// Add the timestamp and the next sequence number of the topic to the properties
+    pub fn stamp(&self, topic: &str, properties: &mut PublishProperties, received: Instant) {
+        let sequence = {
+            // The topic is only copied for its first message
+            let mut sequence = match self.sequences.get_mut(topic) {
+                Some(sequence) => sequence,
+                None => self.sequences.entry(topic.to_string()).or_insert(0),
+            };
+            *sequence += 1;
+            *sequence
+        };
+
+        let received = SystemTime::now()
+            .checked_sub(received.elapsed())
+            .unwrap_or_else(SystemTime::now);
+        let timestamp = received
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+
+        properties
+            .user_properties
+            .retain(|(key, _)| key != TIMESTAMP_PROPERTY && key != SEQUENCE_PROPERTY);
+        properties
+            .user_properties
+            .push((TIMESTAMP_PROPERTY.to_string(), timestamp.to_string()));
+        properties
+            .user_properties
+            .push((SEQUENCE_PROPERTY.to_string(), sequence.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn property<'a>(properties: &'a PublishProperties, key: &str) -> Vec<&'a str> {
+        properties
+            .user_properties
+            .iter()
+            .filter(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_stamp() {
+        let stamper = Stamper::default();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        let mut sequences = Vec::new();
+        for topic in ["a", "a", "b", "a"] {
+            let mut properties = PublishProperties::default();
+            stamper.stamp(topic, &mut properties, Instant::now());
+            sequences.push(property(&properties, SEQUENCE_PROPERTY).concat());
+
+            let timestamp: u128 = property(&properties, TIMESTAMP_PROPERTY)
+                .concat()
+                .parse()
+                .unwrap();
+            assert!(timestamp + 1000 >= now.as_millis());
+        }
+        assert_eq!(sequences, ["1", "2", "1", "3"]);
+
+        // a forged stamp is replaced, other properties are kept
+        let mut properties = PublishProperties {
+            user_properties: vec![
+                (SEQUENCE_PROPERTY.into(), "100".into()),
+                ("unit".into(), "C".into()),
+            ],
+            ..Default::default()
+        };
+        stamper.stamp("b", &mut properties, Instant::now());
+        assert_eq!(property(&properties, SEQUENCE_PROPERTY), ["2"]);
+        assert_eq!(property(&properties, "unit"), ["C"]);
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+use crate::{
+    core::{
+        broker_info::{self, Stats},
+        enums::Command,
+        ClientInfo,
+    },
+    error::MqttError,
+    local_handle::LocalHandle,
+    packets::enums::QosLevel,
+};
+
+/// Windows of the load averages in seconds, published as `1min`, `5min` and `15min`
+const WINDOWS: [(u64, &str); 3] = [(60, "1min"), (300, "5min"), (900, "15min")];
+
+/// ### Load Average
+/// Exponentially weighted moving averages of a rate over 1, 5 and 15 minutes,
+/// like the `$SYS/broker/load/...` topics of mosquitto.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LoadAverage {
+    averages: [f64; 3],
+}
+
+impl LoadAverage {
+    /// Add the rate measured over the last `elapsed` seconds
+    pub fn update(&mut self, rate: f64, elapsed: f64) {
+        for (average, (window, _)) in self.averages.iter_mut().zip(WINDOWS) {
+            let decay = (-elapsed / window as f64).exp();
+            *average = *average * decay + rate * (1.0 - decay);
+        }
+    }
+
+    /// The averages with the suffix of their topic
+    pub fn values(&self) -> impl Iterator<Item = (&'static str, f64)> + '_ {
+        WINDOWS
+            .iter()
+            .zip(self.averages)
+            .map(|((_, name), average)| (*name, average))
+    }
+}
+
+/// Per second load averages of the broker counters
+#[derive(Debug, Default)]
+struct Load {
+    messages_received: LoadAverage,
+    messages_sent: LoadAverage,
+    bytes_received: LoadAverage,
+    bytes_sent: LoadAverage,
+    publish_received: LoadAverage,
+    publish_sent: LoadAverage,
+}
+
+impl Load {
+    fn update(&mut self, previous: &Stats, current: &Stats, elapsed: f64) {
+        let rate = |before: usize, after: usize| after.saturating_sub(before) as f64 / elapsed;
+
+        self.messages_received.update(
+            rate(previous.messages_received, current.messages_received),
+            elapsed,
+        );
+        self.messages_sent
+            .update(rate(previous.messages_sent, current.messages_sent), elapsed);
+        self.bytes_received.update(
+            rate(previous.bytes_received, current.bytes_received),
+            elapsed,
+        );
+        self.bytes_sent
+            .update(rate(previous.bytes_sent, current.bytes_sent), elapsed);
+        self.publish_received.update(
+            rate(previous.publish_received, current.publish_received),
+            elapsed,
+        );
+        self.publish_sent
+            .update(rate(previous.publish_sent, current.publish_sent), elapsed);
+    }
+
+    fn topics(&self) -> impl Iterator<Item = (&'static str, &LoadAverage)> {
+        [
+            ("messages/received", &self.messages_received),
+            ("messages/sent", &self.messages_sent),
+            ("bytes/received", &self.bytes_received),
+            ("bytes/sent", &self.bytes_sent),
+            ("publish/received", &self.publish_received),
+            ("publish/sent", &self.publish_sent),
+        ]
+        .into_iter()
+    }
+}
+
+/// Topics of every client under `$SYS/broker/clients/{client_id}/`, published with `sys_client_metrics`
+#[derive(Debug, Default)]
+struct ClientMetrics {
+    /// Clients that have topics, so they can be cleared once the session is removed
+    published: HashSet<String>,
+}
+
+impl ClientMetrics {
+    /// Values of the topics of the clients, with empty values clearing the topics of the removed clients
+    fn topics(&mut self, clients: &[ClientInfo], now: SystemTime) -> Vec<(String, String)> {
+        let mut values = Vec::new();
+        let mut published = HashSet::new();
+
+        // The client id is a topic level, so ids that are more than one level or wildcards are left out
+        for client in clients
+            .iter()
+            .filter(|client| !client.client_id.contains(['/', '+', '#']))
+        {
+            let prefix = format!("clients/{}", client.client_id);
+            values.push((
+                format!("{}/status", prefix),
+                keepalive_status(client, now).to_string(),
+            ));
+            values.push((
+                format!("{}/connected_at", prefix),
+                unix_secs(client.connected_at).to_string(),
+            ));
+            if let (Some(keepalive), Some(last_seen)) = (client.keepalive, client.last_seen) {
+                values.push((format!("{}/keepalive", prefix), keepalive.to_string()));
+                values.push((
+                    format!("{}/last_seen", prefix),
+                    unix_secs(last_seen).to_string(),
+                ));
+            }
+            published.insert(client.client_id.clone());
+        }
+
+        for removed in self.published.difference(&published) {
+            for topic in ["status", "connected_at", "keepalive", "last_seen"] {
+                values.push((format!("clients/{}/{}", removed, topic), String::new()));
+            }
+        }
+        self.published = published;
+
+        values
+    }
+}
+
+/// `online` while the client sends a packet at least every Keep Alive, `late` once it has gone quiet for longer
+/// and `offline` for a persistent session without a connection
+fn keepalive_status(client: &ClientInfo, now: SystemTime) -> &'static str {
+    if !client.connected {
+        return "offline";
+    }
+    match (client.keepalive, client.last_seen) {
+        (Some(keepalive), Some(last_seen))
+            if keepalive > 0
+                && now.duration_since(last_seen).unwrap_or_default()
+                    > Duration::from_secs(keepalive.into()) =>
+        {
+            "late"
+        }
+        _ => "online",
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Publish the `$SYS` topics every `interval` until the cancellation token is triggered.
+///
+/// Every topic is retained so new subscribers get the latest values straight away.
+pub async fn run_sys(
+    interval: Duration,
+    client_metrics: bool,
+    local: LocalHandle,
+    cancellation: CancellationToken,
+) {
+    let start = Instant::now();
+    let mut ticker = tokio::time::interval(interval);
+    let mut previous = broker_info::get_stats();
+    let mut last = Instant::now();
+    let mut load = Load::default();
+    let mut clients_maximum = 0;
+    let mut client_metrics = client_metrics.then(ClientMetrics::default);
+
+    loop {
+        select! {
+            () = cancellation.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        let current = broker_info::get_stats();
+        let elapsed = last.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            load.update(&previous, &current, elapsed);
+        }
+        previous = current;
+        last = Instant::now();
+        clients_maximum = clients_maximum.max(current.clients_connected);
+
+        if let Err(err) = publish_all(
+            &local,
+            &current,
+            &load,
+            clients_maximum,
+            client_metrics.as_mut(),
+            start.elapsed(),
+        )
+        .await
+        {
+            error!("Failed to publish $SYS topics: {}", err);
+            break;
+        }
+    }
+
+    debug!("Exiting $SYS loop");
+}
+
+async fn publish_all(
+    local: &LocalHandle,
+    stats: &Stats,
+    load: &Load,
+    clients_maximum: usize,
+    client_metrics: Option<&mut ClientMetrics>,
+    uptime: Duration,
+) -> Result<(), MqttError> {
+    let clients = local
+        .request(|callback| Command::ListClients { callback })
+        .await?;
+    let retained = local.request(Command::RetainedStats).await?;
+    let subscriptions = local.request(Command::SubscriptionStats).await?;
+
+    let now = SystemTime::now();
+
+    let mut values = vec![
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("uptime".into(), format!("{} seconds", uptime.as_secs())),
+        ("time".into(), unix_secs(now).to_string()),
+        (
+            "load/bytes/received".into(),
+            stats.bytes_received.to_string(),
+        ),
+        ("load/bytes/sent".into(), stats.bytes_sent.to_string()),
+        (
+            "listeners/accept_errors".into(),
+            stats.accept_errors.to_string(),
+        ),
+        (
+            "clients/connected".into(),
+            stats.clients_connected.to_string(),
+        ),
+        (
+            "clients/disconnected".into(),
+            clients
+                .len()
+                .saturating_sub(stats.clients_connected)
+                .to_string(),
+        ),
+        ("clients/maximum".into(), clients_maximum.to_string()),
+        (
+            "clients/panicked".into(),
+            stats.clients_panicked.to_string(),
+        ),
+        ("clients/total".into(), clients.len().to_string()),
+        (
+            "messages/received".into(),
+            stats.messages_received.to_string(),
+        ),
+        ("messages/sent".into(), stats.messages_sent.to_string()),
+        (
+            "messages/inflight".into(),
+            clients
+                .iter()
+                .map(|client| client.inflight_outgoing + client.inflight_incoming)
+                .sum::<usize>()
+                .to_string(),
+        ),
+        (
+            "messages/publish/dropped".into(),
+            stats.publish_dropped.to_string(),
+        ),
+        (
+            "messages/publish/received".into(),
+            stats.publish_received.to_string(),
+        ),
+        (
+            "messages/publish/sent".into(),
+            stats.publish_sent.to_string(),
+        ),
+        (
+            "messages/publish/throttled/dropped".into(),
+            stats.publish_throttled_dropped.to_string(),
+        ),
+        (
+            "messages/publish/throttled/delayed".into(),
+            stats.publish_throttled_delayed.to_string(),
+        ),
+        (
+            "messages/retained/count".into(),
+            retained.messages.to_string(),
+        ),
+        ("messages/retained/bytes".into(), retained.bytes.to_string()),
+        (
+            "messages/retained/evicted".into(),
+            retained.evicted.to_string(),
+        ),
+        (
+            "messages/retained/rejected".into(),
+            retained.rejected.to_string(),
+        ),
+        (
+            "subscriptions/count".into(),
+            subscriptions.subscriptions.to_string(),
+        ),
+        (
+            "subscriptions/nodes".into(),
+            subscriptions.nodes.to_string(),
+        ),
+    ];
+
+    let latency = broker_info::delivery_latency();
+    values.push(("latency/delivery/count".into(), latency.count.to_string()));
+    values.push((
+        "latency/delivery/mean".into(),
+        format!("{} microseconds", latency.mean().as_micros()),
+    ));
+    for (bound, count) in latency.buckets {
+        values.push((
+            format!("latency/delivery/buckets/{}", bucket_name(bound)),
+            count.to_string(),
+        ));
+    }
+
+    for (name, average) in load.topics() {
+        for (window, value) in average.values() {
+            values.push((format!("load/{}/{}", name, window), format!("{:.2}", value)));
+        }
+    }
+
+    if let Some(client_metrics) = client_metrics {
+        values.extend(client_metrics.topics(&clients, now));
+    }
+
+    #[cfg(feature = "topic-stats")]
+    for (topic, topic_stats) in broker_info::topic_stats() {
+        values.push((
+            format!("topics/{}/messages", topic),
+            topic_stats.messages.to_string(),
+        ));
+        values.push((
+            format!("topics/{}/bytes", topic),
+            topic_stats.bytes.to_string(),
+        ));
+    }
+
+    for (topic, value) in values {
+        local
+            .publish(
+                format!("$SYS/broker/{}", topic),
+                Bytes::from(value),
+                QosLevel::AtMost,
+                true,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Name of a latency bucket from its bound in microseconds, like `250us`, `2.5ms` or `inf`
+fn bucket_name(bound: Option<u64>) -> String {
+    match bound {
+        None => "inf".into(),
+        Some(micros) if micros >= 1_000_000 => format!("{}s", micros as f64 / 1_000_000.0),
+        Some(micros) if micros >= 1_000 => format!("{}ms", micros as f64 / 1_000.0),
+        Some(micros) => format!("{}us", micros),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::enums::ProtocalVersion;
+
+    #[test]
+    fn test_bucket_name() {
+        assert_eq!(bucket_name(Some(250)), "250us");
+        assert_eq!(bucket_name(Some(2_500)), "2.5ms");
+        assert_eq!(bucket_name(Some(10_000)), "10ms");
+        assert_eq!(bucket_name(Some(1_000_000)), "1s");
+        assert_eq!(bucket_name(None), "inf");
+    }
+
+    fn client(
+        client_id: &str,
+        keepalive: Option<u16>,
+        last_seen: Option<SystemTime>,
+    ) -> ClientInfo {
+        ClientInfo {
+            client_id: client_id.into(),
+            protocol: ProtocalVersion::Five,
+            subscriptions: 0,
+            addr: None,
+            keepalive,
+            connected_at: UNIX_EPOCH + Duration::from_secs(1_000),
+            connected: true,
+            last_seen,
+            inflight_outgoing: 0,
+            inflight_pending: 0,
+            inflight_incoming: 0,
+        }
+    }
+
+    #[test]
+    fn test_keepalive_status() {
+        let now = UNIX_EPOCH + Duration::from_secs(2_000);
+        let seen = |secs_ago| Some(now - Duration::from_secs(secs_ago));
+
+        assert_eq!(
+            keepalive_status(&client("a", Some(60), seen(30)), now),
+            "online"
+        );
+        assert_eq!(
+            keepalive_status(&client("a", Some(60), seen(90)), now),
+            "late"
+        );
+        assert_eq!(
+            keepalive_status(&client("a", Some(0), seen(900)), now),
+            "online"
+        );
+        assert_eq!(
+            keepalive_status(&client("local.sys", None, None), now),
+            "online"
+        );
+
+        let offline = ClientInfo {
+            connected: false,
+            ..client("a", Some(60), seen(30))
+        };
+        assert_eq!(keepalive_status(&offline, now), "offline");
+    }
+
+    #[test]
+    fn test_client_metrics() {
+        let now = UNIX_EPOCH + Duration::from_secs(2_000);
+        let mut metrics = ClientMetrics::default();
+
+        let topics = metrics.topics(
+            &[
+                client("sensor-1", Some(60), Some(now)),
+                client("bridge.cloud", None, None),
+                client("a/b", Some(60), Some(now)),
+            ],
+            now,
+        );
+        assert!(topics.contains(&("clients/sensor-1/status".into(), "online".into())));
+        assert!(topics.contains(&("clients/sensor-1/keepalive".into(), "60".into())));
+        assert!(topics.contains(&("clients/sensor-1/last_seen".into(), "2000".into())));
+        assert!(topics.contains(&("clients/bridge.cloud/connected_at".into(), "1000".into())));
+        assert!(!topics
+            .iter()
+            .any(|(topic, _)| topic.starts_with("clients/bridge.cloud/last_seen")));
+        assert!(!topics
+            .iter()
+            .any(|(topic, _)| topic.starts_with("clients/a/")));
+
+        // The topics of a removed session are cleared once
+        let topics = metrics.topics(&[client("bridge.cloud", None, None)], now);
+        assert!(topics.contains(&("clients/sensor-1/status".into(), String::new())));
+        let topics = metrics.topics(&[client("bridge.cloud", None, None)], now);
+        assert!(!topics
+            .iter()
+            .any(|(topic, _)| topic.starts_with("clients/sensor-1/")));
+    }
+
+    #[test]
+    fn test_load_average() {
+        let mut load = LoadAverage::default();
+
+        // a steady rate converges on the rate, shorter windows faster
+        for _ in 0..60 {
+            load.update(10.0, 10.0);
+        }
+        let values = load.values().collect::<Vec<_>>();
+        assert_eq!(values[0].0, "1min");
+        assert!((values[0].1 - 10.0).abs() < 0.01);
+        assert!(values[1].1 < values[0].1);
+        assert!(values[2].1 < values[1].1);
+
+        load.update(0.0, 60.0);
+        assert!(load.values().next().unwrap().1 < 4.0);
+    }
+}
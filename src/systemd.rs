@@ -0,0 +1,182 @@
+//! ### systemd
+//! Socket activation and service notifications, enabled with the `systemd` option.
+//!
+//! Listeners passed by socket activation (`LISTEN_FDS`) are used instead of binding `bind_address`.
+//! A unit with `Type=notify` is told when the broker is accepting connections and when it is stopping,
+//! and with `WatchdogSec` set the watchdog is pinged at half its interval while the command loop responds.
+//!
+//! Both only work on Linux, elsewhere the broker binds its own listeners and sends no notifications.
+//!
+//! [sd_listen_fds(3)](https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html),
+//! [sd_notify(3)](https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html)
+
+use std::{env, io, time::Duration};
+
+use tokio::{net::TcpListener, select, sync::mpsc::Sender};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::core::enums::Command;
+
+/// First file descriptor passed by socket activation
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Listeners passed by socket activation, empty if the broker was not socket activated
+#[cfg(target_os = "linux")]
+pub fn listen_fds() -> io::Result<Vec<TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    // The descriptors belong to this process, not to anything it starts
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands the descriptors from 3 to 3 + LISTEN_FDS to this process,
+            // and nothing else in the process uses them
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            // Fails if systemd passed something other than a listening socket
+            listener.local_addr()?;
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listen_fds() -> io::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// Send a state like `READY=1` to the service manager, returns false if the broker is not run by systemd
+pub fn notify(state: &str) -> bool {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+
+    match notify_socket(&path, state) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!("Failed to notify systemd: {}", err);
+            false
+        }
+    }
+}
+
+/// Send a state to a notify socket, a path starting with `@` is an abstract socket
+#[cfg(target_os = "linux")]
+fn notify_socket(path: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    };
+
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify_socket(_path: &std::ffi::OsStr, _state: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// How often to ping the watchdog, `None` if the unit has no `WatchdogSec`
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(|usec| Duration::from_micros(usec) / 2)
+}
+
+/// Ping the watchdog every `interval` until the cancellation token is triggered.
+///
+/// The command loop has to answer a command within the interval for each ping,
+/// so systemd restarts a broker that is stuck rather than one that is only alive.
+pub async fn run_watchdog(
+    interval: Duration,
+    message_bridge: Sender<Command>,
+    cancellation: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        select! {
+            () = cancellation.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        if message_bridge
+            .send(Command::RetainedCount(tx))
+            .await
+            .is_err()
+        {
+            break;
+        }
+        match tokio::time::timeout(interval, rx).await {
+            Ok(Ok(_)) => {
+                notify("WATCHDOG=1");
+            }
+            _ => warn!("Command loop did not respond, skipped the watchdog ping"),
+        }
+    }
+
+    debug!("Exiting watchdog loop");
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+
+    #[test]
+    fn test_notify_socket() {
+        let path = env::temp_dir().join(format!("mqtt-broker-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+
+        // abstract sockets are given with a leading @
+        let name = format!("mqtt-broker-notify-{}", std::process::id());
+        let socket = {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap()
+        };
+        notify_socket(format!("@{}", name).as_ref(), "STOPPING=1").unwrap();
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tokio::select;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::error::MqttError;
+
+/// ### TLS
+/// TLS settings for the client listener, set with the `certfile`, `keyfile`, `cafile`, `crlfile`,
+/// `require_certificate`, `use_identity_as_username` and `tls_reload_interval` options.
+///
+/// Client certificates are verified against `cafile`, with `require_certificate`
+/// clients without a valid certificate can't connect. Certificates revoked by `crlfile` are refused.
+///
+/// The files are loaded again when the config is reloaded, and when they change with `tls_reload_interval` set,
+/// so certificates can be rotated without a restart. Clients that are already connected stay connected.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM encoded server certificate chain
+    pub cert_file: PathBuf,
+    /// PEM encoded server private key
+    pub key_file: PathBuf,
+    /// PEM encoded certificate authorities that client certificates are verified against
+    pub ca_file: Option<PathBuf>,
+    /// PEM encoded certificate revocation lists of the `ca_file` authorities
+    pub crl_file: Option<PathBuf>,
+    /// Only accept clients with a valid certificate
+    pub require_certificate: bool,
+    /// Use the identity of the client certificate as the MQTT username, see [`peer_identity`]
+    pub use_identity_as_username: bool,
+    /// How often the files are checked for changes, `None` to only load them again on a config reload
+    pub reload_interval: Option<Duration>,
+}
+
+impl TlsConfig {
+    /// Modification times of the files, to tell when they have changed
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [
+            Some(&self.cert_file),
+            Some(&self.key_file),
+            self.ca_file.as_ref(),
+            self.crl_file.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+    }
+}
+
+/// An acceptor that was built from a [`TlsConfig`]
+struct Loaded {
+    config: TlsConfig,
+    acceptor: TlsAcceptor,
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// ### Reloadable Acceptor
+/// The acceptor of the TLS listeners, shared with the broker handle so it can be replaced while the broker runs.
+///
+/// Each connection takes the acceptor when it is accepted, so a reload only applies to new connections.
+#[derive(Clone, Default)]
+pub(crate) struct SharedAcceptor(Arc<RwLock<Option<Loaded>>>);
+
+impl std::fmt::Debug for SharedAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SharedAcceptor")
+            .field(&self.is_loaded())
+            .finish()
+    }
+}
+
+impl SharedAcceptor {
+    /// Build an acceptor from the config and use it for new connections.
+    ///
+    /// If the files can't be loaded the previous acceptor is kept.
+    pub fn load(&self, config: &TlsConfig) -> Result<(), MqttError> {
+        // Read before loading so a file written during the load is loaded again
+        let modified = config.modified();
+        let acceptor = acceptor(config)?;
+        *self.0.write().unwrap_or_else(|err| err.into_inner()) = Some(Loaded {
+            config: config.clone(),
+            acceptor,
+            modified,
+        });
+        Ok(())
+    }
+
+    /// The current acceptor, `None` if TLS is off
+    pub fn get(&self) -> Option<TlsAcceptor> {
+        self.0
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_ref()
+            .map(|loaded| loaded.acceptor.clone())
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.0
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .is_some()
+    }
+
+    /// The config if any of its files changed since it was loaded
+    fn changed(&self) -> Option<TlsConfig> {
+        let loaded = self.0.read().unwrap_or_else(|err| err.into_inner());
+        let loaded = loaded.as_ref()?;
+        (loaded.config.modified() != loaded.modified).then(|| loaded.config.clone())
+    }
+
+    /// Load the files again whenever they change, until the cancellation token is triggered.
+    ///
+    /// A failed load is tried again on the next check, certificates are often replaced one file at a time.
+    pub async fn watch(self, interval: Duration, cancellation: CancellationToken) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            select! {
+                () = cancellation.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+
+            let Some(config) = self.changed() else {
+                continue;
+            };
+            match self.load(&config) {
+                Ok(()) => info!("Reloaded TLS certificates"),
+                Err(err) => warn!("Failed to reload TLS certificates: {}", err),
+            }
+        }
+    }
+}
+
+/// Build the acceptor for TLS connections
+pub(crate) fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, MqttError> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| MqttError::Tls(err.to_string()))?;
+
+    let builder = match &config.ca_file {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots
+                    .add(cert)
+                    .map_err(|err| MqttError::Tls(err.to_string()))?;
+            }
+
+            let mut verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            if let Some(crl_file) = &config.crl_file {
+                // Like mosquitto only the client certificate is checked, not the intermediates
+                verifier = verifier
+                    .with_crls(load_crls(crl_file)?)
+                    .only_check_end_entity_revocation();
+            }
+            let verifier = if config.require_certificate {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+
+            builder.with_client_cert_verifier(
+                verifier
+                    .build()
+                    .map_err(|err| MqttError::Tls(err.to_string()))?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder
+        .with_single_cert(load_certs(&config.cert_file)?, load_key(&config.key_file)?)
+        .map_err(|err| MqttError::Tls(err.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Identity of a client certificate, the subject Common Name or else the first
+/// DNS, email or URI Subject Alternative Name.
+pub(crate) fn peer_identity(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+
+    if let Some(cn) = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok())
+    {
+        return Some(cn.to_string());
+    }
+
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+            Some(name.to_string())
+        }
+        _ => None,
+    })
+}
+
+fn load_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, MqttError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if certs.is_empty() {
+        return Err(MqttError::Tls(format!(
+            "No certificates in '{}'",
+            path.display()
+        )));
+    }
+
+    Ok(certs)
+}
+
+fn load_crls(path: &PathBuf) -> Result<Vec<CertificateRevocationListDer<'static>>, MqttError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let crls = rustls_pemfile::crls(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if crls.is_empty() {
+        return Err(MqttError::Tls(format!(
+            "No revocation lists in '{}'",
+            path.display()
+        )));
+    }
+
+    Ok(crls)
+}
+
+fn load_key(path: &PathBuf) -> Result<PrivateKeyDer<'static>, MqttError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| MqttError::Tls(format!("No private key in '{}'", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+
+    use super::*;
+
+    fn certificate(common_name: Option<&str>, san: Vec<String>) -> CertificateDer<'static> {
+        let mut params = CertificateParams::new(san).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        if let Some(cn) = common_name {
+            params.distinguished_name.push(DnType::CommonName, cn);
+        }
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().clone()
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        let cert = certificate(Some("device-1"), vec!["device-1.local".into()]);
+        assert_eq!(peer_identity(&cert).as_deref(), Some("device-1"));
+
+        let cert = certificate(None, vec!["device-2.local".into()]);
+        assert_eq!(peer_identity(&cert).as_deref(), Some("device-2.local"));
+
+        assert_eq!(peer_identity(&CertificateDer::from(vec![0u8; 4])), None);
+    }
+
+    #[test]
+    fn test_shared_acceptor() {
+        let dir = std::env::temp_dir().join(format!("mqtt-broker-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".into()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        std::fs::write(dir.join("server.crt"), cert.pem()).unwrap();
+        std::fs::write(dir.join("server.key"), key.serialize_pem()).unwrap();
+
+        let mut config = TlsConfig {
+            cert_file: dir.join("server.crt"),
+            key_file: dir.join("server.key"),
+            ca_file: None,
+            crl_file: None,
+            require_certificate: false,
+            use_identity_as_username: false,
+            reload_interval: None,
+        };
+        let shared = SharedAcceptor::default();
+        assert!(shared.get().is_none());
+        shared.load(&config).unwrap();
+        assert!(shared.get().is_some());
+        assert!(shared.changed().is_none());
+
+        // A file without revocation lists fails to load and the loaded acceptor is kept
+        config.ca_file = Some(dir.join("server.crt"));
+        config.crl_file = Some(dir.join("server.key"));
+        assert!(shared.load(&config).is_err());
+        assert!(shared.get().is_some());
+
+        std::fs::remove_file(dir.join("server.key")).unwrap();
+        assert!(shared.changed().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
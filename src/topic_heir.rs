@@ -1,18 +1,38 @@
-use crate::{core::enums::ClientEvent, packets::enums::QosLevel, utils};
-use dashmap::DashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use crate::{
+    core::enums::{ClientEvent, ProtocalVersion},
+    direct,
+    packets::{enums::QosLevel, RetainHandling},
+    utils,
+};
 use tokio::sync::mpsc::Sender;
 // https://github.com/eclipse/mosquitto/blob/master/src/mosquitto_broker_internal.h#L327
 // https://github.com/eclipse/mosquitto/blob/master/src/subs.c#L551
 // https://github.com/eclipse/mosquitto/blob/master/src/subs.c#L335
 //https://github.com/eclipse/mosquitto/blob/master/src/handle_subscribe.c
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SubscriptionLeaf {
-    qos: QosLevel,
-    identifier: u128,
-    bridge: Sender<ClientEvent>,
-    //no_local: bool,
-    //retain_as_published: bool,
+    pub qos: QosLevel,
+    pub identifier: u128,
+    pub bridge: Sender<ClientEvent>,
+    /// Protocol of the subscribed client, used when packing messages for it.
+    pub protocol: ProtocalVersion,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    /// Whether retained messages are sent when the subscription is made
+    pub retain_handling: RetainHandling,
+    /// v5 Subscription Identifier sent with messages matching this subscription
+    pub subscription_identifier: Option<u32>,
+    /// Mount point of the client, removed from the topics of the messages it is sent
+    pub mount_point: Option<Arc<str>>,
 }
 
 impl SubscriptionLeaf {
@@ -20,226 +40,441 @@ impl SubscriptionLeaf {
         qos: QosLevel,
         identifier: u128,
         bridge: Sender<ClientEvent>,
-        //  no_local: bool,
-        // retain_as_published: bool,
+        protocol: ProtocalVersion,
+        no_local: bool,
+        retain_as_published: bool,
+        subscription_identifier: Option<u32>,
     ) -> Self {
         Self {
             qos,
             bridge,
             identifier,
-            //no_local,
-            //retain_as_published,
+            protocol,
+            no_local,
+            retain_as_published,
+            retain_handling: RetainHandling::default(),
+            subscription_identifier,
+            mount_point: None,
         }
     }
+
+    pub fn with_retain_handling(mut self, retain_handling: RetainHandling) -> Self {
+        self.retain_handling = retain_handling;
+        self
+    }
+
+    pub fn with_mount_point(mut self, mount_point: Option<Arc<str>>) -> Self {
+        self.mount_point = mount_point;
+        self
+    }
 }
 
+/// A client with at least one subscription matching a topic, or the member of a matching share group chosen for the message
 #[derive(Debug)]
-struct SubHier {
-    children: DashMap<String, SubHier>,
-    subs: Vec<SubscriptionLeaf>,
-    shared: DashMap<String, Vec<SubscriptionLeaf>>,
+pub struct Subscriber {
+    /// First subscription of the client that matched, with the options of its other matching subscriptions merged in
+    pub leaf: SubscriptionLeaf,
+    /// Subscription Identifiers of all the client's matching subscriptions
+    pub subscription_identifiers: Vec<u32>,
+    /// Matched through a shared subscription, the next message may go to another member of the group
+    pub shared: bool,
 }
-impl SubHier {
-    pub fn new() -> Self {
+
+/// Member of a share group picked for a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharePick {
+    /// Topic filter of the group, with its `$share/{ShareName}/` prefix
+    pub filter: Arc<str>,
+    pub identifier: u128,
+}
+
+/// How the member of each matching share group is chosen
+#[derive(Debug, Clone, Copy)]
+pub enum SharePicks<'a> {
+    /// The members of a group take turns, the tree holds every member of its groups
+    RoundRobin,
+    /// Members picked by [`SubscriptionTree::pick_shared`] on a tree holding the members on every shard,
+    /// a group with no picked member in this tree is left out
+    Given(&'a [SharePick]),
+}
+
+/// Matching subscriptions.
+///
+/// A client with overlapping non-shared subscriptions is only added once, with the identifiers of each of them.
+/// It is sent the message at the highest QoS of those subscriptions, keeps the RETAIN flag if any of them
+/// has Retain As Published, and is only left out as the publisher if all of them have No Local.
+/// Each matching share group adds one of its members on top of that, so a client that is also
+/// in a matching group gets a copy for its own subscriptions and one for the group.
+///
+/// [(MQTT 5) 4.8.2 Shared Subscriptions](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901250)
+struct Matches<'a> {
+    subscribers: Vec<Subscriber>,
+    /// Position of each client's non-shared match in `subscribers`
+    index: HashMap<u128, usize>,
+    picks: SharePicks<'a>,
+    /// The member added for each matching share group
+    picked: Vec<SharePick>,
+}
+
+impl<'a> Matches<'a> {
+    fn new(picks: SharePicks<'a>) -> Self {
         Self {
-            children: DashMap::new(),
-            subs: Vec::default(),
-            shared: DashMap::new(),
+            subscribers: Vec::new(),
+            index: HashMap::new(),
+            picks,
+            picked: Vec::new(),
         }
     }
 
-    pub fn insert(
-        &mut self,
-        mut iter: impl std::iter::Iterator<Item = String>,
-        sub: SubscriptionLeaf,
-        share: Option<String>,
-    ) {
-        if let Some(topic) = iter.next() {
-            if !self.children.contains_key(&topic) {
-                self.children.insert(topic.clone(), Self::new());
-            }
+    fn add_shared(&mut self, group: &ShareGroup) {
+        let member = match self.picks {
+            SharePicks::RoundRobin => group.pick(),
+            SharePicks::Given(picks) => group.members.iter().find(|member| {
+                picks
+                    .iter()
+                    .any(|pick| pick.identifier == member.identifier && pick.filter == group.filter)
+            }),
+        };
+        let Some(leaf) = member else {
+            return;
+        };
+
+        self.picked.push(SharePick {
+            filter: group.filter.clone(),
+            identifier: leaf.identifier,
+        });
+        self.subscribers.push(Subscriber {
+            leaf: leaf.clone(),
+            subscription_identifiers: leaf.subscription_identifier.into_iter().collect(),
+            shared: true,
+        });
+    }
 
-            if let Some(mut child) = self.children.get_mut(&topic) {
-                child.insert(iter, sub, share);
-            }
-        } else if let Some(sharename) = share {
-            if !self.shared.contains_key(&sharename) {
-                self.shared.insert(sharename.clone(), Vec::new());
+    fn add(&mut self, leaf: &SubscriptionLeaf) {
+        if let Some(&idx) = self.index.get(&leaf.identifier) {
+            let sub = &mut self.subscribers[idx];
+            // [MQTT-3.3.4-2] the maximum QoS of the matching subscriptions
+            sub.leaf.qos = sub.leaf.qos.max(leaf.qos);
+            sub.leaf.retain_as_published |= leaf.retain_as_published;
+            sub.leaf.no_local &= leaf.no_local;
+            if let Some(id) = leaf.subscription_identifier {
+                if !sub.subscription_identifiers.contains(&id) {
+                    sub.subscription_identifiers.push(id);
+                }
             }
+            return;
+        }
+
+        self.index.insert(leaf.identifier, self.subscribers.len());
+        self.subscribers.push(Subscriber {
+            leaf: leaf.clone(),
+            subscription_identifiers: leaf.subscription_identifier.into_iter().collect(),
+            shared: false,
+        });
+    }
+}
+
+/// Clients subscribed to the same filter with the same ShareName, each message goes to one of them
+#[derive(Debug)]
+struct ShareGroup {
+    /// Topic filter of the group, with its `$share/{ShareName}/` prefix
+    filter: Arc<str>,
+    members: Vec<SubscriptionLeaf>,
+    /// Member the next message goes to, taking turns
+    next: AtomicUsize,
+}
+
+impl ShareGroup {
+    fn new(filter: &str) -> Self {
+        Self {
+            filter: filter.into(),
+            members: Vec::new(),
+            next: AtomicUsize::new(0),
+        }
+    }
 
-            if let Some(mut s) = self.shared.get_mut(&sharename) {
-                if let Some(idx) = s.iter().position(|e| e.identifier == sub.identifier) {
-                    s.remove(idx);
+    /// Member to send a message to, members whose connection has closed are skipped while others are connected
+    fn pick(&self) -> Option<&SubscriptionLeaf> {
+        let len = self.members.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|offset| &self.members[(start + offset) % len])
+            .find(|member| !member.bridge.is_closed())
+            .or_else(|| self.members.get(start % len.max(1)))
+    }
+}
+
+/// ### Subscription Stats
+/// Size of the subscription tree, for finding clients that keep adding topic filters.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SubscriptionStats {
+    /// Levels of topic filters in the tree
+    pub nodes: usize,
+    /// Subscriptions, each client has one per topic filter
+    pub subscriptions: usize,
+    /// Subscriptions by the first level of their topic filter, shared subscriptions by the level after the share name
+    pub first_levels: BTreeMap<String, usize>,
+}
+
+/// A level of the tree, children are keyed by the next level of the topic filter.
+///
+/// The tree is only used by the command loop so it needs no locking,
+/// and lookups walk the levels of the topic as `&str` without allocating.
+#[derive(Debug, Default)]
+struct SubHier {
+    children: HashMap<String, SubHier>,
+    subs: Vec<SubscriptionLeaf>,
+    /// Share groups subscribed to this filter by their ShareName
+    shared: HashMap<String, ShareGroup>,
+}
+
+impl SubHier {
+    fn insert<'a>(
+        &mut self,
+        mut levels: impl Iterator<Item = &'a str>,
+        sub: SubscriptionLeaf,
+        share: Option<&str>,
+        filter: &str,
+    ) {
+        let subs = match (levels.next(), share) {
+            (Some(level), _) => {
+                // the key of a level is only allocated the first time it is subscribed to
+                if !self.children.contains_key(level) {
+                    self.children.insert(level.to_string(), Self::default());
+                }
+                if let Some(child) = self.children.get_mut(level) {
+                    child.insert(levels, sub, share, filter);
                 }
-                s.push(sub);
+                return;
             }
-        } else {
-            if let Some(idx) = self
-                .subs
-                .iter()
-                .position(|e| e.identifier == sub.identifier)
-            {
-                self.subs.remove(idx);
+            (None, Some(share)) => {
+                &mut self
+                    .shared
+                    .entry(share.to_string())
+                    .or_insert_with(|| ShareGroup::new(filter))
+                    .members
             }
-            self.subs.push(sub);
-        }
+            (None, None) => &mut self.subs,
+        };
+
+        // a client has one subscription per filter, resubscribing replaces it
+        subs.retain(|e| e.identifier != sub.identifier);
+        subs.push(sub);
     }
 
-    pub fn delete(
+    /// Returns true if the level is empty and can be removed
+    fn delete<'a>(
         &mut self,
-        mut iter: impl std::iter::Iterator<Item = String>,
+        mut levels: impl Iterator<Item = &'a str>,
         identifer: u128,
-        share: Option<String>,
+        share: Option<&str>,
     ) -> bool {
-        if let Some(topic) = iter.next() {
-            if let Some(mut child) = self.children.get_mut(&topic) {
-                let can_remove = child.delete(iter, identifer, share);
-                // map can deadlock if holding any sort of reference into the map
-                // so drop child to prevent deadlock
-                drop(child);
-                if can_remove {
-                    self.children.remove(&topic);
+        match (levels.next(), share) {
+            (Some(level), _) => {
+                if let Some(child) = self.children.get_mut(level) {
+                    if child.delete(levels, identifer, share) {
+                        self.children.remove(level);
+                    }
                 }
             }
-        } else if let Some(sharename) = share {
-            if let Some(mut s) = self.shared.get_mut(&sharename) {
-                if let Some(idx) = s.iter().position(|e| e.identifier == identifer) {
-                    s.remove(idx);
+            (None, Some(share)) => {
+                if let Some(group) = self.shared.get_mut(share) {
+                    group.members.retain(|e| e.identifier != identifer);
+                    if group.members.is_empty() {
+                        self.shared.remove(share);
+                    }
                 }
             }
-        } else if let Some(idx) = self.subs.iter().position(|e| e.identifier == identifer) {
-            self.subs.remove(idx);
+            (None, None) => self.subs.retain(|e| e.identifier != identifer),
         }
 
         self.children.is_empty() && self.shared.is_empty() && self.subs.is_empty()
     }
 
-    pub fn get(
+    /// Add the subscriptions matching the rest of the topic.
+    ///
+    /// `reserved` is set for the first level of a `$` topic, which wildcards don't match.
+    fn get<'a>(
         &self,
-        iter: &mut impl std::iter::Iterator<Item = String>,
-        subscribers: &mut Vec<(u128, Sender<ClientEvent>, QosLevel)>,
-        share: &Option<String>,
+        mut levels: impl Iterator<Item = &'a str> + Clone,
+        reserved: bool,
+        subscribers: &mut Matches<'_>,
     ) {
-        if let Some(topic) = iter.next() {
-            if let Some(child) = self.children.get(&topic) {
-                child.get(iter, subscribers, share);
-            }
-
-            if let Some(child) = self.children.get("+") {
-                child.get(iter, subscribers, share);
+        if let Some(level) = levels.next() {
+            if let Some(child) = self.children.get(level) {
+                child.get(levels.clone(), false, subscribers);
             }
-        } else if let Some(share) = share {
-            if let Some(s) = self.shared.get(share) {
-                for x in s.iter() {
-                    if !subscribers.iter().any(|e| e.0 == x.identifier) {
-                        subscribers.push((x.identifier, x.bridge.clone(), x.qos));
-                    }
-                }
+            // single level match
+            if let Some(child) = self.children.get("+").filter(|_| !reserved) {
+                child.get(levels, false, subscribers);
             }
         } else {
-            for x in &self.subs {
-                if !subscribers.iter().any(|e| e.0 == x.identifier) {
-                    subscribers.push((x.identifier, x.bridge.clone(), x.qos));
-                }
-            }
+            self.add_subscribers(subscribers);
         }
 
-        if let Some(child) = self.children.get("#") {
-            if let Some(share) = share {
-                if let Some(s) = child.shared.get(share) {
-                    for x in s.iter() {
-                        if !subscribers.iter().any(|e| e.0 == x.identifier) {
-                            subscribers.push((x.identifier, x.bridge.clone(), x.qos));
-                        }
-                    }
-                }
-            } else {
-                for x in &child.subs {
-                    if !subscribers.iter().any(|e| e.0 == x.identifier) {
-                        subscribers.push((x.identifier, x.bridge.clone(), x.qos));
-                    }
-                }
+        // multi level match, also matches the parent level
+        if let Some(child) = self.children.get("#").filter(|_| !reserved) {
+            child.add_subscribers(subscribers);
+        }
+    }
+
+    /// The subscription of a client to the rest of the filter
+    fn leaf_mut<'a>(
+        &mut self,
+        mut levels: impl Iterator<Item = &'a str>,
+        identifier: u128,
+        share: Option<&str>,
+    ) -> Option<&mut SubscriptionLeaf> {
+        let subs = match (levels.next(), share) {
+            (Some(level), _) => {
+                return self
+                    .children
+                    .get_mut(level)?
+                    .leaf_mut(levels, identifier, share)
             }
+            (None, Some(share)) => &mut self.shared.get_mut(share)?.members,
+            (None, None) => &mut self.subs,
+        };
+        subs.iter_mut().find(|e| e.identifier == identifier)
+    }
+
+    /// Levels below this one and subscriptions at or below this one
+    fn count(&self) -> (usize, usize) {
+        let mut nodes = self.children.len();
+        let mut subscriptions = self.subs.len()
+            + self
+                .shared
+                .values()
+                .map(|group| group.members.len())
+                .sum::<usize>();
+        for child in self.children.values() {
+            let (child_nodes, child_subscriptions) = child.count();
+            nodes += child_nodes;
+            subscriptions += child_subscriptions;
+        }
+        (nodes, subscriptions)
+    }
+
+    fn add_subscribers(&self, subscribers: &mut Matches<'_>) {
+        for sub in &self.subs {
+            subscribers.add(sub);
+        }
+        for group in self.shared.values() {
+            subscribers.add_shared(group);
         }
     }
 }
 
-#[derive(Debug)]
-pub struct SubscriptionTree(DashMap<String, SubHier>);
+#[derive(Debug, Default)]
+pub struct SubscriptionTree {
+    root: SubHier,
+    /// Tree holding the share group members of every shard, the shared subscriptions of this tree are added to it too
+    shares: Option<Arc<Mutex<SubscriptionTree>>>,
+}
+
+/// The lock is never held across an await, and only taken for shared subscriptions
+fn lock(shares: &Mutex<SubscriptionTree>) -> MutexGuard<'_, SubscriptionTree> {
+    shares.lock().unwrap_or_else(|err| err.into_inner())
+}
 
 impl SubscriptionTree {
     pub fn new() -> Self {
-        Self(DashMap::new())
+        Self::default()
     }
-    pub fn insert(&mut self, filter: String, sub: SubscriptionLeaf) -> Result<(), u8> {
-        let (filter_list, sharename) = utils::tokenise_topic(filter)?;
-
-        let mut iter = filter_list.into_iter();
 
-        if let Some(topic) = iter.next() {
-            if !self.0.contains_key(&topic) {
-                self.0.insert(topic.clone(), SubHier::new());
-            }
+    /// Keep the shared subscriptions in the tree of every shard as well, see [`SubscriptionTree::shared_picks`]
+    pub fn with_shares(mut self, shares: Arc<Mutex<SubscriptionTree>>) -> Self {
+        self.shares = Some(shares);
+        self
+    }
 
-            let mut child = self.0.get_mut(&topic).unwrap();
-            child.insert(iter, sub, sharename);
+    pub fn insert(&mut self, filter: &str, sub: SubscriptionLeaf) -> Result<(), u8> {
+        let (levels, sharename) = utils::split_topic(filter)?;
+        if let (Some(_), Some(shares)) = (sharename, &self.shares) {
+            lock(shares).insert(filter, sub.clone())?;
         }
+        self.root.insert(levels, sub, sharename, filter);
+        direct::invalidate();
         Ok(())
     }
-    pub fn delete(&mut self, filter: String, sub: u128) -> Result<(), u8> {
-        let (filter_list, sharename) = utils::tokenise_topic(filter)?;
-        let mut iter = filter_list.into_iter();
-
-        if let Some(topic) = iter.next() {
-            if let Some(mut child) = self.0.get_mut(&topic) {
-                let can_drop = child.delete(iter, sub, sharename);
-                // Map can deadlock if holding any sort of reference into the map
-                // so drop child to prevent deadlock
-                drop(child);
-                if can_drop {
-                    self.0.remove(&topic);
-                }
-            }
-        }
 
+    pub fn delete(&mut self, filter: &str, sub: u128) -> Result<(), u8> {
+        let (levels, sharename) = utils::split_topic(filter)?;
+        if let (Some(_), Some(shares)) = (sharename, &self.shares) {
+            lock(shares).delete(filter, sub)?;
+        }
+        self.root.delete(levels, sub, sharename);
+        direct::invalidate();
         Ok(())
     }
-    pub fn get(&self, filter: String) -> Result<Vec<(u128, Sender<ClientEvent>, QosLevel)>, u8> {
-        let mut subscribers = Vec::new();
-        let (filter_list, sharename) = utils::tokenise_topic(filter)?;
-
-        let mut iter = filter_list.into_iter();
 
-        if let Some(topic) = iter.next() {
-            if let Some(child) = self.0.get(&topic) {
-                child.get(&mut iter, &mut subscribers, &sharename);
-            }
-            // single level '+' match
-            if let Some(child) = self.0.get("+") {
-                child.get(&mut iter, &mut subscribers, &sharename);
-            }
+    /// Send the messages of a client's subscription to the channel of its new connection
+    pub fn rebind(
+        &mut self,
+        filter: &str,
+        identifier: u128,
+        bridge: Sender<ClientEvent>,
+        protocol: ProtocalVersion,
+    ) -> Result<(), u8> {
+        let (levels, sharename) = utils::split_topic(filter)?;
+        if let (Some(_), Some(shares)) = (sharename, &self.shares) {
+            lock(shares).rebind(filter, identifier, bridge.clone(), protocol)?;
         }
+        if let Some(leaf) = self.root.leaf_mut(levels, identifier, sharename) {
+            leaf.bridge = bridge;
+            leaf.protocol = protocol;
+            direct::invalidate();
+        }
+        Ok(())
+    }
 
-        // multi level match
-        if let Some(child) = self.0.get("#") {
-            if let Some(share) = sharename {
-                if let Some(s) = child.shared.get(&share) {
-                    for x in s.iter() {
-                        if !subscribers.iter().any(|e| e.0 == x.identifier) {
-                            subscribers.push((x.identifier, x.bridge.clone(), x.qos));
-                        }
-                    }
-                }
-            } else {
-                for x in &child.subs {
-                    if !subscribers.iter().any(|e| e.0 == x.identifier) {
-                        subscribers.push((x.identifier, x.bridge.clone(), x.qos));
-                    }
-                }
-            }
+    /// Count the nodes and subscriptions of the tree, walks the whole tree.
+    /// The root is not counted.
+    pub fn stats(&self) -> SubscriptionStats {
+        let mut stats = SubscriptionStats::default();
+        for (level, child) in &self.root.children {
+            let (nodes, subscriptions) = child.count();
+            stats.nodes += nodes + 1;
+            stats.subscriptions += subscriptions;
+            stats.first_levels.insert(level.clone(), subscriptions);
         }
+        stats
+    }
 
-        Ok(subscribers)
+    /// Subscribers a message published to the topic goes to, one member is taken from each matching share group
+    pub fn get(&self, topic: &str) -> Result<Vec<Subscriber>, u8> {
+        self.get_picked(topic, SharePicks::RoundRobin)
+    }
+
+    /// Subscribers a message published to the topic goes to, with the members of the share groups chosen by `picks`
+    pub fn get_picked(&self, topic: &str, picks: SharePicks<'_>) -> Result<Vec<Subscriber>, u8> {
+        Ok(self.matches(topic, picks).subscribers)
+    }
+
+    /// Take the member of each share group matching the topic, for the trees that only hold some of the members
+    pub fn pick_shared(&self, topic: &str) -> Vec<SharePick> {
+        self.matches(topic, SharePicks::RoundRobin).picked
+    }
+
+    /// Members of the share groups matching the topic picked on the tree of every shard, `None` without one.
+    ///
+    /// The members of a group may be on several shards, so they are picked once for a message
+    /// instead of by each shard from the members it holds.
+    pub fn shared_picks(&self, topic: &str) -> Option<Vec<SharePick>> {
+        self.shares
+            .as_ref()
+            .map(|shares| lock(shares).pick_shared(topic))
+    }
+
+    fn matches<'a>(&self, topic: &str, picks: SharePicks<'a>) -> Matches<'a> {
+        let mut subscribers = Matches::new(picks);
+
+        // The Server MUST NOT match Topic Filters starting with a wildcard character (# or +) with Topic Names beginning with a $ character
+        let reserved = topic.starts_with('$');
+
+        self.root.get(topic.split('/'), reserved, &mut subscribers);
+
+        subscribers
     }
 }
 
@@ -253,8 +488,16 @@ mod tests {
         let mut tree = SubscriptionTree::new();
 
         tree.insert(
-            "$share/GroupA/hello/test".to_string(),
-            SubscriptionLeaf::new(QosLevel::AtMost, 7, s),
+            "$share/GroupA/hello/test",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s,
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
 
@@ -266,13 +509,29 @@ mod tests {
         let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
         let mut tree = SubscriptionTree::new();
         tree.insert(
-            "$share/GroupA/hello/test".to_string(),
-            SubscriptionLeaf::new(QosLevel::AtMost, 7, s.clone()),
+            "$share/GroupA/hello/test",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s.clone(),
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
         tree.insert(
-            "$share/GroupA/hello/test".to_string(),
-            SubscriptionLeaf::new(QosLevel::Exactly, 7, s),
+            "$share/GroupA/hello/test",
+            SubscriptionLeaf::new(
+                QosLevel::Exactly,
+                7,
+                s,
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
 
@@ -284,13 +543,20 @@ mod tests {
         let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
         let mut tree = SubscriptionTree::new();
         tree.insert(
-            "/hello/test".to_string(),
-            SubscriptionLeaf::new(QosLevel::AtMost, 7, s),
+            "/hello/test",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s,
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
 
-        tree.delete("/hello/test".to_string(), 7)
-            .expect("Failed to delete");
+        tree.delete("/hello/test", 7).expect("Failed to delete");
 
         println!("{:#?}", tree);
     }
@@ -301,45 +567,163 @@ mod tests {
         let (sc, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
         let mut tree = SubscriptionTree::new();
         tree.insert(
-            "/hello/test".to_string(),
-            SubscriptionLeaf::new(QosLevel::AtMost, 7, s),
+            "/hello/test",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s,
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
         tree.insert(
-            "/hello/test".to_string(),
-            SubscriptionLeaf::new(QosLevel::AtMost, 34, sc.clone()),
+            "/hello/test",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                34,
+                sc.clone(),
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
         tree.insert(
-            "$share/GroupA/hello/test".to_string(),
-            SubscriptionLeaf::new(QosLevel::AtMost, 34, sc),
+            "$share/GroupA/hello/test",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                34,
+                sc,
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
 
-        let subscribers = tree.get("/hello/test".to_string()).expect("Failed to get");
+        let subscribers = tree.get("/hello/test").expect("Failed to get");
 
         println!("{:#?}", subscribers);
         println!("{:#?}", tree);
+        assert_eq!(subscribers.len(), 2);
+
+        // the shared subscription is to hello/test
+        let subscribers = tree.get("hello/test").expect("Failed to get");
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].leaf.identifier, 34);
+        assert!(subscribers[0].shared);
+    }
 
+    #[test]
+    fn test_get_shared() {
+        let (s1, _r1) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let (s2, r2) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let mut tree = SubscriptionTree::new();
+        for (filter, id, bridge) in [
+            ("sensors/#", 1, &s1),
+            ("sensors/temp", 1, &s1),
+            ("$share/g/sensors/+", 1, &s1),
+            ("$share/g/sensors/+", 2, &s2),
+            ("$share/h/sensors/temp", 2, &s2),
+        ] {
+            tree.insert(
+                filter,
+                SubscriptionLeaf::new(
+                    QosLevel::AtMost,
+                    id,
+                    bridge.clone(),
+                    ProtocalVersion::Five,
+                    false,
+                    false,
+                    None,
+                ),
+            )
+            .expect("Failed to insert");
+        }
+
+        // one copy for the overlapping subscriptions of client 1 and one for each group,
+        // the members of group g take turns
+        let mut members = Vec::new();
+        for _ in 0..2 {
+            let subscribers = tree.get("sensors/temp").expect("Failed to get");
+            assert_eq!(subscribers.len(), 3);
+            let own = subscribers
+                .iter()
+                .filter(|sub| !sub.shared)
+                .collect::<Vec<_>>();
+            assert_eq!(own.len(), 1);
+            assert_eq!(own[0].leaf.identifier, 1);
+
+            let mut shared = subscribers
+                .iter()
+                .filter(|sub| sub.shared)
+                .map(|sub| sub.leaf.identifier)
+                .collect::<Vec<_>>();
+            shared.sort();
+            assert!(shared == vec![1, 2] || shared == vec![2, 2], "{:?}", shared);
+            members.push(shared[0]);
+        }
+        members.sort();
+        assert_eq!(members, vec![1, 2]);
+
+        // a member whose connection closed is skipped
+        drop(r2);
+        for _ in 0..2 {
+            let subscribers = tree.get("sensors/a").expect("Failed to get");
+            let shared = subscribers
+                .iter()
+                .filter(|sub| sub.shared)
+                .map(|sub| sub.leaf.identifier)
+                .collect::<Vec<_>>();
+            assert_eq!(shared, vec![1]);
+        }
+
+        // members picked on another tree, a group without a member in this one is left out
+        let picks = [SharePick {
+            filter: "$share/h/sensors/temp".into(),
+            identifier: 2,
+        }];
         let subscribers = tree
-            .get("$share/GroupA/hello/test".to_string())
+            .get_picked("sensors/temp", SharePicks::Given(&picks))
             .expect("Failed to get");
-        println!("{:#?}", subscribers)
+        assert_eq!(subscribers.len(), 2);
+        assert!(subscribers[1].shared);
+        assert_eq!(subscribers[1].leaf.identifier, 2);
+        let picked = tree.pick_shared("sensors/temp");
+        assert_eq!(picked.len(), 2);
+        assert!(picked.contains(&picks[0]));
+
+        // the group is gone once its last member unsubscribes
+        tree.delete("$share/h/sensors/temp", 2)
+            .expect("Failed to delete");
+        let subscribers = tree.get("sensors/temp").expect("Failed to get");
+        assert_eq!(subscribers.len(), 2);
     }
 
     #[test]
     fn test_get_single_wild() {
-        let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(0);
+        let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
         let mut tree = SubscriptionTree::new();
         tree.insert(
-            "/+/test".to_string(),
-            SubscriptionLeaf::new(QosLevel::AtMost, 7, s),
+            "/+/test",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s,
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
 
-        let subscribers = tree
-            .get("/hello/test".to_string())
-            .expect("Failed to get subscribers");
+        let subscribers = tree.get("/hello/test").expect("Failed to get subscribers");
 
         println!("{:#?}", subscribers);
     }
@@ -349,15 +733,242 @@ mod tests {
         let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
         let mut tree = SubscriptionTree::new();
         tree.insert(
-            "#".to_string(),
-            SubscriptionLeaf::new(QosLevel::AtMost, 7, s),
+            "#",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s,
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
         )
         .expect("Failed to insert");
 
+        let subscribers = tree.get("/hello/test").expect("Failed to get subscribers");
+
+        println!("{:#?}", subscribers);
+    }
+
+    #[test]
+    fn test_get_exact_and_single_wild() {
+        let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let mut tree = SubscriptionTree::new();
+        for (filter, id) in [("a/b/c", 1), ("a/+/c", 2), ("a/+", 3)] {
+            tree.insert(
+                filter,
+                SubscriptionLeaf::new(
+                    QosLevel::AtMost,
+                    id,
+                    s.clone(),
+                    ProtocalVersion::Four,
+                    false,
+                    false,
+                    None,
+                ),
+            )
+            .expect("Failed to insert");
+        }
+
+        // both branches see the rest of the topic
+        let mut ids = tree
+            .get("a/b/c")
+            .expect("Failed to get subscribers")
+            .iter()
+            .map(|sub| sub.leaf.identifier)
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+
+        tree.delete("a/+/c", 2).expect("Failed to delete");
+        assert_eq!(tree.get("a/b/c").expect("Failed to get").len(), 1);
+    }
+
+    #[test]
+    fn test_get_overlapping_subscription_identifiers() {
+        let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let mut tree = SubscriptionTree::new();
+        tree.insert(
+            "a/+",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s.clone(),
+                ProtocalVersion::Five,
+                false,
+                false,
+                Some(1),
+            ),
+        )
+        .expect("Failed to insert");
+        tree.insert(
+            "a/#",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s,
+                ProtocalVersion::Five,
+                false,
+                false,
+                Some(2),
+            ),
+        )
+        .expect("Failed to insert");
+
+        let subscribers = tree.get("a/b").expect("Failed to get subscribers");
+
+        assert_eq!(subscribers.len(), 1);
+        let mut ids = subscribers[0].subscription_identifiers.clone();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_get_overlapping_options() {
+        let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let mut tree = SubscriptionTree::new();
+        for (filter, qos, no_local, retain_as_published) in [
+            ("a/b", QosLevel::AtMost, true, false),
+            ("a/+", QosLevel::AtMost, true, true),
+            ("a/#", QosLevel::Exactly, false, false),
+            ("c/+", QosLevel::AtLeast, true, false),
+            ("c/#", QosLevel::AtMost, true, false),
+        ] {
+            tree.insert(
+                filter,
+                SubscriptionLeaf::new(
+                    qos,
+                    7,
+                    s.clone(),
+                    ProtocalVersion::Five,
+                    no_local,
+                    retain_as_published,
+                    None,
+                ),
+            )
+            .expect("Failed to insert");
+        }
+
+        // the highest QoS, Retain As Published from any and No Local only if all have it
+        let subscribers = tree.get("a/b").expect("Failed to get subscribers");
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].leaf.qos, QosLevel::Exactly);
+        assert!(subscribers[0].leaf.retain_as_published);
+        assert!(!subscribers[0].leaf.no_local);
+
+        let subscribers = tree.get("a/c").expect("Failed to get subscribers");
+        assert_eq!(subscribers[0].leaf.qos, QosLevel::Exactly);
+        assert!(subscribers[0].leaf.retain_as_published);
+        assert!(!subscribers[0].leaf.no_local);
+
+        let subscribers = tree.get("c/d").expect("Failed to get subscribers");
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].leaf.qos, QosLevel::AtLeast);
+        assert!(!subscribers[0].leaf.retain_as_published);
+        assert!(subscribers[0].leaf.no_local);
+    }
+
+    #[test]
+    fn test_get_reserved_topic() {
+        let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let mut tree = SubscriptionTree::new();
+        for (filter, id) in [("#", 1), ("+/broker/uptime", 2), ("$SYS/#", 3)] {
+            tree.insert(
+                filter,
+                SubscriptionLeaf::new(
+                    QosLevel::AtMost,
+                    id,
+                    s.clone(),
+                    ProtocalVersion::Four,
+                    false,
+                    false,
+                    None,
+                ),
+            )
+            .expect("Failed to insert");
+        }
+
         let subscribers = tree
-            .get("/hello/test".to_string())
+            .get("$SYS/broker/uptime")
             .expect("Failed to get subscribers");
 
-        println!("{:#?}", subscribers);
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].leaf.identifier, 3);
+    }
+
+    #[test]
+    fn test_rebind() {
+        let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let (sc, mut rc) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let mut tree = SubscriptionTree::new();
+        tree.insert(
+            "a/+",
+            SubscriptionLeaf::new(
+                QosLevel::AtMost,
+                7,
+                s,
+                ProtocalVersion::Four,
+                false,
+                false,
+                None,
+            ),
+        )
+        .expect("Failed to insert");
+
+        tree.rebind("a/+", 7, sc, ProtocalVersion::Five)
+            .expect("Failed to rebind");
+
+        let subscribers = tree.get("a/b").expect("Failed to get subscribers");
+        assert_eq!(subscribers[0].leaf.protocol, ProtocalVersion::Five);
+        subscribers[0]
+            .leaf
+            .bridge
+            .try_send(ClientEvent::Message(bytes::Bytes::new()))
+            .expect("Failed to send");
+        assert!(rc.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_stats() {
+        let (s, _) = tokio::sync::mpsc::channel::<ClientEvent>(1);
+        let mut tree = SubscriptionTree::new();
+        for (filter, id) in [
+            ("a/b", 1),
+            ("a/+", 1),
+            ("a/b", 2),
+            ("$share/group/a/c", 3),
+            ("#", 4),
+            ("$SYS/#", 3),
+        ] {
+            tree.insert(
+                filter,
+                SubscriptionLeaf::new(
+                    QosLevel::AtMost,
+                    id,
+                    s.clone(),
+                    ProtocalVersion::Four,
+                    false,
+                    false,
+                    None,
+                ),
+            )
+            .expect("Failed to insert");
+        }
+
+        let stats = tree.stats();
+        // a, a/b, a/+, a/c, #, $SYS and $SYS/#
+        assert_eq!(stats.nodes, 7);
+        assert_eq!(stats.subscriptions, 6);
+        assert_eq!(
+            stats.first_levels.into_iter().collect::<Vec<_>>(),
+            vec![("#".into(), 1), ("$SYS".into(), 1), ("a".into(), 4)]
+        );
+
+        tree.delete("a/b", 1).expect("Failed to delete");
+        tree.delete("a/b", 2).expect("Failed to delete");
+        let stats = tree.stats();
+        assert_eq!(stats.nodes, 6);
+        assert_eq!(stats.subscriptions, 4);
     }
 }
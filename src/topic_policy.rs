@@ -0,0 +1,287 @@
+use std::str::FromStr;
+
+use crate::{config::Config, error::MqttError, utils::topic_matches};
+
+/// How topic filters that only differ by empty levels are treated when clients subscribe and unsubscribe
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FilterNormalization {
+    /// Use filters as they are sent, `a/b/` and `a//b` are different filters from `a/b` as the spec says
+    #[default]
+    Keep,
+    /// Refuse filters with a trailing `/` or an empty level after the first
+    Reject,
+    /// Remove the trailing `/` and the empty levels after the first, so `a//b/` is the filter `a/b`
+    Collapse,
+}
+
+impl FromStr for FilterNormalization {
+    type Err = MqttError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "keep" => Ok(Self::Keep),
+            "reject" => Ok(Self::Reject),
+            "collapse" => Ok(Self::Collapse),
+            _ => Err(MqttError::InvalidConfig(format!(
+                "Invalid value '{}' for 'topic_filter_normalization'",
+                value
+            ))),
+        }
+    }
+}
+
+/// ### Topic Policy
+/// Rules for topics starting with `$`, which are reserved for the server, and for anonymous clients.
+///
+/// - Clients can subscribe to `$SYS` and to whitelisted namespaces.
+/// - Clients can only publish to whitelisted namespaces, never to `$SYS`.
+/// - Clients that connected without a username can be confined to the topics matching the `anonymous_topics` filters.
+/// - Topic filters can be brought to one spelling, see [`FilterNormalization`].
+///
+/// Filters starting with a wildcard never match `$` topics, see [`crate::utils::topic_matches`].
+///
+/// [(MQTT 5) 4.7.2 Topics beginning with $](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901246)
+#[derive(Debug, Default, Clone)]
+pub struct TopicPolicy {
+    /// `$` namespaces clients can publish and subscribe to
+    namespaces: Vec<String>,
+    /// Filters anonymous clients are confined to, no restriction if empty
+    anonymous_topics: Vec<String>,
+    normalization: FilterNormalization,
+}
+
+const SYS: &str = "$SYS";
+const SHARE: &str = "$share";
+
+impl TopicPolicy {
+    pub fn new(namespaces: Vec<String>) -> Self {
+        Self {
+            namespaces,
+            anonymous_topics: Vec::new(),
+            normalization: FilterNormalization::Keep,
+        }
+    }
+
+    /// Confine anonymous clients to the topics matching `filters`
+    pub fn with_anonymous_topics(mut self, filters: Vec<String>) -> Self {
+        self.anonymous_topics = filters;
+        self
+    }
+
+    /// Set how the topic filters of subscribes and unsubscribes are normalized
+    pub fn with_filter_normalization(mut self, normalization: FilterNormalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn filter_normalization(&self) -> FilterNormalization {
+        self.normalization
+    }
+
+    /// The filter a client subscribes or unsubscribes with in its canonical form, `None` if it is refused.
+    ///
+    /// The ShareName of a shared subscription is kept as it is, only the levels of its filter are normalized.
+    pub fn normalize_filter(&self, filter: String) -> Option<String> {
+        if self.normalization == FilterNormalization::Keep {
+            return Some(filter);
+        }
+
+        let levels = strip_share(&filter);
+        let share = &filter[..filter.len() - levels.len()];
+        let mut canonical = String::with_capacity(filter.len());
+        canonical.push_str(share);
+        for (index, level) in levels.split('/').enumerate() {
+            // a leading `/` is an empty first level, which is kept
+            if index > 0 {
+                if level.is_empty() {
+                    continue;
+                }
+                canonical.push('/');
+            }
+            canonical.push_str(level);
+        }
+
+        match self.normalization {
+            _ if canonical.len() == filter.len() => Some(filter),
+            FilterNormalization::Collapse => Some(canonical),
+            _ => None,
+        }
+    }
+
+    /// Are anonymous clients confined to a sandbox
+    pub fn confines_anonymous(&self) -> bool {
+        !self.anonymous_topics.is_empty()
+    }
+
+    /// Can a client subscribe to the topic filter.
+    ///
+    /// An anonymous client can only subscribe to filters that match nothing outside its sandbox,
+    /// so `public/sensors/+` is allowed by `public/#` but `+/sensors` is not.
+    pub fn can_subscribe(&self, filter: &str, anonymous: bool) -> bool {
+        let filter = strip_share(filter);
+        if anonymous
+            && !self.anonymous_topics.is_empty()
+            && !self
+                .anonymous_topics
+                .iter()
+                .any(|sandbox| filter_within(sandbox, filter))
+        {
+            return false;
+        }
+
+        match namespace(filter) {
+            Some(SYS) => true,
+            Some(ns) => self.is_allowed(ns),
+            None => true,
+        }
+    }
+
+    /// Can a client publish to the topic name
+    pub fn can_publish(&self, topic: &str, anonymous: bool) -> bool {
+        if anonymous
+            && !self.anonymous_topics.is_empty()
+            && !self
+                .anonymous_topics
+                .iter()
+                .any(|sandbox| topic_matches(sandbox, topic))
+        {
+            return false;
+        }
+
+        match namespace(topic) {
+            Some(SYS) => false,
+            Some(ns) => self.is_allowed(ns),
+            None => true,
+        }
+    }
+
+    fn is_allowed(&self, namespace: &str) -> bool {
+        self.namespaces.iter().any(|ns| ns == namespace)
+    }
+}
+
+impl From<&Config> for TopicPolicy {
+    fn from(config: &Config) -> Self {
+        Self::new(config.dollar_namespaces.clone())
+            .with_anonymous_topics(config.anonymous_topics.clone())
+            .with_filter_normalization(config.topic_filter_normalization)
+    }
+}
+
+/// Does every topic matched by `filter` also match `sandbox`
+fn filter_within(sandbox: &str, filter: &str) -> bool {
+    let mut sandbox_levels = sandbox.split('/');
+    let mut filter_levels = filter.split('/');
+
+    loop {
+        match (sandbox_levels.next(), filter_levels.next()) {
+            (Some("#"), _) => return true,
+            // `+` can't stand in for the levels a `#` matches
+            (Some("+"), Some(level)) if level != "#" => {}
+            (Some(s), Some(f)) if s == f && f != "+" && f != "#" => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// First level of a topic if it is reserved
+fn namespace(topic: &str) -> Option<&str> {
+    let first = topic.split('/').next()?;
+    first.starts_with('$').then_some(first)
+}
+
+/// Shared subscriptions are `$share/{ShareName}/{filter}`
+fn strip_share(filter: &str) -> &str {
+    match filter.strip_prefix(SHARE).and_then(|f| f.strip_prefix('/')) {
+        Some(rest) => rest.split_once('/').map_or("", |(_, filter)| filter),
+        None => filter,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_policy() {
+        let policy = TopicPolicy::new(vec!["$internal".into()]);
+
+        assert!(policy.can_subscribe("$SYS/broker/uptime", false));
+        assert!(policy.can_subscribe("$internal/#", false));
+        assert!(policy.can_subscribe("$share/group/sensors/#", false));
+        assert!(!policy.can_subscribe("$other/#", false));
+        assert!(!policy.can_subscribe("$share/group/$other/a", false));
+
+        assert!(policy.can_publish("sensors/temp", false));
+        assert!(policy.can_publish("$internal/a", false));
+        assert!(!policy.can_publish("$SYS/broker/uptime", false));
+        assert!(!policy.can_publish("$other/a", false));
+
+        // Without a sandbox anonymous clients have the same rules
+        assert!(policy.can_subscribe("#", true));
+        assert!(policy.can_publish("sensors/temp", true));
+    }
+
+    #[test]
+    fn test_anonymous_topics() {
+        let policy = TopicPolicy::default()
+            .with_anonymous_topics(vec!["public/#".into(), "devices/+/status".into()]);
+
+        assert!(policy.can_subscribe("public/#", true));
+        assert!(policy.can_subscribe("public", true));
+        assert!(policy.can_subscribe("public/sensors/+", true));
+        assert!(policy.can_subscribe("$share/group/public/a", true));
+        assert!(policy.can_subscribe("devices/+/status", true));
+        assert!(policy.can_subscribe("devices/lamp/status", true));
+        assert!(!policy.can_subscribe("#", true));
+        assert!(!policy.can_subscribe("+/sensors", true));
+        assert!(!policy.can_subscribe("devices/#", true));
+        assert!(!policy.can_subscribe("devices/+/status/#", true));
+        assert!(!policy.can_subscribe("$SYS/#", true));
+
+        assert!(policy.can_publish("public/chat", true));
+        assert!(policy.can_publish("devices/lamp/status", true));
+        assert!(!policy.can_publish("devices/lamp/config", true));
+        assert!(!policy.can_publish("private/chat", true));
+
+        // Clients with a username are not confined
+        assert!(policy.can_subscribe("#", false));
+        assert!(policy.can_publish("private/chat", false));
+    }
+
+    #[test]
+    fn test_filter_normalization() {
+        let keep = TopicPolicy::default();
+        let reject = TopicPolicy::default().with_filter_normalization(FilterNormalization::Reject);
+        let collapse =
+            TopicPolicy::default().with_filter_normalization(FilterNormalization::Collapse);
+
+        for filter in ["a/b", "/a/+", "#", "$share/group/a/#"] {
+            for policy in [&keep, &reject, &collapse] {
+                assert_eq!(
+                    policy.normalize_filter(filter.into()).as_deref(),
+                    Some(filter)
+                );
+            }
+        }
+
+        for (filter, canonical) in [
+            ("a/b/", "a/b"),
+            ("a//b", "a/b"),
+            ("//a///+/", "/a/+"),
+            ("$share/group/a//#", "$share/group/a/#"),
+            ("/", ""),
+        ] {
+            assert_eq!(
+                keep.normalize_filter(filter.into()).as_deref(),
+                Some(filter)
+            );
+            assert_eq!(reject.normalize_filter(filter.into()), None);
+            assert_eq!(
+                collapse.normalize_filter(filter.into()).as_deref(),
+                Some(canonical)
+            );
+        }
+    }
+}
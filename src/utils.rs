@@ -1,23 +1,40 @@
-/// Parse a topic string into its parts and parse out $share
-pub fn tokenise_topic(value: String) -> Result<(Vec<String>, Option<String>), u8> {
-    let mut topic = value.split('/').collect::<Vec<&str>>();
+/// Split a topic into its levels without allocating and parse out $share.
+///
+/// The ShareName of a `$share/{ShareName}/{filter}` filter is returned separately with the levels of its filter,
+/// so the shared subscription is kept with the non-shared subscriptions to the same filter.
+pub fn split_topic(value: &str) -> Result<(impl Iterator<Item = &str> + Clone, Option<&str>), u8> {
+    let (filter, sharename) = match value.strip_prefix("$share/") {
+        Some(rest) => match rest.split_once('/') {
+            Some((sharename, filter)) => (filter, Some(sharename)),
+            None => return Err(1),
+        },
+        None if value == "$share" => return Err(1),
+        None => (value, None),
+    };
 
-    let sharename = if let Some(&"$share") = topic.first() {
-        if topic.len() < 2 {
-            return Err(1);
-        }
-        topic.remove(0);
-        let share = topic.first().ok_or(1)?.to_string();
-        if let Some(first) = topic.first_mut() {
-            *first = "";
-        }
+    Ok((filter.split('/'), sharename))
+}
 
-        Some(share)
-    } else {
-        None
-    };
+/// Check if a topic name matches a topic filter, honoring the `+` and `#` wildcards
+///
+/// Filters starting with a wildcard don't match topics starting with `$`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
 
-    Ok((topic.iter().map(|x| x.to_string()).collect(), sharename))
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -25,38 +42,44 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_tokenize_topic() {
-        if let Ok((topic, share)) = tokenise_topic("topic/Hello".to_string()) {
-            assert!(share.is_none());
-            assert_eq!(topic, vec!["topic".to_string(), "Hello".to_string()]);
-        } else {
-            panic!("Failed to parse topic")
-        }
+    fn test_topic_matches() {
+        assert!(topic_matches(
+            "sport/tennis/player1",
+            "sport/tennis/player1"
+        ));
+        assert!(topic_matches("sport/+/player1", "sport/tennis/player1"));
+        assert!(topic_matches("sport/#", "sport/tennis/player1"));
+        assert!(topic_matches("sport/#", "sport"));
+        assert!(topic_matches("#", "sport/tennis"));
+        assert!(!topic_matches("sport/+", "sport/tennis/player1"));
+        assert!(!topic_matches("sport/tennis", "sport/tennis/player1"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
     }
+
     #[test]
-    fn test_tokenize_topic_with_leading_slash() {
-        if let Ok((topic, share)) = tokenise_topic("/topic/Hello".to_string()) {
-            assert!(share.is_none());
-            assert_eq!(
-                topic,
-                vec!["".to_string(), "topic".to_string(), "Hello".to_string()]
-            );
-        } else {
-            panic!("Failed to parse topic")
-        }
+    fn test_split_topic() {
+        let (levels, share) = split_topic("topic/Hello").expect("Failed to parse topic");
+        assert!(share.is_none());
+        assert_eq!(levels.collect::<Vec<_>>(), vec!["topic", "Hello"]);
     }
+
     #[test]
-    fn test_tokenize_topic_with_share() {
-        if let Ok((topic, share)) = tokenise_topic("$share/GroupA/topic/Hello".to_string()) {
-            println!("{:#?} {:#?}", topic, share);
-            assert!(share.is_some_and(|x| x == *"GroupA"), "Share is not GroupA");
-
-            assert_eq!(
-                topic,
-                vec!["".to_string(), "topic".to_string(), "Hello".to_string()]
-            );
-        } else {
-            panic!("Failed to parse topic")
-        }
+    fn test_split_topic_with_leading_slash() {
+        let (levels, share) = split_topic("/topic/Hello").expect("Failed to parse topic");
+        assert!(share.is_none());
+        assert_eq!(levels.collect::<Vec<_>>(), vec!["", "topic", "Hello"]);
+    }
+
+    #[test]
+    fn test_split_topic_with_share() {
+        let (levels, share) =
+            split_topic("$share/GroupA/topic/Hello").expect("Failed to parse topic");
+        assert_eq!(share, Some("GroupA"));
+        assert_eq!(levels.collect::<Vec<_>>(), vec!["topic", "Hello"]);
+
+        assert!(split_topic("$share").is_err());
+        assert!(split_topic("$share/GroupA").is_err());
     }
 }
@@ -0,0 +1,110 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{io::AsyncWrite, time::Sleep};
+
+/// ### Write Timeout
+/// Fails a write with [`io::ErrorKind::TimedOut`] once it has been waiting on the peer for longer than the timeout.
+///
+/// A peer that stops reading without closing the connection fills the socket buffer,
+/// without a timeout every write to it would then wait forever.
+#[derive(Debug)]
+pub struct WriteTimeout<W> {
+    inner: W,
+    timeout: Option<Duration>,
+    /// Started when a write has to wait, reset once it completes
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> WriteTimeout<W> {
+    /// Wrap a writer, `None` never times out
+    pub fn new(inner: W, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    fn poll_timeout<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.deadline = None;
+            return poll;
+        }
+
+        let Some(timeout) = self.timeout else {
+            return Poll::Pending;
+        };
+
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.deadline = None;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Write timed out",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.poll_timeout(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.poll_timeout(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_shutdown(cx);
+        this.poll_timeout(cx, poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let (mut client, server) = duplex(8);
+        let mut writer = WriteTimeout::new(server, Some(Duration::from_millis(50)));
+
+        // room in the buffer
+        writer.write_all(&[0; 8]).await.unwrap();
+
+        // the peer is not reading
+        let err = writer.write_all(&[0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let mut buf = [0; 8];
+        client.read_exact(&mut buf).await.unwrap();
+        writer.write_all(&[0; 8]).await.unwrap();
+    }
+}
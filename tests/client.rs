@@ -0,0 +1,532 @@
+//! End to end tests that run the broker on a local port and talk to it with rumqttc.
+
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::Bytes;
+use mqtt_broker::{
+    config::ConfigBuilder, error::MqttError, packets::enums::QosLevel, Broker, BrokerHandle,
+};
+use rumqttc::{
+    AsyncClient, ConnectReturnCode, Event, EventLoop, Incoming, MqttOptions, Outgoing, QoS,
+};
+use tokio::{net::TcpStream, task::JoinHandle, time::timeout};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TestBroker {
+    addr: SocketAddr,
+    handle: BrokerHandle,
+    task: JoinHandle<Result<(), MqttError>>,
+}
+
+impl TestBroker {
+    /// Run a broker on a free port and wait for it to accept connections
+    async fn start() -> Self {
+        Self::start_with(ConfigBuilder::new()).await
+    }
+
+    /// Run a broker with the options of `config` on a free port
+    async fn start_with(config: ConfigBuilder) -> Self {
+        // the listener is dropped so the broker can bind the port it was given
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Failed to find a free port");
+
+        let broker = Broker::builder()
+            .config(config)
+            .bind(addr)
+            .build()
+            .expect("Failed to build broker");
+        let handle = broker.handle();
+        let task = tokio::spawn(broker.run());
+
+        timeout(TIMEOUT, async {
+            while TcpStream::connect(addr).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Broker did not start");
+
+        Self { addr, handle, task }
+    }
+
+    /// Connect a client and wait for its CONNACK
+    async fn connect(&self, client_id: &str) -> (AsyncClient, EventLoop) {
+        let (client, eventloop, _) = self.connect_session(client_id, true).await;
+        (client, eventloop)
+    }
+
+    /// Connect a client and return the Session Present flag of its CONNACK
+    async fn connect_session(
+        &self,
+        client_id: &str,
+        clean_session: bool,
+    ) -> (AsyncClient, EventLoop, bool) {
+        let mut options = MqttOptions::new(client_id, self.addr.ip().to_string(), self.addr.port());
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_clean_session(clean_session);
+
+        let (client, mut eventloop) = AsyncClient::new(options, 100);
+        let session_present = match next_incoming(&mut eventloop).await {
+            Incoming::ConnAck(ack) => {
+                assert_eq!(ack.code, ConnectReturnCode::Success);
+                ack.session_present
+            }
+            packet => panic!("Expected a CONNACK, got {:?}", packet),
+        };
+
+        (client, eventloop, session_present)
+    }
+
+    async fn stop(self) {
+        self.handle.shutdown();
+        // in-flight messages get up to `shutdown_timeout` to be acknowledged
+        timeout(TIMEOUT * 2, self.task)
+            .await
+            .expect("Broker did not shut down")
+            .expect("Failed to join broker")
+            .expect("Broker failed");
+    }
+}
+
+/// Poll the event loop until the next packet from the broker
+async fn next_incoming(eventloop: &mut EventLoop) -> Incoming {
+    loop {
+        let event = timeout(TIMEOUT, eventloop.poll())
+            .await
+            .expect("Timed out waiting for a packet")
+            .expect("Connection failed");
+
+        match event {
+            Event::Incoming(Incoming::PingResp) | Event::Outgoing(_) => {}
+            Event::Incoming(packet) => return packet,
+        }
+    }
+}
+
+/// Poll the event loop until a packet matching `f` is received, a second CONNACK fails the test
+async fn wait_for<T>(eventloop: &mut EventLoop, mut f: impl FnMut(Incoming) -> Option<T>) -> T {
+    loop {
+        match next_incoming(eventloop).await {
+            Incoming::ConnAck(_) => panic!("Received a second CONNACK"),
+            packet => {
+                if let Some(value) = f(packet) {
+                    return value;
+                }
+            }
+        }
+    }
+}
+
+async fn subscribe(client: &AsyncClient, eventloop: &mut EventLoop, filter: &str, qos: QoS) {
+    client
+        .subscribe(filter, qos)
+        .await
+        .expect("Failed to subscribe");
+    wait_for(eventloop, |packet| match packet {
+        Incoming::SubAck(ack) => Some(ack),
+        _ => None,
+    })
+    .await;
+}
+
+/// Drive the publisher's event loop in the background so its acknowledgements are handled
+fn drive(mut eventloop: EventLoop) -> JoinHandle<()> {
+    tokio::spawn(async move { while eventloop.poll().await.is_ok() {} })
+}
+
+async fn next_publish(eventloop: &mut EventLoop) -> rumqttc::Publish {
+    wait_for(eventloop, |packet| match packet {
+        Incoming::Publish(publish) => Some(publish),
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_connect() {
+    let broker = TestBroker::start().await;
+
+    let (client, mut eventloop) = broker.connect("connect").await;
+    // a SUBACK after the CONNACK shows no second CONNACK was sent in between
+    subscribe(&client, &mut eventloop, "a/b", QoS::AtMostOnce).await;
+
+    client.disconnect().await.expect("Failed to disconnect");
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_publish_qos() {
+    let broker = TestBroker::start().await;
+
+    let (subscriber, mut sub_events) = broker.connect("subscriber").await;
+    subscribe(&subscriber, &mut sub_events, "sensors/+", QoS::ExactlyOnce).await;
+
+    let (publisher, pub_events) = broker.connect("publisher").await;
+    let _publisher_task = drive(pub_events);
+
+    for (topic, qos) in [
+        ("sensors/0", QoS::AtMostOnce),
+        ("sensors/1", QoS::AtLeastOnce),
+        ("sensors/2", QoS::ExactlyOnce),
+    ] {
+        publisher
+            .publish(topic, qos, false, topic.as_bytes().to_vec())
+            .await
+            .expect("Failed to publish");
+
+        let publish = next_publish(&mut sub_events).await;
+        assert_eq!(publish.topic, topic);
+        assert_eq!(publish.qos, qos);
+        assert_eq!(publish.payload, Bytes::from(topic));
+    }
+
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_message_order() {
+    let broker = TestBroker::start().await;
+
+    let (subscriber, mut sub_events) = broker.connect("subscriber").await;
+    subscribe(&subscriber, &mut sub_events, "order", QoS::AtLeastOnce).await;
+
+    let (publisher, pub_events) = broker.connect("publisher").await;
+    let _publisher_task = drive(pub_events);
+
+    for i in 0..50u32 {
+        publisher
+            .publish("order", QoS::AtLeastOnce, false, i.to_be_bytes().to_vec())
+            .await
+            .expect("Failed to publish");
+    }
+
+    for i in 0..50u32 {
+        let publish = next_publish(&mut sub_events).await;
+        assert_eq!(publish.payload, Bytes::from(i.to_be_bytes().to_vec()));
+    }
+
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_retained() {
+    let broker = TestBroker::start().await;
+
+    let (publisher, mut pub_events) = broker.connect("publisher").await;
+    publisher
+        .publish("status", QoS::AtLeastOnce, true, "online")
+        .await
+        .expect("Failed to publish");
+    wait_for(&mut pub_events, |packet| match packet {
+        Incoming::PubAck(ack) => Some(ack),
+        _ => None,
+    })
+    .await;
+
+    let (subscriber, mut sub_events) = broker.connect("subscriber").await;
+    subscriber
+        .subscribe("status", QoS::AtLeastOnce)
+        .await
+        .expect("Failed to subscribe");
+
+    let publish = next_publish(&mut sub_events).await;
+    assert_eq!(publish.topic, "status");
+    assert!(publish.retain);
+    assert_eq!(publish.payload, Bytes::from_static(b"online"));
+
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_in_process_to_client() {
+    let broker = TestBroker::start().await;
+
+    let (subscriber, mut sub_events) = broker.connect("subscriber").await;
+    subscribe(&subscriber, &mut sub_events, "internal/#", QoS::AtLeastOnce).await;
+
+    broker
+        .handle
+        .publish(
+            "internal/event",
+            Bytes::from_static(b"data"),
+            QosLevel::AtLeast,
+        )
+        .await
+        .expect("Failed to publish");
+
+    let publish = next_publish(&mut sub_events).await;
+    assert_eq!(publish.topic, "internal/event");
+    assert_eq!(publish.payload, Bytes::from_static(b"data"));
+
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_health() {
+    let broker = TestBroker::start().await;
+
+    let health = broker.handle.health().await;
+    assert!(health.is_live());
+    assert!(health.is_ready());
+    assert_eq!(health.listeners, 1);
+
+    let handle = broker.handle.clone();
+    broker.stop().await;
+
+    // the command loop has exited
+    let health = handle.health().await;
+    assert!(!health.is_live());
+    assert!(!health.is_ready());
+    assert!(health.shutting_down);
+    assert_eq!(health.listeners, 0);
+}
+
+#[tokio::test]
+async fn test_session_present() {
+    let broker = TestBroker::start().await;
+
+    // (Clean Session, expected Session Present)
+    for (clean_session, expected) in [(false, false), (false, true), (true, false), (false, false)]
+    {
+        let (client, mut eventloop, session_present) =
+            broker.connect_session("persistent", clean_session).await;
+        assert_eq!(session_present, expected);
+
+        client.disconnect().await.expect("Failed to disconnect");
+        while let Ok(Ok(event)) = timeout(TIMEOUT, eventloop.poll()).await {
+            if matches!(event, Event::Outgoing(Outgoing::Disconnect)) {
+                break;
+            }
+        }
+    }
+
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_v5_receive_maximum() {
+    use rumqttc::v5::{
+        mqttbytes::{v5::Packet, QoS as QoS5},
+        AsyncClient as AsyncClient5, Event as Event5, MqttOptions as MqttOptions5,
+    };
+
+    let config = ConfigBuilder::new()
+        .parse("max_inflight_messages 2")
+        .expect("Failed to parse config");
+    let broker = TestBroker::start_with(config).await;
+
+    let (subscriber, mut sub_events) = broker.connect("subscriber").await;
+    subscribe(&subscriber, &mut sub_events, "burst", QoS::ExactlyOnce).await;
+
+    let mut options = MqttOptions5::new(
+        "publisher",
+        broker.addr.ip().to_string(),
+        broker.addr.port(),
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    let (publisher, mut pub_events) = AsyncClient5::new(options, 100);
+
+    let connack = loop {
+        let event = timeout(TIMEOUT, pub_events.poll())
+            .await
+            .expect("Timed out waiting for a packet")
+            .expect("Connection failed");
+        if let Event5::Incoming(Packet::ConnAck(connack)) = event {
+            break connack;
+        }
+    };
+    let receive_maximum = connack
+        .properties
+        .and_then(|properties| properties.receive_max);
+    assert_eq!(receive_maximum, Some(2));
+
+    // The client keeps to the Receive Maximum, so every message goes through without a DISCONNECT
+    for i in 0..10u32 {
+        publisher
+            .publish("burst", QoS5::ExactlyOnce, false, i.to_be_bytes().to_vec())
+            .await
+            .expect("Failed to publish");
+    }
+    let _publisher_task = tokio::spawn(async move { while pub_events.poll().await.is_ok() {} });
+
+    for i in 0..10u32 {
+        let publish = next_publish(&mut sub_events).await;
+        assert_eq!(publish.payload, Bytes::from(i.to_be_bytes().to_vec()));
+    }
+
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_direct_delivery() {
+    let config = ConfigBuilder::new()
+        .parse("direct_delivery true")
+        .expect("Failed to parse config");
+    let broker = TestBroker::start_with(config).await;
+
+    let (first, mut first_events) = broker.connect("first").await;
+    subscribe(&first, &mut first_events, "fast", QoS::AtMostOnce).await;
+
+    let (publisher, pub_events) = broker.connect("publisher").await;
+    let _publisher_task = drive(pub_events);
+
+    // the first messages go through the command loop until the route is given, the order is kept
+    for i in 0..50u32 {
+        publisher
+            .publish("fast", QoS::AtMostOnce, false, i.to_be_bytes().to_vec())
+            .await
+            .expect("Failed to publish");
+    }
+    for i in 0..50u32 {
+        let publish = next_publish(&mut first_events).await;
+        assert_eq!(publish.payload, Bytes::from(i.to_be_bytes().to_vec()));
+    }
+
+    // a second subscriber drops the route
+    let (second, mut second_events) = broker.connect("second").await;
+    subscribe(&second, &mut second_events, "fast", QoS::AtMostOnce).await;
+    publisher
+        .publish("fast", QoS::AtMostOnce, false, "both")
+        .await
+        .expect("Failed to publish");
+    for events in [&mut first_events, &mut second_events] {
+        let publish = next_publish(events).await;
+        assert_eq!(publish.payload, Bytes::from_static(b"both"));
+    }
+
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_subscription_quota() {
+    use rumqttc::{SubscribeFilter, SubscribeReasonCode};
+
+    let config = ConfigBuilder::new()
+        .parse("max_subscriptions_per_client 2")
+        .expect("Failed to parse config");
+    let broker = TestBroker::start_with(config).await;
+    let (client, mut eventloop) = broker.connect("limited").await;
+
+    let subscribe_many = |filters: &[(&str, QoS)]| {
+        let client = client.clone();
+        let filters: Vec<_> = filters
+            .iter()
+            .map(|(filter, qos)| SubscribeFilter::new(filter.to_string(), *qos))
+            .collect();
+        async move {
+            client
+                .subscribe_many(filters)
+                .await
+                .expect("Failed to subscribe");
+        }
+    };
+
+    // the filters before the limit are granted, the ones after it fail
+    subscribe_many(&[
+        ("a", QoS::AtMostOnce),
+        ("b", QoS::AtLeastOnce),
+        ("c", QoS::AtMostOnce),
+    ])
+    .await;
+    let ack = wait_for(&mut eventloop, |packet| match packet {
+        Incoming::SubAck(ack) => Some(ack),
+        _ => None,
+    })
+    .await;
+    assert_eq!(
+        ack.return_codes,
+        [
+            SubscribeReasonCode::Success(QoS::AtMostOnce),
+            SubscribeReasonCode::Success(QoS::AtLeastOnce),
+            SubscribeReasonCode::Failure,
+        ]
+    );
+
+    // replacing a subscription doesn't count towards the limit, a new one still fails
+    subscribe_many(&[("a", QoS::AtLeastOnce), ("d", QoS::AtMostOnce)]).await;
+    let ack = wait_for(&mut eventloop, |packet| match packet {
+        Incoming::SubAck(ack) => Some(ack),
+        _ => None,
+    })
+    .await;
+    assert_eq!(
+        ack.return_codes,
+        [
+            SubscribeReasonCode::Success(QoS::AtLeastOnce),
+            SubscribeReasonCode::Failure,
+        ]
+    );
+
+    // unsubscribing frees a place
+    client
+        .unsubscribe("b")
+        .await
+        .expect("Failed to unsubscribe");
+    wait_for(&mut eventloop, |packet| match packet {
+        Incoming::UnsubAck(ack) => Some(ack),
+        _ => None,
+    })
+    .await;
+    subscribe_many(&[("d", QoS::AtMostOnce)]).await;
+    let ack = wait_for(&mut eventloop, |packet| match packet {
+        Incoming::SubAck(ack) => Some(ack),
+        _ => None,
+    })
+    .await;
+    assert_eq!(
+        ack.return_codes,
+        [SubscribeReasonCode::Success(QoS::AtMostOnce)]
+    );
+
+    broker.stop().await;
+}
+
+#[tokio::test]
+async fn test_shared_and_own_subscription() {
+    let broker = TestBroker::start().await;
+
+    let (worker_1, mut events_1) = broker.connect("worker-1").await;
+    subscribe(&worker_1, &mut events_1, "jobs/#", QoS::AtLeastOnce).await;
+    subscribe(
+        &worker_1,
+        &mut events_1,
+        "$share/workers/jobs/+",
+        QoS::AtLeastOnce,
+    )
+    .await;
+    let (worker_2, mut events_2) = broker.connect("worker-2").await;
+    subscribe(
+        &worker_2,
+        &mut events_2,
+        "$share/workers/jobs/+",
+        QoS::AtLeastOnce,
+    )
+    .await;
+
+    let (publisher, pub_events) = broker.connect("publisher").await;
+    let _publisher_task = drive(pub_events);
+    for i in 0..4u8 {
+        publisher
+            .publish(format!("jobs/{}", i), QoS::AtLeastOnce, false, vec![i])
+            .await
+            .expect("Failed to publish");
+    }
+
+    // worker-1 gets every message for its own subscription, and takes turns with worker-2 for the group's
+    let mut received = Vec::new();
+    for _ in 0..6 {
+        received.push(next_publish(&mut events_1).await.payload[0]);
+    }
+    received.sort();
+    assert_eq!(received, [0, 0, 1, 2, 2, 3]);
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        received.push(next_publish(&mut events_2).await.payload[0]);
+    }
+    assert_eq!(received, [1, 3]);
+
+    broker.stop().await;
+}